@@ -0,0 +1,128 @@
+"""Export a trained OpenFF NAGL model to the JSON format read by
+`GnnModel::load`, along with an SD file of reference molecules carrying the
+charges NAGL assigns them in an `atom.dprop.PartialCharge` tag.
+
+    python export-nagl.py openff-gnn-am1bcc-0.1.0-rc.3.pt ../testfiles/nagl
+
+The SMILES to use as references can be passed with --smiles, one per line in
+a file.
+"""
+
+import argparse
+import json
+from pathlib import Path
+
+from openff.nagl import GNNModel
+from openff.toolkit import Molecule
+from rdkit import Chem
+
+SMILES = [
+    "CCO",
+    "CC(=O)[O-]",
+    "NC(=[NH2+])N",
+    "CC(=O)NC",
+    "c1ccncc1",
+    "c1cc[nH]c1",
+    "O=[N+]([O-])c1ccccc1",
+    "CS(=O)(=O)N",
+    "C[NH3+]",
+    "FC(F)(F)c1ccc(Cl)cc1Br",
+    "OP(=O)(O)O",
+    "Ic1ccccc1",
+]
+
+ACTIVATIONS = {"ReLU", "Sigmoid", "Tanh", "Identity"}
+
+
+def linear(layer):
+    ret = {"weight": layer.weight.detach().tolist()}
+    if layer.bias is not None:
+        ret["bias"] = layer.bias.detach().tolist()
+    return ret
+
+
+def activation(module):
+    name = type(module).__name__
+    if name not in ACTIVATIONS:
+        raise ValueError(f"unsupported activation {name}")
+    return name
+
+
+def export_model(model):
+    features = []
+    for feature in model.config.model.atom_features:
+        features.append(feature.dict())
+
+    convolution = []
+    conv_config = model.config.model.convolution_module
+    if conv_config.architecture != "SAGEConv":
+        raise ValueError(f"unsupported convolution {conv_config.architecture}")
+    hidden = model.convolution_module.gcn_layers.hidden_layers
+    for layer, config in zip(hidden, conv_config.layers):
+        sage = layer.gcn_layer
+        if sage._aggre_type != "mean":
+            raise ValueError(f"unsupported aggregation {sage._aggre_type}")
+        convolution.append(
+            {
+                "fc_self": linear(sage.fc_self),
+                "fc_neigh": linear(sage.fc_neigh),
+                "bias": None if sage.bias is None else sage.bias.tolist(),
+                "activation": config.activation_function,
+            }
+        )
+
+    (readout_module,) = model.readout_modules.values()
+    if type(readout_module.postprocess_layer).__name__ != "ComputePartialCharges":
+        raise ValueError("the readout does not compute partial charges")
+    readout = []
+    pending = None
+    for module in readout_module.readout_layers.sequential:
+        if type(module).__name__ == "Linear":
+            if pending is not None:
+                readout.append(pending)
+            pending = {**linear(module), "activation": "Identity"}
+        elif type(module).__name__ == "Dropout":
+            continue
+        else:
+            pending["activation"] = activation(module)
+    readout.append(pending)
+
+    return {
+        "atom_features": features,
+        "convolution": convolution,
+        "readout": readout,
+    }
+
+
+def main():
+    parser = argparse.ArgumentParser()
+    parser.add_argument("model")
+    parser.add_argument("output", type=Path)
+    parser.add_argument("--smiles", type=Path)
+    args = parser.parse_args()
+
+    model = GNNModel.load(args.model, eval_mode=True)
+    args.output.mkdir(parents=True, exist_ok=True)
+    with open(args.output / "model.json", "w") as out:
+        json.dump(export_model(model), out)
+
+    smiles = SMILES
+    if args.smiles is not None:
+        smiles = args.smiles.read_text().split()
+    writer = Chem.SDWriter(str(args.output / "reference.sdf"))
+    writer.SetKekulize(True)
+    for s in smiles:
+        molecule = Molecule.from_smiles(s)
+        molecule.generate_conformers(n_conformers=1)
+        charges = model.compute_property(molecule, as_numpy=True)
+        rdmol = molecule.to_rdkit()
+        rdmol.SetProp("_Name", s)
+        rdmol.SetProp(
+            "atom.dprop.PartialCharge", " ".join(f"{q:.8f}" for q in charges)
+        )
+        writer.write(rdmol)
+    writer.close()
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,505 @@
+//! Benchmarking force fields against QM optimization datasets, in the style of
+//! the yammbs and ib-benchmark packages. For each QM-optimized conformer in a
+//! dataset, the MM energy is minimized starting from the QM geometry, and the
+//! two are compared with three metrics:
+//!
+//! - ddE, the difference between the MM and QM conformer energies, each taken
+//!   relative to the conformer that is the QM global minimum for its molecule
+//! - the heavy-atom RMSD between the QM and MM geometries after Kabsch
+//!   alignment, minimized over symmetry-equivalent atom orderings
+//! - the torsion fingerprint deviation (TFD) of Schulz-Gahbauer and Lindsay,
+//!   J. Chem. Inf. Model. 52, 1499 (2012), over the rotatable bonds
+//!
+//! Energies are reported in kcal/mol and RMSDs in angstroms.
+
+use std::{collections::HashMap, error::Error, fs::write, path::Path};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    interchange::Minimized,
+    qcportal::models::Record,
+    qcsubmit::results::ResultCollection,
+    smirnoff::ForceField,
+    topology::{
+        molecule::{Atom, Bond, MoleculeGraph},
+        Topology,
+    },
+    utils::{
+        geometry::{dihedral, kabsch_rmsd},
+        Skipped, BOHR_TO_ANGSTROM, HARTREE_TO_KCAL,
+    },
+};
+
+pub mod torsion;
+
+#[cfg(test)]
+mod tests;
+
+/// The maximum number of symmetry-equivalent atom orderings to consider when
+/// computing RMSDs
+const MAX_AUTOMORPHISMS: usize = 1000;
+
+/// A single QM-optimized conformer
+#[derive(Clone, Debug)]
+pub struct QmConformer {
+    pub record_id: String,
+
+    /// final energy in kcal/mol
+    pub energy: f64,
+
+    /// final geometry in angstroms
+    pub positions: Vec<f64>,
+
+    /// QM gradient at the final geometry in kcal/mol/angstrom, if known
+    pub gradient: Option<Vec<f64>>,
+}
+
+/// All of the conformers of a single molecule in the dataset
+struct MoleculeRecords {
+    molecule: Molecule,
+    graph: MoleculeGraph,
+    automorphisms: Vec<Vec<usize>>,
+    conformers: Vec<QmConformer>,
+}
+
+/// The comparison between the QM and MM results for a single record
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordResult {
+    pub record_id: String,
+    pub force_field: String,
+
+    /// index of the molecule this record belongs to in the [Benchmark]
+    pub molecule: usize,
+
+    /// QM energy relative to the QM global minimum for the molecule
+    pub qm_energy: f64,
+
+    /// MM energy relative to the MM energy of the QM global minimum conformer
+    pub mm_energy: f64,
+
+    pub dde: f64,
+    pub rmsd: f64,
+    pub tfd: f64,
+}
+
+/// Aggregate statistics over all of the records for one force field
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Summary {
+    pub force_field: String,
+    pub n_records: usize,
+    pub mean_dde: f64,
+    pub mean_abs_dde: f64,
+    pub rmse_dde: f64,
+    pub mean_rmsd: f64,
+    pub median_rmsd: f64,
+    pub mean_tfd: f64,
+    pub median_tfd: f64,
+}
+
+/// The results of comparing one or more force fields on a [Benchmark]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Report {
+    pub records: Vec<RecordResult>,
+    pub summaries: Vec<Summary>,
+
+    /// the records left out of the benchmark or of a force field's results
+    #[serde(default)]
+    pub skipped: Vec<Skipped>,
+}
+
+pub struct Benchmark {
+    molecules: Vec<MoleculeRecords>,
+
+    /// the records left out because they have no conformer
+    pub skipped: Vec<Skipped>,
+}
+
+impl Benchmark {
+    /// Group `records`, as returned by [ResultCollection::to_records], into
+    /// molecules. Records are considered to be conformers of the same molecule
+    /// if their molecules have identical graphs, including atom ordering,
+    /// which is the case for records sharing a CMILES.
+    pub fn new(records: Vec<(Record, Molecule)>) -> Self {
+        let (molecules, skipped) = group_conformers(records);
+        let molecules = molecules
+            .into_iter()
+            .map(|(molecule, graph, conformers)| MoleculeRecords {
+                molecule,
+                automorphisms: graph
+                    .heavy_atom_automorphisms(MAX_AUTOMORPHISMS),
+                graph,
+                conformers,
+            })
+            .collect();
+        Self { molecules, skipped }
+    }
+
+    /// Download the records in `dataset` and group them with [Benchmark::new]
+    pub fn from_dataset(dataset: ResultCollection) -> Self {
+        Self::new(dataset.to_records())
+    }
+
+    /// The number of distinct molecules in the benchmark
+    pub fn n_molecules(&self) -> usize {
+        self.molecules.len()
+    }
+
+    /// The total number of conformers in the benchmark
+    pub fn len(&self) -> usize {
+        self.molecules.iter().map(|m| m.conformers.len()).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Minimize every conformer with `force_field` and compare the results to
+    /// QM, labeling the results with `name`. The records of molecules that
+    /// cannot be parameterized are returned separately.
+    pub fn run(
+        &self,
+        name: &str,
+        force_field: &ForceField,
+    ) -> (Vec<RecordResult>, Vec<Skipped>) {
+        let mut ret = Vec::new();
+        let mut skipped = Vec::new();
+        for (m, mol) in self.molecules.iter().enumerate() {
+            let topology = Topology::from_molecules(vec![mol.molecule.clone()]);
+            let interchange = match force_field.create_interchange(&topology) {
+                Ok(i) => i,
+                Err(e) => {
+                    skipped.extend(mol.conformers.iter().map(|c| {
+                        Skipped::new(&c.record_id, format!("{name}: {e}"))
+                    }));
+                    continue;
+                }
+            };
+            let minimized: Vec<_> = mol
+                .conformers
+                .iter()
+                .map(|c| interchange.minimize(&c.positions))
+                .collect();
+            ret.extend(compare(
+                name,
+                m,
+                &mol.graph,
+                &mol.automorphisms,
+                &mol.conformers,
+                &minimized,
+            ));
+        }
+        (ret, skipped)
+    }
+
+    /// Run the benchmark for each of the named force fields in `force_fields`
+    /// and summarize the results, along with the records left out of the
+    /// benchmark and those skipped by each force field
+    pub fn compare(&self, force_fields: &[(&str, &ForceField)]) -> Report {
+        let mut records = Vec::new();
+        let mut skipped = self.skipped.clone();
+        for (name, ff) in force_fields {
+            let (r, s) = self.run(name, ff);
+            records.extend(r);
+            skipped.extend(s);
+        }
+        let summaries = summarize(&records);
+        Report {
+            records,
+            summaries,
+            skipped,
+        }
+    }
+}
+
+/// The conformers of each molecule as returned by [group_conformers]
+pub(crate) type Grouped = Vec<(Molecule, MoleculeGraph, Vec<QmConformer>)>;
+
+/// Group `records` into molecules with identical graphs, returning each
+/// molecule, its graph, and its QM conformers, along with the records without
+/// a conformer
+pub(crate) fn group_conformers(
+    records: Vec<(Record, Molecule)>,
+) -> (Grouped, Vec<Skipped>) {
+    let mut index: HashMap<(Vec<Atom>, Vec<Bond>), usize> = HashMap::new();
+    let mut ret: Grouped = Vec::new();
+    let mut skipped = Vec::new();
+    for (record, molecule) in records {
+        let mut graph = MoleculeGraph::from(&molecule);
+        let Some(positions) = graph.conformers.pop() else {
+            skipped.push(Skipped::new(record.id, "no conformer"));
+            continue;
+        };
+        let conformer = QmConformer {
+            energy: record.get_final_energy() * HARTREE_TO_KCAL,
+            gradient: record.gradient.as_ref().map(|g| {
+                g.iter()
+                    .map(|x| x * HARTREE_TO_KCAL / BOHR_TO_ANGSTROM)
+                    .collect()
+            }),
+            record_id: record.id,
+            positions,
+        };
+        let key = (graph.atoms.clone(), graph.bonds.clone());
+        match index.get(&key) {
+            Some(&i) => ret[i].2.push(conformer),
+            None => {
+                index.insert(key, ret.len());
+                ret.push((molecule, graph, vec![conformer]));
+            }
+        }
+    }
+    (ret, skipped)
+}
+
+/// Compare the QM `conformers` of a single molecule with their MM-minimized
+/// counterparts in `minimized`
+pub(crate) fn compare(
+    force_field: &str,
+    molecule: usize,
+    graph: &MoleculeGraph,
+    automorphisms: &[Vec<usize>],
+    conformers: &[QmConformer],
+    minimized: &[Minimized],
+) -> Vec<RecordResult> {
+    let Some(reference) = conformers
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.energy.total_cmp(&b.energy))
+        .map(|(i, _)| i)
+    else {
+        return Vec::new();
+    };
+    let heavy = graph.heavy_atoms();
+    conformers
+        .iter()
+        .zip(minimized)
+        .map(|(qm, mm)| {
+            let qm_energy = qm.energy - conformers[reference].energy;
+            let mm_energy = mm.energy - minimized[reference].energy;
+            RecordResult {
+                record_id: qm.record_id.clone(),
+                force_field: force_field.to_owned(),
+                molecule,
+                qm_energy,
+                mm_energy,
+                dde: mm_energy - qm_energy,
+                rmsd: symmetric_rmsd(
+                    &heavy,
+                    automorphisms,
+                    &qm.positions,
+                    &mm.positions,
+                ),
+                tfd: torsion_fingerprint_deviation(
+                    graph,
+                    &qm.positions,
+                    &mm.positions,
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Return the smallest Kabsch RMSD between the `atoms` of `a` and `b` over all
+/// of the atom orderings in `automorphisms`
+pub fn symmetric_rmsd(
+    atoms: &[usize],
+    automorphisms: &[Vec<usize>],
+    a: &[f64],
+    b: &[f64],
+) -> f64 {
+    automorphisms
+        .iter()
+        .map(|map| kabsch_rmsd(a, b, atoms, Some(map)))
+        .fold(kabsch_rmsd(a, b, atoms, None), f64::min)
+}
+
+/// Return the torsion fingerprint deviation between two conformers of `graph`.
+/// Each rotatable bond contributes the circular difference in one of its
+/// dihedrals, normalized by 180°, defined by the heaviest heavy-atom neighbor
+/// on either side (ties broken by the lower index). Contributions are weighted
+/// by `exp(-β d²)`, where `d` is the topological distance of the bond from the
+/// most central bond and `β` is chosen so that the most distant bond has a
+/// weight of 0.1. Ring torsions are not included. Returns 0 for molecules
+/// without rotatable bonds.
+pub fn torsion_fingerprint_deviation(
+    graph: &MoleculeGraph,
+    a: &[f64],
+    b: &[f64],
+) -> f64 {
+    let rotatable = graph.rotatable_bonds();
+    if rotatable.is_empty() {
+        return 0.0;
+    }
+    let distances = graph.topological_distances();
+    let heavy = graph.heavy_atoms();
+
+    // distance from bond `b` to atom `i`
+    let bond_distance =
+        |b: &Bond, i: usize| distances[b.atom1][i].min(distances[b.atom2][i]);
+    // the central bond is the one whose farthest heavy atom is nearest
+    let central = graph
+        .bonds
+        .iter()
+        .filter(|b| {
+            graph.atoms[b.atom1].atomic_number != 1
+                && graph.atoms[b.atom2].atomic_number != 1
+        })
+        .min_by_key(|b| heavy.iter().map(|&i| bond_distance(b, i)).max())
+        .unwrap();
+
+    let reference = |atom: usize, exclude: usize| {
+        graph
+            .neighbors(atom)
+            .filter(|&n| n != exclude && graph.atoms[n].atomic_number != 1)
+            .max_by_key(|&n| (graph.atoms[n].atomic_number, usize::MAX - n))
+            .unwrap()
+    };
+
+    let bond_distances: Vec<usize> = rotatable
+        .iter()
+        .map(|&r| {
+            let bond = &graph.bonds[r];
+            bond_distance(central, bond.atom1)
+                .min(bond_distance(central, bond.atom2))
+        })
+        .collect();
+    let dmax = *bond_distances.iter().max().unwrap();
+    let beta = if dmax == 0 {
+        0.0
+    } else {
+        -(0.1f64.ln()) / (dmax * dmax) as f64
+    };
+
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for (&r, &d) in rotatable.iter().zip(&bond_distances) {
+        let Bond {
+            atom1: j, atom2: k, ..
+        } = graph.bonds[r];
+        let (i, l) = (reference(j, k), reference(k, j));
+        let diff = (dihedral(a, i, j, k, l) - dihedral(b, i, j, k, l))
+            .to_degrees()
+            .abs();
+        let diff = diff.min(360.0 - diff);
+        let w = (-beta * (d * d) as f64).exp();
+        num += w * diff / 180.0;
+        den += w;
+    }
+    num / den
+}
+
+fn mean(v: &[f64]) -> f64 {
+    v.iter().sum::<f64>() / v.len() as f64
+}
+
+fn median(v: &[f64]) -> f64 {
+    let mut v = v.to_vec();
+    v.sort_by(f64::total_cmp);
+    let n = v.len();
+    if n.is_multiple_of(2) {
+        (v[n / 2 - 1] + v[n / 2]) / 2.0
+    } else {
+        v[n / 2]
+    }
+}
+
+/// Aggregate `records` by force field, in the order the force fields first
+/// appear
+pub fn summarize(records: &[RecordResult]) -> Vec<Summary> {
+    let mut order = Vec::new();
+    let mut groups: HashMap<&str, Vec<&RecordResult>> = HashMap::new();
+    for r in records {
+        groups
+            .entry(&r.force_field)
+            .or_insert_with(|| {
+                order.push(r.force_field.as_str());
+                Vec::new()
+            })
+            .push(r);
+    }
+    order
+        .into_iter()
+        .map(|ff| {
+            let group = &groups[ff];
+            let dde: Vec<f64> = group.iter().map(|r| r.dde).collect();
+            let abs: Vec<f64> = dde.iter().map(|d| d.abs()).collect();
+            let sq: Vec<f64> = dde.iter().map(|d| d * d).collect();
+            let rmsd: Vec<f64> = group.iter().map(|r| r.rmsd).collect();
+            let tfd: Vec<f64> = group.iter().map(|r| r.tfd).collect();
+            Summary {
+                force_field: ff.to_owned(),
+                n_records: group.len(),
+                mean_dde: mean(&dde),
+                mean_abs_dde: mean(&abs),
+                rmse_dde: mean(&sq).sqrt(),
+                mean_rmsd: mean(&rmsd),
+                median_rmsd: median(&rmsd),
+                mean_tfd: mean(&tfd),
+                median_tfd: median(&tfd),
+            }
+        })
+        .collect()
+}
+
+impl Report {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(&self)
+    }
+
+    /// Return the per-record results as CSV
+    pub fn records_csv(&self) -> String {
+        let mut ret = String::from(
+            "record_id,force_field,molecule,qm_energy,mm_energy,dde,rmsd,tfd\n",
+        );
+        for r in &self.records {
+            ret.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                r.record_id,
+                r.force_field,
+                r.molecule,
+                r.qm_energy,
+                r.mm_energy,
+                r.dde,
+                r.rmsd,
+                r.tfd
+            ));
+        }
+        ret
+    }
+
+    /// Return the per-force field summaries as CSV
+    pub fn summary_csv(&self) -> String {
+        let mut ret = String::from(
+            "force_field,n_records,mean_dde,mean_abs_dde,rmse_dde,\
+             mean_rmsd,median_rmsd,mean_tfd,median_tfd\n",
+        );
+        for s in &self.summaries {
+            ret.push_str(&format!(
+                "{},{},{},{},{},{},{},{},{}\n",
+                s.force_field,
+                s.n_records,
+                s.mean_dde,
+                s.mean_abs_dde,
+                s.rmse_dde,
+                s.mean_rmsd,
+                s.median_rmsd,
+                s.mean_tfd,
+                s.median_tfd
+            ));
+        }
+        ret
+    }
+
+    /// Write the per-record results to `records.csv`, the summaries to
+    /// `summary.csv`, and the whole report to `report.json` in `dir`
+    pub fn write(&self, dir: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let dir = dir.as_ref();
+        write(dir.join("records.csv"), self.records_csv())?;
+        write(dir.join("summary.csv"), self.summary_csv())?;
+        write(dir.join("report.json"), self.to_json()?)?;
+        Ok(())
+    }
+}
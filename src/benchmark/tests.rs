@@ -0,0 +1,112 @@
+use crate::testing::graph;
+
+use super::*;
+
+/// the carbon skeleton of butane, without hydrogens
+fn butane_skeleton() -> MoleculeGraph {
+    graph(&[6; 4], &[(0, 1, 1), (1, 2, 1), (2, 3, 1)])
+}
+
+/// butane positions with a C-C-C-C dihedral of `phi` degrees
+fn positions(phi: f64) -> Vec<f64> {
+    let phi = phi.to_radians();
+    vec![
+        1.0,
+        1.0,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        1.5,
+        0.0,
+        0.0,
+        0.5,
+        phi.cos(),
+        phi.sin(),
+    ]
+}
+
+#[test]
+fn tfd() {
+    let mol = butane_skeleton();
+    let got = torsion_fingerprint_deviation(
+        &mol,
+        &positions(180.0),
+        &positions(180.0),
+    );
+    assert_eq!(got, 0.0);
+
+    let got = torsion_fingerprint_deviation(
+        &mol,
+        &positions(180.0),
+        &positions(60.0),
+    );
+    assert!((got - 120.0 / 180.0).abs() < 1e-10, "{got}");
+
+    // the circular difference is used across the ±180° boundary
+    let got = torsion_fingerprint_deviation(
+        &mol,
+        &positions(170.0),
+        &positions(-170.0),
+    );
+    assert!((got - 20.0 / 180.0).abs() < 1e-10, "{got}");
+}
+
+#[test]
+fn symmetric() {
+    // swapping the two identical ends of butane is an automorphism
+    let mol = butane_skeleton();
+    let autos = mol.heavy_atom_automorphisms(MAX_AUTOMORPHISMS);
+    assert_eq!(autos, vec![vec![0, 1, 2, 3], vec![3, 2, 1, 0]]);
+
+    let a = positions(180.0);
+    let mut b = a.clone();
+    // relabel b so that atom 0 is where atom 3 was and so on
+    for i in 0..4 {
+        b[3 * i..3 * i + 3].copy_from_slice(&a[3 * (3 - i)..3 * (3 - i) + 3]);
+    }
+    let got = symmetric_rmsd(&mol.heavy_atoms(), &autos, &a, &b);
+    assert!(got < 1e-8, "{got}");
+}
+
+#[test]
+fn dde() {
+    let mol = butane_skeleton();
+    let qm = [(-1.0, 180.0), (0.0, 60.0)].map(|(energy, phi)| QmConformer {
+        record_id: format!("{phi}"),
+        energy,
+        positions: positions(phi),
+        gradient: None,
+    });
+    let mm = [2.0, 4.5].map(|energy| Minimized {
+        energy,
+        positions: positions(180.0),
+        converged: true,
+    });
+    let autos = mol.heavy_atom_automorphisms(MAX_AUTOMORPHISMS);
+    let got = compare("ff", 0, &mol, &autos, &qm, &mm);
+    assert_eq!(got[0].dde, 0.0);
+    assert_eq!(got[1].qm_energy, 1.0);
+    assert_eq!(got[1].mm_energy, 2.5);
+    assert_eq!(got[1].dde, 1.5);
+    assert!(got[0].rmsd < 1e-8);
+    assert!(got[1].rmsd > 0.1);
+
+    let summary = summarize(&got);
+    assert_eq!(summary.len(), 1);
+    assert_eq!(summary[0].n_records, 2);
+    assert_eq!(summary[0].mean_abs_dde, 0.75);
+
+    let report = Report {
+        records: got,
+        summaries: summary,
+        skipped: Vec::new(),
+    };
+    let csv = report.records_csv();
+    assert_eq!(csv.lines().count(), 3);
+    assert!(csv
+        .lines()
+        .nth(2)
+        .unwrap()
+        .starts_with("60,ff,0,1,2.5,1.5,"));
+}
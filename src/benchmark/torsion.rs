@@ -0,0 +1,435 @@
+//! Torsion profile benchmarks against QCArchive TorsionDrives. Each grid point
+//! of a QM torsion scan is reproduced with the force field, either as a single
+//! point at the QM geometry ([ScanMode::Rigid]) or by minimizing everything
+//! but the driven dihedral starting from the QM geometry
+//! ([ScanMode::Relaxed]).
+
+use std::{error::Error, fmt::Display};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    interchange::Interchange,
+    qcsubmit::{
+        client::{FractalClient, TorsionDriveRecord},
+        results::ResultCollection,
+    },
+    smirnoff::ForceField,
+    topology::{molecule::MoleculeGraph, Topology},
+    utils::{
+        geometry::{accumulate, dihedral, dihedral_gradient, kabsch_rmsd},
+        Skipped, BOHR_TO_ANGSTROM, HARTREE_TO_KCAL,
+    },
+};
+
+/// Force constant of the harmonic restraint holding the driven dihedral at its
+/// grid value during relaxed scans, in kcal/mol/rad²
+const RESTRAINT_K: f64 = 1000.0;
+
+/// How the MM energy at each grid point is obtained
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum ScanMode {
+    /// evaluate the MM energy at the QM geometry
+    Rigid,
+
+    /// restrain the driven dihedral to the grid angle and minimize the rest of
+    /// the molecule, starting from the QM geometry
+    Relaxed,
+}
+
+#[derive(Debug)]
+pub struct TorsionScanError {
+    pub record_id: String,
+    pub message: String,
+}
+
+impl Error for TorsionScanError {}
+
+impl Display for TorsionScanError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "torsion drive {}: {}", self.record_id, self.message)
+    }
+}
+
+/// The QM results for a one-dimensional torsion drive, sorted by grid angle
+#[derive(Clone, Debug)]
+pub struct TorsionScan {
+    pub record_id: String,
+
+    /// the 0-based indices of the driven dihedral
+    pub dihedral: [usize; 4],
+
+    /// grid angles in degrees
+    pub angles: Vec<f64>,
+
+    /// final energies in kcal/mol
+    pub energies: Vec<f64>,
+
+    /// final geometries in angstroms
+    pub positions: Vec<Vec<f64>>,
+}
+
+impl TorsionScan {
+    /// Build a [TorsionScan] from `record` and its grid `geometries`, as
+    /// returned by [FractalClient::torsion_drive_records]. The geometries are
+    /// expected in bohr and sorted by grid angle.
+    pub fn new(
+        record: &TorsionDriveRecord,
+        geometries: Vec<Vec<f64>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let err = |message: String| TorsionScanError {
+            record_id: record.id.clone(),
+            message,
+        };
+        let &[(i, j, k, l)] = record.dihedrals() else {
+            return Err(Box::new(err(format!(
+                "expected 1 driven dihedral, found {}",
+                record.dihedrals().len()
+            ))));
+        };
+
+        let mut grid = Vec::with_capacity(record.final_energies.len());
+        for (grid_id, energy) in &record.final_energies {
+            let x: &[_] = &['[', ']'];
+            let angle: f64 = grid_id.trim_matches(x).parse().map_err(|_| {
+                err(format!("failed to parse grid id {grid_id}"))
+            })?;
+            grid.push((angle, energy * HARTREE_TO_KCAL));
+        }
+        grid.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        if grid.len() != geometries.len() {
+            return Err(Box::new(err(format!(
+                "{} grid energies but {} geometries",
+                grid.len(),
+                geometries.len()
+            ))));
+        }
+
+        let (angles, energies) = grid.into_iter().unzip();
+        Ok(Self {
+            record_id: record.id.clone(),
+            dihedral: [i, j, k, l],
+            angles,
+            energies,
+            positions: geometries
+                .into_iter()
+                .map(|g| g.into_iter().map(|x| x * BOHR_TO_ANGSTROM).collect())
+                .collect(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.angles.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The QM and MM torsion profiles for a single torsion drive
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TorsionProfile {
+    pub record_id: String,
+    pub force_field: String,
+    pub mode: ScanMode,
+    pub dihedral: [usize; 4],
+
+    /// grid angles in degrees
+    pub angles: Vec<f64>,
+
+    /// QM energies relative to the QM minimum
+    pub qm_energies: Vec<f64>,
+
+    /// MM energies relative to the MM energy at the QM minimum
+    pub mm_energies: Vec<f64>,
+
+    /// the final value of the driven dihedral in the MM geometries, in degrees
+    pub mm_angles: Vec<f64>,
+
+    /// heavy-atom RMSD between the QM and MM geometries at each grid point.
+    /// These are all zero for rigid scans
+    pub rmsd: Vec<f64>,
+
+    /// root-mean-square error between the relative MM and QM energies
+    pub rmse: f64,
+
+    pub mean_rmsd: f64,
+}
+
+impl TorsionProfile {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(&self)
+    }
+}
+
+/// Compute the MM torsion profile for `scan` using `interchange`, labeling the
+/// result with `force_field`. `heavy_atoms` are the atoms used for the RMSD
+/// between the QM and MM geometries.
+pub fn mm_profile(
+    force_field: &str,
+    interchange: &Interchange,
+    heavy_atoms: &[usize],
+    scan: &TorsionScan,
+    mode: ScanMode,
+) -> TorsionProfile {
+    let [i, j, k, l] = scan.dihedral;
+    let mut mm = Vec::with_capacity(scan.len());
+    let mut mm_angles = Vec::with_capacity(scan.len());
+    let mut rmsd = Vec::with_capacity(scan.len());
+    for (angle, positions) in scan.angles.iter().zip(&scan.positions) {
+        let (energy, final_positions) = match mode {
+            ScanMode::Rigid => {
+                (interchange.energy(positions), positions.clone())
+            }
+            ScanMode::Relaxed => {
+                let target = angle.to_radians();
+                let min = interchange.minimize_with(positions, |x, grad| {
+                    let (phi, g) = dihedral_gradient(x, scan.dihedral);
+                    let d = wrap(phi - target);
+                    for (&a, g) in scan.dihedral.iter().zip(g) {
+                        accumulate(grad, a, RESTRAINT_K * d * g);
+                    }
+                    0.5 * RESTRAINT_K * d * d
+                });
+                (min.energy, min.positions)
+            }
+        };
+        mm.push(energy);
+        mm_angles.push(dihedral(&final_positions, i, j, k, l).to_degrees());
+        rmsd.push(kabsch_rmsd(positions, &final_positions, heavy_atoms, None));
+    }
+
+    let reference = scan
+        .energies
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    let qm_energies: Vec<f64> = scan
+        .energies
+        .iter()
+        .map(|e| e - scan.energies[reference])
+        .collect();
+    let mm_energies: Vec<f64> = mm.iter().map(|e| e - mm[reference]).collect();
+
+    let n = scan.len().max(1) as f64;
+    let rmse = (qm_energies
+        .iter()
+        .zip(&mm_energies)
+        .map(|(q, m)| (m - q).powi(2))
+        .sum::<f64>()
+        / n)
+        .sqrt();
+    let mean_rmsd = rmsd.iter().sum::<f64>() / n;
+
+    TorsionProfile {
+        record_id: scan.record_id.clone(),
+        force_field: force_field.to_owned(),
+        mode,
+        dihedral: scan.dihedral,
+        angles: scan.angles.clone(),
+        qm_energies,
+        mm_energies,
+        mm_angles,
+        rmsd,
+        rmse,
+        mean_rmsd,
+    }
+}
+
+/// wrap an angle difference in radians into [-π, π)
+fn wrap(x: f64) -> f64 {
+    use std::f64::consts::PI;
+    (x + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// A collection of one-dimensional torsion drives to compare force fields on
+pub struct TorsionBenchmark {
+    scans: Vec<(Molecule, TorsionScan)>,
+
+    /// the records that are not one-dimensional scans or whose CMILES cannot
+    /// be parsed
+    pub skipped: Vec<Skipped>,
+}
+
+impl TorsionBenchmark {
+    /// Build a benchmark from the output of
+    /// [FractalClient::torsion_drive_records]. Records that are not
+    /// one-dimensional scans or whose CMILES cannot be parsed are recorded in
+    /// [TorsionBenchmark::skipped].
+    pub fn new(
+        records: Vec<(TorsionDriveRecord, String, Vec<Vec<f64>>)>,
+    ) -> Self {
+        let mut scans = Vec::with_capacity(records.len());
+        let mut skipped = Vec::new();
+        for (record, cmiles, geometries) in records {
+            let molecule = match Molecule::from_mapped_smiles(&cmiles) {
+                Ok(m) => m,
+                Err(e) => {
+                    skipped.push(Skipped::new(record.id, format!("{e:?}")));
+                    continue;
+                }
+            };
+            match TorsionScan::new(&record, geometries) {
+                Ok(scan) => scans.push((molecule, scan)),
+                Err(e) => skipped.push(Skipped::new(&record.id, e)),
+            }
+        }
+        Self { scans, skipped }
+    }
+
+    /// Download the torsion drives in `dataset` and build a benchmark with
+    /// [TorsionBenchmark::new]
+    pub fn from_dataset(dataset: ResultCollection) -> Self {
+        let client = FractalClient::new();
+        Self::new(client.torsion_drive_records(dataset.into(), 400))
+    }
+
+    pub fn scans(&self) -> impl Iterator<Item = &TorsionScan> {
+        self.scans.iter().map(|(_, s)| s)
+    }
+
+    /// The scans paired with their molecules, as expected by
+    /// [ForceField::fit_torsions]
+    pub fn molecules_and_scans(&self) -> &[(Molecule, TorsionScan)] {
+        &self.scans
+    }
+
+    pub fn len(&self) -> usize {
+        self.scans.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Compute the MM profile of every scan with `force_field`, labeling the
+    /// results with `name`. The records of molecules that cannot be
+    /// parameterized are returned separately.
+    pub fn run(
+        &self,
+        name: &str,
+        force_field: &ForceField,
+        mode: ScanMode,
+    ) -> (Vec<TorsionProfile>, Vec<Skipped>) {
+        let mut ret = Vec::new();
+        let mut skipped = Vec::new();
+        for (molecule, scan) in &self.scans {
+            let topology = Topology::from_molecules(vec![molecule.clone()]);
+            let interchange = match force_field.create_interchange(&topology) {
+                Ok(i) => i,
+                Err(e) => {
+                    let reason = format!("{name}: {e}");
+                    skipped.push(Skipped::new(&scan.record_id, reason));
+                    continue;
+                }
+            };
+            let heavy = MoleculeGraph::from(molecule).heavy_atoms();
+            ret.push(mm_profile(name, &interchange, &heavy, scan, mode));
+        }
+        (ret, skipped)
+    }
+}
+
+/// Return the per-grid point results of `profiles` as CSV
+pub fn profiles_csv(profiles: &[TorsionProfile]) -> String {
+    let mut ret = String::from(
+        "record_id,force_field,angle,qm_energy,mm_energy,mm_angle,rmsd\n",
+    );
+    for p in profiles {
+        for i in 0..p.angles.len() {
+            ret.push_str(&format!(
+                "{},{},{},{},{},{},{}\n",
+                p.record_id,
+                p.force_field,
+                p.angles[i],
+                p.qm_energies[i],
+                p.mm_energies[i],
+                p.mm_angles[i],
+                p.rmsd[i],
+            ));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{butane, butane_positions, butane_torsion};
+
+    use super::*;
+
+    /// butane with a single three-fold torsion
+    fn three_fold() -> Interchange {
+        butane(vec![butane_torsion(3.0, 1.0, 1)])
+    }
+
+    fn scan() -> TorsionScan {
+        let angles = vec![-120.0, -60.0, 0.0, 60.0, 120.0, 180.0];
+        TorsionScan {
+            record_id: "1".to_owned(),
+            dihedral: [0, 1, 2, 3],
+            energies: angles
+                .iter()
+                .map(|a: &f64| 1.0 + (3.0 * a.to_radians()).cos())
+                .collect(),
+            positions: angles
+                .iter()
+                // slightly stretched bonds, so relaxing them lowers the energy
+                .map(|&a| butane_positions(a, 1.6))
+                .collect(),
+            angles,
+        }
+    }
+
+    #[test]
+    fn rigid() {
+        let scan = scan();
+        let got = mm_profile(
+            "ff",
+            &three_fold(),
+            &[0, 1, 2, 3],
+            &scan,
+            ScanMode::Rigid,
+        );
+        assert_eq!(got.qm_energies[1], 0.0);
+        // the bond and angle energies are the same at every grid point, so
+        // the profiles match exactly
+        assert!(got.rmse < 1e-10, "{}", got.rmse);
+        assert!(got.rmsd.iter().all(|&r| r < 1e-8));
+        assert_eq!(profiles_csv(&[got]).lines().count(), 7);
+    }
+
+    #[test]
+    fn relaxed() {
+        let scan = scan();
+        let got = mm_profile(
+            "ff",
+            &three_fold(),
+            &[0, 1, 2, 3],
+            &scan,
+            ScanMode::Relaxed,
+        );
+        for (want, got) in scan.angles.iter().zip(&got.mm_angles) {
+            let diff = wrap((got - want).to_radians()).abs().to_degrees();
+            assert!(diff < 0.5, "{want} {got}");
+        }
+        // relaxing the bonds lowers the MM energy by the same amount at every
+        // point, leaving only the restraint error in the profile
+        assert!(got.rmse < 1e-2, "{}", got.rmse);
+        assert!(got.mean_rmsd > 1e-3);
+    }
+
+    #[test]
+    fn wrapping() {
+        use std::f64::consts::PI;
+        assert!((wrap(1.5 * PI) + 0.5 * PI).abs() < 1e-12);
+        assert!((wrap(-1.5 * PI) - 0.5 * PI).abs() < 1e-12);
+    }
+}
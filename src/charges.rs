@@ -0,0 +1,315 @@
+//! Partial charge assignment.
+//!
+//! [ligand::molecule::Molecule] has no field for partial charges, so they are
+//! stored in [MoleculeGraph::partial_charges] instead, which is where the rest
+//! of the crate reads them from. A [ligand::molecule::Molecule] is charged
+//! through [crate::topology::molecule::MoleculeExt::assign_partial_charges],
+//! which returns its converted graph.
+
+use std::{error::Error, fmt::Display};
+
+use crate::topology::molecule::MoleculeGraph;
+
+pub mod bcc;
+pub mod eem;
+pub mod gnn;
+pub mod resp;
+pub mod sqm;
+
+/// The number of Gasteiger-Marsili charge equalization iterations
+pub const GASTEIGER_ITERATIONS: usize = 6;
+
+/// The electronegativity of a hydrogen cation used in place of `a + b + c` in
+/// the Gasteiger-Marsili scheme
+const HYDROGEN_CATION_ELECTRONEGATIVITY: f64 = 20.02;
+
+#[derive(Debug, PartialEq)]
+pub enum ChargeError {
+    /// the method has no parameters for this element in this bonding
+    /// environment
+    UnsupportedElement { atom: usize, atomic_number: u8 },
+
+    /// no bond charge correction matches the bond between these atoms
+    MissingBcc { atom1: usize, atom2: usize },
+
+    /// the method needs a conformer but the molecule has none
+    NoConformers,
+
+    /// the linear system for the charges could not be solved
+    Singular,
+
+    /// the method needs Kekulé bond orders, but the molecule's aromatic
+    /// bonds have none
+    NoKekuleStructure,
+}
+
+impl Error for ChargeError {}
+
+impl Display for ChargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChargeError::UnsupportedElement {
+                atom,
+                atomic_number,
+            } => write!(
+                f,
+                "unsupported element with atomic number {atomic_number} \
+                 on atom {atom}"
+            ),
+            ChargeError::MissingBcc { atom1, atom2 } => write!(
+                f,
+                "no bond charge correction for the bond between atoms \
+                 {atom1} and {atom2}"
+            ),
+            ChargeError::NoConformers => {
+                write!(f, "charge method requires a conformer")
+            }
+            ChargeError::Singular => {
+                write!(f, "singular charge equilibration problem")
+            }
+            ChargeError::NoKekuleStructure => {
+                write!(f, "aromatic bonds without a Kekulé structure")
+            }
+        }
+    }
+}
+
+/// The available partial charge methods
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChargeMethod {
+    /// the iterative partial equalization of orbital electronegativity method
+    /// of Gasteiger and Marsili
+    Gasteiger,
+
+    /// each atom gets its formal charge
+    FormalCharge,
+
+    /// electronegativity equalization, see [eem]
+    Eem(eem::EemGeometry),
+}
+
+impl ChargeMethod {
+    /// Compute partial charges for the atoms of `graph`, in units of the
+    /// elementary charge
+    pub fn compute(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, ChargeError> {
+        match self {
+            ChargeMethod::Gasteiger => gasteiger(graph),
+            ChargeMethod::FormalCharge => Ok(formal_charges(graph)),
+            ChargeMethod::Eem(geometry) => eem::eem(graph, *geometry),
+        }
+    }
+}
+
+/// A source of AM1 partial charges, typically an external quantum chemistry
+/// program, that also provides the bond charge corrections for AM1-BCC
+pub trait ChargeBackend {
+    /// Compute AM1 Mulliken charges for `graph`, using its first conformer
+    fn am1_mulliken(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, Box<dyn Error>>;
+
+    /// The bond charge corrections applied by [ChargeBackend::am1bcc]
+    fn bccs(&self) -> &bcc::BccCollection;
+
+    /// Compute AM1-BCC charges for `graph` by applying [ChargeBackend::bccs]
+    /// to its AM1 Mulliken charges
+    fn am1bcc(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let charges = self.am1_mulliken(graph)?;
+        self.bccs().apply(graph, &charges)
+    }
+}
+
+impl MoleculeGraph {
+    /// Compute partial charges with `method` and store them in
+    /// [MoleculeGraph::partial_charges]
+    pub fn assign_partial_charges(
+        &mut self,
+        method: ChargeMethod,
+    ) -> Result<(), ChargeError> {
+        self.partial_charges = Some(method.compute(self)?);
+        Ok(())
+    }
+
+    /// Compute AM1-BCC charges with `backend` and store them in
+    /// [MoleculeGraph::partial_charges]
+    pub fn assign_am1bcc_charges(
+        &mut self,
+        backend: &dyn ChargeBackend,
+    ) -> Result<(), Box<dyn Error>> {
+        self.partial_charges = Some(backend.am1bcc(self)?);
+        Ok(())
+    }
+}
+
+fn formal_charges(graph: &MoleculeGraph) -> Vec<f64> {
+    graph
+        .atoms
+        .iter()
+        .map(|a| f64::from(a.formal_charge))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hybridization {
+    Sp,
+    Sp2,
+    Sp3,
+}
+
+fn hybridization(graph: &MoleculeGraph, atom: usize) -> Hybridization {
+    let (mut double, mut triple, mut aromatic) = (0, 0, false);
+    for &(_, b) in graph.adjacent(atom) {
+        let bond = &graph.bonds[b];
+        if bond.is_aromatic {
+            aromatic = true;
+        } else if bond.bond_order == 2 {
+            double += 1;
+        } else if bond.bond_order == 3 {
+            triple += 1;
+        }
+    }
+    if triple > 0 || double > 1 {
+        Hybridization::Sp
+    } else if double > 0 || aromatic {
+        Hybridization::Sp2
+    } else {
+        Hybridization::Sp3
+    }
+}
+
+/// The Gasteiger-Marsili `(a, b, c)` parameters of the electronegativity
+/// polynomial `χ = a + bq + cq²` for atom `i` of `graph`
+fn gasteiger_parameters(
+    graph: &MoleculeGraph,
+    i: usize,
+) -> Result<(f64, f64, f64), ChargeError> {
+    use Hybridization::*;
+    let atomic_number = graph.atoms[i].atomic_number;
+    Ok(match (atomic_number, hybridization(graph, i)) {
+        (1, _) => (7.17, 6.24, -0.56),
+        (6, Sp3) => (7.98, 9.18, 1.88),
+        (6, Sp2) => (8.79, 9.32, 1.51),
+        (6, Sp) => (10.39, 9.45, 0.73),
+        (7, Sp3) => (11.54, 10.82, 1.36),
+        (7, Sp2) => (12.87, 11.15, 0.85),
+        (7, Sp) => (15.68, 11.70, -0.27),
+        (8, Sp3) => (14.18, 12.92, 1.39),
+        (8, _) => (17.07, 13.79, 0.47),
+        (9, _) => (14.66, 13.85, 2.31),
+        (15, _) => (8.90, 8.24, 0.96),
+        (16, Sp3) => (10.14, 9.13, 1.38),
+        (16, _) => (10.88, 9.49, 1.33),
+        (17, _) => (11.00, 9.69, 1.35),
+        (35, _) => (10.08, 8.47, 1.16),
+        (53, _) => (9.90, 7.96, 0.96),
+        _ => {
+            return Err(ChargeError::UnsupportedElement {
+                atom: i,
+                atomic_number,
+            })
+        }
+    })
+}
+
+/// Gasteiger-Marsili charges, starting from the formal charges and
+/// transferring charge along each bond from the less to the more
+/// electronegative atom, damped by a factor of two each iteration
+fn gasteiger(graph: &MoleculeGraph) -> Result<Vec<f64>, ChargeError> {
+    let params = (0..graph.n_atoms())
+        .map(|i| gasteiger_parameters(graph, i))
+        .collect::<Result<Vec<_>, _>>()?;
+    let cation: Vec<f64> = graph
+        .atoms
+        .iter()
+        .zip(&params)
+        .map(|(atom, (a, b, c))| {
+            if atom.atomic_number == 1 {
+                HYDROGEN_CATION_ELECTRONEGATIVITY
+            } else {
+                a + b + c
+            }
+        })
+        .collect();
+
+    let mut charges = formal_charges(graph);
+    let mut damping = 1.0;
+    for _ in 0..GASTEIGER_ITERATIONS {
+        damping *= 0.5;
+        let chi: Vec<f64> = charges
+            .iter()
+            .zip(&params)
+            .map(|(q, (a, b, c))| a + b * q + c * q * q)
+            .collect();
+        for bond in &graph.bonds {
+            let (i, j) = (bond.atom1, bond.atom2);
+            // the less electronegative atom donates
+            let (donor, acceptor) =
+                if chi[i] < chi[j] { (i, j) } else { (j, i) };
+            let dq = damping * (chi[acceptor] - chi[donor]) / cation[donor];
+            charges[donor] += dq;
+            charges[acceptor] -= dq;
+        }
+    }
+    Ok(charges)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    use super::*;
+
+    /// a central atom with `n` hydrogens
+    fn hydride(atomic_number: u8, n: usize) -> MoleculeGraph {
+        let mut atoms = vec![atomic_number];
+        atoms.extend((0..n).map(|_| 1));
+        let bonds: Vec<_> = (1..=n).map(|i| (0, i, 1)).collect();
+        graph(&atoms, &bonds)
+    }
+
+    #[test]
+    fn gasteiger() {
+        // reference values from RDKit's ComputeGasteigerCharges
+        for (atomic_number, n, want) in [(6, 4, -0.0776), (8, 2, -0.4105)] {
+            let mut graph = hydride(atomic_number, n);
+            graph
+                .assign_partial_charges(ChargeMethod::Gasteiger)
+                .unwrap();
+            let got = graph.partial_charges.unwrap();
+            assert!((got[0] - want).abs() < 1e-3, "{got:?}");
+            assert!(got.iter().sum::<f64>().abs() < 1e-12);
+            assert!(got[1..].iter().all(|&q| (q - got[1]).abs() < 1e-12));
+        }
+    }
+
+    #[test]
+    fn formal() {
+        let mut graph = hydride(7, 4);
+        graph.atoms[0].formal_charge = 1;
+        let got = ChargeMethod::FormalCharge.compute(&graph).unwrap();
+        assert_eq!(got, vec![1.0, 0.0, 0.0, 0.0, 0.0]);
+
+        // Gasteiger charges conserve the total formal charge
+        let got = ChargeMethod::Gasteiger.compute(&graph).unwrap();
+        assert!((got.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn unsupported() {
+        let graph = hydride(14, 4);
+        assert_eq!(
+            ChargeMethod::Gasteiger.compute(&graph),
+            Err(ChargeError::UnsupportedElement {
+                atom: 0,
+                atomic_number: 14
+            })
+        );
+    }
+}
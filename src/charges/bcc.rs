@@ -0,0 +1,181 @@
+//! Bond charge corrections for AM1-BCC, typed by SMIRKS patterns. The
+//! published AM1-BCC table is not bundled; it can be loaded from the
+//! `original-am1-bcc.json` file distributed with openff-recharge. The
+//! patterns are matched after perceiving aromaticity with the collection's
+//! `aromaticity_model`, which is normally `AM1BCC`.
+
+use std::{collections::HashMap, error::Error, fs::read_to_string, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::topology::{
+    aromaticity::AromaticityModel, molecule::MoleculeGraph, smarts::Smarts,
+};
+
+use super::ChargeError;
+
+/// A single bond charge correction. `value` is added to the charge of the
+/// atom matching map index 1 of `smirks` and subtracted from the charge of
+/// the atom matching map index 2.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BccParameter {
+    pub smirks: String,
+    pub value: f64,
+}
+
+/// An ordered collection of bond charge corrections. As for SMIRNOFF
+/// parameters, later corrections take precedence over earlier ones matching
+/// the same bond.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BccCollection {
+    pub parameters: Vec<BccParameter>,
+
+    /// the name of the [AromaticityModel] the patterns are written for
+    #[serde(default = "default_aromaticity_model")]
+    pub aromaticity_model: String,
+}
+
+fn default_aromaticity_model() -> String {
+    AromaticityModel::Am1Bcc.name().to_owned()
+}
+
+impl Default for BccCollection {
+    fn default() -> Self {
+        Self {
+            parameters: Vec::new(),
+            aromaticity_model: default_aromaticity_model(),
+        }
+    }
+}
+
+/// The index of a correction and whether it applies in the direction of the
+/// bond
+type Assignment = Option<(usize, bool)>;
+
+impl BccCollection {
+    /// Load a collection from a JSON file in the openff-recharge format
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&read_to_string(path)?)?)
+    }
+
+    /// Return the index of the correction assigned to each bond of `graph`,
+    /// and whether the bond's first atom matched map index 1, after
+    /// perceiving aromaticity with the collection's model
+    fn assign(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<Assignment>, Box<dyn Error>> {
+        let mut graph = graph.clone();
+        graph.perceive_aromaticity(self.aromaticity_model.parse()?)?;
+        let graph = &graph;
+        let bond_index: HashMap<_, _> = graph
+            .bonds
+            .iter()
+            .enumerate()
+            .map(|(b, bond)| ((bond.atom1, bond.atom2), b))
+            .collect();
+        let mut ret = vec![None; graph.bonds.len()];
+        for (p, parameter) in self.parameters.iter().enumerate() {
+            for m in Smarts::parse(&parameter.smirks)?.find_matches(graph) {
+                let &[i, j] = m.as_slice() else {
+                    continue;
+                };
+                if let Some(&b) = bond_index.get(&(i, j)) {
+                    ret[b] = Some((p, true));
+                } else if let Some(&b) = bond_index.get(&(j, i)) {
+                    ret[b] = Some((p, false));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Apply the corrections to the AM1 `charges` of `graph`. Every bond must
+    /// be matched by a correction.
+    pub fn apply(
+        &self,
+        graph: &MoleculeGraph,
+        charges: &[f64],
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = charges.to_vec();
+        for (bond, assigned) in graph.bonds.iter().zip(self.assign(graph)?) {
+            let Some((p, forward)) = assigned else {
+                return Err(Box::new(ChargeError::MissingBcc {
+                    atom1: bond.atom1,
+                    atom2: bond.atom2,
+                }));
+            };
+            let value = self.parameters[p].value;
+            let (first, second) = if forward {
+                (bond.atom1, bond.atom2)
+            } else {
+                (bond.atom2, bond.atom1)
+            };
+            ret[first] += value;
+            ret[second] -= value;
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{bcc_collection as collection, graph, water};
+
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let graph = water();
+        let got = collection().apply(&graph, &[0.3, -0.6, 0.3]).unwrap();
+        let want = [0.2, -0.4, 0.2];
+        for (g, w) in got.iter().zip(want) {
+            assert!((g - w).abs() < 1e-12, "{got:?}");
+        }
+
+        let mut missing = collection();
+        missing.parameters.truncate(0);
+        assert!(missing.apply(&graph, &[0.0; 3]).is_err());
+    }
+
+    #[test]
+    fn aromaticity_model() {
+        // furan with Hückel aromatic flags, which are not aromatic in the
+        // AM1BCC model
+        let mut furan = graph(
+            &[8, 6, 6, 6, 6, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 2),
+                (2, 3, 1),
+                (3, 4, 2),
+                (4, 0, 1),
+                (1, 5, 1),
+                (2, 6, 1),
+                (3, 7, 1),
+                (4, 8, 1),
+            ],
+        );
+        furan
+            .perceive_aromaticity(AromaticityModel::Huckel)
+            .unwrap();
+        let mut bccs = BccCollection {
+            parameters: vec![
+                BccParameter {
+                    smirks: "[*:1]~[*:2]".to_owned(),
+                    value: 0.0,
+                },
+                BccParameter {
+                    smirks: "[#6:1]:[#6:2]".to_owned(),
+                    value: 0.1,
+                },
+            ],
+            ..Default::default()
+        };
+        let zero = vec![0.0; 9];
+        assert_eq!(bccs.apply(&furan, &zero).unwrap(), zero);
+
+        bccs.aromaticity_model = "Huckel".to_owned();
+        assert_ne!(bccs.apply(&furan, &zero).unwrap(), zero);
+    }
+}
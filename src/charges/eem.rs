@@ -0,0 +1,154 @@
+//! Charge equilibration. The charges minimize the electrostatic energy
+//!
+//! ```text
+//! E(q) = Σᵢ (χᵢ qᵢ + ½ Jᵢ qᵢ²) + Σᵢ<ⱼ Jᵢⱼ qᵢ qⱼ
+//! ```
+//!
+//! subject to the total charge being equal to the sum of the formal charges,
+//! where `χ` and `J` are the electronegativity and hardness of each element
+//! from the QEq parameterization of Rappé and Goddard. The interaction `Jᵢⱼ`
+//! is the Ohno-Klopman shielded Coulomb potential, which tends to the mean
+//! hardness of the two atoms at short range.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::{topology::molecule::MoleculeGraph, utils::geometry::distance};
+
+use super::ChargeError;
+
+/// The Coulomb constant in eV Å per squared elementary charge
+const COULOMB: f64 = 14.399645;
+
+/// The interatomic distance per bond assumed by [EemGeometry::Topological],
+/// in angstroms
+pub const TOPOLOGICAL_BOND_LENGTH: f64 = 1.5;
+
+/// How the distances between atoms are obtained
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EemGeometry {
+    /// distances in the first conformer
+    Conformer,
+
+    /// distances estimated from the number of bonds between atoms, which
+    /// makes the charges independent of the conformer
+    Topological,
+}
+
+/// The QEq electronegativity and hardness, in eV, for `atomic_number`
+pub fn eem_parameters(atomic_number: u8) -> Option<(f64, f64)> {
+    Some(match atomic_number {
+        1 => (4.528, 13.890),
+        3 => (3.006, 4.772),
+        6 => (5.343, 10.126),
+        7 => (6.899, 11.760),
+        8 => (8.741, 13.364),
+        9 => (10.874, 14.948),
+        11 => (2.843, 4.592),
+        14 => (4.168, 6.974),
+        15 => (5.463, 8.000),
+        16 => (6.928, 8.972),
+        17 => (8.564, 9.892),
+        19 => (2.421, 3.840),
+        35 => (7.790, 8.850),
+        53 => (6.822, 7.524),
+        _ => return None,
+    })
+}
+
+/// Compute equilibrated charges for `graph`, in units of the elementary
+/// charge
+pub fn eem(
+    graph: &MoleculeGraph,
+    geometry: EemGeometry,
+) -> Result<Vec<f64>, ChargeError> {
+    let n = graph.n_atoms();
+    let params = graph
+        .atoms
+        .iter()
+        .enumerate()
+        .map(|(atom, a)| {
+            eem_parameters(a.atomic_number).ok_or(
+                ChargeError::UnsupportedElement {
+                    atom,
+                    atomic_number: a.atomic_number,
+                },
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let r: Box<dyn Fn(usize, usize) -> f64> = match geometry {
+        EemGeometry::Conformer => {
+            let conformer =
+                graph.conformers.first().ok_or(ChargeError::NoConformers)?;
+            Box::new(move |i, j| distance(conformer, i, j))
+        }
+        EemGeometry::Topological => {
+            let distances = graph.topological_distances();
+            Box::new(move |i, j| match distances[i][j] {
+                // atoms in separate molecules do not interact
+                usize::MAX => f64::INFINITY,
+                d => d as f64 * TOPOLOGICAL_BOND_LENGTH,
+            })
+        }
+    };
+
+    // the stationarity conditions, with the Lagrange multiplier for the
+    // total charge in the last column
+    let mut a = DMatrix::zeros(n + 1, n + 1);
+    let mut b = DVector::zeros(n + 1);
+    for i in 0..n {
+        let (chi_i, j_i) = params[i];
+        a[(i, i)] = j_i;
+        for j in i + 1..n {
+            let j_j = params[j].1;
+            let shielding = 2.0 * COULOMB / (j_i + j_j);
+            let jij = COULOMB / r(i, j).hypot(shielding);
+            a[(i, j)] = jij;
+            a[(j, i)] = jij;
+        }
+        a[(i, n)] = 1.0;
+        a[(n, i)] = 1.0;
+        b[i] = -chi_i;
+    }
+    b[n] = graph.atoms.iter().map(|a| f64::from(a.formal_charge)).sum();
+
+    let x = a.lu().solve(&b).ok_or(ChargeError::Singular)?;
+    Ok(x.iter().take(n).copied().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::water;
+
+    use super::*;
+
+    #[test]
+    fn charges() {
+        let graph = water();
+        for geometry in [EemGeometry::Conformer, EemGeometry::Topological] {
+            let got = eem(&graph, geometry).unwrap();
+            assert!(got.iter().sum::<f64>().abs() < 1e-10);
+            assert!(got[1] < -0.2, "{got:?}");
+            assert!((got[0] - got[2]).abs() < 1e-10);
+        }
+
+        let mut bare = graph.clone();
+        bare.conformers.clear();
+        assert_eq!(
+            eem(&bare, EemGeometry::Conformer),
+            Err(ChargeError::NoConformers)
+        );
+        assert!(eem(&bare, EemGeometry::Topological).is_ok());
+    }
+
+    #[test]
+    fn total_charge() {
+        let mut graph = water();
+        graph.atoms[1].formal_charge = -1;
+        let got = eem(&graph, EemGeometry::Topological).unwrap();
+        assert!((got.iter().sum::<f64>() + 1.0).abs() < 1e-10);
+
+        graph.atoms[0].atomic_number = 92;
+        assert!(eem(&graph, EemGeometry::Topological).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! Partial charges from a graph neural network in the style of OpenFF NAGL.
+//! Atoms are featurized, the features are mixed between neighbors by a stack
+//! of GraphSAGE convolutions with mean aggregation, and a readout network
+//! maps each atom's final features to an electronegativity `e` and a hardness
+//! `s`. The charges are then
+//!
+//! ```text
+//! qᵢ = (-eᵢ + (Q + Σⱼ eⱼ / sⱼ) / Σⱼ 1 / sⱼ) / sᵢ
+//! ```
+//!
+//! which sum to the total formal charge `Q`.
+//!
+//! Models are loaded from JSON files holding the feature definitions and the
+//! layer weights, exported once from the PyTorch checkpoint of a trained
+//! model by `python/export-nagl.py`. Weight matrices are stored as in
+//! PyTorch, with one row per output.
+
+use std::{error::Error, fmt::Display, fs::read_to_string, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{topology::molecule::MoleculeGraph, utils::elements::SYMBOLS};
+
+use super::ChargeError;
+
+#[derive(Debug)]
+pub struct GnnError {
+    pub message: String,
+}
+
+impl Error for GnnError {}
+
+impl Display for GnnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "gnn: {}", self.message)
+    }
+}
+
+fn err(message: impl Into<String>) -> GnnError {
+    GnnError {
+        message: message.into(),
+    }
+}
+
+/// A per-atom input feature. One-hot features contribute one value per
+/// category, and the others a single value.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum AtomFeature {
+    /// one-hot element symbol
+    AtomicElement { categories: Vec<String> },
+
+    /// one-hot number of bonded neighbors
+    AtomConnectivity { categories: Vec<usize> },
+
+    /// one-hot formal charge
+    AtomFormalCharge { categories: Vec<i8> },
+
+    /// the formal charge averaged over the lowest energy resonance
+    /// structures, from [MoleculeGraph::average_formal_charges]
+    AtomAverageFormalCharge,
+
+    /// 1 if the atom is in any ring
+    AtomIsInRing,
+
+    /// 1 if the smallest ring through one of the atom's bonds has
+    /// `ring_size` atoms
+    AtomInRingOfSize { ring_size: usize },
+
+    /// 1 if the atom is aromatic
+    AtomIsAromatic,
+}
+
+impl AtomFeature {
+    /// The number of values this feature contributes
+    pub fn len(&self) -> usize {
+        match self {
+            AtomFeature::AtomicElement { categories } => categories.len(),
+            AtomFeature::AtomConnectivity { categories } => categories.len(),
+            AtomFeature::AtomFormalCharge { categories } => categories.len(),
+            _ => 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Append the values of this feature for `atom` to `out`
+    fn encode(
+        &self,
+        graph: &MoleculeGraph,
+        ring_sizes: &[Vec<usize>],
+        average_charges: &[f64],
+        atom: usize,
+        out: &mut Vec<f64>,
+    ) -> Result<(), ChargeError> {
+        fn one_hot<T: PartialEq>(categories: &[T], value: &T) -> Vec<f64> {
+            categories
+                .iter()
+                .map(|c| if c == value { 1.0 } else { 0.0 })
+                .collect()
+        }
+        let a = &graph.atoms[atom];
+        let flag = |b: bool| if b { 1.0 } else { 0.0 };
+        match self {
+            AtomFeature::AtomicElement { categories } => {
+                let symbol = SYMBOLS
+                    .get(usize::from(a.atomic_number))
+                    .filter(|s| categories.iter().any(|c| c == *s))
+                    .ok_or(ChargeError::UnsupportedElement {
+                        atom,
+                        atomic_number: a.atomic_number,
+                    })?;
+                out.extend(one_hot(categories, &symbol.to_string()));
+            }
+            AtomFeature::AtomConnectivity { categories } => {
+                out.extend(one_hot(categories, &graph.degree(atom)));
+            }
+            AtomFeature::AtomFormalCharge { categories } => {
+                out.extend(one_hot(categories, &a.formal_charge));
+            }
+            AtomFeature::AtomAverageFormalCharge => {
+                out.push(average_charges[atom])
+            }
+            AtomFeature::AtomIsInRing => {
+                out.push(flag(!ring_sizes[atom].is_empty()))
+            }
+            AtomFeature::AtomInRingOfSize { ring_size } => {
+                out.push(flag(ring_sizes[atom].contains(ring_size)))
+            }
+            AtomFeature::AtomIsAromatic => out.push(flag(a.is_aromatic)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum Activation {
+    ReLU,
+    Sigmoid,
+    Tanh,
+    #[default]
+    Identity,
+}
+
+impl Activation {
+    fn apply(&self, x: f64) -> f64 {
+        match self {
+            Activation::ReLU => x.max(0.0),
+            Activation::Sigmoid => 1.0 / (1.0 + (-x).exp()),
+            Activation::Tanh => x.tanh(),
+            Activation::Identity => x,
+        }
+    }
+}
+
+/// An affine map `Wx + b`, with `weight` stored as rows of outputs
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Linear {
+    pub weight: Vec<Vec<f64>>,
+    #[serde(default)]
+    pub bias: Option<Vec<f64>>,
+}
+
+impl Linear {
+    fn inputs(&self) -> usize {
+        self.weight.first().map_or(0, Vec::len)
+    }
+
+    fn outputs(&self) -> usize {
+        self.weight.len()
+    }
+
+    fn check(&self, inputs: usize, name: &str) -> Result<(), GnnError> {
+        if self.weight.iter().any(|row| row.len() != inputs) {
+            return Err(err(format!("{name} does not take {inputs} inputs")));
+        }
+        if self
+            .bias
+            .as_ref()
+            .is_some_and(|b| b.len() != self.outputs())
+        {
+            return Err(err(format!("{name} bias has the wrong length")));
+        }
+        Ok(())
+    }
+
+    fn forward(&self, x: &[f64]) -> Vec<f64> {
+        self.weight
+            .iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let b = self.bias.as_ref().map_or(0.0, |b| b[i]);
+                b + row.iter().zip(x).map(|(w, x)| w * x).sum::<f64>()
+            })
+            .collect()
+    }
+}
+
+/// A GraphSAGE convolution with mean aggregation, computing
+/// `act(fc_self(hᵢ) + fc_neigh(mean(hⱼ)) + bias)` over the neighbors `j` of
+/// each atom `i`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SageLayer {
+    pub fc_self: Linear,
+    pub fc_neigh: Linear,
+    #[serde(default)]
+    pub bias: Option<Vec<f64>>,
+    #[serde(default)]
+    pub activation: Activation,
+}
+
+/// A fully connected layer of the readout network
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DenseLayer {
+    #[serde(flatten)]
+    pub linear: Linear,
+    #[serde(default)]
+    pub activation: Activation,
+}
+
+/// A trained charge model
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct GnnModel {
+    pub atom_features: Vec<AtomFeature>,
+    pub convolution: Vec<SageLayer>,
+
+    /// the readout network, whose last layer has two outputs: the
+    /// electronegativity and the hardness of each atom
+    pub readout: Vec<DenseLayer>,
+}
+
+impl GnnModel {
+    /// Load a model from a JSON file, checking that the layer shapes are
+    /// consistent
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let ret: Self = serde_json::from_str(&read_to_string(path)?)?;
+        ret.check()?;
+        Ok(ret)
+    }
+
+    /// Check that each layer takes the outputs of the previous one
+    pub fn check(&self) -> Result<(), GnnError> {
+        let mut width: usize =
+            self.atom_features.iter().map(AtomFeature::len).sum();
+        for (i, layer) in self.convolution.iter().enumerate() {
+            let name = format!("convolution layer {i}");
+            layer.fc_self.check(width, &name)?;
+            layer.fc_neigh.check(width, &name)?;
+            width = layer.fc_self.outputs();
+            if layer.fc_neigh.outputs() != width
+                || layer.bias.as_ref().is_some_and(|b| b.len() != width)
+            {
+                return Err(err(format!("{name} has mismatched outputs")));
+            }
+        }
+        for (i, layer) in self.readout.iter().enumerate() {
+            layer.linear.check(width, &format!("readout layer {i}"))?;
+            width = layer.linear.outputs();
+        }
+        if width != 2 {
+            return Err(err(format!(
+                "readout has {width} outputs, expected 2"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Return the input features of every atom in `graph`
+    pub fn featurize(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<Vec<f64>>, ChargeError> {
+        let mut ring_sizes = vec![Vec::new(); graph.n_atoms()];
+        for b in 0..graph.bonds.len() {
+            if let Some(size) = graph.smallest_ring_with_bond(b) {
+                let bond = &graph.bonds[b];
+                ring_sizes[bond.atom1].push(size);
+                ring_sizes[bond.atom2].push(size);
+            }
+        }
+        let average_charges = if self
+            .atom_features
+            .contains(&AtomFeature::AtomAverageFormalCharge)
+        {
+            let mut kekule = graph.clone();
+            kekule
+                .kekulize()
+                .map_err(|_| ChargeError::NoKekuleStructure)?;
+            kekule.average_formal_charges()
+        } else {
+            Vec::new()
+        };
+        (0..graph.n_atoms())
+            .map(|atom| {
+                let mut ret = Vec::new();
+                for feature in &self.atom_features {
+                    feature.encode(
+                        graph,
+                        &ring_sizes,
+                        &average_charges,
+                        atom,
+                        &mut ret,
+                    )?;
+                }
+                Ok(ret)
+            })
+            .collect()
+    }
+
+    /// Compute partial charges for the atoms of `graph`, in units of the
+    /// elementary charge
+    pub fn compute(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, ChargeError> {
+        let mut h = self.featurize(graph)?;
+        for layer in &self.convolution {
+            h = (0..graph.n_atoms())
+                .map(|i| {
+                    let mut mean = vec![0.0; layer.fc_neigh.inputs()];
+                    let degree = graph.degree(i);
+                    for j in graph.neighbors(i) {
+                        for (m, x) in mean.iter_mut().zip(&h[j]) {
+                            *m += x / degree as f64;
+                        }
+                    }
+                    let s = layer.fc_self.forward(&h[i]);
+                    let n = layer.fc_neigh.forward(&mean);
+                    s.iter()
+                        .zip(n)
+                        .enumerate()
+                        .map(|(k, (s, n))| {
+                            let b = layer.bias.as_ref().map_or(0.0, |b| b[k]);
+                            layer.activation.apply(s + n + b)
+                        })
+                        .collect()
+                })
+                .collect();
+        }
+        for layer in &self.readout {
+            for x in h.iter_mut() {
+                *x = layer
+                    .linear
+                    .forward(x)
+                    .into_iter()
+                    .map(|y| layer.activation.apply(y))
+                    .collect();
+            }
+        }
+
+        let total: f64 =
+            graph.atoms.iter().map(|a| f64::from(a.formal_charge)).sum();
+        let inverse_hardness: Vec<f64> = h.iter().map(|x| 1.0 / x[1]).collect();
+        let sum_inverse: f64 = inverse_hardness.iter().sum();
+        let sum_ratio: f64 =
+            h.iter().zip(&inverse_hardness).map(|(x, s)| x[0] * s).sum();
+        if !(sum_inverse.is_finite() && sum_ratio.is_finite())
+            || sum_inverse == 0.0
+        {
+            return Err(ChargeError::Singular);
+        }
+        Ok(h.iter()
+            .zip(&inverse_hardness)
+            .map(|(x, s)| (-x[0] + (total + sum_ratio) / sum_inverse) * s)
+            .collect())
+    }
+}
+
+impl MoleculeGraph {
+    /// Compute charges with `model` and store them in
+    /// [MoleculeGraph::partial_charges]
+    pub fn assign_gnn_charges(
+        &mut self,
+        model: &GnnModel,
+    ) -> Result<(), ChargeError> {
+        self.partial_charges = Some(model.compute(self)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{graph, water},
+        topology::molfile::parse_sdf,
+    };
+
+    use super::*;
+
+    /// one convolution that averages the two element flags with the
+    /// neighbors', and a readout mapping them to `e = 1 + O` and `s = 2`
+    fn model() -> GnnModel {
+        serde_json::from_str(
+            r#"{
+                "atom_features": [
+                    {"name": "atomic_element", "categories": ["H", "O"]},
+                    {"name": "atom_connectivity", "categories": [1, 2]},
+                    {"name": "atom_is_in_ring"}
+                ],
+                "convolution": [{
+                    "fc_self": {"weight": [[1, 0, 0, 0, 0], [0, 1, 0, 0, 0]]},
+                    "fc_neigh": {"weight": [[1, 0, 0, 0, 0], [0, 1, 0, 0, 0]]},
+                    "bias": [0, 0],
+                    "activation": "ReLU"
+                }],
+                "readout": [
+                    {"weight": [[0, 1], [0, 0]], "bias": [1, 2]}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn features() {
+        let model = model();
+        model.check().unwrap();
+        let got = model.featurize(&water()).unwrap();
+        assert_eq!(got[0], vec![1.0, 0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(got[1], vec![0.0, 1.0, 0.0, 1.0, 0.0]);
+
+        let mut graph = water();
+        graph.atoms[1].atomic_number = 16;
+        assert_eq!(
+            model.featurize(&graph),
+            Err(ChargeError::UnsupportedElement {
+                atom: 1,
+                atomic_number: 16
+            })
+        );
+
+        let mut bad = model;
+        bad.readout[0].linear.weight.pop();
+        assert!(bad.check().is_err());
+    }
+
+    #[test]
+    fn average_formal_charge() {
+        let model: GnnModel = serde_json::from_str(
+            r#"{
+                "atom_features": [
+                    {"name": "atom_formal_charge", "categories": [-1, 0]},
+                    {"name": "atom_average_formal_charge"}
+                ],
+                "convolution": [],
+                "readout": [{"weight": [[0, 0, 0], [0, 0, 0]]}]
+            }"#,
+        )
+        .unwrap();
+        model.check().unwrap();
+        // acetate
+        let mut graph =
+            graph(&[6, 6, 8, 8], &[(0, 1, 1), (1, 2, 2), (1, 3, 1)]);
+        graph.atoms[3].formal_charge = -1;
+        let got = model.featurize(&graph).unwrap();
+        assert_eq!(got[2], vec![0.0, 1.0, -0.5]);
+        assert_eq!(got[3], vec![1.0, 0.0, -0.5]);
+    }
+
+    /// compare to the charges assigned by OpenFF NAGL itself, in the files
+    /// written by `python/export-nagl.py` from an installed NAGL model
+    #[test]
+    #[ignore]
+    fn nagl_reference() {
+        let model = GnnModel::load("testfiles/nagl/model.json").unwrap();
+        let molecules =
+            parse_sdf(&read_to_string("testfiles/nagl/reference.sdf").unwrap())
+                .unwrap();
+        assert!(!molecules.is_empty());
+        for molecule in molecules {
+            let (_, want) = molecule.properties[0]
+                .iter()
+                .find(|(k, _)| k == "atom.dprop.PartialCharge")
+                .unwrap();
+            let want: Vec<f64> = want
+                .split_whitespace()
+                .map(|q| q.parse().unwrap())
+                .collect();
+            let got = model.compute(&molecule.graph).unwrap();
+            assert_eq!(got.len(), want.len());
+            for (g, w) in got.iter().zip(want) {
+                assert!((g - w).abs() < 1e-5, "{}: {got:?}", molecule.name);
+            }
+        }
+    }
+
+    #[test]
+    fn charges() {
+        // after the convolution every atom has both element flags set, so
+        // every e is 2 and the charges are all zero
+        let mut graph = water();
+        graph.assign_gnn_charges(&model()).unwrap();
+        assert_eq!(graph.partial_charges, Some(vec![0.0; 3]));
+
+        // with no convolution, the oxygen is more electronegative
+        let mut model = model();
+        model.convolution.clear();
+        model.readout[0].linear.weight = vec![vec![0.0, 1.0, 0.0, 0.0, 0.0]; 2];
+        model.readout[0].linear.weight[1] = vec![0.0; 5];
+        graph.atoms[1].formal_charge = -1;
+        let got = model.compute(&graph).unwrap();
+        // e = (1, 2, 1), s = 2: q = (-e + (Q + Σe/s) / Σ1/s) / s
+        let want = [-1.0 / 6.0, -2.0 / 3.0, -1.0 / 6.0];
+        for (g, w) in got.iter().zip(want) {
+            assert!((g - w).abs() < 1e-12, "{got:?}");
+        }
+    }
+}
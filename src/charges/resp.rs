@@ -0,0 +1,616 @@
+//! Restrained electrostatic potential (RESP) charges. The charges minimize
+//!
+//! ```text
+//! χ²(q) = Σₖ (Vₖ - Σᵢ qᵢ / rᵢₖ)² + a Σᵢ (√(qᵢ² + b²) - b)
+//! ```
+//!
+//! over the ESP `V` sampled at grid points around one or more conformers,
+//! subject to the total charge being equal to the sum of the formal charges
+//! and to topologically equivalent atoms having equal charges. The hyperbolic
+//! restraint on heavy atoms is handled by iteratively reweighting the normal
+//! equations, and the fit is done in two stages following Bayly et al. In the
+//! second stage, the charges of methyl and methylene groups are refit with a
+//! stronger restraint while every other charge is held at its first-stage
+//! value.
+
+use std::{error::Error, fmt::Display, fs::read_to_string, path::Path};
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    qcsubmit::client::ResultRecord,
+    topology::{molecule::MoleculeGraph, Topology, TopologyError},
+    utils::BOHR_TO_ANGSTROM,
+};
+
+/// The scale factors applied to the van der Waals radii to give the shells of
+/// a Merz-Kollman grid
+pub const MK_SHELLS: [f64; 4] = [1.4, 1.6, 1.8, 2.0];
+
+#[derive(Debug)]
+pub struct RespError {
+    pub message: String,
+}
+
+impl Error for RespError {}
+
+impl Display for RespError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "resp: {}", self.message)
+    }
+}
+
+fn err(message: impl Into<String>) -> RespError {
+    RespError {
+        message: message.into(),
+    }
+}
+
+/// The Bondi van der Waals radius of `atomic_number`, in angstroms
+pub fn vdw_radius(atomic_number: u8) -> Option<f64> {
+    Some(match atomic_number {
+        1 => 1.20,
+        3 => 1.82,
+        6 => 1.70,
+        7 => 1.55,
+        8 => 1.52,
+        9 => 1.47,
+        11 => 2.27,
+        14 => 2.10,
+        15 => 1.80,
+        16 => 1.80,
+        17 => 1.75,
+        19 => 2.75,
+        35 => 1.85,
+        53 => 1.98,
+        _ => return None,
+    })
+}
+
+/// How grid points are laid out around a conformer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GridLayout {
+    /// points on the [MK_SHELLS] around each atom, with `density` points per
+    /// square angstrom of shell surface
+    MerzKollman { density: f64 },
+
+    /// points of a cubic lattice with `spacing` angstroms between points,
+    /// outside the van der Waals radius of every atom and within `extent`
+    /// angstroms of at least one of them
+    Chelpg { spacing: f64, extent: f64 },
+}
+
+impl GridLayout {
+    pub const MK: Self = Self::MerzKollman { density: 1.0 };
+    pub const CHELPG: Self = Self::Chelpg {
+        spacing: 0.3,
+        extent: 2.8,
+    };
+}
+
+/// Return the van der Waals radius of each atom in `graph`
+fn radii(graph: &MoleculeGraph) -> Result<Vec<f64>, RespError> {
+    graph
+        .atoms
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            vdw_radius(a.atomic_number).ok_or_else(|| {
+                err(format!(
+                    "no van der Waals radius for atomic number {} on atom {i}",
+                    a.atomic_number
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Generate the grid points, in angstroms, for the flattened `conformer` of
+/// `graph`, also in angstroms
+pub fn esp_grid(
+    graph: &MoleculeGraph,
+    conformer: &[f64],
+    layout: GridLayout,
+) -> Result<Vec<[f64; 3]>, RespError> {
+    let radii = radii(graph)?;
+    let atoms: Vec<[f64; 3]> = conformer
+        .chunks_exact(3)
+        .map(|c| [c[0], c[1], c[2]])
+        .collect();
+    if atoms.len() != radii.len() {
+        return Err(err("conformer does not match the molecule"));
+    }
+    let dist = |p: &[f64; 3], a: &[f64; 3]| {
+        ((p[0] - a[0]).powi(2) + (p[1] - a[1]).powi(2) + (p[2] - a[2]).powi(2))
+            .sqrt()
+    };
+
+    let mut ret = Vec::new();
+    match layout {
+        GridLayout::MerzKollman { density } => {
+            let golden = std::f64::consts::PI * (3.0 - 5.0f64.sqrt());
+            for scale in MK_SHELLS {
+                for (center, radius) in atoms.iter().zip(&radii) {
+                    let r = scale * radius;
+                    let area = 4.0 * std::f64::consts::PI * r * r;
+                    let n = (area * density).ceil() as usize;
+                    // a Fibonacci lattice on the sphere
+                    for i in 0..n {
+                        let z = 1.0 - 2.0 * (i as f64 + 0.5) / n as f64;
+                        let rho = (1.0 - z * z).sqrt();
+                        let phi = golden * i as f64;
+                        let p = [
+                            center[0] + r * rho * phi.cos(),
+                            center[1] + r * rho * phi.sin(),
+                            center[2] + r * z,
+                        ];
+                        // skip points buried in the shells of other atoms
+                        let buried = atoms
+                            .iter()
+                            .zip(&radii)
+                            .any(|(a, ra)| dist(&p, a) < scale * ra - 1e-10);
+                        if !buried {
+                            ret.push(p);
+                        }
+                    }
+                }
+            }
+        }
+        GridLayout::Chelpg { spacing, extent } => {
+            let mut lo = [f64::INFINITY; 3];
+            let mut hi = [f64::NEG_INFINITY; 3];
+            for a in &atoms {
+                for c in 0..3 {
+                    lo[c] = lo[c].min(a[c] - extent);
+                    hi[c] = hi[c].max(a[c] + extent);
+                }
+            }
+            let steps = |c: usize| ((hi[c] - lo[c]) / spacing).floor() as usize;
+            for i in 0..=steps(0) {
+                for j in 0..=steps(1) {
+                    for k in 0..=steps(2) {
+                        let p = [
+                            lo[0] + i as f64 * spacing,
+                            lo[1] + j as f64 * spacing,
+                            lo[2] + k as f64 * spacing,
+                        ];
+                        let mut inside = false;
+                        let mut near = false;
+                        for (a, r) in atoms.iter().zip(&radii) {
+                            let d = dist(&p, a);
+                            inside |= d < *r;
+                            near |= d <= extent;
+                        }
+                        if near && !inside {
+                            ret.push(p);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// The electrostatic potential sampled around one conformer of a molecule
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EspData {
+    /// the flattened conformer in angstroms
+    pub conformer: Vec<f64>,
+
+    /// the grid points in angstroms
+    pub grid: Vec<[f64; 3]>,
+
+    /// the ESP at each grid point, in hartrees per elementary charge
+    pub esp: Vec<f64>,
+}
+
+impl EspData {
+    /// Load ESP data from a JSON file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_str(&read_to_string(path)?)?)
+    }
+
+    /// Build ESP data from a QCArchive single-point `record` storing the grid
+    /// points in bohr under the `grid` key of its `extras` and the ESP under
+    /// the `esp` key. `geometry` is the flattened geometry of the record's
+    /// molecule, also in bohr.
+    pub fn from_result_record(
+        record: &ResultRecord,
+        geometry: &[f64],
+    ) -> Result<Self, RespError> {
+        let field = |key: &str| {
+            record.extras.get(key).ok_or_else(|| {
+                err(format!("record {} has no `{key}` in extras", record.id))
+            })
+        };
+        let grid: Vec<[f64; 3]> =
+            serde_json::from_value(field("grid")?.clone())
+                .map_err(|e| err(format!("invalid grid: {e}")))?;
+        let esp: Vec<f64> = serde_json::from_value(field("esp")?.clone())
+            .map_err(|e| err(format!("invalid esp: {e}")))?;
+        Ok(Self {
+            conformer: geometry.iter().map(|x| x * BOHR_TO_ANGSTROM).collect(),
+            grid: grid
+                .into_iter()
+                .map(|p| p.map(|x| x * BOHR_TO_ANGSTROM))
+                .collect(),
+            esp,
+        })
+    }
+}
+
+/// Settings for [fit_resp]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RespOptions {
+    /// the restraint strength `a` in the first stage
+    pub stage1_restraint: f64,
+
+    /// the restraint strength `a` in the second stage
+    pub stage2_restraint: f64,
+
+    /// the width `b` of the hyperbolic restraint
+    pub hyperbolic_b: f64,
+
+    /// whether the second stage is run at all
+    pub two_stage: bool,
+
+    /// whether hydrogen charges are restrained as well as heavy atoms
+    pub restrain_hydrogens: bool,
+
+    pub max_iterations: usize,
+
+    /// the largest change in any charge between iterations at convergence
+    pub tolerance: f64,
+}
+
+impl Default for RespOptions {
+    fn default() -> Self {
+        Self {
+            stage1_restraint: 0.0005,
+            stage2_restraint: 0.001,
+            hyperbolic_b: 0.1,
+            two_stage: true,
+            restrain_hydrogens: false,
+            max_iterations: 50,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+/// One stage of the fit. `classes[i]` is the variable for the charge of atom
+/// `i`, or None if it is held at `fixed[i]`
+struct Stage<'a> {
+    classes: Vec<Option<usize>>,
+    fixed: &'a [f64],
+    restraint: f64,
+}
+
+/// The normal equations `AᵀA` and `AᵀV` summed over all conformers, with
+/// distances in bohr
+fn normal_equations(
+    n: usize,
+    data: &[EspData],
+) -> Result<(DMatrix<f64>, DVector<f64>), RespError> {
+    let mut ata = DMatrix::zeros(n, n);
+    let mut atv = DVector::zeros(n);
+    for (c, d) in data.iter().enumerate() {
+        if d.conformer.len() != 3 * n {
+            return Err(err(format!(
+                "conformer {c} has {} coordinates, expected {}",
+                d.conformer.len(),
+                3 * n
+            )));
+        }
+        if d.grid.len() != d.esp.len() {
+            return Err(err(format!(
+                "conformer {c} has {} grid points but {} ESP values",
+                d.grid.len(),
+                d.esp.len()
+            )));
+        }
+        let mut row = vec![0.0; n];
+        for (p, v) in d.grid.iter().zip(&d.esp) {
+            for (i, x) in d.conformer.chunks_exact(3).enumerate() {
+                let r = ((p[0] - x[0]).powi(2)
+                    + (p[1] - x[1]).powi(2)
+                    + (p[2] - x[2]).powi(2))
+                .sqrt();
+                row[i] = BOHR_TO_ANGSTROM / r;
+            }
+            for i in 0..n {
+                atv[i] += row[i] * v;
+                for j in 0..n {
+                    ata[(i, j)] += row[i] * row[j];
+                }
+            }
+        }
+    }
+    Ok((ata, atv))
+}
+
+/// Fit RESP charges for `graph` to the ESP of one or more of its conformers,
+/// in units of the elementary charge
+pub fn fit_resp(
+    graph: &MoleculeGraph,
+    data: &[EspData],
+    options: &RespOptions,
+) -> Result<Vec<f64>, RespError> {
+    let n = graph.n_atoms();
+    if data.iter().all(|d| d.grid.is_empty()) {
+        return Err(err("no ESP grid points"));
+    }
+    let (ata, atv) = normal_equations(n, data)?;
+    let total: f64 =
+        graph.atoms.iter().map(|a| f64::from(a.formal_charge)).sum();
+    let symmetry = graph.symmetry_classes();
+    let restrained: Vec<bool> = graph
+        .atoms
+        .iter()
+        .map(|a| options.restrain_hydrogens || a.atomic_number != 1)
+        .collect();
+
+    // the carbons of methyl and methylene groups, and their hydrogens
+    let mut refit = vec![false; n];
+    if options.two_stage {
+        for (i, a) in graph.atoms.iter().enumerate() {
+            if a.atomic_number == 6
+                && graph.degree(i) == 4
+                && graph.n_hydrogens(i) >= 2
+            {
+                refit[i] = true;
+                for j in graph.neighbors(i) {
+                    if graph.atoms[j].atomic_number == 1 {
+                        refit[j] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // in the first stage, the groups refit later are free of equivalences
+    let classes = (0..n)
+        .map(|i| Some(if refit[i] { n + i } else { symmetry[i] }))
+        .collect();
+    let zeros = vec![0.0; n];
+    let stage1 = Stage {
+        classes,
+        fixed: &zeros,
+        restraint: options.stage1_restraint,
+    };
+    let charges = solve(&ata, &atv, total, &restrained, &stage1, options)?;
+    if !refit.contains(&true) {
+        return Ok(charges);
+    }
+
+    let classes = (0..n).map(|i| refit[i].then_some(symmetry[i])).collect();
+    let stage2 = Stage {
+        classes,
+        fixed: &charges,
+        restraint: options.stage2_restraint,
+    };
+    solve(&ata, &atv, total, &restrained, &stage2, options)
+}
+
+fn solve(
+    ata: &DMatrix<f64>,
+    atv: &DVector<f64>,
+    total: f64,
+    restrained: &[bool],
+    stage: &Stage,
+    options: &RespOptions,
+) -> Result<Vec<f64>, RespError> {
+    let n = stage.classes.len();
+
+    // number the variables densely and map them back to atoms
+    let mut labels: Vec<usize> =
+        stage.classes.iter().flatten().copied().collect();
+    labels.sort();
+    labels.dedup();
+    let m = labels.len();
+    let var: Vec<Option<usize>> = stage
+        .classes
+        .iter()
+        .map(|c| c.map(|c| labels.binary_search(&c).unwrap()))
+        .collect();
+    let mut p = DMatrix::zeros(n, m);
+    let mut q_fixed = DVector::zeros(n);
+    for i in 0..n {
+        match var[i] {
+            Some(k) => p[(i, k)] = 1.0,
+            None => q_fixed[i] = stage.fixed[i],
+        }
+    }
+    let h = p.transpose() * ata * &p;
+    let g = p.transpose() * (atv - ata * &q_fixed);
+    let counts: Vec<f64> = (0..m).map(|k| p.column(k).sum()).collect();
+    let target = total - q_fixed.sum();
+    let n_restrained: Vec<f64> = (0..m)
+        .map(|k| {
+            (0..n)
+                .filter(|&i| var[i] == Some(k) && restrained[i])
+                .count() as f64
+        })
+        .collect();
+
+    let b = options.hyperbolic_b;
+    let mut c = DVector::zeros(m);
+    for _ in 0..options.max_iterations.max(1) {
+        let mut a = DMatrix::zeros(m + 1, m + 1);
+        a.view_mut((0, 0), (m, m)).copy_from(&h);
+        let mut rhs = DVector::zeros(m + 1);
+        for k in 0..m {
+            let ck: f64 = c[k];
+            a[(k, k)] +=
+                stage.restraint * n_restrained[k] / (ck * ck + b * b).sqrt();
+            a[(k, m)] = counts[k];
+            a[(m, k)] = counts[k];
+            rhs[k] = g[k];
+        }
+        rhs[m] = target;
+        let x = a
+            .lu()
+            .solve(&rhs)
+            .ok_or_else(|| err("singular RESP equations"))?;
+        let next = x.rows(0, m).into_owned();
+        let change = (&next - &c).amax();
+        c = next;
+        if change < options.tolerance {
+            break;
+        }
+    }
+
+    Ok((0..n)
+        .map(|i| match var[i] {
+            Some(k) => c[k],
+            None => q_fixed[i],
+        })
+        .collect())
+}
+
+impl Topology {
+    /// Fit RESP charges for distinct molecule `molecule` of the topology to
+    /// `data` and store them in its [MoleculeGraph::partial_charges]
+    pub fn assign_resp_charges(
+        &mut self,
+        molecule: usize,
+        data: &[EspData],
+        options: &RespOptions,
+    ) -> Result<(), Box<dyn Error>> {
+        let graph = self
+            .molecules
+            .get_mut(molecule)
+            .ok_or(TopologyError::NoSuchMolecule(molecule))?;
+        graph.partial_charges = Some(fit_resp(graph, data, options)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{graph, water};
+
+    use super::*;
+
+    /// the ESP of point `charges` at `conformer` on an MK grid
+    fn synthetic(
+        graph: &MoleculeGraph,
+        conformer: &[f64],
+        charges: &[f64],
+    ) -> EspData {
+        let grid = esp_grid(graph, conformer, GridLayout::MK).unwrap();
+        let esp = grid
+            .iter()
+            .map(|p| {
+                conformer
+                    .chunks_exact(3)
+                    .zip(charges)
+                    .map(|(x, q)| {
+                        let r = ((p[0] - x[0]).powi(2)
+                            + (p[1] - x[1]).powi(2)
+                            + (p[2] - x[2]).powi(2))
+                        .sqrt();
+                        q * BOHR_TO_ANGSTROM / r
+                    })
+                    .sum()
+            })
+            .collect();
+        EspData {
+            conformer: conformer.to_vec(),
+            grid,
+            esp,
+        }
+    }
+
+    #[test]
+    fn grids() {
+        let graph = water();
+        let conformer = &graph.conformers[0];
+        for layout in [GridLayout::MK, GridLayout::CHELPG] {
+            let grid = esp_grid(&graph, conformer, layout).unwrap();
+            assert!(grid.len() > 100, "{layout:?}");
+            for p in &grid {
+                for (x, z) in conformer.chunks_exact(3).zip([1, 8, 1]) {
+                    let r = ((p[0] - x[0]).powi(2)
+                        + (p[1] - x[1]).powi(2)
+                        + (p[2] - x[2]).powi(2))
+                    .sqrt();
+                    assert!(r >= vdw_radius(z).unwrap() - 1e-8);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn water_charges() {
+        let graph = water();
+        let want = [0.4, -0.8, 0.4];
+        let data = [synthetic(&graph, &graph.conformers[0], &want)];
+
+        // without restraints the exact charges are recovered
+        let options = RespOptions {
+            stage1_restraint: 0.0,
+            ..Default::default()
+        };
+        let got = fit_resp(&graph, &data, &options).unwrap();
+        for (g, w) in got.iter().zip(want) {
+            assert!((g - w).abs() < 1e-6, "{got:?}");
+        }
+
+        // the restraint pulls the oxygen toward zero
+        let got = fit_resp(&graph, &data, &RespOptions::default()).unwrap();
+        assert!(got[1] > -0.8 && got[1] < -0.7, "{got:?}");
+        assert!(got.iter().sum::<f64>().abs() < 1e-10);
+        assert!((got[0] - got[2]).abs() < 1e-10);
+
+        let mut bad = data[0].clone();
+        bad.esp.pop();
+        assert!(fit_resp(&graph, &[bad], &options).is_err());
+
+        // the topology stores the charges on its copy of the molecule
+        let mut top = Topology::default();
+        let m = top.add_molecule(graph.clone());
+        top.assign_resp_charges(m, &data, &options).unwrap();
+        let got = top.molecules[m].partial_charges.as_ref().unwrap();
+        assert!((got[1] + 0.8).abs() < 1e-6, "{got:?}");
+        assert!(top.assign_resp_charges(m + 1, &data, &options).is_err());
+    }
+
+    #[test]
+    fn two_stage() {
+        // methanol, with a second conformer rotating the hydroxyl hydrogen
+        let bonds = [(0, 1, 1), (0, 2, 1), (0, 3, 1), (0, 4, 1), (1, 5, 1)];
+        let graph = graph(&[6, 8, 1, 1, 1, 1], &bonds);
+        #[rustfmt::skip]
+        let conformers = [
+            vec![
+                0.0, 0.0, 0.0, 1.43, 0.0, 0.0, -0.36, 1.03, 0.0,
+                -0.36, -0.51, 0.89, -0.36, -0.51, -0.89, 1.75, 0.9, 0.0,
+            ],
+            vec![
+                0.0, 0.0, 0.0, 1.43, 0.0, 0.0, -0.36, 1.03, 0.0,
+                -0.36, -0.51, 0.89, -0.36, -0.51, -0.89, 1.75, -0.45, 0.78,
+            ],
+        ];
+        let want = [0.1, -0.6, 0.05, 0.05, 0.0, 0.4];
+        let data: Vec<_> = conformers
+            .iter()
+            .map(|c| synthetic(&graph, c, &want))
+            .collect();
+
+        let got = fit_resp(&graph, &data, &RespOptions::default()).unwrap();
+        assert!(got.iter().sum::<f64>().abs() < 1e-10);
+        // the methyl hydrogens are equivalent after the second stage
+        assert!((got[2] - got[3]).abs() < 1e-10, "{got:?}");
+        assert!((got[3] - got[4]).abs() < 1e-10, "{got:?}");
+        assert!(got[1] < -0.5 && got[5] > 0.3, "{got:?}");
+
+        let one_stage = RespOptions {
+            two_stage: false,
+            ..Default::default()
+        };
+        let got = fit_resp(&graph, &data, &one_stage).unwrap();
+        assert!((got[2] - got[4]).abs() < 1e-10, "{got:?}");
+    }
+}
@@ -0,0 +1,299 @@
+//! AM1 charges from the `sqm` semi-empirical program distributed with
+//! AmberTools
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::{Display, Write},
+    fs::{create_dir_all, read_to_string, remove_dir_all, write},
+    path::PathBuf,
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::topology::molecule::MoleculeGraph;
+
+use super::{bcc::BccCollection, ChargeBackend};
+
+#[derive(Debug)]
+pub struct SqmError {
+    pub message: String,
+}
+
+impl Error for SqmError {}
+
+impl Display for SqmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sqm: {}", self.message)
+    }
+}
+
+fn err(message: impl Into<String>) -> SqmError {
+    SqmError {
+        message: message.into(),
+    }
+}
+
+/// A [ChargeBackend] running an `sqm` executable. AM1 charges are cached by
+/// the canonical form of the molecular graph and the coordinates of the
+/// conformer they were computed for, so charging another copy of the same
+/// molecule in the same geometry, in any atom order, does not run `sqm`
+/// again.
+pub struct SqmBackend {
+    pub executable: PathBuf,
+
+    /// the maximum number of geometry optimization steps taken before the
+    /// charges are computed. 0 computes them at the input conformer
+    pub max_cycles: usize,
+
+    pub bccs: BccCollection,
+
+    /// AM1 charges in canonical rank order, keyed by [cache_key]
+    cache: Mutex<HashMap<String, Vec<f64>>>,
+}
+
+impl SqmBackend {
+    pub fn new(executable: impl Into<PathBuf>, bccs: BccCollection) -> Self {
+        Self {
+            executable: executable.into(),
+            max_cycles: 0,
+            bccs,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Write the `sqm` input file for the first conformer of `graph`
+    pub fn input(&self, graph: &MoleculeGraph) -> Result<String, SqmError> {
+        let conformer = graph
+            .conformers
+            .first()
+            .ok_or_else(|| err("molecule has no conformers"))?;
+        let charge: i32 =
+            graph.atoms.iter().map(|a| i32::from(a.formal_charge)).sum();
+        let mut ret = format!(
+            "AM1 charges\n \
+             &qmmm\n  \
+             qm_theory='AM1', grms_tol=0.0005, scfconv=1.d-10,\n  \
+             ndiis_attempts=700, qmcharge={charge}, maxcyc={},\n \
+             /\n",
+            self.max_cycles
+        );
+        for (i, atom) in graph.atoms.iter().enumerate() {
+            let [x, y, z] = [0, 1, 2].map(|c| conformer[3 * i + c]);
+            writeln!(
+                ret,
+                "{:>4} A{:<5} {x:>12.6} {y:>12.6} {z:>12.6}",
+                atom.atomic_number,
+                i + 1
+            )
+            .unwrap();
+        }
+        Ok(ret)
+    }
+
+    fn run(&self, graph: &MoleculeGraph) -> Result<Vec<f64>, SqmError> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "sqm-{}-{}",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let io = |e: std::io::Error| err(e.to_string());
+        create_dir_all(&dir).map_err(io)?;
+        let result = (|| {
+            write(dir.join("sqm.in"), self.input(graph)?).map_err(io)?;
+            let output = Command::new(&self.executable)
+                .args(["-O", "-i", "sqm.in", "-o", "sqm.out"])
+                .current_dir(&dir)
+                .output()
+                .map_err(|e| {
+                    err(format!("failed to run {:?}: {e}", self.executable))
+                })?;
+            if !output.status.success() {
+                return Err(err(format!(
+                    "exited with {}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            let out = read_to_string(dir.join("sqm.out")).map_err(io)?;
+            parse_mulliken(&out, graph.n_atoms())
+        })();
+        let _ = remove_dir_all(&dir);
+        result
+    }
+}
+
+/// Parse the final Mulliken charges from `sqm` output, checking that there is
+/// one per atom
+pub fn parse_mulliken(
+    output: &str,
+    n_atoms: usize,
+) -> Result<Vec<f64>, SqmError> {
+    let mut ret = None;
+    let mut lines = output.lines();
+    while let Some(line) = lines.next() {
+        if !(line.contains("Atom") && line.contains("Mulliken Charge")) {
+            continue;
+        }
+        let mut charges = Vec::new();
+        for line in lines.by_ref() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let &[_, _, charge] = fields.as_slice() else {
+                break;
+            };
+            charges.push(charge.parse::<f64>().map_err(|_| {
+                err(format!("failed to parse charge line `{line}`"))
+            })?);
+        }
+        ret = Some(charges);
+    }
+    let ret = ret.ok_or_else(|| err("no Mulliken charges in output"))?;
+    if ret.len() != n_atoms {
+        return Err(err(format!(
+            "expected {n_atoms} charges, found {}",
+            ret.len()
+        )));
+    }
+    Ok(ret)
+}
+
+/// The key of the AM1 charges of `graph` with canonical `ranks` in the
+/// [SqmBackend] cache: [MoleculeGraph::canonical_key] followed by the first
+/// conformer in canonical atom order, rounded to 10⁻⁴ Å
+fn cache_key(graph: &MoleculeGraph, ranks: &[usize]) -> String {
+    let mut ret = graph.canonical_key();
+    let Some(conformer) = graph.conformers.first() else {
+        return ret;
+    };
+    let mut order: Vec<_> = (0..graph.n_atoms()).collect();
+    order.sort_by_key(|&i| ranks[i]);
+    for i in order {
+        for x in &conformer[3 * i..3 * i + 3] {
+            write!(ret, " {x:.4}").unwrap();
+        }
+    }
+    ret
+}
+
+impl ChargeBackend for SqmBackend {
+    fn am1_mulliken(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let ranks = graph.canonical_ranks();
+        let key = cache_key(graph, &ranks);
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            return Ok(ranks.iter().map(|&r| cached[r]).collect());
+        }
+        let charges = self.run(graph)?;
+        let mut canonical = vec![0.0; charges.len()];
+        for (&r, &q) in ranks.iter().zip(&charges) {
+            canonical[r] = q;
+        }
+        self.cache.lock().unwrap().insert(key, canonical);
+        Ok(charges)
+    }
+
+    fn bccs(&self) -> &BccCollection {
+        &self.bccs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{bcc_collection as collection, water};
+
+    use super::*;
+
+    const OUTPUT: &str = "
+  Atom    Element       Mulliken Charge
+     1      H                 0.3000
+     2      O                -0.6000
+     3      H                 0.3000
+ Total Mulliken Charge =       0.0000
+";
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_mulliken(OUTPUT, 3).unwrap(), vec![0.3, -0.6, 0.3]);
+        assert!(parse_mulliken(OUTPUT, 4).is_err());
+        assert!(parse_mulliken("", 3).is_err());
+    }
+
+    #[test]
+    fn input() {
+        let backend = SqmBackend::new("sqm", BccCollection::default());
+        let got = backend.input(&water()).unwrap();
+        assert!(got.contains("qmcharge=0, maxcyc=0"));
+        let atoms: Vec<_> = got.lines().skip(5).collect();
+        assert_eq!(atoms.len(), 3);
+        assert!(atoms[1].starts_with("   8 A2"));
+    }
+
+    /// a stand-in for sqm that writes [OUTPUT] and records each call
+    #[cfg(unix)]
+    fn stub() -> (PathBuf, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir()
+            .join(format!("sqm-stub-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let calls = dir.join("calls");
+        let script = dir.join("sqm");
+        write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                 echo run >> {calls:?}\n\
+                 cat > sqm.out <<EOF{OUTPUT}EOF\n"
+            ),
+        )
+        .unwrap();
+        let mut perms = std::fs::metadata(&script).unwrap().permissions();
+        perms.set_mode(0o755);
+        std::fs::set_permissions(&script, perms).unwrap();
+        (script, calls)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stub_backend() {
+        let (script, calls) = stub();
+        let dir = script.parent().unwrap().to_owned();
+        let backend = SqmBackend::new(script, collection());
+        let mut graph = water();
+        graph.assign_am1bcc_charges(&backend).unwrap();
+        let got = graph.partial_charges.unwrap();
+        assert!((got[1] + 0.4).abs() < 1e-12, "{got:?}");
+
+        // a second copy is served from the cache, even in another atom order
+        let got = backend.am1_mulliken(&water()).unwrap();
+        assert_eq!(got, vec![0.3, -0.6, 0.3]);
+        let w = water();
+        let mut atoms = w.atoms.clone();
+        atoms.swap(0, 1);
+        let mut bonds = w.bonds.clone();
+        bonds[0].atom1 = 1;
+        bonds[0].atom2 = 0;
+        bonds[1].atom1 = 0;
+        let mut permuted = MoleculeGraph::new(atoms, bonds);
+        let c = &w.conformers[0];
+        permuted.conformers = vec![[&c[3..6], &c[0..3], &c[6..9]].concat()];
+        let got = backend.am1_mulliken(&permuted).unwrap();
+        assert_eq!(got, vec![-0.6, 0.3, 0.3]);
+        assert_eq!(read_to_string(&calls).unwrap().lines().count(), 1);
+
+        // but another geometry runs sqm again
+        let mut bent = water();
+        bent.conformers[0][1] += 0.1;
+        backend.am1_mulliken(&bent).unwrap();
+        assert_eq!(read_to_string(&calls).unwrap().lines().count(), 2);
+
+        let missing = SqmBackend::new("/nonexistent/sqm", collection());
+        assert!(missing.am1_mulliken(&water()).is_err());
+        remove_dir_all(dir).unwrap();
+    }
+}
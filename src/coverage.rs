@@ -0,0 +1,367 @@
+//! Counting how many records and molecules in a dataset exercise each
+//! parameter in a force field, and selecting the well-covered parameters for
+//! fitting
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    fs::write,
+    path::Path,
+};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    qcportal::models::Record,
+    qcsubmit::results::ResultCollection,
+    smirnoff::{ForceField, MoleculeLabels, Parameter, UnknownHandlerError},
+    topology::{molecule::MoleculeGraph, Topology},
+};
+
+/// The default minimum number of records a parameter must appear in to be
+/// selected for fitting
+pub const DEFAULT_MIN_COVERAGE: usize = 5;
+
+/// The records and molecules that exercise a single parameter
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ParameterCoverage {
+    pub handler: String,
+    pub id: String,
+    pub smirks: String,
+
+    /// the number of records whose molecule the parameter is assigned to
+    pub n_records: usize,
+
+    /// the number of distinct molecules the parameter is assigned to
+    pub n_molecules: usize,
+
+    /// the record ids and their numbers of heavy atoms, sorted by record id
+    pub records: Vec<(String, usize)>,
+}
+
+/// The [ParameterCoverage] of every parameter in the requested handlers,
+/// including those not assigned to any molecule
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Coverage {
+    pub parameters: Vec<ParameterCoverage>,
+}
+
+impl Coverage {
+    /// Label every molecule in `records` with `force_field` and count the
+    /// coverage of each parameter in `handlers`, such as `"Bonds"` or
+    /// `"ProperTorsions"`. Returns an error if labeling fails or a handler
+    /// is not recognized.
+    pub fn new(
+        records: &[(Record, Molecule)],
+        force_field: &ForceField,
+        handlers: &[&str],
+    ) -> Result<Self, Box<dyn Error>> {
+        let molecules = records.iter().map(|(_, m)| m.clone()).collect();
+        let labels =
+            force_field.label_molecules(Topology::from_molecules(molecules))?;
+        let graphs: Vec<_> = records
+            .iter()
+            .map(|(r, m)| (r.id.clone(), MoleculeGraph::from(m)))
+            .collect();
+        Ok(Self::from_labels(&graphs, &labels, force_field, handlers)?)
+    }
+
+    /// Download the records in `dataset` and count their coverage with
+    /// [Coverage::new]
+    pub fn from_dataset(
+        dataset: ResultCollection,
+        force_field: &ForceField,
+        handlers: &[&str],
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::new(&dataset.to_records(), force_field, handlers)
+    }
+
+    /// Count the coverage of each parameter in `handlers` given the (record id,
+    /// molecule) pairs in `graphs` and their corresponding `labels`. Records
+    /// count as the same molecule if their graphs have the same
+    /// [MoleculeGraph::canonical_key], whatever their atom order.
+    pub(crate) fn from_labels(
+        graphs: &[(String, MoleculeGraph)],
+        labels: &[MoleculeLabels],
+        force_field: &ForceField,
+        handlers: &[&str],
+    ) -> Result<Self, UnknownHandlerError> {
+        type Seen<'a> = (Vec<(&'a str, usize)>, HashSet<usize>);
+        let mut molecule_index: HashMap<String, usize> = HashMap::new();
+        let mut seen: HashMap<(&str, String), Seen> = HashMap::new();
+        for ((record_id, graph), labels) in graphs.iter().zip(labels) {
+            let n = molecule_index.len();
+            let molecule =
+                *molecule_index.entry(graph.canonical_key()).or_insert(n);
+            let n_heavy_atoms = graph.n_heavy_atoms();
+            for &handler in handlers {
+                let Some(matches) = labels.get(handler) else {
+                    continue;
+                };
+                let ids: HashSet<&String> = matches.values().collect();
+                for id in ids {
+                    let entry = seen.entry((handler, id.clone())).or_default();
+                    entry.0.push((record_id, n_heavy_atoms));
+                    entry.1.insert(molecule);
+                }
+            }
+        }
+
+        let mut parameters = Vec::new();
+        for &handler in handlers {
+            let handler_params = force_field.get_parameter_handler(handler)?;
+            for parameter in handler_params.iter() {
+                let id = parameter.id().clone();
+                let (records, molecules) =
+                    seen.remove(&(handler, id.clone())).unwrap_or_default();
+                let mut records: Vec<_> = records
+                    .into_iter()
+                    .map(|(r, n)| (r.to_owned(), n))
+                    .collect();
+                records.sort();
+                parameters.push(ParameterCoverage {
+                    handler: handler.to_owned(),
+                    id,
+                    smirks: parameter.smirks().clone(),
+                    n_records: records.len(),
+                    n_molecules: molecules.len(),
+                    records,
+                });
+            }
+        }
+        Ok(Self { parameters })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ParameterCoverage> {
+        self.parameters.iter().find(|p| p.id == id)
+    }
+
+    /// Return the ids of the parameters covered by at least `min_coverage`
+    /// records, grouped by handler
+    pub fn selected_ids(
+        &self,
+        min_coverage: usize,
+    ) -> BTreeMap<String, Vec<String>> {
+        self.select(min_coverage, |p| p.id.clone())
+    }
+
+    /// Return the SMIRKS of the parameters covered by at least `min_coverage`
+    /// records, grouped by handler
+    pub fn select_parameters(
+        &self,
+        min_coverage: usize,
+    ) -> BTreeMap<String, Vec<String>> {
+        self.select(min_coverage, |p| p.smirks.clone())
+    }
+
+    fn select(
+        &self,
+        min_coverage: usize,
+        f: impl Fn(&ParameterCoverage) -> String,
+    ) -> BTreeMap<String, Vec<String>> {
+        let mut ret = BTreeMap::new();
+        for p in &self.parameters {
+            if p.n_records >= min_coverage {
+                ret.entry(p.handler.clone())
+                    .or_insert_with(Vec::new)
+                    .push(f(p));
+            }
+        }
+        ret
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(&self)
+    }
+
+    /// Return the per-parameter counts as CSV. The heavy-atom counts are
+    /// summarized by their minimum and maximum
+    pub fn to_csv(&self) -> String {
+        let mut ret = String::from(
+            "handler,id,smirks,n_records,n_molecules,\
+             min_heavy_atoms,max_heavy_atoms\n",
+        );
+        for p in &self.parameters {
+            let heavy = p.records.iter().map(|(_, n)| *n);
+            let (min, max) = (heavy.clone().min(), heavy.max());
+            let show =
+                |x: Option<usize>| x.map(|x| x.to_string()).unwrap_or_default();
+            ret.push_str(&format!(
+                "{},{},\"{}\",{},{},{},{}\n",
+                p.handler,
+                p.id,
+                p.smirks,
+                p.n_records,
+                p.n_molecules,
+                show(min),
+                show(max),
+            ));
+        }
+        ret
+    }
+
+    /// Write the report to `path` as JSON if it has a `.json` extension and
+    /// as CSV otherwise
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => self.to_json()?,
+            _ => self.to_csv(),
+        };
+        write(path, contents)?;
+        Ok(())
+    }
+}
+
+impl ForceField {
+    /// Mark the parameters in `coverage` covered by at least `min_coverage`
+    /// records for fitting by setting their `parameterize` attributes, and
+    /// clear the `parameterize` attributes of the others. Bonds are marked
+    /// with `length,k`, angles with `angle,k`, and proper torsions with each
+    /// of their force constants. Returns the number of parameters marked.
+    pub fn set_parameterize(
+        &mut self,
+        coverage: &Coverage,
+        min_coverage: usize,
+    ) -> usize {
+        let selected: HashSet<(&str, &str)> = coverage
+            .parameters
+            .iter()
+            .filter(|p| p.n_records >= min_coverage)
+            .map(|p| (p.handler.as_str(), p.id.as_str()))
+            .collect();
+        let considered: HashSet<&str> = coverage
+            .parameters
+            .iter()
+            .map(|p| p.handler.as_str())
+            .collect();
+        let mut count = 0;
+        let mut update = |target: &mut Option<String>,
+                          handler,
+                          id: &String,
+                          attrs: String| {
+            if selected.contains(&(handler, id.as_str())) {
+                *target = Some(attrs);
+                count += 1;
+            } else if considered.contains(handler) {
+                *target = None;
+            }
+        };
+        for bond in &mut self.bonds {
+            let id = bond.id().clone();
+            update(&mut bond.parameterize, "Bonds", &id, "length,k".to_owned());
+        }
+        for angle in &mut self.angles {
+            let id = angle.id().clone();
+            update(
+                &mut angle.parameterize,
+                "Angles",
+                &id,
+                "angle,k".to_owned(),
+            );
+        }
+        for proper in &mut self.proper_torsions {
+            let ks: Vec<_> = ["k1", "k2", "k3", "k4", "k5", "k6"]
+                .into_iter()
+                .filter(|k| proper.as_hash(k).is_some())
+                .collect();
+            let id = proper.id().clone();
+            update(
+                &mut proper.parameterize,
+                "ProperTorsions",
+                &id,
+                ks.join(","),
+            );
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{ethanol, graph, reversed};
+
+    use super::*;
+
+    /// a record id and a molecule with `n` heavy atoms and a hydrogen
+    fn record(id: &str, n: usize) -> (String, MoleculeGraph) {
+        let mut atoms = vec![6; n];
+        atoms.push(1);
+        (id.to_owned(), graph(&atoms, &[]))
+    }
+
+    fn labels(bonds: &[&str]) -> MoleculeLabels {
+        MoleculeLabels::from([(
+            "Bonds".to_owned(),
+            bonds
+                .iter()
+                .enumerate()
+                .map(|(i, id)| (vec![i, i + 1], id.to_string()))
+                .collect(),
+        )])
+    }
+
+    fn coverage() -> (ForceField, Coverage) {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let graphs = [record("1", 2), record("2", 2), record("3", 5)];
+        let labels = [labels(&["b1", "b1"]), labels(&["b1"]), labels(&["b2"])];
+        let coverage =
+            Coverage::from_labels(&graphs, &labels, &ff, &["Bonds"]).unwrap();
+        (ff, coverage)
+    }
+
+    #[test]
+    fn counts() {
+        let (ff, coverage) = coverage();
+        assert_eq!(coverage.parameters.len(), ff.bonds.bonds.len());
+
+        let b1 = coverage.get("b1").unwrap();
+        assert_eq!(b1.n_records, 2);
+        assert_eq!(b1.n_molecules, 1);
+        assert_eq!(b1.records, vec![("1".to_owned(), 2), ("2".to_owned(), 2)]);
+
+        let b2 = coverage.get("b2").unwrap();
+        assert_eq!((b2.n_records, b2.n_molecules), (1, 1));
+        assert_eq!(b2.records[0].1, 5);
+
+        assert_eq!(coverage.get("b3").unwrap().n_records, 0);
+
+        let csv = coverage.to_csv();
+        assert!(csv.lines().nth(1).unwrap().ends_with(",2,1,2,2"));
+    }
+
+    #[test]
+    fn same_molecule() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        // the same molecule in two atom orders
+        let graphs = [
+            ("1".to_owned(), ethanol()),
+            ("2".to_owned(), reversed(&ethanol())),
+        ];
+        let labels = [labels(&["b1"]), labels(&["b1"])];
+        let coverage =
+            Coverage::from_labels(&graphs, &labels, &ff, &["Bonds"]).unwrap();
+        let b1 = coverage.get("b1").unwrap();
+        assert_eq!((b1.n_records, b1.n_molecules), (2, 1));
+
+        let err = Coverage::from_labels(&graphs, &labels, &ff, &["Bond"]);
+        assert_eq!(err.unwrap_err().handler, "Bond");
+    }
+
+    #[test]
+    fn select() {
+        let (mut ff, coverage) = coverage();
+        assert_eq!(
+            coverage.selected_ids(2),
+            BTreeMap::from([("Bonds".to_owned(), vec!["b1".to_owned()])])
+        );
+        assert_eq!(coverage.selected_ids(1)["Bonds"].len(), 2);
+        assert!(coverage.selected_ids(3).is_empty());
+
+        ff.bonds[2].parameterize = Some("k".to_owned());
+        assert_eq!(ff.set_parameterize(&coverage, 2), 1);
+        assert_eq!(ff.bonds[0].parameterize.as_deref(), Some("length,k"));
+        assert_eq!(ff.bonds[2].parameterize, None);
+    }
+}
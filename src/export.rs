@@ -0,0 +1,594 @@
+//! Exporting a force field applied to a set of molecules in tensor form, for
+//! fitting with differentiable frameworks like smee. [TensorExport::write]
+//! produces a directory containing
+//!
+//! - `parameters.npz`, with one `(n_parameters, n_columns)` array of parameter
+//!   values per handler
+//! - `molecule_NNNN.npz` for each molecule, holding its atomic numbers, the
+//!   atom indices of each valence term, the assignment matrices mapping those
+//!   terms to rows of the parameter tables, the nonbonded pairs, and the
+//!   conformers, QM energies, and QM forces of its records
+//! - `index.json`, describing the rows, columns, and units of each parameter
+//!   table, the contents of each molecule file, and the molecules skipped
+//!   because they could not be parameterized
+//!
+//! Every array is a little-endian `float64` or `int64` array readable with
+//! `numpy.load`. Values are in kcal/mol, angstroms, and radians. Forces are
+//! only written if at least one record of the molecule has a QM gradient, and
+//! are NaN for the conformers of any others.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{create_dir_all, write},
+    path::Path,
+};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    benchmark::group_conformers,
+    interchange::Interchange,
+    qcportal::models::Record,
+    qcsubmit::results::ResultCollection,
+    smirnoff::ForceField,
+    topology::{molecule::MoleculeGraph, Topology},
+    utils::npy::{write_npz, NpyArray},
+};
+
+/// The handlers exported, with the columns of their parameter tables, the
+/// units of those columns, and the number of atoms in each term
+const HANDLERS: [(&str, &[&str], &[&str], usize); 5] = [
+    (
+        "Bonds",
+        &["k", "length"],
+        &["kcal/mol/angstrom**2", "angstrom"],
+        2,
+    ),
+    (
+        "Angles",
+        &["k", "angle"],
+        &["kcal/mol/radian**2", "radian"],
+        3,
+    ),
+    (
+        "ProperTorsions",
+        &["k", "periodicity", "phase", "idivf"],
+        &["kcal/mol", "dimensionless", "radian", "dimensionless"],
+        4,
+    ),
+    (
+        "ImproperTorsions",
+        &["k", "periodicity", "phase", "idivf"],
+        &["kcal/mol", "dimensionless", "radian", "dimensionless"],
+        4,
+    ),
+    (
+        "vdW",
+        &["epsilon", "rmin_half"],
+        &["kcal/mol", "angstrom"],
+        1,
+    ),
+];
+
+/// The parameters of a single handler assigned to at least one molecule
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ParameterTable {
+    pub handler: String,
+
+    /// the parameter id for each row
+    pub ids: Vec<String>,
+
+    /// for torsions, the `N` in `kN` for each row, since each term of a
+    /// torsion parameter gets its own row
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terms: Option<Vec<usize>>,
+
+    pub columns: Vec<String>,
+    pub units: Vec<String>,
+
+    /// the parameter values in row-major order, written to `parameters.npz`
+    /// rather than the index
+    #[serde(skip)]
+    pub values: Vec<f64>,
+}
+
+impl ParameterTable {
+    pub fn n_rows(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// The contents of a single molecule file, as recorded in the index
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MoleculeIndex {
+    pub file: String,
+    pub n_atoms: usize,
+    pub n_conformers: usize,
+
+    /// the record each conformer came from, if any
+    pub record_ids: Vec<String>,
+
+    /// the names of the arrays in the file
+    pub arrays: Vec<String>,
+}
+
+/// A molecule left out of the export because it could not be parameterized,
+/// or a record left out because it has no conformer
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Skipped {
+    /// the index of the molecule among those passed to
+    /// [TensorExport::from_molecules], or among the groups of records with
+    /// the same molecule passed to [TensorExport::new]. This is None for
+    /// records without a conformer, which are not grouped into a molecule.
+    pub molecule: Option<usize>,
+
+    /// the records of its conformers, if any
+    pub record_ids: Vec<String>,
+
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Index {
+    pub parameters: Vec<ParameterTable>,
+    pub molecules: Vec<MoleculeIndex>,
+    pub skipped: Vec<Skipped>,
+}
+
+/// The terms of one handler in one molecule
+#[derive(Clone, Debug, Default)]
+struct Terms {
+    /// flattened atom indices, with the handler's number of atoms per term
+    atoms: Vec<usize>,
+
+    /// the parameter table row for each term
+    rows: Vec<usize>,
+}
+
+#[derive(Clone, Debug)]
+struct MoleculeTensors {
+    atomic_numbers: Vec<i64>,
+    terms: Vec<Terms>,
+    pairs: Vec<(usize, usize, f64)>,
+    record_ids: Vec<String>,
+    conformers: Vec<Vec<f64>>,
+    energies: Vec<f64>,
+
+    /// the QM gradient of each conformer in kcal/mol/angstrom, if known
+    gradients: Vec<Option<Vec<f64>>>,
+}
+
+/// A force field applied to a set of molecules, in tensor form
+pub struct TensorExport {
+    pub parameters: Vec<ParameterTable>,
+    molecules: Vec<MoleculeTensors>,
+
+    /// the molecules that could not be parameterized and the records without
+    /// a conformer
+    pub skipped: Vec<Skipped>,
+
+    /// the row of each (handler, id, term) in its parameter table
+    rows: HashMap<(usize, String, usize), usize>,
+}
+
+impl TensorExport {
+    fn empty() -> Self {
+        Self {
+            parameters: HANDLERS
+                .iter()
+                .map(|(handler, columns, units, _)| ParameterTable {
+                    handler: handler.to_string(),
+                    ids: Vec::new(),
+                    terms: handler.ends_with("Torsions").then_some(Vec::new()),
+                    columns: columns.iter().map(|s| s.to_string()).collect(),
+                    units: units.iter().map(|s| s.to_string()).collect(),
+                    values: Vec::new(),
+                })
+                .collect(),
+            molecules: Vec::new(),
+            skipped: Vec::new(),
+            rows: HashMap::new(),
+        }
+    }
+
+    /// Apply `force_field` to the molecules in `records`, as returned by
+    /// [ResultCollection::to_records], with records sharing a molecule grouped
+    /// into its conformers. Molecules that cannot be parameterized and records
+    /// without a conformer are recorded in [TensorExport::skipped].
+    pub fn new(
+        force_field: &ForceField,
+        records: Vec<(Record, Molecule)>,
+    ) -> Self {
+        let mut ret = Self::empty();
+        let (molecules, skipped) = group_conformers(records);
+        ret.skipped.extend(skipped.into_iter().map(|s| Skipped {
+            molecule: None,
+            record_ids: vec![s.id],
+            reason: s.reason,
+        }));
+        for (m, (molecule, graph, conformers)) in
+            molecules.into_iter().enumerate()
+        {
+            let record_ids: Vec<_> =
+                conformers.iter().map(|c| c.record_id.clone()).collect();
+            match ret.molecule(force_field, &molecule, &graph) {
+                Ok(tensors) => ret.molecules.push(MoleculeTensors {
+                    energies: conformers.iter().map(|c| c.energy).collect(),
+                    gradients: conformers
+                        .iter()
+                        .map(|c| c.gradient.clone())
+                        .collect(),
+                    conformers: conformers
+                        .into_iter()
+                        .map(|c| c.positions)
+                        .collect(),
+                    record_ids,
+                    ..tensors
+                }),
+                Err(e) => ret.skipped.push(Skipped {
+                    molecule: Some(m),
+                    record_ids,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        ret
+    }
+
+    /// Download the records in `dataset` with their gradients and export them
+    /// with [TensorExport::new]
+    pub fn from_dataset(
+        force_field: &ForceField,
+        dataset: ResultCollection,
+    ) -> Self {
+        Self::new(force_field, dataset.to_records_with_gradients())
+    }
+
+    /// Apply `force_field` to `molecules`, exporting their conformers without
+    /// any QM energies. Molecules that cannot be parameterized are recorded in
+    /// [TensorExport::skipped].
+    pub fn from_molecules(
+        force_field: &ForceField,
+        molecules: &[Molecule],
+    ) -> Self {
+        let mut ret = Self::empty();
+        for (m, molecule) in molecules.iter().enumerate() {
+            let graph = MoleculeGraph::from(molecule);
+            match ret.molecule(force_field, molecule, &graph) {
+                Ok(tensors) => ret.molecules.push(tensors),
+                Err(e) => ret.skipped.push(Skipped {
+                    molecule: Some(m),
+                    record_ids: Vec::new(),
+                    reason: e.to_string(),
+                }),
+            }
+        }
+        ret
+    }
+
+    /// Apply `force_field` to `molecule`, adding its parameters to the tables
+    /// and returning its tensors with the conformers of `graph` and no
+    /// records
+    fn molecule(
+        &mut self,
+        force_field: &ForceField,
+        molecule: &Molecule,
+        graph: &MoleculeGraph,
+    ) -> Result<MoleculeTensors, Box<dyn Error>> {
+        let topology = Topology::from_molecules(vec![molecule.clone()]);
+        let interchange = force_field.create_interchange(&topology)?;
+        let terms = self.terms(&interchange);
+        Ok(MoleculeTensors {
+            atomic_numbers: graph
+                .atoms
+                .iter()
+                .map(|a| i64::from(a.atomic_number))
+                .collect(),
+            terms,
+            pairs: interchange.vdw_pairs().collect(),
+            record_ids: Vec::new(),
+            conformers: graph.conformers.clone(),
+            energies: Vec::new(),
+            gradients: Vec::new(),
+        })
+    }
+
+    /// Return the row for the parameter `(handler, id, term)`, adding it to the
+    /// parameter table with `values` if it is new
+    fn row(
+        &mut self,
+        handler: usize,
+        id: &str,
+        term: usize,
+        values: &[f64],
+    ) -> usize {
+        let table = &mut self.parameters[handler];
+        *self
+            .rows
+            .entry((handler, id.to_owned(), term))
+            .or_insert_with(|| {
+                table.ids.push(id.to_owned());
+                if let Some(terms) = &mut table.terms {
+                    terms.push(term);
+                }
+                table.values.extend(values);
+                table.ids.len() - 1
+            })
+    }
+
+    fn terms(&mut self, interchange: &Interchange) -> Vec<Terms> {
+        let mut ret = vec![Terms::default(); HANDLERS.len()];
+        for b in &interchange.bonds {
+            ret[0].atoms.extend(b.atoms);
+            ret[0].rows.push(self.row(0, &b.id, 0, &[b.k, b.length]));
+        }
+        for a in &interchange.angles {
+            ret[1].atoms.extend(a.atoms);
+            ret[1].rows.push(self.row(1, &a.id, 0, &[a.k, a.angle]));
+        }
+        for (h, torsions) in
+            [(2, &interchange.torsions), (3, &interchange.impropers)]
+        {
+            for t in torsions {
+                let values = [t.k, t.periodicity, t.phase, t.idivf];
+                ret[h].atoms.extend(t.atoms);
+                ret[h].rows.push(self.row(h, &t.id, t.term, &values));
+            }
+        }
+        for (i, lj) in interchange.vdw.iter().enumerate() {
+            ret[4].atoms.push(i);
+            let values = [lj.epsilon, lj.rmin_half];
+            ret[4].rows.push(self.row(4, &lj.id, 0, &values));
+        }
+        ret
+    }
+
+    pub fn n_molecules(&self) -> usize {
+        self.molecules.len()
+    }
+
+    /// Return the index describing the files written by [TensorExport::write]
+    pub fn index(&self) -> Index {
+        Index {
+            parameters: self.parameters.clone(),
+            molecules: self
+                .molecules
+                .iter()
+                .enumerate()
+                .map(|(m, mol)| MoleculeIndex {
+                    file: molecule_file(m),
+                    n_atoms: mol.atomic_numbers.len(),
+                    n_conformers: mol.conformers.len(),
+                    record_ids: mol.record_ids.clone(),
+                    arrays: self
+                        .molecule_arrays(mol)
+                        .into_iter()
+                        .map(|(name, _)| name)
+                        .collect(),
+                })
+                .collect(),
+            skipped: self.skipped.clone(),
+        }
+    }
+
+    fn molecule_arrays(
+        &self,
+        mol: &MoleculeTensors,
+    ) -> Vec<(String, NpyArray)> {
+        let n_atoms = mol.atomic_numbers.len();
+        let mut ret = vec![(
+            "atomic_numbers".to_owned(),
+            NpyArray::i64(&[n_atoms], mol.atomic_numbers.clone()),
+        )];
+        for ((handler, _, _, arity), (terms, table)) in
+            HANDLERS.iter().zip(mol.terms.iter().zip(&self.parameters))
+        {
+            let n = terms.rows.len();
+            let atoms = terms.atoms.iter().map(|&a| a as i64).collect();
+            ret.push((
+                format!("{handler}_atoms"),
+                NpyArray::i64(&[n, *arity], atoms),
+            ));
+            let mut assignment = vec![0.0; n * table.n_rows()];
+            for (i, &row) in terms.rows.iter().enumerate() {
+                assignment[i * table.n_rows() + row] = 1.0;
+            }
+            ret.push((
+                format!("{handler}_assignment"),
+                NpyArray::f64(&[n, table.n_rows()], assignment),
+            ));
+        }
+        let n_pairs = mol.pairs.len();
+        ret.push((
+            "vdW_pairs".to_owned(),
+            NpyArray::i64(
+                &[n_pairs, 2],
+                mol.pairs
+                    .iter()
+                    .flat_map(|&(i, j, _)| [i as i64, j as i64])
+                    .collect(),
+            ),
+        ));
+        ret.push((
+            "vdW_scales".to_owned(),
+            NpyArray::f64(&[n_pairs], mol.pairs.iter().map(|p| p.2).collect()),
+        ));
+        ret.push((
+            "conformers".to_owned(),
+            NpyArray::f64(
+                &[mol.conformers.len(), n_atoms, 3],
+                mol.conformers.concat(),
+            ),
+        ));
+        if !mol.energies.is_empty() {
+            ret.push((
+                "energies".to_owned(),
+                NpyArray::f64(&[mol.energies.len()], mol.energies.clone()),
+            ));
+        }
+        if mol.gradients.iter().any(Option::is_some) {
+            let forces = mol
+                .gradients
+                .iter()
+                .flat_map(|g| match g {
+                    Some(g) => g.iter().map(|x| -x).collect(),
+                    None => vec![f64::NAN; 3 * n_atoms],
+                })
+                .collect();
+            ret.push((
+                "forces".to_owned(),
+                NpyArray::f64(&[mol.gradients.len(), n_atoms, 3], forces),
+            ));
+        }
+        ret
+    }
+
+    /// Write `parameters.npz`, one `molecule_NNNN.npz` per molecule, and
+    /// `index.json` to the directory `dir`, creating it if needed
+    pub fn write(&self, dir: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let dir = dir.as_ref();
+        create_dir_all(dir)?;
+
+        let tables: Vec<_> = self
+            .parameters
+            .iter()
+            .map(|t| {
+                let shape = [t.n_rows(), t.columns.len()];
+                (t.handler.as_str(), NpyArray::f64(&shape, t.values.clone()))
+            })
+            .collect();
+        let tables: Vec<_> = tables.iter().map(|(h, a)| (*h, a)).collect();
+        write_npz(dir.join("parameters.npz"), &tables)?;
+
+        for (m, mol) in self.molecules.iter().enumerate() {
+            let arrays = self.molecule_arrays(mol);
+            let arrays: Vec<_> =
+                arrays.iter().map(|(n, a)| (n.as_str(), a)).collect();
+            write_npz(dir.join(molecule_file(m)), &arrays)?;
+        }
+
+        write(
+            dir.join("index.json"),
+            serde_json::to_string_pretty(&self.index())?,
+        )?;
+        Ok(())
+    }
+}
+
+fn molecule_file(m: usize) -> String {
+    format!("molecule_{m:04}.npz")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, read_to_string, remove_dir_all};
+
+    use crate::interchange::{BondTerm, LjTerm};
+
+    use super::*;
+
+    /// a hydrogen molecule and a chain of three atoms sharing a bond parameter
+    fn export() -> TensorExport {
+        let bond = |atoms, id: &str| BondTerm {
+            atoms,
+            k: 500.0,
+            length: 1.0,
+            id: id.to_owned(),
+        };
+        let lj = |id: &str| LjTerm {
+            epsilon: 0.1,
+            rmin_half: 1.2,
+            id: id.to_owned(),
+            sigma: false,
+        };
+        let h2 = Interchange {
+            n_atoms: 2,
+            bonds: vec![bond([0, 1], "b2")],
+            vdw: vec![lj("n1"), lj("n1")],
+            pairs: vec![(0, 1, 0.0)],
+            ..Default::default()
+        };
+        let chain = Interchange {
+            n_atoms: 3,
+            bonds: vec![bond([0, 1], "b1"), bond([1, 2], "b2")],
+            vdw: vec![lj("n1"), lj("n2"), lj("n1")],
+            pairs: vec![(0, 1, 0.0), (1, 2, 0.0), (0, 2, 0.5)],
+            ..Default::default()
+        };
+        let mut ret = TensorExport::empty();
+        let gradient = Some(vec![1.0, 0.0, 0.0, -1.0, 0.0, 0.0]);
+        for (interchange, energies, gradients) in
+            [(h2, vec![-1.0], vec![gradient]), (chain, vec![], vec![])]
+        {
+            let terms = ret.terms(&interchange);
+            let n = interchange.n_atoms;
+            ret.molecules.push(MoleculeTensors {
+                atomic_numbers: vec![1; n],
+                terms,
+                pairs: interchange.vdw_pairs().collect(),
+                record_ids: Vec::new(),
+                conformers: vec![vec![0.0; 3 * n]],
+                energies,
+                gradients,
+            });
+        }
+        ret
+    }
+
+    #[test]
+    fn tables() {
+        let got = export();
+        let bonds = &got.parameters[0];
+        assert_eq!(bonds.ids, ["b2", "b1"]);
+        assert_eq!(bonds.values, [500.0, 1.0, 500.0, 1.0]);
+        assert_eq!(got.parameters[4].ids, ["n1", "n2"]);
+        assert_eq!(got.parameters[2].terms, Some(Vec::new()));
+        assert_eq!(got.molecules[1].terms[0].rows, [1, 0]);
+
+        let arrays = got.molecule_arrays(&got.molecules[1]);
+        let (name, assignment) = &arrays[2];
+        assert_eq!(name, "Bonds_assignment");
+        assert_eq!(
+            assignment,
+            &NpyArray::f64(&[2, 2], vec![0.0, 1.0, 1.0, 0.0])
+        );
+        let names: Vec<_> = arrays.iter().map(|(n, _)| n.as_str()).collect();
+        assert!(names.contains(&"vdW_pairs"));
+        assert!(!names.contains(&"energies"));
+        assert!(!names.contains(&"forces"));
+        let arrays = got.molecule_arrays(&got.molecules[0]);
+        let (name, forces) = arrays.last().unwrap();
+        assert_eq!(name, "forces");
+        assert_eq!(
+            forces,
+            &NpyArray::f64(&[1, 2, 3], vec![-1.0, 0.0, 0.0, 1.0, 0.0, 0.0])
+        );
+        assert_eq!(arrays[arrays.len() - 2].0, "energies");
+    }
+
+    #[test]
+    fn write_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("export-test-{}", std::process::id()));
+        let got = export();
+        got.write(&dir).unwrap();
+        let index: Index = serde_json::from_str(
+            &read_to_string(dir.join("index.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(index.molecules.len(), 2);
+        assert_eq!(index.molecules[1].file, "molecule_0001.npz");
+        assert!(index.skipped.is_empty());
+        assert_eq!(index.parameters[0].units[1], "angstrom");
+        // values are only written to the npz file
+        assert!(index.parameters[0].values.is_empty());
+        for file in ["parameters.npz", "molecule_0000.npz", "molecule_0001.npz"]
+        {
+            let bytes = read(dir.join(file)).unwrap();
+            assert_eq!(&bytes[..4], b"PK\x03\x04");
+        }
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+//! Fitting force field parameters to QM data
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::smirnoff::{ForceField, Parameter};
+
+pub mod multiplicity;
+pub mod optimize;
+pub mod targets;
+pub mod torsions;
+
+/// A single numeric attribute of a force field parameter, such as the `k` of
+/// the bond parameter `b1` or the `k2` of the proper torsion `t5`
+#[derive(
+    Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct ParameterKey {
+    /// the parameter handler, one of `Bonds`, `Angles`, `ProperTorsions`,
+    /// `ImproperTorsions`, or `vdW`
+    pub handler: String,
+    pub id: String,
+    pub attribute: String,
+}
+
+impl ParameterKey {
+    pub fn new(handler: &str, id: &str, attribute: &str) -> Self {
+        Self {
+            handler: handler.to_owned(),
+            id: id.to_owned(),
+            attribute: attribute.to_owned(),
+        }
+    }
+}
+
+impl Display for ParameterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.handler, self.id, self.attribute)
+    }
+}
+
+impl ForceField {
+    /// Return the attributes selected for fitting by the `parameterize`
+    /// attributes of the bond, angle, torsion, and vdW parameters, in force
+    /// field order. Attributes that the parameter does not have are ignored,
+    /// so a vdW parameter written with `sigma` can only have its `sigma`
+    /// fitted, not its `rmin_half`.
+    pub fn parameterized(&self) -> Vec<ParameterKey> {
+        let mut ret = Vec::new();
+        let mut push = |handler, id: &String, parameterize: &Option<String>| {
+            let Some(parameterize) = parameterize else {
+                return;
+            };
+            for attr in parameterize.split(',').map(str::trim) {
+                ret.push(ParameterKey::new(handler, id, attr));
+            }
+        };
+        for bond in &self.bonds {
+            push("Bonds", bond.id(), &bond.parameterize);
+        }
+        for angle in &self.angles {
+            push("Angles", angle.id(), &angle.parameterize);
+        }
+        for proper in &self.proper_torsions {
+            push("ProperTorsions", proper.id(), &proper.parameterize);
+        }
+        for improper in &self.improper_torsions {
+            push("ImproperTorsions", improper.id(), &improper.parameterize);
+        }
+        for atom in &self.vdw {
+            push("vdW", atom.id(), &atom.parameterize);
+        }
+        ret.retain(|key| self.get_attribute(key).is_some());
+        ret
+    }
+
+    /// Return the numeric value of the attribute identified by `key`, in the
+    /// units it is written with in the force field
+    pub fn get_attribute(&self, key: &ParameterKey) -> Option<f64> {
+        let attr = key.attribute.as_str();
+        let q = match key.handler.as_str() {
+            "Bonds" => (&self.bonds)
+                .into_iter()
+                .find(|p| p.id() == &key.id)?
+                .as_hash(attr),
+            "Angles" => (&self.angles)
+                .into_iter()
+                .find(|p| p.id() == &key.id)?
+                .as_hash(attr),
+            "ProperTorsions" => (&self.proper_torsions)
+                .into_iter()
+                .find(|p| p.id() == &key.id)?
+                .as_hash(attr),
+            "ImproperTorsions" => (&self.improper_torsions)
+                .into_iter()
+                .find(|p| p.id() == &key.id)?
+                .as_hash(attr),
+            "vdW" => (&self.vdw)
+                .into_iter()
+                .find(|p| p.id() == &key.id)?
+                .as_hash(attr),
+            _ => None,
+        };
+        q.map(|q| q.value)
+    }
+
+    /// Overwrite the numeric value of the attribute identified by `key`,
+    /// keeping its units. Returns false if there is no such attribute.
+    pub fn set_attribute(&mut self, key: &ParameterKey, value: f64) -> bool {
+        let attr = key.attribute.as_str();
+        let q = match key.handler.as_str() {
+            "Bonds" => (&mut self.bonds)
+                .into_iter()
+                .find(|p| p.id() == &key.id)
+                .and_then(|p| p.as_hash_mut(attr)),
+            "Angles" => (&mut self.angles)
+                .into_iter()
+                .find(|p| p.id() == &key.id)
+                .and_then(|p| p.as_hash_mut(attr)),
+            "ProperTorsions" => (&mut self.proper_torsions)
+                .into_iter()
+                .find(|p| p.id() == &key.id)
+                .and_then(|p| p.as_hash_mut(attr)),
+            "ImproperTorsions" => (&mut self.improper_torsions)
+                .into_iter()
+                .find(|p| p.id() == &key.id)
+                .and_then(|p| p.as_hash_mut(attr)),
+            "vdW" => (&mut self.vdw)
+                .into_iter()
+                .find(|p| p.id() == &key.id)
+                .and_then(|p| p.as_hash_mut(attr)),
+            _ => None,
+        };
+        match q {
+            Some(q) => {
+                q.value = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        assert!(ff.parameterized().is_empty());
+
+        ff.bonds[0].parameterize = Some("length, k".to_owned());
+        ff.proper_torsions[0].parameterize = Some("k1,k6".to_owned());
+        let keys = ff.parameterized();
+        let id = ff.proper_torsions[0].id().clone();
+        assert_eq!(
+            keys,
+            vec![
+                ParameterKey::new("Bonds", "b1", "length"),
+                ParameterKey::new("Bonds", "b1", "k"),
+                ParameterKey::new("ProperTorsions", &id, "k1"),
+            ]
+        );
+
+        let key = &keys[0];
+        assert_eq!(ff.get_attribute(key), Some(ff.bonds[0].length.value));
+        assert!(ff.set_attribute(key, 1.25));
+        assert_eq!(ff.bonds[0].length.to_string(), "1.25 *angstrom");
+        let missing = ParameterKey::new("Bonds", "b1", "k2");
+        assert!(!ff.set_attribute(&missing, 1.0));
+        assert_eq!(ff.get_attribute(&missing), None);
+    }
+
+    #[test]
+    fn nonbonded_attributes() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        ff.improper_torsions[0].parameterize = Some("k1".to_owned());
+        // TIP3P oxygen is written with sigma rather than rmin_half
+        let water = (&ff.vdw)
+            .into_iter()
+            .position(|p| p.sigma.is_some())
+            .unwrap();
+        ff.vdw[0].parameterize = Some("epsilon, rmin_half".to_owned());
+        ff.vdw[water].parameterize = Some("epsilon, rmin_half".to_owned());
+        let keys = ff.parameterized();
+        let water_id = ff.vdw[water].id().clone();
+        assert_eq!(
+            keys,
+            vec![
+                ParameterKey::new("ImproperTorsions", "i1", "k1"),
+                ParameterKey::new("vdW", "n1", "epsilon"),
+                ParameterKey::new("vdW", "n1", "rmin_half"),
+                ParameterKey::new("vdW", &water_id, "epsilon"),
+            ]
+        );
+
+        let sigma = ParameterKey::new("vdW", &water_id, "sigma");
+        assert!(ff.set_attribute(&sigma, 3.0));
+        assert_eq!(ff.get_attribute(&sigma), Some(3.0));
+        assert!(ff.set_attribute(&keys[0], 2.0));
+        assert_eq!(ff.improper_torsions[0].k1.value, 2.0);
+    }
+}
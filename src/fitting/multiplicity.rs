@@ -0,0 +1,391 @@
+//! Detecting the periodicities of proper torsions from QM torsion scans. The
+//! QM profile of each scan, minus the MM energy of everything except the
+//! proper torsions around the driven bond, is fit to the Fourier series
+//!
+//! ```text
+//! E(φ) = c + Σₙ aₙ cos(nφ) + bₙ sin(nφ)
+//! ```
+//!
+//! over the grid angles. Each pair of coefficients corresponds to a torsion
+//! term `k (1 + cos(nφ - phase))` with `k = √(aₙ² + bₙ²)` and
+//! `phase = atan2(bₙ, aₙ)`. The results are aggregated over every scan whose
+//! driven dihedral is assigned to the same parameter.
+
+use std::{collections::BTreeMap, error::Error};
+
+use ligand::molecule::Molecule;
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    benchmark::torsion::TorsionScan,
+    interchange::{Interchange, TorsionTerm},
+    smirnoff::{ForceField, Parameter},
+    topology::Topology,
+    utils::{geometry::dihedral, Skipped},
+};
+
+/// The highest periodicity considered
+pub const MAX_PERIODICITY: usize = 6;
+
+/// Periodicities accounting for less than this fraction of the variance in
+/// the background-subtracted QM profiles are not significant
+pub const SIGNIFICANCE_THRESHOLD: f64 = 0.05;
+
+/// Periodicities with a mean amplitude below this, in kcal/mol, are not
+/// significant
+pub const MIN_AMPLITUDE: f64 = 0.1;
+
+/// The Fourier component of a single periodicity
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PeriodicityTerm {
+    pub periodicity: usize,
+
+    /// the mean amplitude over the scans, in kcal/mol
+    pub amplitude: f64,
+
+    /// the phase of the summed components over the scans, in degrees in
+    /// `[0, 360)`
+    pub phase: f64,
+
+    /// the mean fraction of the variance of each profile explained by this
+    /// periodicity
+    pub variance_fraction: f64,
+}
+
+/// The periodicities detected for a single proper torsion parameter
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Multiplicity {
+    pub id: String,
+    pub n_scans: usize,
+
+    /// the mean number of torsions around each driven bond assigned to the
+    /// parameter, which divides the amplitudes to give a starting `k`
+    pub n_torsions: f64,
+
+    /// every periodicity up to [MAX_PERIODICITY], in increasing order
+    pub terms: Vec<PeriodicityTerm>,
+
+    /// the significant periodicities, in decreasing order of amplitude
+    pub significant: Vec<usize>,
+}
+
+/// The Fourier coefficients `(aₙ, bₙ)` of `values` at `angles` (in radians)
+/// for periodicities `1..=max`, and the variance of `values`
+fn fourier(
+    angles: &[f64],
+    values: &[f64],
+    max: usize,
+) -> (Vec<(f64, f64)>, f64) {
+    let cols = 2 * max + 1;
+    let a = DMatrix::from_fn(angles.len(), cols, |r, c| match c {
+        0 => 1.0,
+        c if c % 2 == 1 => (angles[r] * c.div_ceil(2) as f64).cos(),
+        c => (angles[r] * (c / 2) as f64).sin(),
+    });
+    let b = DVector::from_column_slice(values);
+    let x = a
+        .svd(true, true)
+        .solve(&b, 1e-10)
+        .expect("SVD was computed with U and V");
+    let coefficients = (1..=max).map(|n| (x[2 * n - 1], x[2 * n])).collect();
+    let mean = b.mean();
+    let variance =
+        b.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    (coefficients, variance)
+}
+
+/// Detect the periodicities of the proper torsion parameters assigned to the
+/// driven dihedral of each scan, where each scan is paired with the
+/// [Interchange] for its molecule. Periodicities above `max_periodicity` are
+/// not considered, and neither are those that the number of grid points in a
+/// scan cannot resolve. The results are sorted by parameter id, and are
+/// returned with the scans that were left out.
+pub fn detect_multiplicities(
+    problems: &[(&Interchange, &TorsionScan)],
+    max_periodicity: usize,
+) -> (Vec<Multiplicity>, Vec<Skipped>) {
+    struct Sums {
+        n_scans: usize,
+        n_torsions: usize,
+        amplitude: Vec<f64>,
+        vectors: Vec<(f64, f64)>,
+        fraction: Vec<f64>,
+    }
+
+    let mut sums: BTreeMap<&str, Sums> = BTreeMap::new();
+    let mut skipped = Vec::new();
+    for (interchange, scan) in problems {
+        let [i, j, k, l] = scan.dihedral;
+        let Some(driven) = interchange
+            .torsions
+            .iter()
+            .find(|t| t.atoms == [i, j, k, l] || t.atoms == [l, k, j, i])
+        else {
+            let reason = "driven dihedral has no proper torsion";
+            skipped.push(Skipped::new(&scan.record_id, reason));
+            continue;
+        };
+        let around = |t: &&TorsionTerm| {
+            let [_, b, c, _] = t.atoms;
+            (b == j && c == k) || (b == k && c == j)
+        };
+
+        // the number of grid points limits the periodicities we can resolve
+        let max =
+            max_periodicity.min(scan.energies.len().saturating_sub(1) / 2);
+        if max == 0 {
+            skipped.push(Skipped::new(&scan.record_id, "too few grid points"));
+            continue;
+        }
+
+        let values: Vec<f64> = scan
+            .positions
+            .iter()
+            .zip(&scan.energies)
+            .map(|(positions, qm)| {
+                let torsions: f64 = interchange
+                    .torsions
+                    .iter()
+                    .filter(around)
+                    .map(|t| {
+                        let [a, b, c, d] = t.atoms;
+                        t.k * t.basis(dihedral(positions, a, b, c, d))
+                    })
+                    .sum();
+                qm - (interchange.energy(positions) - torsions)
+            })
+            .collect();
+        let angles: Vec<f64> =
+            scan.angles.iter().map(|a| a.to_radians()).collect();
+        let (coefficients, variance) = fourier(&angles, &values, max);
+
+        let mut dihedrals: Vec<_> = interchange
+            .torsions
+            .iter()
+            .filter(around)
+            .filter(|t| t.id == driven.id)
+            .map(|t| t.atoms)
+            .collect();
+        dihedrals.sort();
+        dihedrals.dedup();
+
+        let s = sums.entry(&driven.id).or_insert_with(|| Sums {
+            n_scans: 0,
+            n_torsions: 0,
+            amplitude: vec![0.0; max_periodicity],
+            vectors: vec![(0.0, 0.0); max_periodicity],
+            fraction: vec![0.0; max_periodicity],
+        });
+        s.n_scans += 1;
+        s.n_torsions += dihedrals.len();
+        for (n, (a, b)) in coefficients.into_iter().enumerate() {
+            let amplitude = a.hypot(b);
+            s.amplitude[n] += amplitude;
+            s.vectors[n].0 += a;
+            s.vectors[n].1 += b;
+            // a sinusoid of amplitude A has variance A²/2
+            if variance > 0.0 {
+                s.fraction[n] += 0.5 * amplitude * amplitude / variance;
+            }
+        }
+    }
+
+    let multiplicities = sums
+        .into_iter()
+        .map(|(id, s)| {
+            let scans = s.n_scans as f64;
+            let terms: Vec<_> = (0..max_periodicity)
+                .map(|n| PeriodicityTerm {
+                    periodicity: n + 1,
+                    amplitude: s.amplitude[n] / scans,
+                    phase: s.vectors[n]
+                        .1
+                        .atan2(s.vectors[n].0)
+                        .to_degrees()
+                        .rem_euclid(360.0),
+                    variance_fraction: s.fraction[n] / scans,
+                })
+                .collect();
+            let mut significant: Vec<_> = terms
+                .iter()
+                .filter(|t| {
+                    t.amplitude >= MIN_AMPLITUDE
+                        && t.variance_fraction >= SIGNIFICANCE_THRESHOLD
+                })
+                .collect();
+            significant.sort_by(|a, b| b.amplitude.total_cmp(&a.amplitude));
+            let significant =
+                significant.iter().map(|t| t.periodicity).collect();
+            Multiplicity {
+                id: id.to_owned(),
+                n_scans: s.n_scans,
+                n_torsions: s.n_torsions as f64 / scans,
+                terms,
+                significant,
+            }
+        })
+        .collect();
+    (multiplicities, skipped)
+}
+
+impl ForceField {
+    /// Detect the periodicities of the proper torsions assigned to the driven
+    /// dihedrals in `scans` with [detect_multiplicities], considering
+    /// periodicities up to [MAX_PERIODICITY]. Molecules that cannot be
+    /// parameterized are returned with the other skipped scans.
+    pub fn detect_multiplicities(
+        &self,
+        scans: &[(Molecule, TorsionScan)],
+    ) -> (Vec<Multiplicity>, Vec<Skipped>) {
+        let mut interchanges = Vec::with_capacity(scans.len());
+        let mut skipped = Vec::new();
+        for (molecule, scan) in scans {
+            let topology = Topology::from_molecules(vec![molecule.clone()]);
+            match self.create_interchange(&topology) {
+                Ok(i) => interchanges.push((i, scan)),
+                Err(e) => skipped.push(Skipped::new(&scan.record_id, e)),
+            }
+        }
+        let problems: Vec<_> =
+            interchanges.iter().map(|(i, s)| (i, *s)).collect();
+        let (multiplicities, mut rest) =
+            detect_multiplicities(&problems, MAX_PERIODICITY);
+        skipped.append(&mut rest);
+        (multiplicities, skipped)
+    }
+
+    /// Rewrite the terms of each proper torsion in `multiplicities` to carry
+    /// its significant periodicities, in decreasing order of amplitude. Phases
+    /// are rounded to 0 or 180 degrees, and each `k` starts from the mean
+    /// amplitude divided by the number of torsions around the driven bonds.
+    /// Parameters with no significant periodicities are left unchanged, and a
+    /// `parameterize` attribute is updated to select the new terms. Returns
+    /// the ids of the parameters that were left unchanged.
+    pub fn set_multiplicities(
+        &mut self,
+        multiplicities: &[Multiplicity],
+    ) -> Result<Vec<Skipped>, Box<dyn Error>> {
+        let mut skipped = Vec::new();
+        for m in multiplicities {
+            let Some(proper) = (&mut self.proper_torsions)
+                .into_iter()
+                .find(|p| p.id() == &m.id)
+            else {
+                skipped.push(Skipped::new(&m.id, "no such proper torsion"));
+                continue;
+            };
+            if m.significant.is_empty() {
+                let reason = "no significant periodicities";
+                skipped.push(Skipped::new(&m.id, reason));
+                continue;
+            }
+
+            let terms: Vec<_> = m
+                .significant
+                .iter()
+                .map(|&n| {
+                    let term = &m.terms[n - 1];
+                    let phase = if term.phase.to_radians().cos() >= 0.0 {
+                        0.0
+                    } else {
+                        180.0
+                    };
+                    (n, phase, term.amplitude / m.n_torsions.max(1.0))
+                })
+                .collect();
+            proper.set_terms(&terms)?;
+            if proper.parameterize.is_some() {
+                let ks: Vec<_> =
+                    (1..=terms.len()).map(|n| format!("k{n}")).collect();
+                proper.parameterize = Some(ks.join(","));
+            }
+        }
+        Ok(skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{butane, butane_positions, butane_torsion};
+
+    use super::*;
+
+    /// butane with a one-fold torsion term
+    fn one_fold() -> Interchange {
+        butane(vec![butane_torsion(1.0, 3.0, 1)])
+    }
+
+    /// a QM scan whose torsional part is a two-fold term with phase 180 and a
+    /// three-fold term with phase 0 on top of the non-torsional MM energy
+    fn scan() -> TorsionScan {
+        let mut background = one_fold();
+        background.torsions.clear();
+        let angles: Vec<f64> =
+            (-165..=180).step_by(15).map(f64::from).collect();
+        let positions: Vec<_> =
+            angles.iter().map(|&a| butane_positions(a, 1.5)).collect();
+        TorsionScan {
+            record_id: "1".to_owned(),
+            dihedral: [0, 1, 2, 3],
+            energies: positions
+                .iter()
+                .zip(&angles)
+                .map(|(p, a)| {
+                    let phi = a.to_radians();
+                    background.energy(p)
+                        + 1.5 * (1.0 + (2.0 * phi - std::f64::consts::PI).cos())
+                        + 0.8 * (1.0 + (3.0 * phi).cos())
+                        - 500.0
+                })
+                .collect(),
+            positions,
+            angles,
+        }
+    }
+
+    #[test]
+    fn detect() {
+        let interchange = one_fold();
+        let scan = scan();
+        let (got, skipped) = detect_multiplicities(&[(&interchange, &scan)], 6);
+        assert!(skipped.is_empty());
+        assert_eq!(got.len(), 1);
+        let m = &got[0];
+        assert_eq!(m.id, "t1");
+        assert_eq!(m.n_scans, 1);
+        assert_eq!(m.n_torsions, 1.0);
+        assert_eq!(m.significant, vec![2, 3]);
+        assert!((m.terms[1].amplitude - 1.5).abs() < 1e-8);
+        assert!((m.terms[1].phase - 180.0).abs() < 1e-6);
+        assert!((m.terms[2].amplitude - 0.8).abs() < 1e-8);
+        assert!(m.terms[2].phase.min(360.0 - m.terms[2].phase) < 1e-6);
+        assert!(m.terms[0].amplitude < 1e-8);
+    }
+
+    #[test]
+    fn set() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let interchange = one_fold();
+        let (mut got, _) = detect_multiplicities(&[(&interchange, &scan())], 6);
+        got[0].id = "t2".to_owned();
+        let mut missing = got[0].clone();
+        missing.id = "missing".to_owned();
+        got.push(missing);
+        let skipped = ff.set_multiplicities(&got).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].id, "missing");
+        let t2 = (&ff.proper_torsions)
+            .into_iter()
+            .find(|p| p.id() == "t2")
+            .unwrap();
+        let terms = t2.terms().unwrap();
+        assert_eq!(terms.len(), 2);
+        let (periodicity, phase, k, idivf) = terms[0].1;
+        assert_eq!((periodicity, phase, idivf), (2.0, 180.0, 1.0));
+        assert!((k - 1.5).abs() < 1e-8);
+        assert_eq!((terms[1].1).0, 3.0);
+        assert_eq!((terms[1].1).1, 0.0);
+        assert!(t2.k3.is_none());
+    }
+}
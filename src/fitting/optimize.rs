@@ -0,0 +1,919 @@
+//! A ForceBalance-style optimizer for force field parameters. The objective
+//! function is
+//!
+//! ```text
+//! X(θ) = Σₜ wₜ Σᵢ rₜᵢ(θ)² + Σⱼ ((θⱼ - θ⁰ⱼ) / σⱼ)²
+//! ```
+//!
+//! where the `rₜᵢ` are the residuals of each [Target] with weight `wₜ` and the
+//! second sum is a Gaussian prior centered on the initial parameter values `θ⁰`
+//! with widths `σ`. As in ForceBalance, the optimization is carried out in
+//! terms of the scaled parameters `(θ - θ⁰) / σ`, which puts parameters with
+//! very different units on an equal footing. The objective is minimized with
+//! the Gauss-Newton or Levenberg-Marquardt algorithm using the Jacobian of the
+//! residuals, which is computed analytically for targets that provide one and
+//! by central finite differences otherwise.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Display,
+    fs::{create_dir_all, read_to_string, write},
+    path::PathBuf,
+};
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::smirnoff::ForceField;
+
+use super::ParameterKey;
+
+/// The number of times a step is shortened, or the Levenberg-Marquardt damping
+/// is increased, before giving up on reducing the objective
+const MAX_REJECTIONS: usize = 10;
+
+/// A weighted contribution to the objective function
+pub trait Target {
+    /// a name identifying the target in the optimization history
+    fn name(&self) -> &str;
+
+    fn weight(&self) -> f64;
+
+    /// Return the residuals for `force_field`, scaled so that the sum of their
+    /// squares is the unweighted contribution of the target to the objective.
+    /// The number of residuals must not depend on the parameter values.
+    fn residuals(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Vec<f64>, Box<dyn Error>>;
+
+    /// Return the derivatives of the residuals with respect to the parameters
+    /// in `keys`, in the units the parameters are written in, as a matrix with
+    /// one row per residual and one column per key, or `None` to compute them
+    /// by finite differences
+    fn jacobian(
+        &self,
+        _force_field: &ForceField,
+        _keys: &[ParameterKey],
+    ) -> Option<Result<DMatrix<f64>, Box<dyn Error>>> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum OptimizeError {
+    /// no parameters are marked for fitting with `parameterize`
+    NoParameters,
+
+    /// no prior width is available for the parameter
+    MissingPrior(ParameterKey),
+
+    /// the force field passed to [Optimizer::resume] lacks a parameter
+    MissingParameter(ParameterKey),
+
+    /// a target returned a different number of residuals than expected
+    ResidualCount {
+        target: String,
+        expected: usize,
+        got: usize,
+    },
+
+    /// the linear system for the step could not be solved
+    Singular,
+}
+
+impl Error for OptimizeError {}
+
+impl Display for OptimizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptimizeError::NoParameters => {
+                write!(f, "no parameters selected for optimization")
+            }
+            OptimizeError::MissingPrior(key) => {
+                write!(f, "no prior width for {key}")
+            }
+            OptimizeError::MissingParameter(key) => {
+                write!(f, "force field has no parameter {key}")
+            }
+            OptimizeError::ResidualCount {
+                target,
+                expected,
+                got,
+            } => write!(
+                f,
+                "target {target} returned {got} residuals instead of \
+                 {expected}"
+            ),
+            OptimizeError::Singular => {
+                write!(f, "singular optimization step")
+            }
+        }
+    }
+}
+
+/// The widths of the Gaussian priors on each kind of parameter attribute,
+/// keyed by handler and attribute. Numbered attributes like the `k2` of a
+/// proper torsion share the prior of the unnumbered attribute, `k`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Priors {
+    widths: BTreeMap<String, f64>,
+}
+
+impl Default for Priors {
+    /// The prior widths used for the OpenFF Sage force fields, in kcal/mol/Å²,
+    /// Å, kcal/mol/rad², degrees, and kcal/mol
+    fn default() -> Self {
+        let mut ret = Self {
+            widths: BTreeMap::new(),
+        };
+        ret.set("Bonds", "k", 100.0);
+        ret.set("Bonds", "length", 0.1);
+        ret.set("Angles", "k", 100.0);
+        ret.set("Angles", "angle", 5.0);
+        ret.set("ProperTorsions", "k", 5.0);
+        ret
+    }
+}
+
+impl Priors {
+    pub fn set(&mut self, handler: &str, attribute: &str, width: f64) {
+        self.widths.insert(format!("{handler}/{attribute}"), width);
+    }
+
+    /// Return the prior width for the parameter attribute `key`
+    pub fn width(&self, key: &ParameterKey) -> Option<f64> {
+        let attribute =
+            key.attribute.trim_end_matches(|c: char| c.is_ascii_digit());
+        self.widths
+            .get(&format!("{}/{attribute}", key.handler))
+            .copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Algorithm {
+    /// undamped Gauss-Newton steps, halved until they reduce the objective
+    GaussNewton,
+
+    /// Gauss-Newton steps with an adaptive damping term
+    LevenbergMarquardt,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub algorithm: Algorithm,
+    pub max_iterations: usize,
+
+    /// stop when an iteration reduces the objective by less than this
+    pub objective_tolerance: f64,
+
+    /// stop when the norm of a step in scaled parameters is less than this
+    pub step_tolerance: f64,
+
+    /// the initial Levenberg-Marquardt damping
+    pub initial_lambda: f64,
+
+    /// the finite difference step in scaled parameters
+    pub finite_difference_step: f64,
+
+    /// use the analytic Jacobians of targets that provide them
+    pub analytic: bool,
+
+    /// if set, the force field from each iteration is written to
+    /// `iter_NNNN.offxml` in this directory, along with the optimization
+    /// history in `history.json`
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::LevenbergMarquardt,
+            max_iterations: 20,
+            objective_tolerance: 1e-4,
+            step_tolerance: 1e-4,
+            initial_lambda: 1e-2,
+            finite_difference_step: 1e-3,
+            analytic: true,
+            output_dir: None,
+        }
+    }
+}
+
+/// The state of the optimization after a single iteration
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Iteration {
+    pub iteration: usize,
+    pub objective: f64,
+
+    /// the contribution of the prior to the objective
+    pub prior: f64,
+
+    /// the weighted contribution of each target to the objective
+    pub targets: Vec<(String, f64)>,
+
+    /// the Levenberg-Marquardt damping used for the step that produced this
+    /// iteration
+    pub lambda: f64,
+
+    /// the parameter values, in the order of [Optimizer::parameters]
+    pub parameters: Vec<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FittedParameter {
+    pub key: ParameterKey,
+    pub initial: f64,
+    pub fitted: f64,
+}
+
+/// Why [Optimizer::run] stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// the objective or step tolerance was met, including by a step that
+    /// did not reduce the objective
+    Converged,
+
+    /// no step could reduce the objective, although the tolerances were not
+    /// met
+    Stalled,
+
+    /// the maximum number of iterations was reached
+    MaxIterations,
+}
+
+/// The results of [Optimizer::run]
+#[derive(Clone, Debug)]
+pub struct Optimization {
+    pub force_field: ForceField,
+    pub parameters: Vec<FittedParameter>,
+    pub history: Vec<Iteration>,
+    pub termination: Termination,
+}
+
+impl Optimization {
+    /// whether the convergence criteria were met
+    pub fn converged(&self) -> bool {
+        self.termination == Termination::Converged
+    }
+}
+
+/// The weighted residuals of every target and the prior at one point
+struct Evaluation {
+    residuals: DVector<f64>,
+
+    /// the number of residuals from each target
+    sizes: Vec<usize>,
+    contributions: Vec<f64>,
+    prior: f64,
+    objective: f64,
+}
+
+pub struct Optimizer {
+    force_field: ForceField,
+    targets: Vec<Box<dyn Target>>,
+    keys: Vec<ParameterKey>,
+    initial: Vec<f64>,
+    widths: Vec<f64>,
+
+    /// the scaled parameters to start from
+    start: DVector<f64>,
+
+    /// the history of the optimization being resumed, ending with the
+    /// iteration at `start`
+    previous: Vec<Iteration>,
+    options: Options,
+}
+
+impl Optimizer {
+    /// Prepare to optimize the parameters of `force_field` selected by their
+    /// `parameterize` attributes against `targets`, with prior widths from
+    /// `priors` centered on the current parameter values
+    pub fn new(
+        force_field: ForceField,
+        targets: Vec<Box<dyn Target>>,
+        priors: &Priors,
+        options: Options,
+    ) -> Result<Self, OptimizeError> {
+        let keys = force_field.parameterized();
+        if keys.is_empty() {
+            return Err(OptimizeError::NoParameters);
+        }
+        let mut initial = Vec::with_capacity(keys.len());
+        let mut widths = Vec::with_capacity(keys.len());
+        for key in &keys {
+            initial.push(force_field.get_attribute(key).unwrap());
+            widths.push(
+                priors
+                    .width(key)
+                    .ok_or_else(|| OptimizeError::MissingPrior(key.clone()))?,
+            );
+        }
+        Ok(Self {
+            start: DVector::zeros(keys.len()),
+            previous: Vec::new(),
+            force_field,
+            targets,
+            keys,
+            initial,
+            widths,
+            options,
+        })
+    }
+
+    /// Continue a previous optimization from the parameter values in
+    /// `force_field`, typically the last `iter_NNNN.offxml` it wrote, loaded
+    /// with [ForceField::load]. The priors remain centered on the parameters of
+    /// the force field passed to [Optimizer::new]. If the output directory
+    /// holds a `history.json`, the new iterations are appended to it and
+    /// numbered after its last one, which should be the iteration that wrote
+    /// `force_field`.
+    pub fn resume(
+        &mut self,
+        force_field: &ForceField,
+    ) -> Result<(), Box<dyn Error>> {
+        for (j, key) in self.keys.iter().enumerate() {
+            let value = force_field
+                .get_attribute(key)
+                .ok_or_else(|| OptimizeError::MissingParameter(key.clone()))?;
+            self.start[j] = (value - self.initial[j]) / self.widths[j];
+        }
+        if let Some(dir) = &self.options.output_dir {
+            let path = dir.join("history.json");
+            if path.exists() {
+                self.previous = serde_json::from_str(&read_to_string(path)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The parameters being optimized
+    pub fn parameters(&self) -> &[ParameterKey] {
+        &self.keys
+    }
+
+    fn values(&self, p: &DVector<f64>) -> Vec<f64> {
+        (0..self.keys.len())
+            .map(|j| self.initial[j] + self.widths[j] * p[j])
+            .collect()
+    }
+
+    fn set_parameters(&mut self, p: &DVector<f64>) {
+        for (key, value) in self.keys.iter().zip(self.values(p)) {
+            self.force_field.set_attribute(key, value);
+        }
+    }
+
+    /// The residuals of target `t` for the current force field, scaled by the
+    /// square root of its weight
+    fn target_residuals(&self, t: usize) -> Result<Vec<f64>, Box<dyn Error>> {
+        let target = &self.targets[t];
+        let scale = target.weight().sqrt();
+        Ok(target
+            .residuals(&self.force_field)?
+            .into_iter()
+            .map(|r| scale * r)
+            .collect())
+    }
+
+    fn check_count(
+        &self,
+        t: usize,
+        expected: usize,
+        got: usize,
+    ) -> Result<(), OptimizeError> {
+        if expected != got {
+            return Err(OptimizeError::ResidualCount {
+                target: self.targets[t].name().to_owned(),
+                expected,
+                got,
+            });
+        }
+        Ok(())
+    }
+
+    fn evaluate(
+        &mut self,
+        p: &DVector<f64>,
+        sizes: Option<&[usize]>,
+    ) -> Result<Evaluation, Box<dyn Error>> {
+        self.set_parameters(p);
+        let mut residuals = Vec::new();
+        let mut contributions = Vec::with_capacity(self.targets.len());
+        let mut got_sizes = Vec::with_capacity(self.targets.len());
+        for t in 0..self.targets.len() {
+            let r = self.target_residuals(t)?;
+            if let Some(sizes) = sizes {
+                self.check_count(t, sizes[t], r.len())?;
+            }
+            contributions.push(r.iter().map(|r| r * r).sum());
+            got_sizes.push(r.len());
+            residuals.extend(r);
+        }
+        let prior = p.norm_squared();
+        residuals.extend(p.iter());
+        Ok(Evaluation {
+            objective: contributions.iter().sum::<f64>() + prior,
+            residuals: DVector::from_vec(residuals),
+            sizes: got_sizes,
+            contributions,
+            prior,
+        })
+    }
+
+    /// The Jacobian of the residuals returned by [Optimizer::evaluate] with
+    /// respect to the scaled parameters
+    fn jacobian(
+        &mut self,
+        p: &DVector<f64>,
+        sizes: &[usize],
+    ) -> Result<DMatrix<f64>, Box<dyn Error>> {
+        let n = self.keys.len();
+        let m: usize = sizes.iter().sum();
+        let mut jac = DMatrix::zeros(m + n, n);
+        self.set_parameters(p);
+
+        // (target, first row, number of rows) for each target without an
+        // analytic Jacobian
+        let mut numeric = Vec::new();
+        let mut row = 0;
+        for (t, &size) in sizes.iter().enumerate() {
+            let target = &self.targets[t];
+            let analytic = if self.options.analytic {
+                target.jacobian(&self.force_field, &self.keys)
+            } else {
+                None
+            };
+            match analytic {
+                Some(j) => {
+                    let j = j?;
+                    self.check_count(t, size, j.nrows())?;
+                    let scale = target.weight().sqrt();
+                    for c in 0..n {
+                        for i in 0..size {
+                            jac[(row + i, c)] =
+                                scale * self.widths[c] * j[(i, c)];
+                        }
+                    }
+                }
+                None => numeric.push((t, row, size)),
+            }
+            row += size;
+        }
+
+        if !numeric.is_empty() {
+            let h = self.options.finite_difference_step;
+            for c in 0..n {
+                let mut x = p.clone();
+                x[c] += h;
+                self.set_parameters(&x);
+                let mut plus = Vec::with_capacity(numeric.len());
+                for &(t, _, size) in &numeric {
+                    let r = self.target_residuals(t)?;
+                    self.check_count(t, size, r.len())?;
+                    plus.push(r);
+                }
+                x[c] -= 2.0 * h;
+                self.set_parameters(&x);
+                for (&(t, row, size), plus) in numeric.iter().zip(plus) {
+                    let minus = self.target_residuals(t)?;
+                    self.check_count(t, size, minus.len())?;
+                    for (i, (a, b)) in plus.iter().zip(minus).enumerate() {
+                        jac[(row + i, c)] = (a - b) / (2.0 * h);
+                    }
+                }
+            }
+            self.set_parameters(p);
+        }
+
+        for j in 0..n {
+            jac[(m + j, j)] = 1.0;
+        }
+        Ok(jac)
+    }
+
+    fn record(
+        &self,
+        iteration: usize,
+        eval: &Evaluation,
+        p: &DVector<f64>,
+        lambda: f64,
+    ) -> Iteration {
+        Iteration {
+            iteration,
+            objective: eval.objective,
+            prior: eval.prior,
+            targets: self
+                .targets
+                .iter()
+                .map(|t| t.name().to_owned())
+                .zip(eval.contributions.iter().copied())
+                .collect(),
+            lambda,
+            parameters: self.values(p),
+        }
+    }
+
+    /// Write the force field at `p` and the history so far to the output
+    /// directory, if there is one
+    fn write_iteration(
+        &mut self,
+        p: &DVector<f64>,
+        history: &[Iteration],
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(dir) = self.options.output_dir.clone() else {
+            return Ok(());
+        };
+        create_dir_all(&dir)?;
+        self.set_parameters(p);
+        let iteration = history.last().map(|h| h.iteration).unwrap_or(0);
+        write(
+            dir.join(format!("iter_{iteration:04}.offxml")),
+            self.force_field.to_xml()?,
+        )?;
+        write(
+            dir.join("history.json"),
+            serde_json::to_string_pretty(history)?,
+        )?;
+        Ok(())
+    }
+
+    /// Minimize the objective, starting from the initial parameters or those
+    /// passed to [Optimizer::resume]
+    pub fn run(mut self) -> Result<Optimization, Box<dyn Error>> {
+        let n = self.keys.len();
+        let mut p = self.start.clone();
+        let mut current = self.evaluate(&p, None)?;
+        let mut lambda = match self.options.algorithm {
+            Algorithm::GaussNewton => 0.0,
+            Algorithm::LevenbergMarquardt => self.options.initial_lambda,
+        };
+        // a resumed run replaces the record of the iteration it starts from
+        let mut history = std::mem::take(&mut self.previous);
+        let offset = history.pop().map_or(0, |h| h.iteration);
+        history.push(self.record(offset, &current, &p, lambda));
+        self.write_iteration(&p, &history)?;
+        eprintln!("iteration {offset}: objective = {:.6}", current.objective);
+
+        let mut termination = Termination::MaxIterations;
+        let last = offset + self.options.max_iterations;
+        for iteration in offset + 1..=last {
+            let sizes = current.sizes.clone();
+            let jac = self.jacobian(&p, &sizes)?;
+            let jt = jac.transpose();
+            let jtj = &jt * &jac;
+            let gradient = &jt * &current.residuals;
+
+            let mut accepted = None;
+            let mut scale = 1.0;
+            // the norm of the first step tried
+            let mut proposed = None;
+            for _ in 0..MAX_REJECTIONS {
+                let lhs = &jtj + DMatrix::identity(n, n) * lambda;
+                let step = scale
+                    * lhs
+                        .lu()
+                        .solve(&-&gradient)
+                        .ok_or(OptimizeError::Singular)?;
+                proposed.get_or_insert(step.norm());
+                let trial = &p + &step;
+                let eval = self.evaluate(&trial, Some(&sizes))?;
+                if eval.objective < current.objective {
+                    accepted = Some((trial, step, eval));
+                    break;
+                }
+                match self.options.algorithm {
+                    Algorithm::GaussNewton => scale /= 2.0,
+                    Algorithm::LevenbergMarquardt => lambda *= 10.0,
+                }
+            }
+            let Some((trial, step, eval)) = accepted else {
+                // a step within the tolerance that only fails to reduce the
+                // objective by rounding means the minimum has been reached
+                termination = match proposed {
+                    Some(norm) if norm < self.options.step_tolerance => {
+                        Termination::Converged
+                    }
+                    _ => {
+                        eprintln!("no step reduced the objective, stopping");
+                        Termination::Stalled
+                    }
+                };
+                break;
+            };
+
+            let decrease = current.objective - eval.objective;
+            history.push(self.record(iteration, &eval, &trial, lambda));
+            p = trial;
+            current = eval;
+            self.write_iteration(&p, &history)?;
+            eprintln!(
+                "iteration {iteration}: objective = {:.6}",
+                current.objective
+            );
+            if self.options.algorithm == Algorithm::LevenbergMarquardt {
+                lambda /= 10.0;
+            }
+            if decrease < self.options.objective_tolerance
+                || step.norm() < self.options.step_tolerance
+            {
+                termination = Termination::Converged;
+                break;
+            }
+        }
+
+        self.set_parameters(&p);
+        let parameters = self
+            .keys
+            .iter()
+            .zip(&self.initial)
+            .zip(self.values(&p))
+            .map(|((key, &initial), fitted)| FittedParameter {
+                key: key.clone(),
+                initial,
+                fitted,
+            })
+            .collect();
+        Ok(Optimization {
+            force_field: self.force_field,
+            parameters,
+            history,
+            termination,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    /// a single residual pulling the length of bond `b1` toward `target`
+    struct Toy {
+        target: f64,
+        denominator: f64,
+        analytic: bool,
+    }
+
+    impl Toy {
+        fn key() -> ParameterKey {
+            ParameterKey::new("Bonds", "b1", "length")
+        }
+    }
+
+    impl Target for Toy {
+        fn name(&self) -> &str {
+            "toy"
+        }
+
+        fn weight(&self) -> f64 {
+            1.0
+        }
+
+        fn residuals(
+            &self,
+            force_field: &ForceField,
+        ) -> Result<Vec<f64>, Box<dyn Error>> {
+            let length = force_field.get_attribute(&Self::key()).unwrap();
+            Ok(vec![(length - self.target) / self.denominator])
+        }
+
+        fn jacobian(
+            &self,
+            _force_field: &ForceField,
+            keys: &[ParameterKey],
+        ) -> Option<Result<DMatrix<f64>, Box<dyn Error>>> {
+            if !self.analytic {
+                return None;
+            }
+            Some(Ok(DMatrix::from_fn(1, keys.len(), |_, c| {
+                if keys[c] == Self::key() {
+                    1.0 / self.denominator
+                } else {
+                    0.0
+                }
+            })))
+        }
+    }
+
+    fn force_field() -> ForceField {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        ff.bonds[0].parameterize = Some("length,k".to_owned());
+        ff
+    }
+
+    /// run the toy problem and compare to the analytic minimum of the
+    /// objective, which balances the residual against the prior
+    fn check(algorithm: Algorithm, analytic: bool) {
+        let ff = force_field();
+        let start = ff.bonds[0].length.value;
+        let target = Toy {
+            target: start + 0.05,
+            denominator: 0.01,
+            analytic,
+        };
+        let (d, s) = (target.denominator, 0.1);
+        let want = start + 0.05 * s * s / (s * s + d * d);
+
+        let options = Options {
+            algorithm,
+            max_iterations: 50,
+            objective_tolerance: 1e-12,
+            ..Options::default()
+        };
+        let opt = Optimizer::new(
+            ff,
+            vec![Box::new(target)],
+            &Priors::default(),
+            options,
+        )
+        .unwrap();
+        assert_eq!(opt.parameters().len(), 2);
+        let got = opt.run().unwrap();
+        assert_eq!(got.termination, Termination::Converged);
+        let fitted = got.force_field.bonds[0].length.value;
+        assert!((fitted - want).abs() < 1e-6, "{fitted} {want}");
+        assert_eq!(got.parameters[0].fitted, fitted);
+        assert_eq!(got.parameters[0].initial, start);
+        // the force constant is not exercised and stays put
+        assert!(
+            (got.parameters[1].fitted - got.parameters[1].initial).abs() < 1e-8
+        );
+        let last = got.history.last().unwrap();
+        assert!(last.objective < got.history[0].objective);
+        assert_eq!(last.targets[0].0, "toy");
+    }
+
+    #[test]
+    fn levenberg_marquardt() {
+        check(Algorithm::LevenbergMarquardt, false);
+        check(Algorithm::LevenbergMarquardt, true);
+    }
+
+    #[test]
+    fn gauss_newton() {
+        check(Algorithm::GaussNewton, false);
+        check(Algorithm::GaussNewton, true);
+    }
+
+    #[test]
+    fn stalled() {
+        // a Jacobian of the wrong sign only ever points uphill
+        struct Uphill(Toy);
+
+        impl Target for Uphill {
+            fn name(&self) -> &str {
+                self.0.name()
+            }
+
+            fn weight(&self) -> f64 {
+                self.0.weight()
+            }
+
+            fn residuals(
+                &self,
+                force_field: &ForceField,
+            ) -> Result<Vec<f64>, Box<dyn Error>> {
+                self.0.residuals(force_field)
+            }
+
+            fn jacobian(
+                &self,
+                force_field: &ForceField,
+                keys: &[ParameterKey],
+            ) -> Option<Result<DMatrix<f64>, Box<dyn Error>>> {
+                Some(self.0.jacobian(force_field, keys)?.map(|j| -j))
+            }
+        }
+
+        let ff = force_field();
+        let target = Toy {
+            target: ff.bonds[0].length.value + 0.05,
+            denominator: 0.01,
+            analytic: true,
+        };
+        let options = Options {
+            algorithm: Algorithm::GaussNewton,
+            ..Options::default()
+        };
+        let opt = Optimizer::new(
+            ff,
+            vec![Box::new(Uphill(target))],
+            &Priors::default(),
+            options,
+        )
+        .unwrap();
+        let got = opt.run().unwrap();
+        assert_eq!(got.termination, Termination::Stalled);
+        assert!(!got.converged());
+        assert_eq!(got.history.len(), 1);
+    }
+
+    #[test]
+    fn priors() {
+        let priors = Priors::default();
+        let key = ParameterKey::new("ProperTorsions", "t1", "k3");
+        assert_eq!(priors.width(&key), Some(5.0));
+        let key = ParameterKey::new("ProperTorsions", "t1", "periodicity1");
+        assert_eq!(priors.width(&key), None);
+
+        let mut ff = force_field();
+        assert!(matches!(
+            Optimizer::new(
+                ff.clone(),
+                Vec::new(),
+                &Priors {
+                    widths: BTreeMap::new()
+                },
+                Options::default()
+            ),
+            Err(OptimizeError::MissingPrior(_))
+        ));
+        ff.bonds[0].parameterize = None;
+        assert!(matches!(
+            Optimizer::new(ff, Vec::new(), &priors, Options::default()),
+            Err(OptimizeError::NoParameters)
+        ));
+    }
+
+    #[test]
+    fn output_and_resume() {
+        let dir = std::env::temp_dir()
+            .join(format!("optimize-test-{}", std::process::id()));
+        let ff = force_field();
+        let start = ff.bonds[0].length.value;
+        let toy = || Toy {
+            target: start + 0.05,
+            denominator: 0.01,
+            analytic: true,
+        };
+        let options = Options {
+            output_dir: Some(dir.clone()),
+            ..Options::default()
+        };
+        let got = Optimizer::new(
+            ff.clone(),
+            vec![Box::new(toy())],
+            &Priors::default(),
+            options.clone(),
+        )
+        .unwrap()
+        .run()
+        .unwrap();
+        let last = got.history.last().unwrap();
+        let path = dir.join(format!("iter_{:04}.offxml", last.iteration));
+        let written = ForceField::load(&path).unwrap();
+        assert_eq!(written, got.force_field);
+        let history: Vec<Iteration> = serde_json::from_str(
+            &read_to_string(dir.join("history.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.len(), got.history.len());
+
+        // restarting from the last iteration picks up where it left off,
+        // continuing the history rather than overwriting it
+        let first = read_to_string(dir.join("iter_0000.offxml")).unwrap();
+        let resume = |max_iterations| {
+            let mut opt = Optimizer::new(
+                ff.clone(),
+                vec![Box::new(toy())],
+                &Priors::default(),
+                Options {
+                    max_iterations,
+                    objective_tolerance: 0.0,
+                    step_tolerance: 0.0,
+                    ..options.clone()
+                },
+            )
+            .unwrap();
+            opt.resume(&written).unwrap();
+            opt.run().unwrap()
+        };
+        let resumed = resume(0);
+        assert_eq!(resumed.history.len(), got.history.len());
+        assert_eq!(resumed.history[0].iteration, 0);
+        let now = resumed.history.last().unwrap();
+        assert_eq!(now.iteration, last.iteration);
+        let diff = now.objective - last.objective;
+        assert!(diff.abs() < 1e-8, "{diff}");
+
+        let resumed = resume(1);
+        assert_eq!(
+            read_to_string(dir.join("iter_0000.offxml")).unwrap(),
+            first
+        );
+        let history: Vec<Iteration> = serde_json::from_str(
+            &read_to_string(dir.join("history.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(history.len(), resumed.history.len());
+        assert!(history.len() <= got.history.len() + 1);
+        for (i, h) in history.iter().enumerate() {
+            assert_eq!(h.iteration, i);
+        }
+        remove_dir_all(dir).unwrap();
+    }
+}
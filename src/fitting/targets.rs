@@ -0,0 +1,754 @@
+//! [Target]s for the [super::optimize::Optimizer] built from QCArchive
+//! datasets. Each target compares an MM property to its QM counterpart and
+//! divides the differences by a denominator setting the scale of an acceptable
+//! error, so that the contribution of a target to the objective is the mean of
+//! its squared, scaled differences.
+//!
+//! Each molecule is labeled only once, by the first force field a target is
+//! evaluated with, and later evaluations just update the parameter values as
+//! in [ForceField::update_interchange], so the SMIRKS patterns of the force
+//! field must not change between evaluations.
+
+use std::{error::Error, sync::Mutex};
+
+use ligand::molecule::Molecule;
+use nalgebra::DMatrix;
+
+use crate::{
+    benchmark::{
+        group_conformers,
+        torsion::{mm_profile, ScanMode, TorsionBenchmark, TorsionScan},
+        QmConformer,
+    },
+    interchange::Interchange,
+    qcportal::models::Record,
+    qcsubmit::{
+        client::{Cmiles, FractalClient, ResultRecord},
+        results::ResultCollection,
+    },
+    seminario::Hessian,
+    smirnoff::ForceField,
+    topology::{molecule::MoleculeGraph, Topology},
+    utils::{
+        elements::mass,
+        geometry::{angle, dihedral, distance},
+        Skipped, BOHR_TO_ANGSTROM,
+    },
+};
+
+use super::{optimize::Target, ParameterKey};
+
+/// The default denominator for energies, in kcal/mol
+pub const DEFAULT_ENERGY_DENOMINATOR: f64 = 1.0;
+
+/// The default denominators for bond lengths in angstroms and for angles and
+/// dihedrals in degrees, as used for the OpenFF Sage optimized geometry
+/// targets
+pub const DEFAULT_BOND_DENOMINATOR: f64 = 0.05;
+pub const DEFAULT_ANGLE_DENOMINATOR: f64 = 8.0;
+pub const DEFAULT_DIHEDRAL_DENOMINATOR: f64 = 20.0;
+
+/// The default denominator for vibrational frequencies, in cm⁻¹
+pub const DEFAULT_FREQUENCY_DENOMINATOR: f64 = 200.0;
+
+/// A molecule along with its interchange once it has been labeled
+struct Parametrized {
+    molecule: Molecule,
+    interchange: Mutex<Option<Interchange>>,
+}
+
+impl Parametrized {
+    fn new(molecule: Molecule) -> Self {
+        Self {
+            molecule,
+            interchange: Mutex::new(None),
+        }
+    }
+
+    /// Return the interchange of the molecule with the parameter values of
+    /// `force_field`, labeling the molecule on the first call
+    fn interchange(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Interchange, Box<dyn Error>> {
+        let mut cached = self.interchange.lock().unwrap();
+        if let Some(interchange) = cached.as_ref() {
+            let mut ret = interchange.clone();
+            force_field.update_interchange(&mut ret)?;
+            return Ok(ret);
+        }
+        let topology = Topology::from_molecules(vec![self.molecule.clone()]);
+        let ret = force_field.create_interchange(&topology)?;
+        *cached = Some(ret.clone());
+        Ok(ret)
+    }
+}
+
+/// Scale `residuals` so that the sum of their squares is their mean
+fn normalize(mut residuals: Vec<f64>) -> Vec<f64> {
+    let scale = (residuals.len().max(1) as f64).sqrt();
+    residuals.iter_mut().for_each(|r| *r /= scale);
+    residuals
+}
+
+/// Return the index of the lowest of `energies`
+fn minimum(energies: impl Iterator<Item = f64>) -> usize {
+    energies
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+/// The Jacobian of the energies of `geometries` relative to the energy of
+/// geometry `reference` with respect to the parameters in `keys`
+fn relative_jacobian(
+    interchange: &Interchange,
+    geometries: &[Vec<f64>],
+    reference: usize,
+    keys: &[ParameterKey],
+) -> DMatrix<f64> {
+    let (_, jac) = interchange.parameter_jacobian(geometries);
+    let mut ret = jac.to_dense(keys);
+    let reference = ret.row(reference).clone_owned();
+    for mut row in ret.row_iter_mut() {
+        row -= &reference;
+    }
+    ret
+}
+
+/// Stack the rows of `blocks` into a single matrix with `n_cols` columns, scaled
+/// to match the residuals returned by [normalize]
+fn stack(
+    blocks: Vec<DMatrix<f64>>,
+    n_cols: usize,
+    denominator: f64,
+) -> DMatrix<f64> {
+    let n_rows: usize = blocks.iter().map(|b| b.nrows()).sum();
+    let scale = denominator * (n_rows.max(1) as f64).sqrt();
+    let mut ret = DMatrix::zeros(n_rows, n_cols);
+    let mut row = 0;
+    for block in blocks {
+        let n = block.nrows();
+        ret.rows_mut(row, n).copy_from(&(block / scale));
+        row += n;
+    }
+    ret
+}
+
+/// wrap an angle difference in degrees into [-180, 180)
+fn wrap_degrees(x: f64) -> f64 {
+    (x + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// Torsion profiles from one-dimensional TorsionDrives, compared after
+/// referencing both the QM and MM energies to the QM minimum of each scan
+pub struct TorsionProfileTarget {
+    pub name: String,
+    pub weight: f64,
+    pub mode: ScanMode,
+
+    /// in kcal/mol
+    pub denominator: f64,
+
+    /// each molecule, its heavy atoms, and its scan
+    scans: Vec<(Parametrized, Vec<usize>, TorsionScan)>,
+
+    /// the records left out of the target
+    pub skipped: Vec<Skipped>,
+}
+
+impl TorsionProfileTarget {
+    /// Build a target from the scans paired with their molecules, as returned
+    /// by [TorsionBenchmark::molecules_and_scans], using relaxed MM scans
+    pub fn new(
+        name: &str,
+        weight: f64,
+        scans: Vec<(Molecule, TorsionScan)>,
+    ) -> Self {
+        Self {
+            name: name.to_owned(),
+            weight,
+            mode: ScanMode::Relaxed,
+            denominator: DEFAULT_ENERGY_DENOMINATOR,
+            scans: scans
+                .into_iter()
+                .map(|(m, s)| {
+                    let heavy = MoleculeGraph::from(&m).heavy_atoms();
+                    (Parametrized::new(m), heavy, s)
+                })
+                .collect(),
+            skipped: Vec::new(),
+        }
+    }
+
+    /// Download the torsion drives in `dataset` and build a target from them
+    pub fn from_dataset(
+        name: &str,
+        weight: f64,
+        dataset: ResultCollection,
+    ) -> Self {
+        let benchmark = TorsionBenchmark::from_dataset(dataset);
+        let mut ret =
+            Self::new(name, weight, benchmark.molecules_and_scans().to_vec());
+        ret.skipped = benchmark.skipped;
+        ret
+    }
+}
+
+impl Target for TorsionProfileTarget {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn residuals(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for (molecule, heavy, scan) in &self.scans {
+            let interchange = molecule.interchange(force_field)?;
+            let profile =
+                mm_profile(&self.name, &interchange, heavy, scan, self.mode);
+            ret.extend(
+                profile
+                    .mm_energies
+                    .iter()
+                    .zip(&profile.qm_energies)
+                    .map(|(m, q)| (m - q) / self.denominator),
+            );
+        }
+        Ok(normalize(ret))
+    }
+
+    /// Analytic derivatives are available for rigid scans, where the MM
+    /// energies are evaluated at the fixed QM geometries
+    fn jacobian(
+        &self,
+        force_field: &ForceField,
+        keys: &[ParameterKey],
+    ) -> Option<Result<DMatrix<f64>, Box<dyn Error>>> {
+        if self.mode != ScanMode::Rigid {
+            return None;
+        }
+        let mut blocks = Vec::with_capacity(self.scans.len());
+        for (molecule, _, scan) in &self.scans {
+            let interchange = match molecule.interchange(force_field) {
+                Ok(i) => i,
+                Err(e) => return Some(Err(e)),
+            };
+            let reference = minimum(scan.energies.iter().copied());
+            blocks.push(relative_jacobian(
+                &interchange,
+                &scan.positions,
+                reference,
+                keys,
+            ));
+        }
+        Some(Ok(stack(blocks, keys.len(), self.denominator)))
+    }
+}
+
+/// The bonds, angles, and proper dihedrals of a molecule
+#[derive(Debug, Default, PartialEq)]
+struct InternalCoordinates {
+    bonds: Vec<[usize; 2]>,
+    angles: Vec<[usize; 3]>,
+    dihedrals: Vec<[usize; 4]>,
+}
+
+impl From<&MoleculeGraph> for InternalCoordinates {
+    fn from(graph: &MoleculeGraph) -> Self {
+        let mut ret = Self::default();
+        for bond in &graph.bonds {
+            let (j, k) = (bond.atom1, bond.atom2);
+            ret.bonds.push([j, k]);
+            for i in graph.neighbors(j).filter(|&i| i != k) {
+                for l in graph.neighbors(k).filter(|&l| l != j && l != i) {
+                    ret.dihedrals.push([i, j, k, l]);
+                }
+            }
+        }
+        for j in 0..graph.n_atoms() {
+            let neighbors: Vec<_> = graph.neighbors(j).collect();
+            for (a, &i) in neighbors.iter().enumerate() {
+                for &k in &neighbors[a + 1..] {
+                    ret.angles.push([i, j, k]);
+                }
+            }
+        }
+        ret
+    }
+}
+
+struct Geometry {
+    molecule: Parametrized,
+    positions: Vec<f64>,
+    coordinates: InternalCoordinates,
+}
+
+/// QM-optimized geometries, compared to the MM-minimized geometries in terms
+/// of their bond lengths, angles, and proper dihedrals
+pub struct OptGeoTarget {
+    pub name: String,
+    pub weight: f64,
+
+    /// in angstroms
+    pub bond_denominator: f64,
+
+    /// in degrees
+    pub angle_denominator: f64,
+
+    /// in degrees
+    pub dihedral_denominator: f64,
+
+    geometries: Vec<Geometry>,
+
+    /// the records left out of the target
+    pub skipped: Vec<Skipped>,
+}
+
+impl OptGeoTarget {
+    /// Build a target from the final geometries of `records`, as returned by
+    /// [ResultCollection::to_records]. Records without a conformer are
+    /// recorded in [OptGeoTarget::skipped].
+    pub fn new(
+        name: &str,
+        weight: f64,
+        records: Vec<(Record, Molecule)>,
+    ) -> Self {
+        let mut geometries = Vec::with_capacity(records.len());
+        let mut skipped = Vec::new();
+        for (record, molecule) in records {
+            let mut graph = MoleculeGraph::from(&molecule);
+            let Some(positions) = graph.conformers.pop() else {
+                skipped.push(Skipped::new(record.id, "no conformer"));
+                continue;
+            };
+            geometries.push(Geometry {
+                coordinates: InternalCoordinates::from(&graph),
+                molecule: Parametrized::new(molecule),
+                positions,
+            });
+        }
+        Self {
+            name: name.to_owned(),
+            weight,
+            bond_denominator: DEFAULT_BOND_DENOMINATOR,
+            angle_denominator: DEFAULT_ANGLE_DENOMINATOR,
+            dihedral_denominator: DEFAULT_DIHEDRAL_DENOMINATOR,
+            geometries,
+            skipped,
+        }
+    }
+
+    /// Download the records in `dataset` and build a target from them
+    pub fn from_dataset(
+        name: &str,
+        weight: f64,
+        dataset: ResultCollection,
+    ) -> Self {
+        Self::new(name, weight, dataset.to_records())
+    }
+}
+
+impl Target for OptGeoTarget {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn residuals(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for geom in &self.geometries {
+            let interchange = geom.molecule.interchange(force_field)?;
+            let qm = &geom.positions;
+            let mm = &interchange.minimize(qm).positions;
+            let coords = &geom.coordinates;
+            ret.extend(coords.bonds.iter().map(|&[i, j]| {
+                (distance(mm, i, j) - distance(qm, i, j))
+                    / self.bond_denominator
+            }));
+            ret.extend(coords.angles.iter().map(|&[i, j, k]| {
+                (angle(mm, i, j, k) - angle(qm, i, j, k)).to_degrees()
+                    / self.angle_denominator
+            }));
+            ret.extend(coords.dihedrals.iter().map(|&[i, j, k, l]| {
+                let d = dihedral(mm, i, j, k, l) - dihedral(qm, i, j, k, l);
+                wrap_degrees(d.to_degrees()) / self.dihedral_denominator
+            }));
+        }
+        Ok(normalize(ret))
+    }
+}
+
+/// The energies of the QM-optimized conformers of each molecule relative to
+/// the lowest-energy QM conformer
+pub struct RelativeEnergyTarget {
+    pub name: String,
+    pub weight: f64,
+
+    /// minimize each conformer starting from its QM geometry before computing
+    /// its MM energy, instead of evaluating it at the QM geometry
+    pub minimize: bool,
+
+    /// in kcal/mol
+    pub denominator: f64,
+
+    molecules: Vec<(Parametrized, Vec<QmConformer>)>,
+
+    /// the records left out of the target
+    pub skipped: Vec<Skipped>,
+}
+
+impl RelativeEnergyTarget {
+    /// Build a target from `records`, as returned by
+    /// [ResultCollection::to_records], grouped into molecules as in
+    /// [crate::benchmark::Benchmark::new]. Records without a conformer and
+    /// those of molecules with a single conformer are recorded in
+    /// [RelativeEnergyTarget::skipped].
+    pub fn new(
+        name: &str,
+        weight: f64,
+        records: Vec<(Record, Molecule)>,
+    ) -> Self {
+        let (grouped, mut skipped) = group_conformers(records);
+        let mut molecules = Vec::with_capacity(grouped.len());
+        for (molecule, _, conformers) in grouped {
+            if let [single] = conformers.as_slice() {
+                let reason = "only conformer of its molecule";
+                skipped.push(Skipped::new(&single.record_id, reason));
+            } else {
+                molecules.push((Parametrized::new(molecule), conformers));
+            }
+        }
+        Self {
+            name: name.to_owned(),
+            weight,
+            minimize: false,
+            denominator: DEFAULT_ENERGY_DENOMINATOR,
+            molecules,
+            skipped,
+        }
+    }
+
+    /// Download the records in `dataset` and build a target from them
+    pub fn from_dataset(
+        name: &str,
+        weight: f64,
+        dataset: ResultCollection,
+    ) -> Self {
+        Self::new(name, weight, dataset.to_records())
+    }
+}
+
+impl Target for RelativeEnergyTarget {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn residuals(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for (molecule, conformers) in &self.molecules {
+            let interchange = molecule.interchange(force_field)?;
+            let energies: Vec<f64> = conformers
+                .iter()
+                .map(|c| {
+                    if self.minimize {
+                        interchange.minimize(&c.positions).energy
+                    } else {
+                        interchange.energy(&c.positions)
+                    }
+                })
+                .collect();
+            let reference = minimum(conformers.iter().map(|c| c.energy));
+            let qm_ref = conformers[reference].energy;
+            for (i, (qm, mm)) in conformers.iter().zip(&energies).enumerate() {
+                if i != reference {
+                    let dde = (mm - energies[reference]) - (qm.energy - qm_ref);
+                    ret.push(dde / self.denominator);
+                }
+            }
+        }
+        Ok(normalize(ret))
+    }
+
+    /// Analytic derivatives are available when the MM energies are evaluated
+    /// at the fixed QM geometries
+    fn jacobian(
+        &self,
+        force_field: &ForceField,
+        keys: &[ParameterKey],
+    ) -> Option<Result<DMatrix<f64>, Box<dyn Error>>> {
+        if self.minimize {
+            return None;
+        }
+        let mut blocks = Vec::with_capacity(self.molecules.len());
+        for (molecule, conformers) in &self.molecules {
+            let interchange = match molecule.interchange(force_field) {
+                Ok(i) => i,
+                Err(e) => return Some(Err(e)),
+            };
+            let reference = minimum(conformers.iter().map(|c| c.energy));
+            let geometries: Vec<_> =
+                conformers.iter().map(|c| c.positions.clone()).collect();
+            let jac =
+                relative_jacobian(&interchange, &geometries, reference, keys);
+            blocks.push(jac.remove_row(reference));
+        }
+        Some(Ok(stack(blocks, keys.len(), self.denominator)))
+    }
+}
+
+struct Frequencies {
+    record_id: String,
+    molecule: Parametrized,
+    positions: Vec<f64>,
+    masses: Vec<f64>,
+
+    /// in cm⁻¹
+    qm: Vec<f64>,
+}
+
+/// QM harmonic vibrational frequencies, compared in ascending order to the MM
+/// frequencies at the MM-minimized geometry
+pub struct VibrationalFrequencyTarget {
+    pub name: String,
+    pub weight: f64,
+
+    /// in cm⁻¹
+    pub denominator: f64,
+
+    systems: Vec<Frequencies>,
+
+    /// the records left out of the target
+    pub skipped: Vec<Skipped>,
+}
+
+impl VibrationalFrequencyTarget {
+    /// Build a target from (record id, molecule, QM Hessian) triples, where
+    /// each molecule's first conformer is the geometry the Hessian was
+    /// computed at. Records whose frequencies cannot be computed are recorded
+    /// in [VibrationalFrequencyTarget::skipped].
+    pub fn new(
+        name: &str,
+        weight: f64,
+        records: Vec<(String, Molecule, Hessian)>,
+    ) -> Self {
+        let mut systems = Vec::with_capacity(records.len());
+        let mut skipped = Vec::new();
+        for (record_id, molecule, hessian) in records {
+            let graph = MoleculeGraph::from(&molecule);
+            let Some(positions) = graph.conformers.first().cloned() else {
+                skipped.push(Skipped::new(record_id, "no conformer"));
+                continue;
+            };
+            let Some(masses) = graph
+                .atoms
+                .iter()
+                .map(|a| mass(a.atomic_number))
+                .collect::<Option<Vec<_>>>()
+            else {
+                skipped.push(Skipped::new(record_id, "unknown masses"));
+                continue;
+            };
+            match hessian.frequencies(&masses) {
+                Ok(qm) => systems.push(Frequencies {
+                    record_id,
+                    molecule: Parametrized::new(molecule),
+                    positions,
+                    masses,
+                    qm,
+                }),
+                Err(e) => skipped.push(Skipped::new(record_id, e)),
+            }
+        }
+        Self {
+            name: name.to_owned(),
+            weight,
+            denominator: DEFAULT_FREQUENCY_DENOMINATOR,
+            systems,
+            skipped,
+        }
+    }
+
+    /// Build a target from the output of [FractalClient::hessian_records]
+    pub fn from_hessian_records(
+        name: &str,
+        weight: f64,
+        records: Vec<(ResultRecord, Cmiles, Vec<f64>)>,
+    ) -> Self {
+        let mut triples = Vec::with_capacity(records.len());
+        let mut skipped = Vec::new();
+        for (record, cmiles, geometry) in records {
+            let mut molecule = match Molecule::from_mapped_smiles(&cmiles) {
+                Ok(m) => m,
+                Err(e) => {
+                    skipped.push(Skipped::new(record.id, format!("{e:?}")));
+                    continue;
+                }
+            };
+            molecule.add_conformer(
+                geometry.into_iter().map(|x| x * BOHR_TO_ANGSTROM).collect(),
+            );
+            match Hessian::from_record(&record) {
+                Ok(h) => triples.push((record.id, molecule, h)),
+                Err(e) => skipped.push(Skipped::new(record.id, e)),
+            }
+        }
+        let mut ret = Self::new(name, weight, triples);
+        skipped.append(&mut ret.skipped);
+        ret.skipped = skipped;
+        ret
+    }
+
+    /// Download the Hessian records in `dataset` and build a target from them
+    pub fn from_dataset(
+        name: &str,
+        weight: f64,
+        dataset: ResultCollection,
+    ) -> Self {
+        let ids: Vec<_> = dataset.into_entries().map(|e| e.record_id).collect();
+        let client = FractalClient::new();
+        Self::from_hessian_records(
+            name,
+            weight,
+            client.hessian_records(&ids, 400),
+        )
+    }
+}
+
+impl Target for VibrationalFrequencyTarget {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn weight(&self) -> f64 {
+        self.weight
+    }
+
+    fn residuals(
+        &self,
+        force_field: &ForceField,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for system in &self.systems {
+            let interchange = system.molecule.interchange(force_field)?;
+            let min = interchange.minimize(&system.positions);
+            let mm = Hessian::new(interchange.hessian(&min.positions))?
+                .frequencies(&system.masses)?;
+            if mm.len() != system.qm.len() {
+                return Err(format!(
+                    "record {}: {} MM frequencies but {} QM frequencies",
+                    system.record_id,
+                    mm.len(),
+                    system.qm.len()
+                )
+                .into());
+            }
+            ret.extend(
+                mm.iter()
+                    .zip(&system.qm)
+                    .map(|(m, q)| (m - q) / self.denominator),
+            );
+        }
+        Ok(normalize(ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{interchange::TorsionTerm, testing::graph};
+
+    use super::*;
+
+    #[test]
+    fn internal_coordinates() {
+        // butane's carbon skeleton
+        let graph = graph(&[6; 4], &[(0, 1, 1), (1, 2, 1), (2, 3, 1)]);
+        let got = InternalCoordinates::from(&graph);
+        assert_eq!(got.bonds.len(), 3);
+        assert_eq!(got.angles.len(), 2);
+        assert!(got.angles.iter().all(|a| a[1] == 1 || a[1] == 2));
+        assert_eq!(got.dihedrals.len(), 1);
+        let d = got.dihedrals[0];
+        assert!(d == [0, 1, 2, 3] || d == [3, 2, 1, 0], "{d:?}");
+    }
+
+    #[test]
+    fn relative_energy_jacobian() {
+        let torsion = TorsionTerm {
+            atoms: [0, 1, 2, 3],
+            periodicity: 1.0,
+            phase: 0.0,
+            k: 1.0,
+            idivf: 1.0,
+            id: "t1".to_owned(),
+            term: 1,
+        };
+        let interchange = Interchange {
+            n_atoms: 4,
+            torsions: vec![torsion.clone()],
+            ..Default::default()
+        };
+        // rotate the last atom about the central bond
+        let geometry = |phi: f64| {
+            let phi = phi.to_radians();
+            vec![
+                0.0,
+                1.0,
+                0.0,
+                0.0,
+                0.0,
+                0.0,
+                1.5,
+                0.0,
+                0.0,
+                1.5,
+                phi.cos(),
+                phi.sin(),
+            ]
+        };
+        let geometries = [geometry(180.0), geometry(0.0), geometry(90.0)];
+        let keys = [
+            ParameterKey::new("Bonds", "b1", "k"),
+            ParameterKey::new("ProperTorsions", "t1", "k1"),
+        ];
+        let got = relative_jacobian(&interchange, &geometries, 0, &keys);
+        assert_eq!(got.shape(), (3, 2));
+        assert_eq!(got.column(0).iter().sum::<f64>(), 0.0);
+        let want = [0.0, 2.0, 1.0];
+        for (g, w) in got.column(1).iter().zip(want) {
+            assert!((g - w).abs() < 1e-10, "{got}");
+        }
+
+        let stacked = stack(vec![got.clone(), got], 2, 2.0);
+        assert_eq!(stacked.nrows(), 6);
+        assert!((stacked[(4, 1)] - 2.0 / (2.0 * 6f64.sqrt())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn helpers() {
+        assert_eq!(wrap_degrees(350.0), -10.0);
+        assert_eq!(wrap_degrees(-190.0), 170.0);
+        let got = normalize(vec![3.0, 4.0, 0.0, 0.0]);
+        assert_eq!(got.iter().map(|r| r * r).sum::<f64>(), 25.0 / 4.0);
+    }
+}
@@ -0,0 +1,349 @@
+//! Linear least-squares fitting of proper torsion force constants to QM
+//! torsion scans. At fixed geometries the MM energy is linear in each torsion
+//! `k`, so the fitted force constants are the solution of
+//!
+//! ```text
+//! (AᵀA + λI) k = Aᵀb + λ k₀
+//! ```
+//!
+//! where each row of `A` holds the `(1 + cos(nφ - phase)) / idivf`
+//! contributions of the fitted terms at one grid point, `b` is the QM energy
+//! minus the MM energy of everything that is not being fit, and the
+//! regularization `λ` pulls the solution toward the starting values `k₀`. The
+//! rows and targets of each scan are centered on their means, which is
+//! equivalent to fitting an independent energy offset for every scan.
+
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use ligand::molecule::Molecule;
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    benchmark::torsion::TorsionScan,
+    interchange::Interchange,
+    smirnoff::{ForceField, Parameter},
+    topology::Topology,
+    utils::{geometry::dihedral, Skipped},
+};
+
+/// The default regularization strength `λ`
+pub const DEFAULT_REGULARIZATION: f64 = 1e-3;
+
+#[derive(Debug)]
+pub enum TorsionFitError {
+    /// none of the selected torsion terms are present in the training data
+    NoData,
+
+    /// the normal equations could not be solved
+    Singular,
+}
+
+impl Error for TorsionFitError {}
+
+impl Display for TorsionFitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorsionFitError::NoData => {
+                write!(f, "no selected torsion terms found in the scans")
+            }
+            TorsionFitError::Singular => {
+                write!(f, "singular torsion fitting problem")
+            }
+        }
+    }
+}
+
+/// The fitted value of a single torsion force constant
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FittedTerm {
+    pub id: String,
+
+    /// which of the parameter's terms this is, starting from 1
+    pub term: usize,
+    pub initial: f64,
+    pub fitted: f64,
+}
+
+/// The results of a torsion fit
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TorsionFit {
+    pub terms: Vec<FittedTerm>,
+
+    /// the RMSE between the mean-centered QM and MM profiles before and after
+    /// fitting, in kcal/mol
+    pub initial_rmse: f64,
+    pub final_rmse: f64,
+
+    /// the scans left out of the fit
+    #[serde(default)]
+    pub skipped: Vec<Skipped>,
+}
+
+/// Fit the force constants of the `(id, term)` pairs in `selected` to the QM
+/// energies of each scan, where each scan is paired with the [Interchange]
+/// for its molecule. `regularization` is the `λ` described in the module
+/// documentation.
+pub fn fit_torsions(
+    problems: &[(&Interchange, &TorsionScan)],
+    selected: &[(String, usize)],
+    regularization: f64,
+) -> Result<TorsionFit, TorsionFitError> {
+    let column: HashMap<(&str, usize), usize> = selected
+        .iter()
+        .enumerate()
+        .map(|(c, (id, term))| ((id.as_str(), *term), c))
+        .collect();
+    let n = selected.len();
+
+    let mut initial = vec![None; n];
+    let mut rows: Vec<Vec<f64>> = Vec::new();
+    let mut targets: Vec<f64> = Vec::new();
+    // the mean-centered MM energy of the selected terms with their initial
+    // values, for computing the initial RMSE
+    let mut fitted_part: Vec<f64> = Vec::new();
+    for (interchange, scan) in problems {
+        let start = rows.len();
+        for (positions, qm) in scan.positions.iter().zip(&scan.energies) {
+            let mut row = vec![0.0; n];
+            let mut selected_energy = 0.0;
+            for t in &interchange.torsions {
+                let Some(&c) = column.get(&(t.id.as_str(), t.term)) else {
+                    continue;
+                };
+                let [i, j, k, l] = t.atoms;
+                let basis = t.basis(dihedral(positions, i, j, k, l));
+                row[c] += basis;
+                selected_energy += t.k * basis;
+                initial[c] = Some(t.k);
+            }
+            let fixed = interchange.energy(positions) - selected_energy;
+            rows.push(row);
+            targets.push(qm - fixed);
+            fitted_part.push(selected_energy);
+        }
+        center(&mut rows[start..], &mut targets[start..]);
+        let m = mean(&fitted_part[start..]);
+        fitted_part[start..].iter_mut().for_each(|x| *x -= m);
+    }
+
+    if rows.is_empty() || initial.iter().any(Option::is_none) {
+        return Err(TorsionFitError::NoData);
+    }
+    let k0 = DVector::from_iterator(n, initial.iter().map(|k| k.unwrap()));
+
+    let a = DMatrix::from_fn(rows.len(), n, |r, c| rows[r][c]);
+    let b = DVector::from_vec(targets);
+    let lhs = a.transpose() * &a + DMatrix::identity(n, n) * regularization;
+    let rhs = a.transpose() * &b + &k0 * regularization;
+    let k = lhs.lu().solve(&rhs).ok_or(TorsionFitError::Singular)?;
+
+    let rmse = |residual: DVector<f64>| {
+        (residual.norm_squared() / residual.len() as f64).sqrt()
+    };
+    let initial_rmse = rmse(DVector::from_vec(fitted_part) - &b);
+    let final_rmse = rmse(&a * &k - &b);
+
+    Ok(TorsionFit {
+        terms: selected
+            .iter()
+            .zip(k0.iter().zip(k.iter()))
+            .map(|((id, term), (&initial, &fitted))| FittedTerm {
+                id: id.clone(),
+                term: *term,
+                initial,
+                fitted,
+            })
+            .collect(),
+        initial_rmse,
+        final_rmse,
+        skipped: Vec::new(),
+    })
+}
+
+fn mean(v: &[f64]) -> f64 {
+    v.iter().sum::<f64>() / v.len() as f64
+}
+
+/// Subtract the column means of `rows` and the mean of `targets`
+fn center(rows: &mut [Vec<f64>], targets: &mut [f64]) {
+    if rows.is_empty() {
+        return;
+    }
+    let m = mean(targets);
+    targets.iter_mut().for_each(|t| *t -= m);
+    for c in 0..rows[0].len() {
+        let m = rows.iter().map(|r| r[c]).sum::<f64>() / rows.len() as f64;
+        rows.iter_mut().for_each(|r| r[c] -= m);
+    }
+}
+
+impl ForceField {
+    /// Return the `(id, term)` pairs selected by the `parameterize` attributes
+    /// of the proper torsions. Returns an error if a selected `kN` is not one
+    /// of the torsion's terms, rather than silently leaving it out of the fit.
+    fn selected_torsion_terms(
+        &self,
+    ) -> Result<Vec<(String, usize)>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for proper in &self.proper_torsions {
+            let Some(parameterize) = &proper.parameterize else {
+                continue;
+            };
+            let terms: Vec<usize> =
+                proper.terms()?.into_iter().map(|(n, _)| n).collect();
+            for attr in parameterize.split(',').map(str::trim) {
+                if let Some(term) = attr.strip_prefix('k') {
+                    let term = term.parse()?;
+                    if !terms.contains(&term) {
+                        return Err(format!(
+                            "cannot fit {attr} of {}: no such term",
+                            proper.id()
+                        )
+                        .into());
+                    }
+                    ret.push((proper.id().clone(), term));
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Fit the force constants of the proper torsions selected by their
+    /// `parameterize` attributes (e.g. `parameterize="k1,k2"`) to the QM
+    /// energies of `scans` at the QM geometries, and write the results back
+    /// into `self`. Molecules that cannot be parameterized are recorded in
+    /// [TorsionFit::skipped].
+    pub fn fit_torsions(
+        &mut self,
+        scans: &[(Molecule, TorsionScan)],
+        regularization: f64,
+    ) -> Result<TorsionFit, Box<dyn Error>> {
+        let selected = self.selected_torsion_terms()?;
+
+        let mut interchanges = Vec::with_capacity(scans.len());
+        let mut skipped = Vec::new();
+        for (molecule, scan) in scans {
+            let topology = Topology::from_molecules(vec![molecule.clone()]);
+            match self.create_interchange(&topology) {
+                Ok(i) => interchanges.push((i, scan)),
+                Err(e) => skipped.push(Skipped::new(&scan.record_id, e)),
+            }
+        }
+        let problems: Vec<_> =
+            interchanges.iter().map(|(i, s)| (i, *s)).collect();
+
+        // drop selected terms that no molecule exercises rather than failing
+        let present: Vec<_> = selected
+            .into_iter()
+            .filter(|(id, term)| {
+                problems.iter().any(|(i, _)| {
+                    i.torsions.iter().any(|t| &t.id == id && t.term == *term)
+                })
+            })
+            .collect();
+
+        let mut fit = fit_torsions(&problems, &present, regularization)?;
+        fit.skipped = skipped;
+        for proper in &mut self.proper_torsions {
+            let id = proper.id().clone();
+            for t in fit.terms.iter().filter(|t| t.id == id) {
+                if let Some(k) = proper.as_hash_mut(&format!("k{}", t.term)) {
+                    k.value = t.fitted;
+                }
+            }
+        }
+        Ok(fit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{butane, butane_positions, butane_torsion};
+
+    use super::*;
+
+    /// butane with a one-fold and a three-fold torsion term
+    fn two_term(k1: f64, k3: f64) -> Interchange {
+        butane(vec![butane_torsion(1.0, k1, 1), butane_torsion(3.0, k3, 2)])
+    }
+
+    /// a QM scan generated from the MM model with `k1` and `k3`, plus a large
+    /// constant offset
+    fn scan(k1: f64, k3: f64) -> TorsionScan {
+        let reference = two_term(k1, k3);
+        let angles: Vec<f64> =
+            (-165..=180).step_by(15).map(f64::from).collect();
+        let positions: Vec<_> =
+            angles.iter().map(|&a| butane_positions(a, 1.5)).collect();
+        TorsionScan {
+            record_id: "1".to_owned(),
+            dihedral: [0, 1, 2, 3],
+            energies: positions
+                .iter()
+                .map(|p| reference.energy(p) - 1000.0)
+                .collect(),
+            positions,
+            angles,
+        }
+    }
+
+    #[test]
+    fn recover() {
+        let scan = scan(0.7, 1.9);
+        let start = two_term(0.0, 0.5);
+        let selected = vec![("t1".to_owned(), 1), ("t1".to_owned(), 2)];
+        let got = fit_torsions(&[(&start, &scan)], &selected, 1e-8).unwrap();
+        assert!((got.terms[0].fitted - 0.7).abs() < 1e-6, "{got:?}");
+        assert!((got.terms[1].fitted - 1.9).abs() < 1e-6, "{got:?}");
+        assert_eq!(got.terms[1].initial, 0.5);
+        assert!(got.initial_rmse > 0.1);
+        assert!(got.final_rmse < 1e-6);
+    }
+
+    #[test]
+    fn partial_and_regularized() {
+        // fitting only the three-fold term leaves the one-fold term fixed
+        let scan = scan(0.7, 1.9);
+        let start = two_term(0.7, 0.0);
+        let selected = vec![("t1".to_owned(), 2)];
+        let got = fit_torsions(&[(&start, &scan)], &selected, 1e-8).unwrap();
+        assert!((got.terms[0].fitted - 1.9).abs() < 1e-6);
+
+        // strong regularization keeps k near its starting value
+        let got = fit_torsions(&[(&start, &scan)], &selected, 1e6).unwrap();
+        assert!(got.terms[0].fitted.abs() < 0.1, "{got:?}");
+    }
+
+    #[test]
+    fn selected_terms() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let t25 = (&mut ff.proper_torsions)
+            .into_iter()
+            .find(|p| p.id() == "t25")
+            .unwrap();
+        t25.parameterize = Some("k1, k5".to_owned());
+        assert_eq!(
+            ff.selected_torsion_terms().unwrap(),
+            [("t25".to_owned(), 1), ("t25".to_owned(), 5)]
+        );
+
+        let t25 = (&mut ff.proper_torsions)
+            .into_iter()
+            .find(|p| p.id() == "t25")
+            .unwrap();
+        t25.parameterize = Some("k6".to_owned());
+        assert!(ff.selected_torsion_terms().is_err());
+    }
+
+    #[test]
+    fn missing_term() {
+        let scan = scan(0.7, 1.9);
+        let start = two_term(0.0, 0.0);
+        let selected = vec![("t2".to_owned(), 1)];
+        assert!(matches!(
+            fit_torsions(&[(&start, &scan)], &selected, 1e-3),
+            Err(TorsionFitError::NoData)
+        ));
+    }
+}
@@ -0,0 +1,735 @@
+//! A minimal port of openff-interchange: force field parameters applied to a
+//! specific [crate::topology::Topology], along with the ability to evaluate
+//! the resulting potential energy and minimize it.
+//!
+//! Energies are in kcal/mol, lengths in angstroms, and angles in radians.
+
+use std::collections::{BTreeMap, HashMap};
+
+use nalgebra::DMatrix;
+
+use crate::{
+    fitting::ParameterKey,
+    smirnoff::RMIN_HALF_PER_SIGMA,
+    utils::{
+        geometry::{
+            accumulate, angle, angle_gradient, dihedral, dihedral_gradient,
+            distance, distance_gradient,
+        },
+        minimize::{lbfgs, Options},
+        COULOMB_CONSTANT,
+    },
+};
+
+/// A harmonic bond, `k/2 (r - length)^2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BondTerm {
+    pub atoms: [usize; 2],
+    pub k: f64,
+    pub length: f64,
+    pub id: String,
+}
+
+/// A harmonic angle, `k/2 (θ - angle)^2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct AngleTerm {
+    pub atoms: [usize; 3],
+    pub k: f64,
+    pub angle: f64,
+    pub id: String,
+}
+
+/// A single Fourier term of a torsion, `k/idivf (1 + cos(periodicity φ -
+/// phase))`
+#[derive(Clone, Debug, PartialEq)]
+pub struct TorsionTerm {
+    pub atoms: [usize; 4],
+    pub periodicity: f64,
+    pub phase: f64,
+    pub k: f64,
+    pub idivf: f64,
+    pub id: String,
+
+    /// which of the parameter's terms this is, starting from 1. This is the
+    /// `N` in the `kN` attribute of the parameter
+    pub term: usize,
+}
+
+impl TorsionTerm {
+    /// The energy of this term per unit `k`, which is useful for fitting since
+    /// the energy is linear in `k`
+    pub fn basis(&self, phi: f64) -> f64 {
+        (1.0 + (self.periodicity * phi - self.phase).cos()) / self.idivf
+    }
+}
+
+/// Lennard-Jones parameters for a single atom
+#[derive(Clone, Debug, PartialEq)]
+pub struct LjTerm {
+    pub epsilon: f64,
+    pub rmin_half: f64,
+    pub id: String,
+
+    /// whether the force field gives the size of the atom as `sigma` rather
+    /// than `rmin_half`, which is the attribute its derivatives are reported
+    /// for
+    pub sigma: bool,
+}
+
+/// The energy of an [Interchange] broken down by term type
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EnergyComponents {
+    pub bonds: f64,
+    pub angles: f64,
+    pub torsions: f64,
+    pub impropers: f64,
+    pub vdw: f64,
+    pub electrostatics: f64,
+}
+
+impl EnergyComponents {
+    pub fn total(&self) -> f64 {
+        self.bonds
+            + self.angles
+            + self.torsions
+            + self.impropers
+            + self.vdw
+            + self.electrostatics
+    }
+}
+
+/// The result of [Interchange::minimize]
+#[derive(Clone, Debug)]
+pub struct Minimized {
+    pub energy: f64,
+    pub positions: Vec<f64>,
+    pub converged: bool,
+}
+
+/// The derivatives of an energy with respect to the force field parameters
+/// contributing to it, keyed by handler, parameter id, and attribute. The
+/// derivatives are taken with respect to the parameters in the units they are
+/// written in the force field: kcal/mol for energies, angstroms for lengths,
+/// and degrees for equilibrium angles.
+pub type ParameterGradient = BTreeMap<ParameterKey, f64>;
+
+/// A sparse Jacobian of the energies of several geometries with respect to
+/// force field parameters, with one row per geometry and one column per entry
+/// of the flattened parameter vector
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParameterJacobian {
+    /// the parameter for each column, sorted
+    pub columns: Vec<ParameterKey>,
+
+    /// the nonzero `(column, derivative)` pairs of each row
+    pub rows: Vec<Vec<(usize, f64)>>,
+}
+
+impl ParameterJacobian {
+    /// Return the column index of the parameter `key`
+    pub fn column(&self, key: &ParameterKey) -> Option<usize> {
+        self.columns.binary_search(key).ok()
+    }
+
+    /// Return the derivative of the energy of geometry `row` with respect to
+    /// `key`, which is zero for parameters that do not contribute to it
+    pub fn get(&self, row: usize, key: &ParameterKey) -> f64 {
+        let Some(c) = self.column(key) else {
+            return 0.0;
+        };
+        self.rows[row]
+            .iter()
+            .find(|(col, _)| *col == c)
+            .map_or(0.0, |(_, d)| *d)
+    }
+
+    /// Return the Jacobian as a dense matrix with its columns in the order of
+    /// `keys`. Columns for parameters that are not present are zero.
+    pub fn to_dense(&self, keys: &[ParameterKey]) -> DMatrix<f64> {
+        let mut ret = DMatrix::zeros(self.rows.len(), keys.len());
+        for (c, key) in keys.iter().enumerate() {
+            let Some(col) = self.column(key) else {
+                continue;
+            };
+            for (r, row) in self.rows.iter().enumerate() {
+                if let Some((_, d)) = row.iter().find(|(i, _)| *i == col) {
+                    ret[(r, c)] = *d;
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// The parametrized system. Nonbonded interactions are evaluated between
+/// every pair of atoms without a cutoff, as in vacuum.
+#[derive(Clone, Debug, Default)]
+pub struct Interchange {
+    pub virtual_sites: Vec<()>,
+    pub n_atoms: usize,
+    pub bonds: Vec<BondTerm>,
+    pub angles: Vec<AngleTerm>,
+    pub torsions: Vec<TorsionTerm>,
+
+    /// improper torsions, with each trefoil already expanded into its three
+    /// component torsions with the central atom first
+    pub impropers: Vec<TorsionTerm>,
+
+    /// Lennard-Jones parameters for each atom in the topology
+    pub vdw: Vec<LjTerm>,
+
+    /// the atom pairs whose vdW interaction is scaled, like the 1-2, 1-3,
+    /// and 1-4 pairs within a molecule, and their scale factors. Every other
+    /// pair of atoms interacts at full strength, see [Interchange::vdw_pairs].
+    pub pairs: Vec<(usize, usize, f64)>,
+
+    /// the partial charge of each atom in the topology, in units of the
+    /// elementary charge
+    pub charges: Vec<f64>,
+
+    /// the atom pairs whose Coulomb interaction is scaled and their scale
+    /// factors, like [Interchange::pairs] for the vdW interactions
+    pub electrostatics_pairs: Vec<(usize, usize, f64)>,
+}
+
+impl Interchange {
+    #[cfg(feature = "openmm")]
+    pub fn to_openmm_topology(self) -> openmm::topology::Topology {
+        todo!();
+    }
+
+    /// Return every pair of atoms with a vdW interaction along with its scale
+    /// factor, which is 1 unless the pair is listed in [Interchange::pairs]
+    pub fn vdw_pairs(&self) -> impl Iterator<Item = (usize, usize, f64)> {
+        scaled_pairs(self.vdw.len(), &self.pairs)
+    }
+
+    /// Return every pair of atoms with a Coulomb interaction along with its
+    /// scale factor, which is 1 unless the pair is listed in
+    /// [Interchange::electrostatics_pairs]
+    pub fn coulomb_pairs(&self) -> impl Iterator<Item = (usize, usize, f64)> {
+        scaled_pairs(self.charges.len(), &self.electrostatics_pairs)
+    }
+
+    /// Compute the energy of each component of the system at `positions`
+    pub fn energy_components(&self, positions: &[f64]) -> EnergyComponents {
+        let mut grad = vec![0.0; positions.len()];
+        self.evaluate(positions, &mut grad)
+    }
+
+    pub fn energy(&self, positions: &[f64]) -> f64 {
+        self.energy_components(positions).total()
+    }
+
+    /// Return the total energy and its gradient at `positions`
+    pub fn energy_and_gradient(&self, positions: &[f64]) -> (f64, Vec<f64>) {
+        let mut grad = vec![0.0; positions.len()];
+        let e = self.evaluate(positions, &mut grad);
+        (e.total(), grad)
+    }
+
+    /// Return the Cartesian Hessian at `positions` as a flattened, row-major 3N
+    /// x 3N matrix in kcal/mol/Å², computed by central finite differences of
+    /// the analytic gradient
+    pub fn hessian(&self, positions: &[f64]) -> Vec<f64> {
+        const STEP: f64 = 1e-4;
+        let n = positions.len();
+        let mut ret = vec![0.0; n * n];
+        let mut x = positions.to_vec();
+        for (i, row) in ret.chunks_exact_mut(n).enumerate() {
+            x[i] = positions[i] + STEP;
+            let (_, plus) = self.energy_and_gradient(&x);
+            x[i] = positions[i] - STEP;
+            let (_, minus) = self.energy_and_gradient(&x);
+            x[i] = positions[i];
+            for (h, (p, m)) in row.iter_mut().zip(plus.iter().zip(minus)) {
+                *h = (p - m) / (2.0 * STEP);
+            }
+        }
+        for i in 0..n {
+            for j in 0..i {
+                let avg = 0.5 * (ret[i * n + j] + ret[j * n + i]);
+                ret[i * n + j] = avg;
+                ret[j * n + i] = avg;
+            }
+        }
+        ret
+    }
+
+    /// Return the total energy at `positions` and its derivatives with
+    /// respect to the bond and angle force constants and equilibrium values,
+    /// the proper and improper torsion force constants, and the Lennard-Jones
+    /// `epsilon` and `rmin_half` or `sigma` of every parameter in the system
+    pub fn energy_and_parameter_gradient(
+        &self,
+        positions: &[f64],
+    ) -> (f64, ParameterGradient) {
+        let mut ret = ParameterGradient::new();
+        let mut add = |handler: &str, id: &str, attribute: &str, d: f64| {
+            *ret.entry(ParameterKey::new(handler, id, attribute))
+                .or_default() += d;
+        };
+
+        for BondTerm {
+            atoms: [i, j],
+            k,
+            length,
+            id,
+        } in &self.bonds
+        {
+            let dr = distance(positions, *i, *j) - length;
+            add("Bonds", id, "k", 0.5 * dr * dr);
+            add("Bonds", id, "length", -k * dr);
+        }
+
+        for AngleTerm {
+            atoms: [i, j, l],
+            k,
+            angle: eq,
+            id,
+        } in &self.angles
+        {
+            let dt = angle(positions, *i, *j, *l) - eq;
+            add("Angles", id, "k", 0.5 * dt * dt);
+            // the force field angle is in degrees, so dE/dθ₀ is scaled by
+            // π/180
+            add("Angles", id, "angle", (-k * dt).to_radians());
+        }
+
+        for (handler, terms) in [
+            ("ProperTorsions", &self.torsions),
+            ("ImproperTorsions", &self.impropers),
+        ] {
+            for term in terms {
+                let [i, j, k, l] = term.atoms;
+                let phi = dihedral(positions, i, j, k, l);
+                add(
+                    handler,
+                    &term.id,
+                    &format!("k{}", term.term),
+                    term.basis(phi),
+                );
+            }
+        }
+
+        for (i, j, scale) in self.vdw_pairs() {
+            let (a, b) = (&self.vdw[i], &self.vdw[j]);
+            let epsilon = scale * (a.epsilon * b.epsilon).sqrt();
+            let rmin = a.rmin_half + b.rmin_half;
+            let x6 = (rmin / distance(positions, i, j)).powi(6);
+            let shape = x6 * x6 - 2.0 * x6;
+            // d/dε_a of sqrt(ε_a ε_b) is sqrt(ε_b / ε_a) / 2, which diverges
+            // for ε_a = 0. Those derivatives are reported as zero.
+            for (p, q) in [(a, b), (b, a)] {
+                if p.epsilon > 0.0 {
+                    let d = 0.5 * scale * (q.epsilon / p.epsilon).sqrt();
+                    add("vdW", &p.id, "epsilon", d * shape);
+                }
+                let d = 12.0 * epsilon * (x6 * x6 - x6) / rmin;
+                if p.sigma {
+                    add("vdW", &p.id, "sigma", d * RMIN_HALF_PER_SIGMA);
+                } else {
+                    add("vdW", &p.id, "rmin_half", d);
+                }
+            }
+        }
+
+        (self.energy(positions), ret)
+    }
+
+    /// Return the energy of each of `geometries` along with the Jacobian of the
+    /// energies with respect to the parameters, as described in
+    /// [Interchange::energy_and_parameter_gradient]
+    pub fn parameter_jacobian(
+        &self,
+        geometries: &[Vec<f64>],
+    ) -> (Vec<f64>, ParameterJacobian) {
+        let (energies, gradients): (Vec<_>, Vec<_>) = geometries
+            .iter()
+            .map(|g| self.energy_and_parameter_gradient(g))
+            .unzip();
+        let mut columns: Vec<ParameterKey> =
+            gradients.iter().flat_map(|g| g.keys().cloned()).collect();
+        columns.sort();
+        columns.dedup();
+        let rows = gradients
+            .into_iter()
+            .map(|g| {
+                g.into_iter()
+                    .map(|(key, d)| (columns.binary_search(&key).unwrap(), d))
+                    .collect()
+            })
+            .collect();
+        (energies, ParameterJacobian { columns, rows })
+    }
+
+    fn evaluate(
+        &self,
+        positions: &[f64],
+        grad: &mut [f64],
+    ) -> EnergyComponents {
+        let mut ret = EnergyComponents::default();
+
+        for BondTerm {
+            atoms, k, length, ..
+        } in &self.bonds
+        {
+            let (r, g) = distance_gradient(positions, *atoms);
+            let dr = r - length;
+            ret.bonds += 0.5 * k * dr * dr;
+            for (&a, g) in atoms.iter().zip(g) {
+                accumulate(grad, a, k * dr * g);
+            }
+        }
+
+        for AngleTerm {
+            atoms, k, angle, ..
+        } in &self.angles
+        {
+            let (theta, g) = angle_gradient(positions, *atoms);
+            let dt = theta - angle;
+            ret.angles += 0.5 * k * dt * dt;
+            for (&a, g) in atoms.iter().zip(g) {
+                accumulate(grad, a, k * dt * g);
+            }
+        }
+
+        ret.torsions = torsion_energy(&self.torsions, positions, grad);
+        ret.impropers = torsion_energy(&self.impropers, positions, grad);
+
+        for (i, j, scale) in self.vdw_pairs() {
+            let (a, b) = (&self.vdw[i], &self.vdw[j]);
+            let epsilon = scale * (a.epsilon * b.epsilon).sqrt();
+            let rmin = a.rmin_half + b.rmin_half;
+            let (r, g) = distance_gradient(positions, [i, j]);
+            let x6 = (rmin / r).powi(6);
+            ret.vdw += epsilon * (x6 * x6 - 2.0 * x6);
+            let de = 12.0 * epsilon * (x6 - x6 * x6) / r;
+            accumulate(grad, i, de * g[0]);
+            accumulate(grad, j, de * g[1]);
+        }
+
+        for (i, j, scale) in self.coulomb_pairs() {
+            let qq =
+                scale * COULOMB_CONSTANT * self.charges[i] * self.charges[j];
+            let (r, g) = distance_gradient(positions, [i, j]);
+            ret.electrostatics += qq / r;
+            let de = -qq / (r * r);
+            accumulate(grad, i, de * g[0]);
+            accumulate(grad, j, de * g[1]);
+        }
+
+        ret
+    }
+
+    /// Minimize the energy of the system starting from `positions`
+    pub fn minimize(&self, positions: &[f64]) -> Minimized {
+        self.minimize_with(positions, |_, _| 0.0)
+    }
+
+    /// Minimize the energy of the system plus an additional `restraint`
+    /// starting from `positions`. `restraint` should return its energy and add
+    /// its gradient to the slice it receives. The returned energy excludes the
+    /// restraint energy.
+    pub fn minimize_with<R>(&self, positions: &[f64], restraint: R) -> Minimized
+    where
+        R: Fn(&[f64], &mut [f64]) -> f64,
+    {
+        let options = Options {
+            gradient_tolerance: 1e-3,
+            ..Options::default()
+        };
+        let min = lbfgs(
+            |x| {
+                let (e, mut g) = self.energy_and_gradient(x);
+                let r = restraint(x, &mut g);
+                (e + r, g)
+            },
+            positions,
+            options,
+        );
+        Minimized {
+            energy: self.energy(&min.x),
+            positions: min.x,
+            converged: min.converged,
+        }
+    }
+}
+
+fn torsion_energy(
+    terms: &[TorsionTerm],
+    positions: &[f64],
+    grad: &mut [f64],
+) -> f64 {
+    let mut e = 0.0;
+    for term in terms {
+        let (phi, g) = dihedral_gradient(positions, term.atoms);
+        e += term.k * term.basis(phi);
+        let arg = term.periodicity * phi - term.phase;
+        let de = -term.k * term.periodicity * arg.sin() / term.idivf;
+        for (&a, g) in term.atoms.iter().zip(g) {
+            accumulate(grad, a, de * g);
+        }
+    }
+    e
+}
+
+/// Return every pair of `n` atoms with a nonzero scale factor, which is 1
+/// unless the pair is listed in `scaled`
+fn scaled_pairs(
+    n: usize,
+    scaled: &[(usize, usize, f64)],
+) -> impl Iterator<Item = (usize, usize, f64)> {
+    let scales: HashMap<_, _> = scaled
+        .iter()
+        .map(|&(i, j, scale)| ((i.min(j), i.max(j)), scale))
+        .collect();
+    (0..n)
+        .flat_map(move |i| (i + 1..n).map(move |j| (i, j)))
+        .filter_map(move |(i, j)| {
+            let scale = scales.get(&(i, j)).copied().unwrap_or(1.0);
+            (scale != 0.0).then_some((i, j, scale))
+        })
+}
+
+/// Return the atom pairs within three bonds of each other whose nonbonded
+/// interactions are scaled, given the topological distances between atoms in
+/// `distances` and the scale factors of pairs one, two, and three bonds apart
+/// in `scales`. Pairs with a scale factor of 1 are left out.
+pub(crate) fn nonbonded_pairs(
+    distances: &[Vec<usize>],
+    scales: [f64; 3],
+    offset: usize,
+) -> Vec<(usize, usize, f64)> {
+    let mut ret = Vec::new();
+    for (i, row) in distances.iter().enumerate() {
+        for (j, &d) in row.iter().enumerate().skip(i + 1) {
+            if let Some(&scale) = d.checked_sub(1).and_then(|d| scales.get(d)) {
+                if scale != 1.0 {
+                    ret.push((i + offset, j + offset, scale));
+                }
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a bent triatomic with a torsion-free angle and a nonbonded pair made up
+    /// for the test
+    fn system() -> Interchange {
+        Interchange {
+            n_atoms: 4,
+            bonds: vec![
+                BondTerm {
+                    atoms: [0, 1],
+                    k: 500.0,
+                    length: 1.0,
+                    id: "b1".to_owned(),
+                },
+                BondTerm {
+                    atoms: [1, 2],
+                    k: 500.0,
+                    length: 1.5,
+                    id: "b2".to_owned(),
+                },
+                BondTerm {
+                    atoms: [2, 3],
+                    k: 500.0,
+                    length: 1.0,
+                    id: "b1".to_owned(),
+                },
+            ],
+            angles: vec![
+                AngleTerm {
+                    atoms: [0, 1, 2],
+                    k: 100.0,
+                    angle: 110f64.to_radians(),
+                    id: "a1".to_owned(),
+                },
+                AngleTerm {
+                    atoms: [1, 2, 3],
+                    k: 100.0,
+                    angle: 110f64.to_radians(),
+                    id: "a1".to_owned(),
+                },
+            ],
+            torsions: vec![TorsionTerm {
+                atoms: [0, 1, 2, 3],
+                periodicity: 3.0,
+                phase: 0.0,
+                k: 1.0,
+                idivf: 1.0,
+                id: "t1".to_owned(),
+                term: 1,
+            }],
+            vdw: vec![
+                LjTerm {
+                    epsilon: 0.1,
+                    rmin_half: 1.0,
+                    id: "n1".to_owned(),
+                    sigma: false,
+                };
+                4
+            ],
+            pairs: vec![
+                (0, 1, 0.0),
+                (1, 2, 0.0),
+                (2, 3, 0.0),
+                (0, 2, 0.0),
+                (1, 3, 0.0),
+                (0, 3, 0.5),
+            ],
+            charges: vec![0.3, -0.2, 0.1, -0.2],
+            electrostatics_pairs: vec![
+                (0, 1, 0.0),
+                (1, 2, 0.0),
+                (2, 3, 0.0),
+                (0, 2, 0.0),
+                (1, 3, 0.0),
+                (0, 3, 0.8),
+            ],
+            ..Default::default()
+        }
+    }
+
+    const START: [f64; 12] =
+        [0.0, 1.1, 0.2, 0.0, 0.0, 0.0, 1.4, 0.0, 0.0, 1.5, 0.9, 0.6];
+
+    #[test]
+    fn gradient() {
+        let sys = system();
+        let (_, got) = sys.energy_and_gradient(&START);
+        let h = 1e-6;
+        for i in 0..START.len() {
+            let mut fwd = START;
+            fwd[i] += h;
+            let mut bwd = START;
+            bwd[i] -= h;
+            let want = (sys.energy(&fwd) - sys.energy(&bwd)) / (2.0 * h);
+            assert!((got[i] - want).abs() < 1e-5, "{i}: {} {want}", got[i]);
+        }
+    }
+
+    #[test]
+    fn coulomb() {
+        let sys = Interchange {
+            n_atoms: 3,
+            charges: vec![0.5, -0.5, 1.0],
+            electrostatics_pairs: vec![(0, 2, 0.5)],
+            ..Default::default()
+        };
+        let positions = [0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 4.0, 0.0];
+        let got = sys.energy_components(&positions);
+        let r12 = 20f64.sqrt();
+        let want = COULOMB_CONSTANT * (-0.25 / 2.0 + 0.25 / 4.0 - 0.5 / r12);
+        assert!((got.electrostatics - want).abs() < 1e-10, "{got:?}");
+        assert_eq!(got.total(), got.electrostatics);
+    }
+
+    /// a copy of `sys` with the parameter `key` shifted by `h`
+    fn perturbed(sys: &Interchange, key: &ParameterKey, h: f64) -> Interchange {
+        let mut ret = sys.clone();
+        let id = key.id.as_str();
+        match (key.handler.as_str(), key.attribute.as_str()) {
+            ("Bonds", attr) => {
+                for b in ret.bonds.iter_mut().filter(|b| b.id == id) {
+                    match attr {
+                        "k" => b.k += h,
+                        _ => b.length += h,
+                    }
+                }
+            }
+            ("Angles", attr) => {
+                for a in ret.angles.iter_mut().filter(|a| a.id == id) {
+                    match attr {
+                        "k" => a.k += h,
+                        _ => a.angle += h.to_radians(),
+                    }
+                }
+            }
+            ("ProperTorsions", _) => {
+                for t in ret.torsions.iter_mut().filter(|t| t.id == id) {
+                    t.k += h;
+                }
+            }
+            ("vdW", attr) => {
+                for v in ret.vdw.iter_mut().filter(|v| v.id == id) {
+                    match attr {
+                        "epsilon" => v.epsilon += h,
+                        "sigma" => v.rmin_half += h * RMIN_HALF_PER_SIGMA,
+                        _ => v.rmin_half += h,
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+        ret
+    }
+
+    #[test]
+    fn parameter_gradient() {
+        let mut sys = system();
+        let check = |sys: &Interchange| {
+            let (e, got) = sys.energy_and_parameter_gradient(&START);
+            assert_eq!(e, sys.energy(&START));
+            assert_eq!(got.len(), 9);
+            let h = 1e-6;
+            for (key, d) in &got {
+                let want = (perturbed(sys, key, h).energy(&START)
+                    - perturbed(sys, key, -h).energy(&START))
+                    / (2.0 * h);
+                assert!(
+                    (d - want).abs() < 1e-5 * want.abs().max(1.0),
+                    "{key}: {d} {want}"
+                );
+            }
+            got
+        };
+        check(&sys);
+
+        // the size is reported in the attribute the force field uses
+        sys.vdw.iter_mut().for_each(|v| v.sigma = true);
+        let got = check(&sys);
+        assert!(got.contains_key(&ParameterKey::new("vdW", "n1", "sigma")));
+        assert!(!got.contains_key(&ParameterKey::new(
+            "vdW",
+            "n1",
+            "rmin_half"
+        )));
+    }
+
+    #[test]
+    fn parameter_jacobian() {
+        let sys = system();
+        let mut other = START;
+        other[9] += 0.1;
+        let (energies, jac) =
+            sys.parameter_jacobian(&[START.to_vec(), other.to_vec()]);
+        assert_eq!(energies.len(), 2);
+        assert_eq!(jac.columns.len(), 9);
+        let t1 = ParameterKey::new("ProperTorsions", "t1", "k1");
+        let (_, want) = sys.energy_and_parameter_gradient(&other);
+        assert_eq!(jac.get(1, &t1), want[&t1]);
+
+        let missing = ParameterKey::new("Bonds", "b9", "k");
+        assert_eq!(jac.get(0, &missing), 0.0);
+        let dense = jac.to_dense(&[missing, t1.clone()]);
+        assert_eq!(dense.shape(), (2, 2));
+        assert_eq!(dense[(0, 0)], 0.0);
+        assert_eq!(dense[(1, 1)], want[&t1]);
+    }
+
+    #[test]
+    fn minimize() {
+        let sys = system();
+        let got = sys.minimize(&START);
+        assert!(got.converged);
+        assert!(got.energy < sys.energy(&START));
+        let r = crate::utils::geometry::distance(&got.positions, 1, 2);
+        assert!((r - 1.5).abs() < 1e-2, "{r}");
+    }
+}
@@ -1,5 +1,19 @@
+pub mod charges;
+pub mod coverage;
+pub mod export;
+pub mod fitting;
 pub mod smirnoff;
 pub mod topology;
+pub mod utils;
+
+// TODO this is its own package
+pub mod interchange;
+
+// TODO this is its own package (yammbs)
+pub mod benchmark;
+
+// TODO this is its own package (qubekit)
+pub mod seminario;
 
 // TODO this one goes in the openff-qcsubmit package
 pub mod qcsubmit;
@@ -7,96 +21,18 @@ pub mod qcsubmit;
 // TODO this is its own package
 pub mod qcportal;
 
+#[cfg(test)]
+pub(crate) mod testing;
+
 #[cfg(test)]
 mod tests {
-    use std::{
-        collections::{HashMap, HashSet},
-        path::Path,
-    };
+    use std::path::Path;
 
     use crate::{
-        qcportal::models::Record, qcsubmit::results::ResultCollection,
+        coverage::{Coverage, DEFAULT_MIN_COVERAGE},
+        qcsubmit::results::ResultCollection,
         smirnoff::ForceField,
     };
-    use ligand::molecule::Molecule;
-
-    fn label_and_tag_ids(
-        (record, molecule): (Record, Molecule),
-        force_field: &ForceField,
-        parameter_types: impl IntoIterator<Item = String>,
-    ) -> HashSet<(String, String, usize)> {
-        let mol_labels =
-            &force_field.label_molecules(molecule.clone().to_topology())[0];
-
-        let mut parameter_ids = HashSet::new();
-
-        for parameter_type in parameter_types.into_iter() {
-            let parameter_labels = mol_labels[&parameter_type].clone();
-            let n_heavy_atoms = 0; // TODO
-            for (_indices, parameter) in parameter_labels {
-                parameter_ids.insert((
-                    parameter,
-                    record.id.clone(),
-                    n_heavy_atoms,
-                ));
-            }
-        }
-
-        parameter_ids
-    }
-
-    fn get_parameter_distribution(
-        dataset: ResultCollection,
-        force_field: &ForceField,
-        parameter_types: impl IntoIterator<Item = String> + Clone,
-    ) -> HashMap<String, usize> {
-        let mut ret = HashMap::new();
-        for record in dataset.to_records() {
-            let parameter_ids =
-                label_and_tag_ids(record, force_field, parameter_types.clone());
-            for (parameter_id, _record_id, _n_heavy_atoms) in parameter_ids {
-                *ret.entry(parameter_id).or_insert(0) += 1;
-            }
-        }
-        ret
-    }
-
-    // TODO return some kind of Parameters type
-    fn select_parameters<I>(
-        dataset: ResultCollection,
-        force_field: &ForceField,
-        parameter_types: I,
-    ) -> HashMap<String, Vec<String>>
-    where
-        I: IntoIterator<Item = String> + Clone,
-    {
-        // TODO this is an optional argument in the python version
-        let min_coverage = 5;
-        let coverage = get_parameter_distribution(
-            dataset,
-            force_field,
-            parameter_types.clone(),
-        );
-
-        let mut ret = HashMap::new();
-        for parameter_type in parameter_types.into_iter() {
-            let handler = force_field.get_parameter_handler(&parameter_type);
-
-            for (parameter_id, count) in &coverage {
-                if *count < min_coverage {
-                    continue;
-                }
-                let Some(parameter) = handler.get_parameter_by_id(parameter_id)
-                else {
-                    continue;
-                };
-                ret.entry(parameter_type.clone())
-                    .or_insert(Vec::new())
-                    .push(parameter.smirks().clone());
-            }
-        }
-        ret
-    }
 
     /// this is example code from my valence-fitting repo to combine two
     /// datasets. the python version took over 13 minutes to run
@@ -112,7 +48,9 @@ mod tests {
             "/home/brent/omsf/projects/valence-fitting/01_generate-forcefield/output/initial-force-field-openff-2.1.0.offxml",
         )
         .unwrap();
+        let coverage =
+            Coverage::from_dataset(sage_td, &ff, &["ProperTorsions"]).unwrap();
         let _selected_parameters =
-            select_parameters(sage_td, &ff, ["ProperTorsions".to_owned()]);
+            coverage.select_parameters(DEFAULT_MIN_COVERAGE);
     }
 }
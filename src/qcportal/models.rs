@@ -1,6 +1,10 @@
 pub struct Record {
     pub id: String,
     pub energies: Vec<f64>,
+
+    /// the flattened QM gradient at the final geometry in hartree/bohr, if it
+    /// was retrieved, as by `ResultCollection::to_records_with_gradients`
+    pub gradient: Option<Vec<f64>>,
 }
 
 impl Record {
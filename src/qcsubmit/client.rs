@@ -6,15 +6,18 @@ use serde::{Deserialize, Serialize};
 
 use collection::{CollectionGetBody, CollectionGetResponse};
 use molecule::{Molecule, MoleculeGetBody};
-use procedure::{
-    OptimizationRecord, ProcedureGetBody, Response, TorsionDriveRecord,
-};
+use procedure::{ProcedureGetBody, Response};
+use result::ResultGetBody;
+
+pub use procedure::{OptimizationRecord, TorsionDriveRecord};
+pub use result::ResultRecord;
 
 use self::collection::TorsionDriveResult;
 
 mod collection;
 mod molecule;
 mod procedure;
+mod result;
 
 #[cfg(test)]
 mod tests;
@@ -222,6 +225,10 @@ impl FractalClient {
         self.get("procedure", body).json().unwrap()
     }
 
+    pub fn get_result(&self, body: ResultGetBody) -> Response<ResultRecord> {
+        self.get("result", body).json().unwrap()
+    }
+
     pub fn get_molecule(&self, body: MoleculeGetBody) -> Response<Molecule> {
         self.get("molecule", body).json().unwrap()
     }
@@ -292,6 +299,34 @@ impl FractalClient {
         make_opt_results(records, molecule_ids, molecules)
     }
 
+    /// Request the gradient at the final geometry of each of `records`, the
+    /// last single-point result in its trajectory. Returns a map of
+    /// optimization record id to the flattened gradient in hartree/bohr,
+    /// leaving out records whose final result is incomplete or not a
+    /// gradient.
+    pub fn final_gradients<'a>(
+        &self,
+        records: impl IntoIterator<Item = &'a OptimizationRecord>,
+        query_limit: usize,
+    ) -> HashMap<String, Vec<f64>> {
+        // map of final result id -> optimization record id
+        let record_ids: HashMap<_, _> = records
+            .into_iter()
+            .filter_map(|r| Some((r.trajectory.last()?.clone(), r.id.clone())))
+            .collect();
+        let ids: Vec<_> = record_ids.keys().cloned().collect();
+        self.get_chunked(Self::get_result, &ids, query_limit)
+            .into_iter()
+            .flatten()
+            .filter(|r: &ResultRecord| {
+                r.status.is_complete() && r.driver == "gradient"
+            })
+            .filter_map(|r| {
+                Some((record_ids.get(&r.id)?.clone(), r.return_array()?))
+            })
+            .collect()
+    }
+
     pub fn torsion_drive_records(
         &self,
         collection: CollectionGetResponse,
@@ -356,4 +391,42 @@ impl FractalClient {
 
         make_td_results(results, records, molecule_ids, molecules)
     }
+
+    /// Request the single-point [ResultRecord]s with `ids` computed with the
+    /// `hessian` driver, along with the CMILES and geometry of each record's
+    /// molecule. Incomplete records and records with other drivers are
+    /// skipped.
+    pub fn hessian_records(
+        &self,
+        ids: &[String],
+        query_limit: usize,
+    ) -> Vec<(ResultRecord, Cmiles, Vec<f64>)> {
+        let records: Vec<ResultRecord> = self
+            .get_chunked(Self::get_result, ids, query_limit)
+            .into_iter()
+            .flatten()
+            .filter(|r: &ResultRecord| {
+                r.status.is_complete() && r.driver == "hessian"
+            })
+            .collect();
+
+        eprintln!("{} hessian records", records.len());
+
+        let ids: Vec<_> = records.iter().map(|r| r.molecule.clone()).collect();
+        let molecules: HashMap<_, _> = self
+            .get_chunked(Self::get_molecule, &ids, query_limit)
+            .into_iter()
+            .flatten()
+            .map(|m| (m.id.clone(), m))
+            .collect();
+
+        let mut ret = Vec::with_capacity(records.len());
+        for record in records {
+            let Some(mol) = molecules.get(&record.molecule) else {
+                continue;
+            };
+            ret.push((record, mol.cmiles(), mol.geometry.clone()));
+        }
+        ret
+    }
 }
@@ -121,6 +121,11 @@ pub struct TorsionDriveRecord {
 }
 
 impl TorsionDriveRecord {
+    /// the 0-based indices of the atoms in each driven dihedral
+    pub fn dihedrals(&self) -> &[(usize, usize, usize, usize)] {
+        &self.keywords.dihedrals
+    }
+
     /// return an iterator over the optimization_id -> (record_id, grid_id)
     /// pairs in self.optimization_history. the keys are the ids of the
     /// OptimizationRecords associated with each point along the torsion drive
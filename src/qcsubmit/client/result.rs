@@ -0,0 +1,98 @@
+//! [FractalClient] queries for single-point results, such as the Hessians in a
+//! basic dataset
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::qcsubmit::client::{Body, Status};
+
+#[derive(Default, Serialize)]
+struct QueryFilter {
+    include: Option<usize>,
+    exclude: Option<usize>,
+    limit: Option<usize>,
+    skip: usize,
+}
+
+#[derive(Serialize)]
+struct Data {
+    id: Vec<String>,
+    task_id: Option<usize>,
+    program: Option<usize>,
+    driver: Option<usize>,
+    method: Option<usize>,
+    basis: Option<usize>,
+    molecule: Option<usize>,
+    keywords: Option<usize>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+pub struct ResultGetBody {
+    meta: QueryFilter,
+    data: Data,
+}
+
+impl Body for ResultGetBody {
+    fn new(id: Vec<String>) -> Self {
+        Self {
+            meta: QueryFilter::default(),
+            data: Data {
+                id,
+                task_id: None,
+                program: None,
+                driver: None,
+                method: None,
+                basis: None,
+                molecule: None,
+                keywords: None,
+                status: Status::Complete,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResultRecord {
+    // base identification
+    pub id: String,
+    pub program: String,
+    pub driver: String,
+    pub method: String,
+    pub basis: Option<String>,
+    pub status: Status,
+    #[serde(default)]
+    pub extras: HashMap<String, Value>,
+    pub error: Option<String>,
+    // input data
+    pub molecule: String,
+    // results
+    /// the result of the computation, whose shape depends on `driver`. This is
+    /// a single energy for `energy`, a flattened 3N gradient for `gradient`,
+    /// and a flattened 3N x 3N Hessian for `hessian`, all in atomic units
+    pub return_result: Value,
+}
+
+impl ResultRecord {
+    /// Return `return_result` flattened into a vector, if it is an array of
+    /// numbers or an array of arrays of numbers
+    pub fn return_array(&self) -> Option<Vec<f64>> {
+        fn flatten(v: &Value, out: &mut Vec<f64>) -> Option<()> {
+            match v {
+                Value::Number(n) => out.push(n.as_f64()?),
+                Value::Array(a) => {
+                    for v in a {
+                        flatten(v, out)?;
+                    }
+                }
+                _ => return None,
+            }
+            Some(())
+        }
+        let mut ret = Vec::new();
+        flatten(&self.return_result, &mut ret)?;
+        Some(ret)
+    }
+}
@@ -2,7 +2,14 @@ use std::{collections::HashMap, error::Error, fs::read_to_string, path::Path};
 
 use serde::{Deserialize, Serialize};
 
-use crate::qcportal::models::Record;
+use crate::{
+    qcportal::models::Record,
+    topology::{
+        molecule::MoleculeGraph,
+        molfile::{write_sdf, FileMolecule},
+    },
+    utils::BOHR_TO_ANGSTROM,
+};
 use ligand::molecule::Molecule;
 
 use self::filters::Filters;
@@ -54,15 +61,39 @@ impl ResultCollection {
         Ok(ret)
     }
 
+    /// Download the final geometry and energies of each optimization record,
+    /// without the gradients
     pub fn to_records(self) -> Vec<(Record, Molecule)> {
+        self.records(false)
+    }
+
+    /// Like [ResultCollection::to_records], but also download the gradient
+    /// at each final geometry, which takes a second request per batch
+    pub fn to_records_with_gradients(self) -> Vec<(Record, Molecule)> {
+        self.records(true)
+    }
+
+    fn records(self, with_gradients: bool) -> Vec<(Record, Molecule)> {
         let mut ret = Vec::new();
         let client = FractalClient::new();
         let results = client.optimization_records(self, 400);
+        let mut gradients = if with_gradients {
+            client.final_gradients(results.iter().map(|(r, _, _)| r), 400)
+        } else {
+            HashMap::new()
+        };
         for (record, cmiles, mut geom) in results {
             let mut molecule = Molecule::from_mapped_smiles(&cmiles).unwrap();
-            molecule.add_conformer(geom.swap_remove(0));
+            // QCArchive geometries are in bohr, but conformers are in angstroms
+            molecule.add_conformer(
+                geom.swap_remove(0)
+                    .into_iter()
+                    .map(|x| x * BOHR_TO_ANGSTROM)
+                    .collect(),
+            );
             ret.push((
                 Record {
+                    gradient: gradients.remove(&record.id),
                     id: record.id,
                     energies: record.energies,
                 },
@@ -96,3 +127,29 @@ impl ResultCollection {
         self.len() == 0
     }
 }
+
+/// Wrap the conformer of a record from [ResultCollection::to_records] for SD
+/// output, with the record id and final QM energy in hartrees as the
+/// `record_id` and `qm_energy` tags
+pub fn record_molecule(record: &Record, graph: MoleculeGraph) -> FileMolecule {
+    let mut ret = FileMolecule::new(record.id.clone(), graph);
+    let tags = vec![
+        ("record_id".to_owned(), record.id.clone()),
+        (
+            "qm_energy".to_owned(),
+            record.get_final_energy().to_string(),
+        ),
+    ];
+    ret.properties = vec![tags; ret.graph.conformers.len()];
+    ret
+}
+
+/// Write `records` from [ResultCollection::to_records] as an SD file, tagged
+/// as in [record_molecule]
+pub fn records_to_sdf(records: &[(Record, Molecule)]) -> String {
+    let molecules: Vec<_> = records
+        .iter()
+        .map(|(r, m)| record_molecule(r, MoleculeGraph::from(m)))
+        .collect();
+    write_sdf(&molecules)
+}
@@ -7,3 +7,16 @@ fn load_filtered() {
 
     assert_eq!(1348, got.entries.values().flatten().count());
 }
+
+#[test]
+fn record_sdf() {
+    let record = Record {
+        id: "123".to_owned(),
+        energies: vec![-76.1, -76.2],
+        gradient: None,
+    };
+    let m = record_molecule(&record, crate::testing::water());
+    let sdf = write_sdf(&[m]);
+    assert!(sdf.contains("> <record_id>\n123\n"), "{sdf}");
+    assert!(sdf.contains("> <qm_energy>\n-76.2\n"), "{sdf}");
+}
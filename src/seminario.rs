@@ -0,0 +1,522 @@
+//! Bond and angle force constants from QM Hessians using the modified Seminario
+//! method of Allen et al., J. Chem. Theory Comput. 14, 5484 (2018).
+//!
+//! Force constants are estimated from the 3x3 blocks of the Hessian coupling
+//! pairs of bonded atoms and are reported in the SMIRNOFF `k/2 x²` convention,
+//! in kcal/mol/Å² for bonds and kcal/mol/rad² for angles. Estimates for every
+//! atom tuple sharing a parameter id are averaged before being written back to
+//! a [ForceField].
+
+use std::{
+    collections::HashMap, error::Error, f64::consts::PI, fmt::Display,
+    fs::read_to_string, path::Path,
+};
+
+use ligand::molecule::Molecule;
+use nalgebra::{DMatrix, Matrix3, SymmetricEigen, Vector3};
+
+use crate::{
+    qcsubmit::client::ResultRecord,
+    smirnoff::{ForceField, MoleculeLabels, Parameter},
+    topology::molecule::MoleculeGraph,
+    utils::{geometry::position, BOHR_TO_ANGSTROM, HARTREE_TO_KCAL},
+};
+
+/// The default vibrational scaling factor, appropriate for the
+/// B3LYP-D3BJ/DZVP level of theory used for OpenFF force fields
+pub const DEFAULT_VIBRATIONAL_SCALING: f64 = 0.957;
+
+/// The speed of light in cm/s
+const SPEED_OF_LIGHT: f64 = 2.99792458e10;
+
+#[derive(Debug)]
+pub struct SeminarioError(String);
+
+impl Error for SeminarioError {}
+
+impl Display for SeminarioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SeminarioError: {}", self.0)
+    }
+}
+
+/// A Cartesian Hessian in kcal/mol/Å², stored as a row-major 3N x 3N matrix
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hessian {
+    n_atoms: usize,
+    data: Vec<f64>,
+}
+
+impl Hessian {
+    /// Construct a [Hessian] from a flattened 3N x 3N matrix in kcal/mol/Å²
+    pub fn new(data: Vec<f64>) -> Result<Self, SeminarioError> {
+        let dim = (data.len() as f64).sqrt().round() as usize;
+        if dim * dim != data.len() || !dim.is_multiple_of(3) {
+            return Err(SeminarioError(format!(
+                "{} Hessian elements do not form a 3N x 3N matrix",
+                data.len()
+            )));
+        }
+        Ok(Self {
+            n_atoms: dim / 3,
+            data,
+        })
+    }
+
+    /// Construct a [Hessian] from a flattened 3N x 3N matrix in
+    /// hartree/bohr², the units used by QCArchive
+    pub fn from_atomic_units(data: Vec<f64>) -> Result<Self, SeminarioError> {
+        let conv = HARTREE_TO_KCAL / (BOHR_TO_ANGSTROM * BOHR_TO_ANGSTROM);
+        Self::new(data.into_iter().map(|x| x * conv).collect())
+    }
+
+    /// Extract the Hessian from a single-point `record` computed with the
+    /// `hessian` driver
+    pub fn from_record(record: &ResultRecord) -> Result<Self, SeminarioError> {
+        if record.driver != "hessian" {
+            return Err(SeminarioError(format!(
+                "record {} has driver {}, not hessian",
+                record.id, record.driver
+            )));
+        }
+        let data = record.return_array().ok_or_else(|| {
+            SeminarioError(format!(
+                "record {} does not contain a Hessian",
+                record.id
+            ))
+        })?;
+        Self::from_atomic_units(data)
+    }
+
+    /// Load a Hessian in hartree/bohr² from a whitespace-separated text file,
+    /// like the ones written by `numpy.savetxt`
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let data = read_to_string(path)?
+            .split_ascii_whitespace()
+            .map(|s| s.parse())
+            .collect::<Result<Vec<f64>, _>>()?;
+        Ok(Self::from_atomic_units(data)?)
+    }
+
+    pub fn n_atoms(&self) -> usize {
+        self.n_atoms
+    }
+
+    /// The harmonic vibrational frequencies in cm⁻¹ for atoms with `masses` in
+    /// daltons, sorted in ascending order, with imaginary frequencies reported
+    /// as negative numbers. The six frequencies closest to zero, or five for a
+    /// diatomic, are assumed to belong to the overall translations and
+    /// rotations and are dropped.
+    pub fn frequencies(
+        &self,
+        masses: &[f64],
+    ) -> Result<Vec<f64>, SeminarioError> {
+        if masses.len() != self.n_atoms {
+            return Err(SeminarioError(format!(
+                "got {} masses for a Hessian with {} atoms",
+                masses.len(),
+                self.n_atoms
+            )));
+        }
+        // 1 kcal/mol/Å²/Da is 4.184e26 s⁻², and a wavenumber is the angular
+        // frequency divided by 2πc
+        let conv = 4.184e26f64.sqrt() / (2.0 * PI * SPEED_OF_LIGHT);
+        let dim = 3 * self.n_atoms;
+        let weighted = DMatrix::from_fn(dim, dim, |r, c| {
+            self.data[r * dim + c] / (masses[r / 3] * masses[c / 3]).sqrt()
+        });
+        let mut freqs: Vec<f64> = SymmetricEigen::new(weighted)
+            .eigenvalues
+            .iter()
+            .map(|l| l.signum() * l.abs().sqrt() * conv)
+            .collect();
+        freqs.sort_by(|a, b| a.abs().total_cmp(&b.abs()));
+        let rigid = if self.n_atoms == 2 { 5 } else { 6 };
+        let mut ret = freqs.split_off(rigid.min(freqs.len()));
+        ret.sort_by(f64::total_cmp);
+        Ok(ret)
+    }
+
+    /// The 3x3 block of the Hessian coupling atoms `i` and `j`
+    fn block(&self, i: usize, j: usize) -> Matrix3<f64> {
+        let dim = 3 * self.n_atoms;
+        Matrix3::from_fn(|r, c| self.data[(3 * i + r) * dim + 3 * j + c])
+    }
+
+    /// The eigenvalues and eigenvectors of the negated, symmetrized
+    /// interatomic block for atoms `i` and `j`. The blocks are symmetric when
+    /// the Hessian is evaluated at a stationary point, and symmetrizing
+    /// avoids complex eigenvalues from numerical noise.
+    fn interatomic(
+        &self,
+        i: usize,
+        j: usize,
+    ) -> SymmetricEigen<f64, nalgebra::U3> {
+        let b = -self.block(i, j);
+        SymmetricEigen::new(0.5 * (b + b.transpose()))
+    }
+
+    /// The sum of the eigenvalues of the `i`-`j` block weighted by the
+    /// projection of their eigenvectors onto `u`
+    fn projected(&self, i: usize, j: usize, u: &Vector3<f64>) -> f64 {
+        let eig = self.interatomic(i, j);
+        eig.eigenvalues
+            .iter()
+            .zip(eig.eigenvectors.column_iter())
+            .map(|(l, v)| l * u.dot(&v).abs())
+            .sum()
+    }
+}
+
+/// Return the unit vector from atom `i` to atom `j` and the distance between
+/// them
+fn unit(positions: &[f64], i: usize, j: usize) -> (Vector3<f64>, f64) {
+    let d = position(positions, j) - position(positions, i);
+    let r = d.norm();
+    (d / r, r)
+}
+
+/// Return the unit vector perpendicular to `u_ba` in the plane of the angle
+/// formed by `u_ba` and `u_bc`, pointing away from `u_bc`. For linear angles,
+/// an arbitrary perpendicular is returned.
+fn in_plane_perpendicular(
+    u_ba: &Vector3<f64>,
+    u_bc: &Vector3<f64>,
+) -> Vector3<f64> {
+    let mut n = u_bc.cross(u_ba);
+    if n.norm() < 1e-6 {
+        let trial = if u_ba.x.abs() < 0.9 {
+            Vector3::x()
+        } else {
+            Vector3::y()
+        };
+        n = u_ba.cross(&trial);
+    }
+    n.cross(u_ba).normalize()
+}
+
+/// Estimate the force constant of the bond between atoms `i` and `j`
+pub fn bond_force_constant(
+    hessian: &Hessian,
+    positions: &[f64],
+    i: usize,
+    j: usize,
+) -> f64 {
+    let (u, _) = unit(positions, i, j);
+    hessian.projected(i, j, &u)
+}
+
+/// Estimate the force constant of the angle `a`-`b`-`c` in `graph`, including
+/// the modified Seminario scaling for the other angles around `b` that share
+/// the `a`-`b` or `c`-`b` bond
+pub fn angle_force_constant(
+    hessian: &Hessian,
+    graph: &MoleculeGraph,
+    positions: &[f64],
+    [a, b, c]: [usize; 3],
+) -> f64 {
+    let (u_ba, r_ab) = unit(positions, b, a);
+    let (u_bc, r_cb) = unit(positions, b, c);
+    let u_pa = in_plane_perpendicular(&u_ba, &u_bc);
+    let u_pc = in_plane_perpendicular(&u_bc, &u_ba);
+
+    // the average overlap between the in-plane perpendicular for this angle
+    // and those of the other angles sharing the `b`-`end` bond
+    let scaling = |u_end: &Vector3<f64>, u_p: &Vector3<f64>| {
+        let others: Vec<f64> = graph
+            .neighbors(b)
+            .filter(|&x| x != a && x != c)
+            .map(|x| {
+                let (u_bx, _) = unit(positions, b, x);
+                let u_px = in_plane_perpendicular(u_end, &u_bx);
+                u_p.dot(&u_px).powi(2)
+            })
+            .collect();
+        if others.is_empty() {
+            1.0
+        } else {
+            1.0 + others.iter().sum::<f64>() / others.len() as f64
+        }
+    };
+
+    let k_a = hessian.projected(a, b, &u_pa);
+    let k_c = hessian.projected(c, b, &u_pc);
+    let inv = scaling(&u_ba, &u_pa) / (r_ab * r_ab * k_a)
+        + scaling(&u_bc, &u_pc) / (r_cb * r_cb * k_c);
+    1.0 / inv
+}
+
+/// A force constant averaged over all of the atom tuples assigned a parameter
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForceConstant {
+    pub id: String,
+    pub k: f64,
+    pub count: usize,
+}
+
+/// Accumulates modified Seminario estimates over one or more molecules
+#[derive(Clone, Debug)]
+pub struct Seminario {
+    /// the vibrational scaling factor. Force constants are scaled by its
+    /// square
+    pub vibrational_scaling: f64,
+
+    bonds: HashMap<String, Vec<f64>>,
+    angles: HashMap<String, Vec<f64>>,
+}
+
+impl Default for Seminario {
+    fn default() -> Self {
+        Self::new(DEFAULT_VIBRATIONAL_SCALING)
+    }
+}
+
+impl Seminario {
+    pub fn new(vibrational_scaling: f64) -> Self {
+        Self {
+            vibrational_scaling,
+            bonds: HashMap::new(),
+            angles: HashMap::new(),
+        }
+    }
+
+    /// Estimate the force constant of every bond and angle in `molecule`
+    /// labeled in `labels`, as returned by [ForceField::label_molecules],
+    /// using `hessian` evaluated at the first conformer of `molecule`
+    pub fn add_molecule(
+        &mut self,
+        molecule: &Molecule,
+        hessian: &Hessian,
+        labels: &MoleculeLabels,
+    ) -> Result<(), SeminarioError> {
+        let graph = MoleculeGraph::from(molecule);
+        self.add_graph(&graph, hessian, labels)
+    }
+
+    pub(crate) fn add_graph(
+        &mut self,
+        graph: &MoleculeGraph,
+        hessian: &Hessian,
+        labels: &MoleculeLabels,
+    ) -> Result<(), SeminarioError> {
+        let Some(positions) = graph.conformers.first() else {
+            return Err(SeminarioError(
+                "molecule has no conformers".to_owned(),
+            ));
+        };
+        if hessian.n_atoms() != graph.n_atoms() {
+            return Err(SeminarioError(format!(
+                "Hessian has {} atoms but the molecule has {}",
+                hessian.n_atoms(),
+                graph.n_atoms()
+            )));
+        }
+        let scale = self.vibrational_scaling.powi(2);
+        if let Some(bonds) = labels.get("Bonds") {
+            for (atoms, id) in bonds {
+                let k =
+                    bond_force_constant(hessian, positions, atoms[0], atoms[1]);
+                self.bonds.entry(id.clone()).or_default().push(scale * k);
+            }
+        }
+        if let Some(angles) = labels.get("Angles") {
+            for (atoms, id) in angles {
+                let k = angle_force_constant(
+                    hessian,
+                    graph,
+                    positions,
+                    [atoms[0], atoms[1], atoms[2]],
+                );
+                self.angles.entry(id.clone()).or_default().push(scale * k);
+            }
+        }
+        Ok(())
+    }
+
+    fn average(estimates: &HashMap<String, Vec<f64>>) -> Vec<ForceConstant> {
+        let mut ret: Vec<_> = estimates
+            .iter()
+            .map(|(id, ks)| ForceConstant {
+                id: id.clone(),
+                k: ks.iter().sum::<f64>() / ks.len() as f64,
+                count: ks.len(),
+            })
+            .collect();
+        ret.sort_by(|a, b| a.id.cmp(&b.id));
+        ret
+    }
+
+    /// The averaged bond force constants, sorted by parameter id
+    pub fn bond_force_constants(&self) -> Vec<ForceConstant> {
+        Self::average(&self.bonds)
+    }
+
+    /// The averaged angle force constants, sorted by parameter id
+    pub fn angle_force_constants(&self) -> Vec<ForceConstant> {
+        Self::average(&self.angles)
+    }
+
+    /// Overwrite the `k` of each bond and angle parameter in `force_field`
+    /// with an estimate. Parameters without any estimates are left unchanged.
+    /// The units of the existing parameters are assumed to be kcal/mol/Å² and
+    /// kcal/mol/rad². Returns the number of parameters updated.
+    pub fn apply(&self, force_field: &mut ForceField) -> usize {
+        let bonds: HashMap<_, _> = self
+            .bond_force_constants()
+            .into_iter()
+            .map(|f| (f.id, f.k))
+            .collect();
+        let angles: HashMap<_, _> = self
+            .angle_force_constants()
+            .into_iter()
+            .map(|f| (f.id, f.k))
+            .collect();
+        let mut updated = 0;
+        for bond in &mut force_field.bonds {
+            if let Some(&k) = bonds.get(bond.id()) {
+                bond.as_hash_mut("k").unwrap().value = k;
+                updated += 1;
+            }
+        }
+        for angle in &mut force_field.angles {
+            if let Some(&k) = angles.get(angle.id()) {
+                angle.as_hash_mut("k").unwrap().value = k;
+                updated += 1;
+            }
+        }
+        updated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        interchange::{AngleTerm, BondTerm, Interchange},
+        testing::graph,
+    };
+
+    use super::*;
+
+    /// a water-like triatomic with a bond length of 1 Å and an angle of 104.5°
+    fn water() -> (MoleculeGraph, Interchange) {
+        let mut graph = graph(&[1, 8, 1], &[(0, 1, 1), (1, 2, 1)]);
+        let theta = 104.5f64.to_radians();
+        graph.conformers = vec![vec![
+            1.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            0.0,
+            theta.cos(),
+            theta.sin(),
+            0.0,
+        ]];
+        let bond = |atoms| BondTerm {
+            atoms,
+            k: 1000.0,
+            length: 1.0,
+            id: "b1".to_owned(),
+        };
+        let interchange = Interchange {
+            n_atoms: 3,
+            bonds: vec![bond([0, 1]), bond([1, 2])],
+            angles: vec![AngleTerm {
+                atoms: [0, 1, 2],
+                k: 100.0,
+                angle: theta,
+                id: "a1".to_owned(),
+            }],
+            ..Default::default()
+        };
+        (graph, interchange)
+    }
+
+    fn hessian(interchange: &Interchange, positions: &[f64]) -> Hessian {
+        Hessian::new(interchange.hessian(positions)).unwrap()
+    }
+
+    #[test]
+    fn water_force_constants() {
+        let (graph, interchange) = water();
+        let hess = hessian(&interchange, &graph.conformers[0]);
+        let labels = MoleculeLabels::from([
+            (
+                "Bonds".to_owned(),
+                HashMap::from([
+                    (vec![0, 1], "b1".to_owned()),
+                    (vec![1, 2], "b1".to_owned()),
+                ]),
+            ),
+            (
+                "Angles".to_owned(),
+                HashMap::from([(vec![0, 1, 2], "a1".to_owned())]),
+            ),
+        ]);
+        let mut sem = Seminario::new(1.0);
+        sem.add_graph(&graph, &hess, &labels).unwrap();
+
+        let bonds = sem.bond_force_constants();
+        assert_eq!(bonds.len(), 1);
+        assert_eq!(bonds[0].count, 2);
+        let k = bonds[0].k;
+        assert!((k - 1000.0).abs() / 1000.0 < 0.1, "{k}");
+
+        // the Seminario angle estimate is only approximate, even for a purely
+        // harmonic Hessian
+        let angles = sem.angle_force_constants();
+        let k = angles[0].k;
+        assert!((k - 100.0).abs() / 100.0 < 0.15, "{k}");
+    }
+
+    #[test]
+    fn scaling_and_apply() {
+        let (graph, interchange) = water();
+        let hess = hessian(&interchange, &graph.conformers[0]);
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let id = ff.bonds[0].id.clone();
+        let labels = MoleculeLabels::from([(
+            "Bonds".to_owned(),
+            HashMap::from([(vec![0, 1], id.clone())]),
+        )]);
+
+        let mut unscaled = Seminario::new(1.0);
+        unscaled.add_graph(&graph, &hess, &labels).unwrap();
+        let mut scaled = Seminario::new(0.9);
+        scaled.add_graph(&graph, &hess, &labels).unwrap();
+        let want = 0.81 * unscaled.bond_force_constants()[0].k;
+        assert!((scaled.bond_force_constants()[0].k - want).abs() < 1e-8);
+
+        assert_eq!(scaled.apply(&mut ff), 1);
+        assert_eq!(ff.bonds[0].k.value, want);
+    }
+
+    #[test]
+    fn diatomic_frequency() {
+        let interchange = Interchange {
+            n_atoms: 2,
+            bonds: vec![BondTerm {
+                atoms: [0, 1],
+                k: 500.0,
+                length: 1.0,
+                id: "b1".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let hess = hessian(&interchange, &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]);
+        let masses = [1.008, 18.998];
+        let got = hess.frequencies(&masses).unwrap();
+        assert_eq!(got.len(), 1);
+        // ω = sqrt(k/μ), converted from kcal/mol/Å²/Da to cm⁻¹
+        let mu = masses[0] * masses[1] / (masses[0] + masses[1]);
+        let want = (500.0 / mu).sqrt() * 108.5913;
+        assert!((got[0] - want).abs() < 0.1, "{got:?} {want}");
+        assert!(hess.frequencies(&[1.0]).is_err());
+    }
+
+    #[test]
+    fn bad_hessian() {
+        assert!(Hessian::new(vec![0.0; 10]).is_err());
+        assert_eq!(Hessian::new(vec![0.0; 81]).unwrap().n_atoms(), 3);
+    }
+}
@@ -1,19 +1,34 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     error::Error,
-    fmt::Display,
+    fmt::{Debug, Display},
     fs::read_to_string,
     ops::{Index, IndexMut},
     path::Path,
+    sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
 
-use crate::topology::{ChemicalEnvironmentMatch, Topology};
+use crate::{
+    charges::ChargeBackend,
+    interchange::{
+        nonbonded_pairs, AngleTerm, BondTerm, Interchange, LjTerm, TorsionTerm,
+    },
+    topology::{
+        aromaticity::{AromaticityError, AromaticityModel},
+        molecule::MoleculeGraph,
+        ChemicalEnvironmentMatch, Topology,
+    },
+};
 
 use self::bonds::Bond;
 
+pub mod bespoke;
 mod bonds;
+mod charges;
+pub mod equilibria;
+pub mod splits;
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Unit {
@@ -163,6 +178,16 @@ impl<'a> IntoIterator for &'a Angles {
     }
 }
 
+impl<'a> IntoIterator for &'a mut Angles {
+    type Item = <&'a mut Vec<Angle> as IntoIterator>::Item;
+
+    type IntoIter = <&'a mut Vec<Angle> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.angles.iter_mut()
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Proper {
     #[serde(rename = "@smirks")]
@@ -171,7 +196,7 @@ pub struct Proper {
     #[serde(rename = "@id")]
     id: String,
 
-    // TODO group these into a substruct for each of 1, 2, 3 if that's possible
+    // TODO group these into a substruct for each term if that's possible
     #[serde(rename = "@periodicity1")]
     periodicity1: String,
 
@@ -208,20 +233,214 @@ pub struct Proper {
     #[serde(rename = "@idivf3")]
     idivf3: Option<String>,
 
+    #[serde(rename = "@periodicity4")]
+    periodicity4: Option<String>,
+
+    #[serde(rename = "@phase4")]
+    phase4: Option<String>,
+
     #[serde(rename = "@k4")]
     pub k4: Option<Quantity>,
 
+    #[serde(rename = "@idivf4")]
+    idivf4: Option<String>,
+
+    #[serde(rename = "@periodicity5")]
+    periodicity5: Option<String>,
+
+    #[serde(rename = "@phase5")]
+    phase5: Option<String>,
+
     #[serde(rename = "@k5")]
     pub k5: Option<Quantity>,
 
+    #[serde(rename = "@idivf5")]
+    idivf5: Option<String>,
+
+    #[serde(rename = "@periodicity6")]
+    periodicity6: Option<String>,
+
+    #[serde(rename = "@phase6")]
+    phase6: Option<String>,
+
     #[serde(rename = "@k6")]
     pub k6: Option<Quantity>,
 
+    #[serde(rename = "@idivf6")]
+    idivf6: Option<String>,
+
     #[serde(rename = "@parameterize")]
     pub parameterize: Option<String>,
 }
 
+/// The `(periodicity, phase, k, idivf)` of a single torsion term
+type TorsionTermParams = (f64, f64, f64, f64);
+
+/// The largest number of terms a proper torsion can have
+pub(crate) const MAX_TORSION_TERMS: usize = 6;
+
+/// The `periodicityN`, `phaseN`, `kN`, and `idivfN` attributes of an optional
+/// torsion term
+type OptionalTerm<'a> = (
+    &'a Option<String>,
+    &'a Option<String>,
+    &'a Option<Quantity>,
+    &'a Option<String>,
+);
+
+type OptionalTermMut<'a> = (
+    &'a mut Option<String>,
+    &'a mut Option<String>,
+    &'a mut Option<Quantity>,
+    &'a mut Option<String>,
+);
+
 impl Proper {
+    /// The attributes of term `n`, from 2 to [MAX_TORSION_TERMS]
+    fn optional_term(&self, n: usize) -> OptionalTerm<'_> {
+        match n {
+            2 => (&self.periodicity2, &self.phase2, &self.k2, &self.idivf2),
+            3 => (&self.periodicity3, &self.phase3, &self.k3, &self.idivf3),
+            4 => (&self.periodicity4, &self.phase4, &self.k4, &self.idivf4),
+            5 => (&self.periodicity5, &self.phase5, &self.k5, &self.idivf5),
+            6 => (&self.periodicity6, &self.phase6, &self.k6, &self.idivf6),
+            _ => unreachable!("no torsion term {n}"),
+        }
+    }
+
+    fn optional_term_mut(&mut self, n: usize) -> OptionalTermMut<'_> {
+        match n {
+            2 => (
+                &mut self.periodicity2,
+                &mut self.phase2,
+                &mut self.k2,
+                &mut self.idivf2,
+            ),
+            3 => (
+                &mut self.periodicity3,
+                &mut self.phase3,
+                &mut self.k3,
+                &mut self.idivf3,
+            ),
+            4 => (
+                &mut self.periodicity4,
+                &mut self.phase4,
+                &mut self.k4,
+                &mut self.idivf4,
+            ),
+            5 => (
+                &mut self.periodicity5,
+                &mut self.phase5,
+                &mut self.k5,
+                &mut self.idivf5,
+            ),
+            6 => (
+                &mut self.periodicity6,
+                &mut self.phase6,
+                &mut self.k6,
+                &mut self.idivf6,
+            ),
+            _ => unreachable!("no torsion term {n}"),
+        }
+    }
+
+    /// Return the term number `N`, as in `kN`, and the `(periodicity, phase,
+    /// k, idivf)` of each term in the torsion, with the phase in degrees.
+    /// `idivf` defaults to 1 when it is not provided. Returns an error if a
+    /// term is missing its periodicity, phase, or `k`.
+    pub(crate) fn terms(
+        &self,
+    ) -> Result<Vec<(usize, TorsionTermParams)>, Box<dyn Error>> {
+        let mut ret = vec![(
+            1,
+            (
+                self.periodicity1.parse()?,
+                Quantity::try_from(self.phase1.clone())?.value,
+                self.k1.value,
+                self.idivf1.parse()?,
+            ),
+        )];
+        for term in 2..=MAX_TORSION_TERMS {
+            match self.optional_term(term) {
+                (Some(periodicity), Some(phase), Some(k), idivf) => {
+                    ret.push((
+                        term,
+                        (
+                            periodicity.parse()?,
+                            Quantity::try_from(phase.clone())?.value,
+                            k.value,
+                            idivf.as_deref().unwrap_or("1.0").parse()?,
+                        ),
+                    ));
+                }
+                (None, None, None, None) => {}
+                _ => {
+                    return Err(format!(
+                        "proper torsion {} has an incomplete term {term}",
+                        self.id
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Replace the terms of the torsion with `(periodicity, phase, k)`
+    /// triples, with the phase in degrees and `k` in kcal/mol, clearing any
+    /// remaining terms. Returns an error unless there are between one and
+    /// [MAX_TORSION_TERMS] terms, leaving the torsion unchanged.
+    pub(crate) fn set_terms(
+        &mut self,
+        terms: &[(usize, f64, f64)],
+    ) -> Result<(), Box<dyn Error>> {
+        if !(1..=MAX_TORSION_TERMS).contains(&terms.len()) {
+            return Err(format!(
+                "proper torsion {} cannot have {} terms",
+                self.id,
+                terms.len()
+            )
+            .into());
+        }
+        let quantities = terms
+            .iter()
+            .map(|&(periodicity, phase, k)| {
+                Ok((
+                    periodicity.to_string(),
+                    format!("{phase:?} * degree"),
+                    Quantity::try_from(format!(
+                        "{k} * mole**-1 * kilocalorie"
+                    ))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        let mut quantities = quantities.into_iter();
+
+        let (periodicity, phase, k) = quantities.next().unwrap();
+        self.periodicity1 = periodicity;
+        self.phase1 = phase;
+        self.k1 = k;
+        self.idivf1 = "1.0".to_owned();
+        for term in 2..=MAX_TORSION_TERMS {
+            let (periodicity, phase, k, idivf) = self.optional_term_mut(term);
+            match quantities.next() {
+                Some((p, ph, kk)) => {
+                    *periodicity = Some(p);
+                    *phase = Some(ph);
+                    *k = Some(kk);
+                    *idivf = Some("1.0".to_owned());
+                }
+                None => {
+                    *periodicity = None;
+                    *phase = None;
+                    *k = None;
+                    *idivf = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn as_hash(&self, key: &str) -> Option<&Quantity> {
         match key {
             "k1" => Some(&self.k1),
@@ -278,6 +497,16 @@ impl<'a> IntoIterator for &'a ProperTorsions {
     }
 }
 
+impl<'a> IntoIterator for &'a mut ProperTorsions {
+    type Item = <&'a mut Vec<Proper> as IntoIterator>::Item;
+
+    type IntoIter = <&'a mut Vec<Proper> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.proper_torsions.iter_mut()
+    }
+}
+
 impl Index<usize> for ProperTorsions {
     type Output = Proper;
 
@@ -293,7 +522,7 @@ impl IndexMut<usize> for ProperTorsions {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-struct Improper {
+pub struct Improper {
     #[serde(rename = "@smirks")]
     smirks: String,
 
@@ -308,11 +537,30 @@ struct Improper {
     phase1: String,
 
     #[serde(rename = "@k1")]
-    k1: Quantity,
+    pub k1: Quantity,
+
+    #[serde(rename = "@parameterize")]
+    pub parameterize: Option<String>,
+}
+
+impl Improper {
+    pub fn as_hash(&self, key: &str) -> Option<&Quantity> {
+        match key {
+            "k1" => Some(&self.k1),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash_mut(&mut self, key: &str) -> Option<&mut Quantity> {
+        match key {
+            "k1" => Some(&mut self.k1),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-struct ImproperTorsions {
+pub struct ImproperTorsions {
     #[serde(rename = "@version")]
     version: String,
 
@@ -326,8 +574,45 @@ struct ImproperTorsions {
     improper_torsions: Vec<Improper>,
 }
 
+impl Index<usize> for ImproperTorsions {
+    type Output = Improper;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.improper_torsions[index]
+    }
+}
+
+impl IndexMut<usize> for ImproperTorsions {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.improper_torsions[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a ImproperTorsions {
+    type Item = <&'a Vec<Improper> as IntoIterator>::Item;
+
+    type IntoIter = <&'a Vec<Improper> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.improper_torsions.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut ImproperTorsions {
+    type Item = <&'a mut Vec<Improper> as IntoIterator>::Item;
+
+    type IntoIter = <&'a mut Vec<Improper> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.improper_torsions.iter_mut()
+    }
+}
+
+/// The ratio of `rmin_half` to `sigma` in a Lennard-Jones potential, 2^(1/6)/2
+pub(crate) const RMIN_HALF_PER_SIGMA: f64 = 0.561_231_024_154_686_5;
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-struct Atom {
+pub struct Atom {
     #[serde(rename = "@smirks")]
     smirks: String,
 
@@ -335,14 +620,54 @@ struct Atom {
     id: String,
 
     #[serde(rename = "@epsilon")]
-    epsilon: String,
+    pub epsilon: Quantity,
 
     #[serde(rename = "@rmin_half")]
-    rmin_half: Option<String>,
+    pub rmin_half: Option<Quantity>,
+
+    #[serde(rename = "@sigma")]
+    pub sigma: Option<Quantity>,
+
+    #[serde(rename = "@parameterize")]
+    pub parameterize: Option<String>,
+}
+
+impl Atom {
+    /// The `rmin_half` of this parameter, converted from `sigma` if it is
+    /// given that way
+    fn rmin_half(&self) -> Result<f64, Box<dyn Error>> {
+        match (&self.rmin_half, &self.sigma) {
+            (Some(r), None) => Ok(r.value),
+            (None, Some(s)) => Ok(s.value * RMIN_HALF_PER_SIGMA),
+            _ => Err(format!(
+                "vdW parameter {} needs exactly one of rmin_half and sigma",
+                self.id
+            )
+            .into()),
+        }
+    }
+
+    pub fn as_hash(&self, key: &str) -> Option<&Quantity> {
+        match key {
+            "epsilon" => Some(&self.epsilon),
+            "rmin_half" => self.rmin_half.as_ref(),
+            "sigma" => self.sigma.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn as_hash_mut(&mut self, key: &str) -> Option<&mut Quantity> {
+        match key {
+            "epsilon" => Some(&mut self.epsilon),
+            "rmin_half" => self.rmin_half.as_mut(),
+            "sigma" => self.sigma.as_mut(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
-struct Vdw {
+pub struct Vdw {
     #[serde(rename = "@version")]
     version: String,
 
@@ -377,6 +702,62 @@ struct Vdw {
     atoms: Vec<Atom>,
 }
 
+impl Index<usize> for Vdw {
+    type Output = Atom;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.atoms[index]
+    }
+}
+
+impl IndexMut<usize> for Vdw {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        &mut self.atoms[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a Vdw {
+    type Item = <&'a Vec<Atom> as IntoIterator>::Item;
+
+    type IntoIter = <&'a Vec<Atom> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.atoms.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Vdw {
+    type Item = <&'a mut Vec<Atom> as IntoIterator>::Item;
+
+    type IntoIter = <&'a mut Vec<Atom> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.atoms.iter_mut()
+    }
+}
+
+/// Parse the scale factors of a nonbonded `handler` for pairs of atoms one,
+/// two, and three bonds apart. Pairs further apart must interact at full
+/// strength.
+fn pair_scales(
+    handler: &str,
+    [scale12, scale13, scale14, scale15]: [&str; 4],
+) -> Result<[f64; 3], Box<dyn Error>> {
+    if scale15.parse::<f64>()? != 1.0 {
+        return Err(format!("unsupported {handler} scale15 {scale15}").into());
+    }
+    Ok([scale12.parse()?, scale13.parse()?, scale14.parse()?])
+}
+
+impl Vdw {
+    fn scales(&self) -> Result<[f64; 3], Box<dyn Error>> {
+        pair_scales(
+            "vdW",
+            [&self.scale12, &self.scale13, &self.scale14, &self.scale15],
+        )
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 struct Electrostatics {
     #[serde(rename = "@version")]
@@ -404,6 +785,15 @@ struct Electrostatics {
     method: Option<String>,
 }
 
+impl Electrostatics {
+    fn scales(&self) -> Result<[f64; 3], Box<dyn Error>> {
+        pair_scales(
+            "Electrostatics",
+            [&self.scale12, &self.scale13, &self.scale14, &self.scale15],
+        )
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 struct LibraryCharge {
     #[serde(rename = "@smirks")]
@@ -412,8 +802,9 @@ struct LibraryCharge {
     #[serde(rename = "@id")]
     id: String,
 
-    #[serde(rename = "@charge1")]
-    charge1: String,
+    /// the `chargeN` attributes, keyed by their `@`-prefixed names
+    #[serde(flatten)]
+    charges: BTreeMap<String, String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -429,6 +820,28 @@ struct LibraryCharges {
 struct ToolkitAM1BCC {
     #[serde(rename = "@version")]
     version: String,
+
+    #[serde(skip)]
+    backend: Backend,
+}
+
+/// The [ChargeBackend] computing the AM1-BCC charges called for by
+/// [ToolkitAM1BCC]. It is not part of the serialized force field, so it is
+/// ignored when comparing force fields.
+#[derive(Clone, Default)]
+struct Backend(Option<Arc<dyn ChargeBackend + Send + Sync>>);
+
+impl Debug for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = if self.0.is_some() { "Some(..)" } else { "None" };
+        write!(f, "Backend({name})")
+    }
+}
+
+impl PartialEq for Backend {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
 }
 
 /// A SMIRNOFF force field
@@ -459,10 +872,10 @@ pub struct ForceField {
     pub proper_torsions: ProperTorsions,
 
     #[serde(rename = "ImproperTorsions")]
-    improper_torsions: ImproperTorsions,
+    pub improper_torsions: ImproperTorsions,
 
     #[serde(rename = "vdW")]
-    vdw: Vdw,
+    pub vdw: Vdw,
 
     #[serde(rename = "Electrostatics")]
     electrostatics: Electrostatics,
@@ -502,31 +915,89 @@ macro_rules! impl_parameter {
     }
 }
 
-impl_parameter!(Bond, Angle, Proper, Improper);
+impl_parameter!(Bond, Angle, Proper, Improper, Atom);
+
+/// Canonicalize the atom indices of a match for a parameter of type `typ` so
+/// that a valence term found in either direction, or an improper found with its
+/// outer atoms in any order, always produces the same key
+pub(crate) fn valence_key(typ: &str, mut atoms: Vec<usize>) -> Vec<usize> {
+    if typ == "Improper" && atoms.len() == 4 {
+        let mut outer = [atoms[0], atoms[2], atoms[3]];
+        outer.sort();
+        return vec![outer[0], atoms[1], outer[1], outer[2]];
+    }
+    if atoms.first() > atoms.last() {
+        atoms.reverse();
+    }
+    atoms
+}
 
 #[allow(unused)]
 struct Match {
-    parameter_type: String,
+    parameter_id: String,
     environment_match: ChemicalEnvironmentMatch,
 }
 
 impl Match {
     fn new(
-        parameter_type: String,
+        parameter_id: String,
         environment_match: ChemicalEnvironmentMatch,
     ) -> Self {
         Self {
-            parameter_type,
+            parameter_id,
             environment_match,
         }
     }
 }
 
+/// Returned by [ForceField::create_interchange] when a valence term or atom in
+/// the topology is not matched by any parameter
+#[derive(Debug)]
+pub struct UnassignedParameterError {
+    pub parameter_type: &'static str,
+    pub atoms: Vec<usize>,
+}
+
+impl Error for UnassignedParameterError {}
+
+impl Display for UnassignedParameterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "no {} parameter assigned to atoms {:?}",
+            self.parameter_type, self.atoms
+        )
+    }
+}
+
+/// Returned by [ForceField::get_parameter_handler] for a handler name it does
+/// not support
+#[derive(Debug)]
+pub struct UnknownHandlerError {
+    pub handler: String,
+}
+
+impl Error for UnknownHandlerError {}
+
+impl Display for UnknownHandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unrecognized parameter handler: {}", self.handler)
+    }
+}
+
+/// The parameter ids assigned to a single molecule, keyed by parameter handler
+/// name and then by the (canonicalized) atom indices of each match
+pub type MoleculeLabels = HashMap<String, HashMap<Vec<usize>, String>>;
+
 pub struct ParameterHandler {
     inner: Vec<Box<dyn Parameter>>,
 }
 
 impl ParameterHandler {
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Parameter> {
+        self.inner.iter().map(|p| p.as_ref())
+    }
+
     #[allow(clippy::borrowed_box)]
     pub fn get_parameter_by_id(&self, id: &str) -> Option<&Box<dyn Parameter>> {
         self.inner.iter().find(|&p| p.id() == id)
@@ -539,14 +1010,13 @@ impl ParameterHandler {
             for environment_match in
                 entity.chemical_environment_matches(parameter.smirks())
             {
-                let handler_match = Match::new(
-                    parameter.typ().to_owned(),
-                    environment_match.clone(),
-                );
-                matches_for_this_type.insert(
+                let key = valence_key(
+                    parameter.typ(),
                     environment_match.topology_atom_indices.clone(),
-                    handler_match,
                 );
+                let handler_match =
+                    Match::new(parameter.id().to_owned(), environment_match);
+                matches_for_this_type.insert(key, handler_match);
             }
 
             matches.extend(matches_for_this_type);
@@ -557,12 +1027,23 @@ impl ParameterHandler {
 }
 
 impl ForceField {
+    /// Load a force field from an OFFXML file. Returns an error if its
+    /// aromaticity model is not supported.
     pub fn load(filename: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
         let contents = read_to_string(filename)?;
         let ff: Self = quick_xml::de::from_str(&contents)?;
+        ff.aromaticity_model()?;
         Ok(ff)
     }
 
+    /// The aromaticity model named by the force field, which is used to
+    /// perceive aromaticity before assigning parameters
+    pub fn aromaticity_model(
+        &self,
+    ) -> Result<AromaticityModel, AromaticityError> {
+        self.aromaticity_model.parse()
+    }
+
     pub fn to_xml(&self) -> Result<String, quick_xml::DeError> {
         let mut buf = String::new();
         let mut ser = quick_xml::se::Serializer::new(&mut buf);
@@ -572,10 +1053,12 @@ impl ForceField {
     }
 
     // TODO this should take an enum not string
+    /// Return the parameters of the handler named `parameter_type`, one of
+    /// `Bonds`, `Angles`, `ProperTorsions`, `ImproperTorsions`, or `vdW`
     pub fn get_parameter_handler(
         &self,
         parameter_type: &str,
-    ) -> ParameterHandler {
+    ) -> Result<ParameterHandler, UnknownHandlerError> {
         let mut inner: Vec<Box<dyn Parameter>> = Vec::new();
         match parameter_type {
             "Bonds" => {
@@ -610,27 +1093,55 @@ impl ForceField {
                     inner.push(b);
                 }
             }
-            _ => panic!("unrecognized parameter_type: {parameter_type}"),
+            "vdW" => {
+                for a in self.vdw.atoms.iter().cloned().map(Box::new) {
+                    inner.push(a);
+                }
+            }
+            _ => {
+                return Err(UnknownHandlerError {
+                    handler: parameter_type.to_owned(),
+                })
+            }
         }
-        ParameterHandler { inner }
+        Ok(ParameterHandler { inner })
     }
 
     fn parameter_handlers(&self) -> Vec<(&'static str, ParameterHandler)> {
         let mut ret = Vec::new();
-        for typ in ["Bonds", "Angles", "ProperTorsions", "ImproperTorsions"] {
-            ret.push((typ, self.get_parameter_handler(typ)))
+        for typ in [
+            "Bonds",
+            "Angles",
+            "ProperTorsions",
+            "ImproperTorsions",
+            "vdW",
+        ] {
+            let handler =
+                self.get_parameter_handler(typ).expect("known handler name");
+            ret.push((typ, handler))
         }
         ret
     }
 
+    /// Return the labels of each distinct molecule in `topology`. Aromaticity
+    /// is perceived with the force field's aromaticity model first. Returns
+    /// an error if the model is not supported or perception fails for any
+    /// molecule.
     pub fn label_molecules(
         &self,
         topology: Topology,
-    ) -> Vec<HashMap<String, HashMap<Vec<usize>, String>>> {
+    ) -> Result<Vec<MoleculeLabels>, AromaticityError> {
+        let model = self.aromaticity_model()?;
         let mut molecule_labels = Vec::new();
 
-        for molecule in topology.molecules {
-            let top_mol = Topology::from_molecules(vec![molecule]);
+        for (m, mut molecule) in topology.molecules.into_iter().enumerate() {
+            molecule.perceive_aromaticity(model).map_err(|e| {
+                AromaticityError {
+                    message: format!("molecule {m}: {}", e.message),
+                }
+            })?;
+            let mut top_mol = Topology::default();
+            top_mol.add_molecule(molecule);
             let mut current_molecule_labels = HashMap::new();
 
             for (tag, parameter_handler) in self.parameter_handlers() {
@@ -640,7 +1151,7 @@ impl ForceField {
                     *parameter_matches
                         .entry(match_.clone())
                         .or_insert(String::new()) =
-                        matches[match_].parameter_type.clone();
+                        matches[match_].parameter_id.clone();
                 }
 
                 *current_molecule_labels.entry(tag.to_owned()).or_default() =
@@ -650,23 +1161,260 @@ impl ForceField {
             molecule_labels.push(current_molecule_labels);
         }
 
-        molecule_labels
+        Ok(molecule_labels)
     }
 
-    pub fn create_interchange(&self, _topology: &Topology) -> Interchange {
-        todo!();
+    /// Apply the force field to `topology`. Every bond, angle, proper torsion,
+    /// and atom in the topology must be assigned a parameter. Molecules
+    /// without partial charges get library charges, or AM1-BCC charges from
+    /// the backend set with [ForceField::set_charge_backend].
+    pub fn create_interchange(
+        &self,
+        topology: &Topology,
+    ) -> Result<Interchange, Box<dyn Error>> {
+        let bonds: HashMap<_, _> =
+            self.bonds.bonds.iter().map(|p| (&p.id, p)).collect();
+        let angles: HashMap<_, _> =
+            self.angles.angles.iter().map(|p| (&p.id, p)).collect();
+        let propers: HashMap<_, _> = self
+            .proper_torsions
+            .proper_torsions
+            .iter()
+            .map(|p| (&p.id, p))
+            .collect();
+        let impropers: HashMap<_, _> = self
+            .improper_torsions
+            .improper_torsions
+            .iter()
+            .map(|p| (&p.id, p))
+            .collect();
+        let atoms: HashMap<_, _> =
+            self.vdw.atoms.iter().map(|p| (&p.id, p)).collect();
+
+        let vdw_scales = self.vdw.scales()?;
+        let electrostatics_scales = self.electrostatics.scales()?;
+        let improper_idivf = match self.improper_torsions.default_idivf.as_str()
+        {
+            "auto" => 3.0,
+            s => s.parse()?,
+        };
+
+        let mut ret = Interchange::default();
+        let labels = self.label_molecules(topology.clone())?;
+        let mut distances = Vec::with_capacity(labels.len());
+        let mut charges = Vec::with_capacity(labels.len());
+        for (m, (graph, labels)) in
+            topology.molecules.iter().zip(&labels).enumerate()
+        {
+            check_assigned(graph, labels)?;
+            distances.push(graph.topological_distances());
+            charges.push(
+                self.interchange_charges(graph)
+                    .map_err(|e| format!("molecule {m}: {e}"))?,
+            );
+        }
+        for &m in topology.copies() {
+            let offset = ret.n_atoms;
+            let graph = &topology.molecules[m];
+            let labels = &labels[m];
+            let shift = |atoms: &[usize]| -> Vec<usize> {
+                atoms.iter().map(|a| a + offset).collect()
+            };
+
+            for (key, id) in &labels["Bonds"] {
+                let p = bonds[id];
+                ret.bonds.push(BondTerm {
+                    atoms: shift(key).try_into().unwrap(),
+                    k: p.k.value,
+                    length: p.length.value,
+                    id: id.clone(),
+                });
+            }
+
+            for (key, id) in &labels["Angles"] {
+                let p = angles[id];
+                ret.angles.push(AngleTerm {
+                    atoms: shift(key).try_into().unwrap(),
+                    k: p.k.value,
+                    angle: p.angle.value.to_radians(),
+                    id: id.clone(),
+                });
+            }
+
+            for (key, id) in &labels["ProperTorsions"] {
+                let p = propers[id];
+                for (term, (periodicity, phase, k, idivf)) in p.terms()? {
+                    ret.torsions.push(TorsionTerm {
+                        atoms: shift(key).try_into().unwrap(),
+                        periodicity,
+                        phase: phase.to_radians(),
+                        k,
+                        idivf,
+                        id: id.clone(),
+                        term,
+                    });
+                }
+            }
+
+            for (key, id) in &labels["ImproperTorsions"] {
+                let p = impropers[id];
+                let [a, center, b, c]: [usize; 4] =
+                    shift(key).try_into().unwrap();
+                // each improper is applied along the three paths around the
+                // trefoil with the same handedness, with the central atom
+                // first
+                for [x, y, z] in [[a, b, c], [b, c, a], [c, a, b]] {
+                    ret.impropers.push(TorsionTerm {
+                        atoms: [center, x, y, z],
+                        periodicity: p.periodicity1.parse()?,
+                        phase: Quantity::try_from(p.phase1.clone())?
+                            .value
+                            .to_radians(),
+                        k: p.k1.value,
+                        idivf: improper_idivf,
+                        id: id.clone(),
+                        term: 1,
+                    });
+                }
+            }
+
+            let mut vdw = vec![None; graph.n_atoms()];
+            for (key, id) in &labels["vdW"] {
+                let p = atoms[id];
+                vdw[key[0]] = Some(LjTerm {
+                    epsilon: p.epsilon.value,
+                    rmin_half: p.rmin_half()?,
+                    sigma: p.sigma.is_some(),
+                    id: id.clone(),
+                });
+            }
+            ret.vdw.extend(vdw.into_iter().map(Option::unwrap));
+
+            ret.pairs.extend(nonbonded_pairs(
+                &distances[m],
+                vdw_scales,
+                offset,
+            ));
+            ret.charges.extend(&charges[m]);
+            ret.electrostatics_pairs.extend(nonbonded_pairs(
+                &distances[m],
+                electrostatics_scales,
+                offset,
+            ));
+            ret.n_atoms += graph.n_atoms();
+        }
+
+        Ok(ret)
     }
-}
 
-pub struct Interchange {
-    pub virtual_sites: Vec<()>,
+    /// Replace the parameter values in `interchange`, which must have been
+    /// created by [ForceField::create_interchange] from a force field with the
+    /// same parameter ids, with those in `self`. This avoids labeling the
+    /// topology again when only the parameter values have changed, as during
+    /// an optimization. Charges are left as they are.
+    pub fn update_interchange(
+        &self,
+        interchange: &mut Interchange,
+    ) -> Result<(), Box<dyn Error>> {
+        fn find<'a, T>(
+            parameters: &HashMap<&String, &'a T>,
+            id: &String,
+        ) -> Result<&'a T, String> {
+            parameters
+                .get(id)
+                .copied()
+                .ok_or_else(|| format!("no parameter {id}"))
+        }
+        let bonds: HashMap<_, _> =
+            self.bonds.bonds.iter().map(|p| (&p.id, p)).collect();
+        let angles: HashMap<_, _> =
+            self.angles.angles.iter().map(|p| (&p.id, p)).collect();
+        let propers: HashMap<_, _> = self
+            .proper_torsions
+            .proper_torsions
+            .iter()
+            .map(|p| (&p.id, p))
+            .collect();
+        let impropers: HashMap<_, _> = self
+            .improper_torsions
+            .improper_torsions
+            .iter()
+            .map(|p| (&p.id, p))
+            .collect();
+        let atoms: HashMap<_, _> =
+            self.vdw.atoms.iter().map(|p| (&p.id, p)).collect();
+
+        for t in &mut interchange.bonds {
+            let p = find(&bonds, &t.id)?;
+            t.k = p.k.value;
+            t.length = p.length.value;
+        }
+        for t in &mut interchange.angles {
+            let p = find(&angles, &t.id)?;
+            t.k = p.k.value;
+            t.angle = p.angle.value.to_radians();
+        }
+        for t in &mut interchange.torsions {
+            let terms = find(&propers, &t.id)?.terms()?;
+            let (periodicity, phase, k, idivf) = terms
+                .into_iter()
+                .find_map(|(term, params)| (term == t.term).then_some(params))
+                .ok_or_else(|| format!("{} has no term {}", t.id, t.term))?;
+            t.periodicity = periodicity;
+            t.phase = phase.to_radians();
+            t.k = k;
+            t.idivf = idivf;
+        }
+        for t in &mut interchange.impropers {
+            let p = find(&impropers, &t.id)?;
+            t.periodicity = p.periodicity1.parse()?;
+            t.phase = Quantity::try_from(p.phase1.clone())?.value.to_radians();
+            t.k = p.k1.value;
+        }
+        for t in &mut interchange.vdw {
+            let p = find(&atoms, &t.id)?;
+            t.epsilon = p.epsilon.value;
+            t.rmin_half = p.rmin_half()?;
+            t.sigma = p.sigma.is_some();
+        }
+        Ok(())
+    }
 }
 
-impl Interchange {
-    #[cfg(feature = "openmm")]
-    pub fn to_openmm_topology(self) -> openmm::topology::Topology {
-        todo!();
+/// Check that every bond, angle, proper torsion, and atom in `graph` has a
+/// label in `labels`
+fn check_assigned(
+    graph: &MoleculeGraph,
+    labels: &MoleculeLabels,
+) -> Result<(), UnassignedParameterError> {
+    let check = |handler: &'static str, typ: &str, atoms: Vec<usize>| {
+        let key = valence_key(typ, atoms);
+        if labels[handler].contains_key(&key) {
+            Ok(())
+        } else {
+            Err(UnassignedParameterError {
+                parameter_type: handler,
+                atoms: key,
+            })
+        }
+    };
+    for i in 0..graph.n_atoms() {
+        check("vdW", "Atom", vec![i])?;
+    }
+    for bond in &graph.bonds {
+        let (j, k) = (bond.atom1, bond.atom2);
+        check("Bonds", "Bond", vec![j, k])?;
+        for i in graph.neighbors(j).filter(|&i| i != k) {
+            check("Angles", "Angle", vec![i, j, k])?;
+            for l in graph.neighbors(k).filter(|&l| l != j && l != i) {
+                check("ProperTorsions", "Proper", vec![i, j, k, l])?;
+            }
+        }
+        for l in graph.neighbors(k).filter(|&l| l != j) {
+            check("Angles", "Angle", vec![j, k, l])?;
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -675,7 +1423,165 @@ mod tests {
 
     #[test]
     fn load() {
-        let _ = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        assert_eq!(ff.aromaticity_model(), Ok(AromaticityModel::Mdl));
+    }
+
+    #[test]
+    fn unknown_aromaticity_model() {
+        let mut ff = ForceField::load("testfiles/force-field.offxml").unwrap();
+        ff.aromaticity_model = "OEAroModel_OpenEye".to_owned();
+        assert!(ff.create_interchange(&Topology::default()).is_err());
+        let err = ff.label_molecules(Topology::default()).unwrap_err();
+        assert!(err.message.contains("unsupported aromaticity model"));
+    }
+
+    #[test]
+    fn improper_energy() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let formaldehyde = crate::testing::graph(
+            &[6, 8, 1, 1],
+            &[(0, 1, 2), (0, 2, 1), (0, 3, 1)],
+        );
+        let mut topology = Topology::default();
+        topology.add_molecule(formaldehyde);
+        topology.molecules[0].partial_charges = Some(vec![0.0; 4]);
+        let interchange = ff.create_interchange(&topology).unwrap();
+        assert_eq!(interchange.impropers.len(), 3);
+        assert!(interchange.impropers.iter().all(|t| t.atoms[0] == 0));
+
+        // with the substituents along the axes, each of the three dihedrals
+        // starting from the carbon has cos φ = 1/√3, so each term of i1 is
+        // k/3 (1 + cos(2φ - π)) = k/3 (1 + 1/3), and there are three terms
+        let positions =
+            [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        let k = 5.230790565314;
+        let want = 4.0 * k / 3.0;
+        let got = interchange.energy_components(&positions).impropers;
+        assert!((got - want).abs() < 1e-10, "{got} {want}");
+    }
+
+    #[test]
+    fn update_interchange() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let mut topology = Topology::default();
+        topology.add_molecule(crate::testing::ethanol());
+        topology.molecules[0].partial_charges = Some(vec![0.0; 9]);
+        let mut got = ff.create_interchange(&topology).unwrap();
+
+        let mut changed = ff.clone();
+        changed
+            .bonds
+            .bonds
+            .iter_mut()
+            .for_each(|p| p.k.value *= 2.0);
+        changed
+            .proper_torsions
+            .proper_torsions
+            .iter_mut()
+            .for_each(|p| p.k1.value += 1.0);
+        changed
+            .vdw
+            .atoms
+            .iter_mut()
+            .for_each(|p| p.epsilon.value /= 2.0);
+        changed.update_interchange(&mut got).unwrap();
+        // labels come out in hash order
+        let sorted = |mut i: Interchange| {
+            i.bonds.sort_by_key(|t| t.atoms);
+            i.angles.sort_by_key(|t| t.atoms);
+            i.torsions.sort_by_key(|t| (t.atoms, t.term));
+            i.impropers.sort_by_key(|t| t.atoms);
+            i
+        };
+        let got = sorted(got);
+        let want = sorted(changed.create_interchange(&topology).unwrap());
+        assert_eq!(got.bonds, want.bonds);
+        assert_eq!(got.angles, want.angles);
+        assert_eq!(got.torsions, want.torsions);
+        assert_eq!(got.impropers, want.impropers);
+        assert_eq!(got.vdw, want.vdw);
+        let old = sorted(ff.create_interchange(&topology).unwrap());
+        assert_ne!(got.bonds, old.bonds);
+    }
+
+    #[test]
+    fn implicit_pairs() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let mut topology = Topology::default();
+        let mut ethanol = crate::testing::ethanol();
+        ethanol.partial_charges = Some(vec![0.0; 9]);
+        let m = topology.add_molecule(ethanol);
+        topology.add_copies(m, 99).unwrap();
+        let interchange = ff.create_interchange(&topology).unwrap();
+        // only the 8 bonded, 13 1-3, and 12 1-4 pairs of each ethanol are
+        // stored
+        let pairs = &interchange.pairs;
+        assert_eq!(pairs.len(), 3300);
+        assert!(pairs.iter().all(|&(i, j, _)| i / 9 == j / 9));
+        let excluded = pairs.iter().filter(|p| p.2 == 0.0).count();
+        assert_eq!(interchange.vdw_pairs().count(), 900 * 899 / 2 - excluded);
+    }
+
+    #[test]
+    fn sigma() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let mut topology = Topology::default();
+        topology.add_molecule(crate::testing::water());
+        let interchange = ff.create_interchange(&topology).unwrap();
+        // TIP3P oxygen has σ = 3.1507 Å, and rmin = 2^(1/6) σ
+        let got = interchange.vdw[1].rmin_half;
+        assert!((got - 1.768_270_6).abs() < 1e-6, "{got}");
+    }
+
+    #[test]
+    fn proper_terms() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let proper = |id: &str| {
+            ff.proper_torsions
+                .proper_torsions
+                .iter()
+                .find(|p| p.id == id)
+                .unwrap()
+                .clone()
+        };
+        let periodicities = |p: &Proper| -> Vec<(usize, f64)> {
+            p.terms()
+                .unwrap()
+                .into_iter()
+                .map(|(n, (periodicity, ..))| (n, periodicity))
+                .collect()
+        };
+        assert_eq!(
+            periodicities(&proper("t25")),
+            [(1, 4.0), (2, 3.0), (3, 2.0), (4, 2.0), (5, 1.0)]
+        );
+        let t156 = proper("t156");
+        let terms = t156.terms().unwrap();
+        assert_eq!(terms.len(), 6);
+        assert_eq!(terms[5], (6, (1.0, 0.0, 1.167785785382, 1.0)));
+
+        let mut broken = t156.clone();
+        broken.phase5 = None;
+        assert!(broken.terms().is_err());
+
+        let mut t1 = proper("t1");
+        let four =
+            [(1, 0.0, 0.5), (2, 180.0, 1.0), (3, 0.0, 1.5), (4, 0.0, 2.0)];
+        t1.set_terms(&four).unwrap();
+        assert_eq!(
+            periodicities(&t1),
+            [(1, 1.0), (2, 2.0), (3, 3.0), (4, 4.0)]
+        );
+        assert_eq!(t1.k4.as_ref().unwrap().value, 2.0);
+
+        // too many terms are rejected without touching the torsion
+        let mut six = t156.clone();
+        assert!(six.set_terms(&[(1, 0.0, 1.0); 7]).is_err());
+        assert_eq!(six, t156);
+        six.set_terms(&four[..1]).unwrap();
+        assert_eq!(periodicities(&six), [(1, 1.0)]);
+        assert!(six.k6.is_none() && six.periodicity6.is_none());
     }
 
     #[test]
@@ -0,0 +1,404 @@
+//! Molecule-specific torsion parameters for bespoke fitting
+
+use std::{
+    collections::{HashSet, VecDeque},
+    error::Error,
+    fmt::Display,
+};
+
+use ligand::molecule::Molecule;
+
+use crate::topology::{molecule::MoleculeGraph, smarts::Smarts};
+
+use super::ForceField;
+
+/// The maximum number of heavy-atom automorphisms searched for a bond that
+/// [bespoke_smirks] cannot tell apart from the others
+const MAX_AUTOMORPHISMS: usize = 1000;
+
+#[derive(Debug)]
+pub struct BespokeError {
+    pub bond: usize,
+    pub message: String,
+}
+
+impl Error for BespokeError {}
+
+impl Display for BespokeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bond {}: {}", self.bond, self.message)
+    }
+}
+
+fn bond_symbol(graph: &MoleculeGraph, i: usize, j: usize) -> &'static str {
+    match graph.bond_between(i, j) {
+        Some(b) if b.is_aromatic => ":",
+        Some(b) if b.bond_order == 1 => "-",
+        Some(b) if b.bond_order == 2 => "=",
+        Some(b) if b.bond_order == 3 => "#",
+        _ => "~",
+    }
+}
+
+fn atom_symbol(graph: &MoleculeGraph, i: usize) -> String {
+    let atom = &graph.atoms[i];
+    format!(
+        "[#{}X{}H{}{:+}]",
+        atom.atomic_number,
+        graph.degree(i),
+        graph.n_hydrogens(i),
+        atom.formal_charge
+    )
+}
+
+/// Write a SMARTS pattern rooted at `root` describing every atom within
+/// `radius` bonds of it, following a breadth-first spanning tree. Ring
+/// closures are not written, so the pattern may also match acyclic
+/// environments.
+fn environment(graph: &MoleculeGraph, root: usize, radius: usize) -> String {
+    let mut children = vec![Vec::new(); graph.n_atoms()];
+    let mut depth = vec![None; graph.n_atoms()];
+    depth[root] = Some(0);
+    let mut queue = VecDeque::from([root]);
+    while let Some(i) = queue.pop_front() {
+        let d = depth[i].unwrap();
+        if d == radius {
+            continue;
+        }
+        for n in graph.neighbors(i) {
+            if depth[n].is_none() {
+                depth[n] = Some(d + 1);
+                children[i].push(n);
+                queue.push_back(n);
+            }
+        }
+    }
+
+    fn write(
+        graph: &MoleculeGraph,
+        children: &[Vec<usize>],
+        i: usize,
+    ) -> String {
+        let mut ret = atom_symbol(graph, i);
+        for &c in &children[i] {
+            ret.push('(');
+            ret.push_str(bond_symbol(graph, i, c));
+            ret.push_str(&write(graph, children, c));
+            ret.push(')');
+        }
+        ret
+    }
+    write(graph, &children, root)
+}
+
+/// Return a SMIRKS pattern matching the torsions around the bond between atoms
+/// `j` and `k` of `graph`, describing the environments of both atoms out to
+/// the smallest radius that distinguishes the bond from every other bond in
+/// the molecule. The second return value is false if no radius does, as
+/// happens for bonds related by symmetry.
+pub fn bespoke_smirks(
+    graph: &MoleculeGraph,
+    j: usize,
+    k: usize,
+) -> (String, bool) {
+    let mut last = String::new();
+    for radius in 0.. {
+        let smirks = format!(
+            "[*:1]~[$({}):2]{}[$({}):3]~[*:4]",
+            environment(graph, j, radius),
+            bond_symbol(graph, j, k),
+            environment(graph, k, radius),
+        );
+        if smirks == last {
+            return (smirks, false);
+        }
+        let query = Smarts::parse(&smirks)
+            .expect("generated SMIRKS should always parse");
+        let unique = query
+            .find_matches(graph)
+            .iter()
+            .all(|m| (m[1] == j && m[2] == k) || (m[1] == k && m[2] == j));
+        if unique {
+            return (smirks, true);
+        }
+        last = smirks;
+    }
+    unreachable!()
+}
+
+impl ForceField {
+    /// Add a bespoke proper torsion parameter for each of the bonds in
+    /// `molecule` with indices in `rotatable_bonds`, as returned by
+    /// [MoleculeGraph::rotatable_bonds]. Each new parameter starts from the
+    /// values of the parameter currently assigned to the torsions around its
+    /// bond, is appended to the proper torsions so that it takes precedence,
+    /// and has all of its `kN` terms marked for fitting. Bonds related by
+    /// symmetry share a single parameter. Returns the ids of the new
+    /// parameters, which are distinct from every existing id, or a
+    /// [BespokeError] if the SMIRKS for a bond also matches a bond that is not
+    /// related to it by symmetry.
+    pub fn add_bespoke_torsions(
+        &mut self,
+        molecule: &Molecule,
+        rotatable_bonds: &[usize],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        self.add_bespoke_graph_torsions(
+            &MoleculeGraph::from(molecule),
+            rotatable_bonds,
+        )
+    }
+
+    pub(crate) fn add_bespoke_graph_torsions(
+        &mut self,
+        graph: &MoleculeGraph,
+        rotatable_bonds: &[usize],
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut graph = graph.clone();
+        graph.perceive_aromaticity(self.aromaticity_model()?)?;
+        let graph = &graph;
+        let heavy = graph.heavy_atoms();
+        let mut automorphisms = None;
+        let pair = |i: usize, j: usize| (i.min(j), i.max(j));
+
+        // the bonds matched by the new parameters
+        let mut covered = HashSet::new();
+
+        // the torsions matched by each existing parameter, in force field
+        // order so that later matches take precedence
+        let mut existing = Vec::new();
+        for proper in &self.proper_torsions {
+            let query = Smarts::parse(&proper.smirks)?;
+            existing.push(query.find_matches(graph));
+        }
+
+        let mut ret = Vec::new();
+        for &b in rotatable_bonds {
+            let err = |message: &str| BespokeError {
+                bond: b,
+                message: message.to_owned(),
+            };
+            let bond = graph.bonds.get(b).ok_or(err("no such bond"))?;
+            let (j, k) = (bond.atom1, bond.atom2);
+            if covered.contains(&pair(j, k)) {
+                continue;
+            }
+
+            // any other bonds matched must be symmetry-equivalent to this one
+            let (smirks, unique) = bespoke_smirks(graph, j, k);
+            let matched: HashSet<_> = Smarts::parse(&smirks)?
+                .find_matches(graph)
+                .iter()
+                .map(|m| pair(m[1], m[2]))
+                .collect();
+            if !unique {
+                let automorphisms = automorphisms.get_or_insert_with(|| {
+                    graph.heavy_atom_automorphisms(MAX_AUTOMORPHISMS)
+                });
+                let image = |p: &Vec<usize>, i| {
+                    heavy.iter().position(|&h| h == i).map(|n| p[n])
+                };
+                let equivalent: HashSet<_> = automorphisms
+                    .iter()
+                    .filter_map(|p| Some(pair(image(p, j)?, image(p, k)?)))
+                    .collect();
+                if !matched.is_subset(&equivalent) {
+                    return Err(Box::new(err(
+                        "no SMIRKS distinguishes the bond from bonds that \
+                         are not related to it by symmetry",
+                    )));
+                }
+            }
+            covered.extend(matched);
+
+            // count the torsions around the bond assigned to each parameter
+            let mut counts = vec![0; existing.len()];
+            for i in graph.neighbors(j).filter(|&i| i != k) {
+                for l in graph.neighbors(k).filter(|&l| l != j && l != i) {
+                    let dihedral = [i, j, k, l];
+                    let reversed = [l, k, j, i];
+                    let assigned = existing.iter().rposition(|matches| {
+                        matches.iter().any(|m| m == &dihedral || m == &reversed)
+                    });
+                    if let Some(p) = assigned {
+                        counts[p] += 1;
+                    }
+                }
+            }
+            let Some(parent) = counts
+                .iter()
+                .enumerate()
+                .filter(|(_, c)| **c > 0)
+                .max_by_key(|&(p, c)| (c, std::cmp::Reverse(p)))
+                .map(|(p, _)| p)
+            else {
+                return Err(Box::new(err("no torsion parameters assigned")));
+            };
+
+            let mut proper =
+                self.proper_torsions.proper_torsions[parent].clone();
+            proper.id = (1..)
+                .map(|n| format!("{}-bespoke-{n}", proper.id))
+                .find(|id| {
+                    !self
+                        .proper_torsions
+                        .proper_torsions
+                        .iter()
+                        .any(|p| &p.id == id)
+                })
+                .unwrap();
+            proper.smirks = smirks;
+            let terms: Vec<_> = proper
+                .terms()?
+                .into_iter()
+                .map(|(n, _)| format!("k{n}"))
+                .collect();
+            proper.parameterize = Some(terms.join(","));
+
+            ret.push(proper.id.clone());
+            self.proper_torsions.proper_torsions.push(proper);
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{fitting::ParameterKey, testing::graph};
+
+    use super::*;
+
+    /// butan-1-ol, with the heavy atoms O-C-C-C-C first and then the
+    /// hydrogens
+    fn butanol() -> MoleculeGraph {
+        let mut atoms = vec![8, 6, 6, 6, 6];
+        let mut bonds: Vec<_> = (1..5).map(|i| (i - 1, i, 1)).collect();
+        for (heavy, n_h) in [(0, 1), (1, 2), (2, 2), (3, 2), (4, 3)] {
+            for _ in 0..n_h {
+                bonds.push((heavy, atoms.len(), 1));
+                atoms.push(1);
+            }
+        }
+        graph(&atoms, &bonds)
+    }
+
+    #[test]
+    fn smirks() {
+        let graph = butanol();
+        for bond in &graph.bonds[..4] {
+            let (j, k) = (bond.atom1, bond.atom2);
+            let (smirks, unique) = bespoke_smirks(&graph, j, k);
+            assert!(unique, "{smirks}");
+            let matches = Smarts::parse(&smirks).unwrap().find_matches(&graph);
+            assert!(!matches.is_empty());
+        }
+
+        // the C2-C3 bond needs the neighbors of both carbons to tell it apart
+        // from C1-C2
+        let (smirks, _) = bespoke_smirks(&graph, 2, 3);
+        assert!(smirks.starts_with("[*:1]~[$([#6X4H2+0](-[#6X4H2+0])"));
+    }
+
+    #[test]
+    fn add_bespoke() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let graph = butanol();
+        let n = ff.proper_torsions.proper_torsions.len();
+        let rotatable = graph.rotatable_bonds();
+        assert_eq!(rotatable, vec![1, 2]);
+
+        let ids = ff.add_bespoke_graph_torsions(&graph, &rotatable).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ff.proper_torsions.proper_torsions.len(), n + 2);
+        for id in &ids {
+            let new = ff
+                .proper_torsions
+                .proper_torsions
+                .iter()
+                .find(|p| &p.id == id)
+                .unwrap();
+            let parent_id = id.split("-bespoke").next().unwrap();
+            let parent = ff
+                .proper_torsions
+                .proper_torsions
+                .iter()
+                .find(|p| p.id == parent_id)
+                .unwrap();
+            assert_eq!(new.terms().unwrap(), parent.terms().unwrap());
+            let want: Vec<_> = new
+                .terms()
+                .unwrap()
+                .iter()
+                .map(|(n, _)| format!("k{n}"))
+                .collect();
+            assert_eq!(
+                new.parameterize.as_deref(),
+                Some(want.join(",").as_str())
+            );
+        }
+        assert!(ff.parameterized().contains(&ParameterKey::new(
+            "ProperTorsions",
+            &ids[0],
+            "k1"
+        )));
+
+        assert!(ff.add_bespoke_graph_torsions(&graph, &[100]).is_err());
+    }
+
+    #[test]
+    fn repeated_and_symmetric() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let molecule = butanol();
+        let first = ff.add_bespoke_graph_torsions(&molecule, &[1, 2]).unwrap();
+
+        // a second call gets new ids, starting from the parameters of the
+        // first, and keeps every term of its parent
+        let parent = (&mut ff.proper_torsions)
+            .into_iter()
+            .find(|p| p.id == first[0])
+            .unwrap();
+        let four: Vec<_> = (1..=4).map(|n| (n, 0.0, 0.5)).collect();
+        parent.set_terms(&four).unwrap();
+        let second = ff.add_bespoke_graph_torsions(&molecule, &[1, 2]).unwrap();
+        assert!(second.iter().all(|id| !first.contains(id)), "{second:?}");
+        let new = ff
+            .proper_torsions
+            .proper_torsions
+            .iter()
+            .find(|p| p.id == second[0])
+            .unwrap();
+        assert_eq!(new.terms().unwrap().len(), 4);
+        assert_eq!(new.parameterize.as_deref(), Some("k1,k2,k3,k4"));
+
+        // the two C-O bonds of diethyl ether share a parameter
+        let mut bonds = vec![(0, 1, 1), (1, 2, 1), (2, 3, 1), (3, 4, 1)];
+        let mut atoms = vec![6, 6, 8, 6, 6];
+        for (heavy, n_h) in [(0, 3), (1, 2), (3, 2), (4, 3)] {
+            for _ in 0..n_h {
+                bonds.push((heavy, atoms.len(), 1));
+                atoms.push(1);
+            }
+        }
+        let ether = graph(&atoms, &bonds);
+        let ids = ff.add_bespoke_graph_torsions(&ether, &[1, 2]).unwrap();
+        assert_eq!(ids.len(), 1);
+
+        // without ring closures, the SMIRKS for a cyclohexane bond also
+        // matches the bonds of cyclooctane
+        let mut bonds = Vec::new();
+        for (start, n) in [(0, 6), (6, 8)] {
+            for i in 0..n {
+                bonds.push((start + i, start + (i + 1) % n, 1));
+            }
+        }
+        let mut atoms = vec![6; 14];
+        for heavy in 0..14 {
+            for _ in 0..2 {
+                bonds.push((heavy, atoms.len(), 1));
+                atoms.push(1);
+            }
+        }
+        let rings = graph(&atoms, &bonds);
+        let err = ff.add_bespoke_graph_torsions(&rings, &[0]).unwrap_err();
+        assert!(err.to_string().contains("symmetry"), "{err}");
+    }
+}
@@ -84,6 +84,16 @@ impl<'a> IntoIterator for &'a Bonds {
     }
 }
 
+impl<'a> IntoIterator for &'a mut Bonds {
+    type Item = <&'a mut Vec<Bond> as IntoIterator>::Item;
+
+    type IntoIter = <&'a mut Vec<Bond> as IntoIterator>::IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.bonds.iter_mut()
+    }
+}
+
 impl IntoIterator for Bonds {
     type Item = Bond;
 
@@ -0,0 +1,268 @@
+//! Assigning partial charges from the `LibraryCharges` section, falling back to
+//! a charge method for molecules it does not cover
+
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    charges::{ChargeBackend, ChargeMethod},
+    topology::{molecule::MoleculeGraph, smarts::Smarts},
+};
+
+use super::{Backend, ForceField, LibraryCharge, Quantity};
+
+impl LibraryCharge {
+    /// The values of `charge1`, `charge2`, ..., for the atoms tagged `:1`,
+    /// `:2`, ... in the SMIRKS pattern
+    fn values(&self) -> Result<Vec<f64>, Box<dyn Error>> {
+        let mut ret = Vec::new();
+        for i in 1..=self.charges.len() {
+            let Some(charge) = self.charges.get(&format!("@charge{i}")) else {
+                return Err(format!(
+                    "library charge {} has no charge{i}",
+                    self.id
+                )
+                .into());
+            };
+            ret.push(Quantity::try_from(charge.clone())?.value);
+        }
+        if ret.is_empty() {
+            return Err(
+                format!("library charge {} has no charges", self.id).into()
+            );
+        }
+        Ok(ret)
+    }
+}
+
+impl ForceField {
+    /// Return the library charges for every atom of `graph`, or None if any
+    /// atom is not matched by a library charge. Each match assigns
+    /// `charge{i}` to the atom tagged `:i`, and later library charges take
+    /// precedence over earlier ones matching the same atom. Aromaticity is
+    /// perceived with the force field's aromaticity model first.
+    pub fn library_charges(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Option<Vec<f64>>, Box<dyn Error>> {
+        let mut graph = graph.clone();
+        graph.perceive_aromaticity(self.aromaticity_model()?)?;
+        let graph = &graph;
+        let mut ret = vec![None; graph.n_atoms()];
+        for charge in &self.library_charges.library_charges {
+            let values = charge.values()?;
+            for m in Smarts::parse(&charge.smirks)?.find_matches(graph) {
+                if m.len() != values.len() {
+                    return Err(format!(
+                        "library charge {} has {} charges for {} tagged atoms",
+                        charge.id,
+                        values.len(),
+                        m.len()
+                    )
+                    .into());
+                }
+                for (&atom, &value) in m.iter().zip(&values) {
+                    ret[atom] = Some(value);
+                }
+            }
+        }
+        Ok(ret.into_iter().collect())
+    }
+
+    /// Assign partial charges to `graph` from the library charges if they
+    /// cover the whole molecule, and otherwise with `method`
+    pub fn assign_partial_charges(
+        &self,
+        graph: &mut MoleculeGraph,
+        method: ChargeMethod,
+    ) -> Result<(), Box<dyn Error>> {
+        match self.library_charges(graph)? {
+            Some(charges) => graph.partial_charges = Some(charges),
+            None => graph.assign_partial_charges(method)?,
+        }
+        Ok(())
+    }
+
+    /// Set the backend computing the AM1-BCC charges called for by the
+    /// `ToolkitAM1BCC` section in [ForceField::create_interchange]
+    pub fn set_charge_backend(
+        &mut self,
+        backend: Arc<dyn ChargeBackend + Send + Sync>,
+    ) {
+        self.toolkit_am1_bcc.backend = Backend(Some(backend));
+    }
+
+    /// The partial charges of `graph` in an
+    /// [crate::interchange::Interchange]: the charges already assigned to it,
+    /// otherwise its library charges, and otherwise AM1-BCC charges from the
+    /// charge backend
+    pub(crate) fn interchange_charges(
+        &self,
+        graph: &MoleculeGraph,
+    ) -> Result<Vec<f64>, Box<dyn Error>> {
+        if let Some(charges) = &graph.partial_charges {
+            if charges.len() != graph.n_atoms() {
+                return Err(format!(
+                    "{} partial charges for {} atoms",
+                    charges.len(),
+                    graph.n_atoms()
+                )
+                .into());
+            }
+            return Ok(charges.clone());
+        }
+        if let Some(charges) = self.library_charges(graph)? {
+            return Ok(charges);
+        }
+        match &self.toolkit_am1_bcc.backend.0 {
+            Some(backend) => backend.am1bcc(graph),
+            None => Err("no partial charges or library charges, and no \
+                         backend for AM1-BCC charges"
+                .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        charges::{bcc::BccCollection, eem::EemGeometry},
+        testing::{bcc_collection, ethanol, graph, water},
+        topology::Topology,
+        utils::COULOMB_CONSTANT,
+    };
+
+    use super::*;
+
+    #[test]
+    fn library_or_method() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let method = ChargeMethod::Eem(EemGeometry::Topological);
+
+        let mut graph = water();
+        ff.assign_partial_charges(&mut graph, method).unwrap();
+        assert_eq!(graph.partial_charges, Some(vec![0.417, -0.834, 0.417]));
+
+        // hydrogen sulfide is not in the library
+        graph.atoms[1].atomic_number = 16;
+        assert_eq!(ff.library_charges(&graph).unwrap(), None);
+        ff.assign_partial_charges(&mut graph, method).unwrap();
+        let want = method.compute(&graph).unwrap();
+        assert_eq!(graph.partial_charges, Some(want));
+    }
+
+    #[test]
+    fn multiple_tagged_atoms() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let charges = [("@charge1", "0.5"), ("@charge2", "-1.0")]
+            .into_iter()
+            .map(|(k, v)| (k.to_owned(), format!("{v} * elementary_charge")))
+            .collect();
+        ff.library_charges.library_charges.push(LibraryCharge {
+            smirks: "[#1:1]-[#8X2H2+0:2]-[#1]".to_owned(),
+            id: "water".to_owned(),
+            charges,
+        });
+        let got = ff.library_charges(&water()).unwrap();
+        assert_eq!(got, Some(vec![0.5, -1.0, 0.5]));
+
+        // charge2 without charge1
+        let last = ff.library_charges.library_charges.last_mut().unwrap();
+        last.charges.remove("@charge1");
+        assert!(ff.library_charges(&water()).is_err());
+    }
+
+    #[test]
+    fn aromatic_library_charges() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        ff.library_charges.library_charges.clear();
+        for (smirks, id, q) in [("[c:1]", "c", "-0.1"), ("[#1:1]", "h", "0.1")]
+        {
+            let charges =
+                [("@charge1".to_owned(), format!("{q} * elementary_charge"))];
+            ff.library_charges.library_charges.push(LibraryCharge {
+                smirks: smirks.to_owned(),
+                id: id.to_owned(),
+                charges: charges.into_iter().collect(),
+            });
+        }
+        // a Kekulé benzene without aromaticity flags
+        let mut bonds: Vec<_> = (0..6)
+            .map(|i| (i, (i + 1) % 6, 1 + (i % 2) as u8))
+            .collect();
+        bonds.extend((0..6).map(|i| (i, i + 6, 1)));
+        let benzene = graph(&[6, 6, 6, 6, 6, 6, 1, 1, 1, 1, 1, 1], &bonds);
+        let got = ff.library_charges(&benzene).unwrap().unwrap();
+        assert_eq!(got, [[-0.1; 6], [0.1; 6]].concat());
+    }
+
+    /// a backend whose AM1 charges are all zero
+    struct Zero(BccCollection);
+
+    impl ChargeBackend for Zero {
+        fn am1_mulliken(
+            &self,
+            graph: &MoleculeGraph,
+        ) -> Result<Vec<f64>, Box<dyn Error>> {
+            Ok(vec![0.0; graph.n_atoms()])
+        }
+
+        fn bccs(&self) -> &BccCollection {
+            &self.0
+        }
+    }
+
+    #[test]
+    fn interchange_charges() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+
+        // two waters with library charges, which only interact with each
+        // other since the 1-2 and 1-3 pairs are excluded
+        let mut topology = Topology::default();
+        let w = topology.add_molecule(water());
+        topology.add_copies(w, 1).unwrap();
+        let interchange = ff.create_interchange(&topology).unwrap();
+        let q = [0.417, -0.834, 0.417];
+        assert_eq!(interchange.charges, q.repeat(2));
+        let mut positions = water().conformers[0].clone();
+        positions.extend(positions.clone().iter().map(|x| x + 3.0));
+        let mut want = 0.0;
+        for i in 0..3 {
+            for j in 3..6 {
+                let r = (0..3)
+                    .map(|k| {
+                        (positions[3 * i + k] - positions[3 * j + k]).powi(2)
+                    })
+                    .sum::<f64>()
+                    .sqrt();
+                want += COULOMB_CONSTANT * q[i] * q[j - 3] / r;
+            }
+        }
+        let got = interchange.energy_components(&positions).electrostatics;
+        assert!((got - want).abs() < 1e-10, "{got} {want}");
+
+        // ethanol needs AM1-BCC charges
+        let mut topology = Topology::default();
+        topology.add_molecule(ethanol());
+        let err = ff.create_interchange(&topology).unwrap_err();
+        assert!(err.to_string().contains("AM1-BCC"), "{err}");
+
+        // with a zero correction for the bonds between heavy atoms
+        let mut bccs = bcc_collection();
+        let any = r#"{"smirks": "[*:1]~[*:2]", "value": 0.0}"#;
+        bccs.parameters
+            .insert(0, serde_json::from_str(any).unwrap());
+        let backend = Zero(bccs);
+        let want = backend.am1bcc(&ethanol()).unwrap();
+        ff.set_charge_backend(Arc::new(backend));
+        let interchange = ff.create_interchange(&topology).unwrap();
+        assert_eq!(interchange.charges, want);
+        // the 1-4 pairs are scaled by 1/1.2
+        let scaled: Vec<_> = interchange
+            .electrostatics_pairs
+            .iter()
+            .filter(|p| p.2 != 0.0)
+            .collect();
+        assert_eq!(scaled.len(), 12);
+        assert!(scaled.iter().all(|p| (p.2 - 0.8333333333).abs() < 1e-12));
+    }
+}
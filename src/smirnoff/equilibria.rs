@@ -0,0 +1,337 @@
+//! Initial bond lengths and angles from the distributions of QM geometries
+//! matched by each parameter
+
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    qcportal::models::Record,
+    topology::molecule::MoleculeGraph,
+    utils::geometry::{angle, distance},
+};
+
+use super::{ForceField, MoleculeLabels, Parameter, Quantity};
+
+/// Values farther than this many standard deviations from the mean for their
+/// parameter are reported as outliers
+pub const OUTLIER_THRESHOLD: f64 = 3.0;
+
+#[derive(Debug)]
+pub struct LabelCountError {
+    pub records: usize,
+    pub labels: usize,
+}
+
+impl Error for LabelCountError {}
+
+impl Display for LabelCountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "got labels for {} molecules but {} records",
+            self.labels, self.records
+        )
+    }
+}
+
+/// A single geometry value lying far from the mean for its parameter
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Outlier {
+    pub record_id: String,
+    pub atoms: Vec<usize>,
+    pub value: f64,
+}
+
+/// The distribution of QM bond lengths (in angstroms) or angles (in degrees)
+/// matched by a single parameter
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EquilibriumStatistics {
+    /// the parameter handler, either `Bonds` or `Angles`
+    pub handler: String,
+    pub id: String,
+    pub mean: f64,
+
+    /// population standard deviation
+    pub std: f64,
+    pub count: usize,
+    pub outliers: Vec<Outlier>,
+}
+
+/// The parameters whose equilibria are overwritten by
+/// [ForceField::set_equilibria]
+#[derive(Clone, Debug, PartialEq)]
+pub enum EquilibriumSelection {
+    /// every parameter with statistics
+    All,
+
+    /// the bonds whose `parameterize` attribute includes `length` and the
+    /// angles whose `parameterize` attribute includes `angle`
+    Parameterized,
+
+    /// the parameters with these ids
+    Ids(Vec<String>),
+}
+
+impl EquilibriumSelection {
+    /// Whether the parameter `id`, with `parameterize` attribute
+    /// `parameterize`, has its equilibrium `attr` selected
+    fn selects(
+        &self,
+        id: &str,
+        parameterize: Option<&str>,
+        attr: &str,
+    ) -> bool {
+        match self {
+            EquilibriumSelection::All => true,
+            EquilibriumSelection::Parameterized => {
+                parameterize.is_some_and(|p| {
+                    p.split(',').map(str::trim).any(|a| a == attr)
+                })
+            }
+            EquilibriumSelection::Ids(ids) => ids.iter().any(|i| i == id),
+        }
+    }
+}
+
+/// Compute the distribution of the bond lengths and angles for each parameter
+/// id in `labels` over every conformer in `records`. `labels` should contain
+/// the labels for each record's molecule, in the same order as `records`, as
+/// returned by [ForceField::label_molecules]. The results are sorted by
+/// handler and then parameter id.
+pub fn equilibrium_statistics(
+    records: &[(Record, Molecule)],
+    labels: &[MoleculeLabels],
+) -> Result<Vec<EquilibriumStatistics>, LabelCountError> {
+    let graphs: Vec<_> = records
+        .iter()
+        .map(|(record, molecule)| {
+            (record.id.clone(), MoleculeGraph::from(molecule))
+        })
+        .collect();
+    graph_statistics(&graphs, labels)
+}
+
+pub(crate) fn graph_statistics(
+    graphs: &[(String, MoleculeGraph)],
+    labels: &[MoleculeLabels],
+) -> Result<Vec<EquilibriumStatistics>, LabelCountError> {
+    if graphs.len() != labels.len() {
+        return Err(LabelCountError {
+            records: graphs.len(),
+            labels: labels.len(),
+        });
+    }
+
+    type Values<'a> = Vec<(&'a str, &'a [usize], f64)>;
+    let mut values: HashMap<(&str, &str), Values> = HashMap::new();
+    for ((record_id, graph), labels) in graphs.iter().zip(labels) {
+        for handler in ["Bonds", "Angles"] {
+            let Some(matches) = labels.get(handler) else {
+                continue;
+            };
+            for (atoms, id) in matches {
+                for conformer in &graph.conformers {
+                    let value = match atoms.as_slice() {
+                        [i, j] => distance(conformer, *i, *j),
+                        [i, j, k] => angle(conformer, *i, *j, *k).to_degrees(),
+                        _ => continue,
+                    };
+                    values
+                        .entry((handler, id))
+                        .or_default()
+                        .push((record_id, atoms, value));
+                }
+            }
+        }
+    }
+
+    let mut ret: Vec<_> = values
+        .into_iter()
+        .map(|((handler, id), values)| {
+            let n = values.len() as f64;
+            let mean = values.iter().map(|v| v.2).sum::<f64>() / n;
+            let std =
+                (values.iter().map(|v| (v.2 - mean).powi(2)).sum::<f64>() / n)
+                    .sqrt();
+            let mut outliers: Vec<_> = values
+                .iter()
+                .filter(|v| (v.2 - mean).abs() > OUTLIER_THRESHOLD * std)
+                .map(|&(record_id, atoms, value)| Outlier {
+                    record_id: record_id.to_owned(),
+                    atoms: atoms.to_vec(),
+                    value,
+                })
+                .collect();
+            outliers.sort_by(|a, b| {
+                (&a.record_id, &a.atoms).cmp(&(&b.record_id, &b.atoms))
+            });
+            EquilibriumStatistics {
+                handler: handler.to_owned(),
+                id: id.to_owned(),
+                mean,
+                std,
+                count: values.len(),
+                outliers,
+            }
+        })
+        .collect();
+    ret.sort_by(|a, b| (&a.handler, &a.id).cmp(&(&b.handler, &b.id)));
+    Ok(ret)
+}
+
+impl ForceField {
+    /// Compute the bond length and angle statistics for each parameter with
+    /// [equilibrium_statistics] and overwrite the `length` of each bond and the
+    /// `angle` of each angle parameter in `selection` with its mean, in
+    /// angstroms and degrees, respectively. Parameters not matched in
+    /// `records` are left unchanged. Use [equilibrium_statistics] directly to
+    /// inspect the statistics without changing `self`.
+    pub fn update_equilibria_from(
+        &mut self,
+        records: &[(Record, Molecule)],
+        labels: &[MoleculeLabels],
+        selection: &EquilibriumSelection,
+    ) -> Result<Vec<EquilibriumStatistics>, Box<dyn Error>> {
+        let stats = equilibrium_statistics(records, labels)?;
+        self.set_equilibria(&stats, selection)?;
+        Ok(stats)
+    }
+
+    /// Overwrite the bond lengths and angles of the parameters in `selection`
+    /// with the means in `stats`
+    pub fn set_equilibria(
+        &mut self,
+        stats: &[EquilibriumStatistics],
+        selection: &EquilibriumSelection,
+    ) -> Result<(), Box<dyn Error>> {
+        let means: HashMap<_, _> = stats
+            .iter()
+            .map(|s| ((s.handler.as_str(), s.id.as_str()), s.mean))
+            .collect();
+        for bond in &mut self.bonds {
+            let id = bond.id().as_str();
+            let parameterize = bond.parameterize.as_deref();
+            if !selection.selects(id, parameterize, "length") {
+                continue;
+            }
+            if let Some(mean) = means.get(&("Bonds", id)) {
+                *bond.as_hash_mut("length").unwrap() =
+                    Quantity::try_from(format!("{mean} * angstrom"))?;
+            }
+        }
+        for angle in &mut self.angles {
+            let id = angle.id().as_str();
+            let parameterize = angle.parameterize.as_deref();
+            if !selection.selects(id, parameterize, "angle") {
+                continue;
+            }
+            if let Some(mean) = means.get(&("Angles", id)) {
+                *angle.as_hash_mut("angle").unwrap() =
+                    Quantity::try_from(format!("{mean} * degree"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    use super::*;
+
+    /// a water-like triatomic with the O-H bond lengths in `r`
+    fn water(r: &[[f64; 2]]) -> MoleculeGraph {
+        let mut graph = graph(&[1, 8, 1], &[(0, 1, 1), (1, 2, 1)]);
+        graph.conformers = r
+            .iter()
+            .map(|&[a, b]| vec![a, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, b, 0.0])
+            .collect();
+        graph
+    }
+
+    fn labels() -> MoleculeLabels {
+        MoleculeLabels::from([
+            (
+                "Bonds".to_owned(),
+                HashMap::from([
+                    (vec![0, 1], "b1".to_owned()),
+                    (vec![1, 2], "b1".to_owned()),
+                ]),
+            ),
+            (
+                "Angles".to_owned(),
+                HashMap::from([(vec![0, 1, 2], "a1".to_owned())]),
+            ),
+        ])
+    }
+
+    #[test]
+    fn statistics() {
+        let graphs = vec![
+            ("1".to_owned(), water(&[[1.0, 1.0], [0.9, 1.1]])),
+            ("2".to_owned(), water(&[[1.0, 1.0]])),
+        ];
+        let got = graph_statistics(&graphs, &[labels(), labels()]).unwrap();
+        assert_eq!(got.len(), 2);
+
+        assert_eq!(got[0].handler, "Angles");
+        assert_eq!(got[0].count, 3);
+        assert!((got[0].mean - 90.0).abs() < 1e-10);
+        assert!(got[0].std < 1e-10);
+
+        assert_eq!(got[1].id, "b1");
+        assert_eq!(got[1].count, 6);
+        assert!((got[1].mean - 1.0).abs() < 1e-10);
+        let want_std = (0.02f64 / 6.0).sqrt();
+        assert!((got[1].std - want_std).abs() < 1e-10);
+        assert!(got[1].outliers.is_empty());
+
+        assert!(graph_statistics(&graphs, &[labels()]).is_err());
+    }
+
+    #[test]
+    fn outliers() {
+        let mut r = vec![[1.0, 1.0]; 10];
+        r.push([2.0, 1.0]);
+        let graphs = vec![("1".to_owned(), water(&r))];
+        let got = graph_statistics(&graphs, &[labels()]).unwrap();
+        assert_eq!(
+            got[1].outliers,
+            vec![Outlier {
+                record_id: "1".to_owned(),
+                atoms: vec![0, 1],
+                value: 2.0
+            }]
+        );
+    }
+
+    #[test]
+    fn set_equilibria() {
+        let mut ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let graphs = vec![("1".to_owned(), water(&[[1.0, 1.2]]))];
+        let stats = graph_statistics(&graphs, &[labels()]).unwrap();
+        let (length, angle) = (
+            ff.bonds[0].length.clone(),
+            (&ff.angles).into_iter().next().unwrap().angle.clone(),
+        );
+
+        // nothing is marked for fitting, and only a1 is selected by id
+        ff.set_equilibria(&stats, &EquilibriumSelection::Parameterized)
+            .unwrap();
+        let ids = EquilibriumSelection::Ids(vec!["a1".to_owned()]);
+        ff.set_equilibria(&stats, &ids).unwrap();
+        assert_eq!(ff.bonds[0].length, length);
+        let a1 = (&ff.angles).into_iter().next().unwrap();
+        assert_ne!(a1.angle, angle);
+        assert!((a1.angle.value - 90.0).abs() < 1e-10);
+
+        ff.bonds[0].parameterize = Some("k, length".to_owned());
+        ff.set_equilibria(&stats, &EquilibriumSelection::Parameterized)
+            .unwrap();
+        assert!((ff.bonds[0].length.value - 1.1).abs() < 1e-10);
+        assert_eq!(ff.bonds[0].length.to_string(), "1.1 *angstrom");
+    }
+}
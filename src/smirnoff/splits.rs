@@ -0,0 +1,515 @@
+//! Suggested SMIRKS splits for parameters whose QM data are multimodal.
+//!
+//! For each parameter, the QM values of its matched atom tuples (bond lengths,
+//! angles, or torsion barriers) are split into two clusters. If the clusters
+//! are well separated, every single-primitive specialization of the parameter's
+//! SMIRKS is tried as a child parameter, and the children whose matches
+//! separate the values are reported, ranked by the fraction of the variance
+//! they explain.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    error::Error,
+};
+
+use ligand::molecule::Molecule;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    benchmark::torsion::TorsionScan,
+    qcportal::models::Record,
+    topology::{molecule::MoleculeGraph, smarts::Smarts},
+    utils::{
+        geometry::{angle, distance},
+        Skipped,
+    },
+};
+
+use super::{
+    equilibria::LabelCountError, valence_key, ForceField, MoleculeLabels,
+    UnknownHandlerError,
+};
+
+/// The minimum number of samples on each side of a proposed split
+pub const MIN_GROUP_SIZE: usize = 2;
+
+/// Parameters whose best two-cluster split explains less than this fraction of
+/// the variance in their values are not considered multimodal
+pub const MULTIMODAL_THRESHOLD: f64 = 0.8;
+
+/// A proposed child of an existing parameter
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SplitProposal {
+    /// the parameter handler, one of `Bonds`, `Angles`, or `ProperTorsions`
+    pub handler: String,
+    pub id: String,
+    pub parent: String,
+
+    /// the SMIRKS of the new parameter, to be placed after the parent so that
+    /// it takes over the matches with the added feature
+    pub child: String,
+
+    /// the map index of the atom the feature was added to
+    pub atom: usize,
+
+    /// the SMARTS primitive added to the atom
+    pub feature: String,
+
+    pub child_count: usize,
+    pub child_mean: f64,
+
+    /// the number of samples left on the parent
+    pub parent_count: usize,
+    pub parent_mean: f64,
+
+    /// the fraction of the variance in the parent's values explained by the
+    /// split
+    pub variance_explained: f64,
+
+    /// the fraction of samples that the split places in the same group as a
+    /// one-dimensional two-means clustering of the values
+    pub agreement: f64,
+}
+
+/// The QM value for a single atom tuple matched by a parameter
+struct Sample {
+    graph: usize,
+    atoms: Vec<usize>,
+    value: f64,
+}
+
+/// Suggest splits of the bond and angle parameters in `ff` from the QM bond
+/// lengths (in angstroms) and angles (in degrees) in `records`, averaged over
+/// each record's conformers. `labels` should contain the labels for each
+/// record's molecule, in the same order as `records`. The proposals are sorted
+/// by decreasing variance explained, and are returned with the records and
+/// parameters that were left out.
+pub fn geometry_splits(
+    ff: &ForceField,
+    records: &[(Record, Molecule)],
+    labels: &[MoleculeLabels],
+) -> Result<(Vec<SplitProposal>, Vec<Skipped>), Box<dyn Error>> {
+    let graphs: Vec<_> = records
+        .iter()
+        .map(|(record, molecule)| {
+            (record.id.clone(), MoleculeGraph::from(molecule))
+        })
+        .collect();
+    graph_geometry_splits(ff, &graphs, labels)
+}
+
+pub(crate) fn graph_geometry_splits(
+    ff: &ForceField,
+    graphs: &[(String, MoleculeGraph)],
+    labels: &[MoleculeLabels],
+) -> Result<(Vec<SplitProposal>, Vec<Skipped>), Box<dyn Error>> {
+    if graphs.len() != labels.len() {
+        return Err(LabelCountError {
+            records: graphs.len(),
+            labels: labels.len(),
+        }
+        .into());
+    }
+
+    let mut ret = Vec::new();
+    let mut skipped: Vec<_> = graphs
+        .iter()
+        .filter(|(_, graph)| graph.conformers.is_empty())
+        .map(|(id, _)| Skipped::new(id, "no conformer"))
+        .collect();
+    for handler in ["Bonds", "Angles"] {
+        let mut samples: HashMap<&str, Vec<Sample>> = HashMap::new();
+        for (g, ((_, graph), labels)) in graphs.iter().zip(labels).enumerate() {
+            let Some(matches) = labels.get(handler) else {
+                continue;
+            };
+            if graph.conformers.is_empty() {
+                continue;
+            }
+            for (atoms, id) in matches {
+                let mut value = 0.0;
+                for conformer in &graph.conformers {
+                    value += match atoms.as_slice() {
+                        [i, j] => distance(conformer, *i, *j),
+                        [i, j, k] => angle(conformer, *i, *j, *k).to_degrees(),
+                        _ => continue,
+                    };
+                }
+                samples.entry(id).or_default().push(Sample {
+                    graph: g,
+                    atoms: atoms.clone(),
+                    value: value / graph.conformers.len() as f64,
+                });
+            }
+        }
+        let graphs: Vec<_> = graphs.iter().map(|(_, g)| g).collect();
+        let (proposals, mut rest) = suggest(ff, handler, &graphs, samples)?;
+        ret.extend(proposals);
+        skipped.append(&mut rest);
+    }
+    sort(&mut ret);
+    Ok((ret, skipped))
+}
+
+/// Suggest splits of the proper torsion parameters in `ff` from the QM
+/// barrier heights (in kcal/mol) of the torsion drives in `scans`, assigning
+/// each scan to the parameter matching its driven dihedral. `labels` should
+/// contain the labels for each scan's molecule, in the same order as `scans`.
+/// The proposals are sorted by decreasing variance explained, and are returned
+/// with the scans and parameters that were left out.
+pub fn torsion_splits(
+    ff: &ForceField,
+    scans: &[(Molecule, TorsionScan)],
+    labels: &[MoleculeLabels],
+) -> Result<(Vec<SplitProposal>, Vec<Skipped>), Box<dyn Error>> {
+    let graphs: Vec<_> = scans
+        .iter()
+        .map(|(molecule, _)| MoleculeGraph::from(molecule))
+        .collect();
+    let scans: Vec<_> = scans.iter().map(|(_, scan)| scan).collect();
+    graph_torsion_splits(ff, &graphs, &scans, labels)
+}
+
+pub(crate) fn graph_torsion_splits(
+    ff: &ForceField,
+    graphs: &[MoleculeGraph],
+    scans: &[&TorsionScan],
+    labels: &[MoleculeLabels],
+) -> Result<(Vec<SplitProposal>, Vec<Skipped>), Box<dyn Error>> {
+    if scans.len() != labels.len() {
+        return Err(LabelCountError {
+            records: scans.len(),
+            labels: labels.len(),
+        }
+        .into());
+    }
+
+    let mut samples: HashMap<&str, Vec<Sample>> = HashMap::new();
+    let mut skipped = Vec::new();
+    for (g, (scan, labels)) in scans.iter().zip(labels).enumerate() {
+        let atoms = valence_key("Proper", scan.dihedral.to_vec());
+        let Some(id) = labels
+            .get("ProperTorsions")
+            .and_then(|matches| matches.get(&atoms))
+        else {
+            let reason = "driven dihedral not labeled";
+            skipped.push(Skipped::new(&scan.record_id, reason));
+            continue;
+        };
+        if scan.energies.is_empty() {
+            skipped.push(Skipped::new(&scan.record_id, "no energies"));
+            continue;
+        }
+        let max = scan.energies.iter().copied().fold(f64::MIN, f64::max);
+        let min = scan.energies.iter().copied().fold(f64::MAX, f64::min);
+        samples.entry(id).or_default().push(Sample {
+            graph: g,
+            atoms,
+            value: max - min,
+        });
+    }
+
+    let graphs: Vec<_> = graphs.iter().collect();
+    let (mut ret, mut rest) = suggest(ff, "ProperTorsions", &graphs, samples)?;
+    sort(&mut ret);
+    skipped.append(&mut rest);
+    Ok((ret, skipped))
+}
+
+fn sort(proposals: &mut [SplitProposal]) {
+    proposals.sort_by(|a, b| {
+        b.variance_explained
+            .total_cmp(&a.variance_explained)
+            .then_with(|| {
+                (&a.handler, &a.id, &a.child)
+                    .cmp(&(&b.handler, &b.id, &b.child))
+            })
+    });
+}
+
+/// Insert the SMARTS primitive `feature` into the atom with map index `atom`
+/// in `smirks`, returning None if there is no such atom
+pub fn add_feature(smirks: &str, atom: usize, feature: &str) -> Option<String> {
+    let tag = format!(":{atom}]");
+    let pos = smirks.find(&tag)?;
+    Some(format!("{};{feature}{}", &smirks[..pos], &smirks[pos..]))
+}
+
+/// Find the split of `values` into a lower and upper cluster minimizing the
+/// within-cluster sum of squares. Returns the midpoint between the clusters
+/// and the fraction of the variance explained by the split.
+fn two_means(values: &[f64]) -> (f64, f64) {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len();
+    let total = sum_of_squares(&sorted);
+    if n < 2 || total == 0.0 {
+        return (sorted.first().copied().unwrap_or_default(), 0.0);
+    }
+    let (mut best, mut cut) = (f64::MAX, 1);
+    for k in 1..n {
+        let within =
+            sum_of_squares(&sorted[..k]) + sum_of_squares(&sorted[k..]);
+        if within < best {
+            best = within;
+            cut = k;
+        }
+    }
+    (0.5 * (sorted[cut - 1] + sorted[cut]), 1.0 - best / total)
+}
+
+fn sum_of_squares(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    values.iter().map(|v| (v - mean).powi(2)).sum()
+}
+
+fn mean(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, n) = values.fold((0.0, 0), |(s, n), v| (s + v, n + 1));
+    sum / n as f64
+}
+
+/// The SMARTS primitives describing atom `i` of `graph`, each paired with a
+/// rank used to prefer simpler features when two produce the same split
+fn atom_features(
+    graph: &MoleculeGraph,
+    ring_bonds: &[bool],
+    i: usize,
+) -> Vec<(usize, String)> {
+    let atom = &graph.atoms[i];
+    let mut ret = vec![
+        (0, format!("#{}", atom.atomic_number)),
+        (1, format!("X{}", graph.degree(i))),
+        (2, format!("H{}", graph.n_hydrogens(i))),
+    ];
+    if graph.adjacent(i).iter().any(|&(_, b)| ring_bonds[b]) {
+        ret.push((3, "R".to_owned()));
+    }
+    if atom.is_aromatic {
+        ret.push((4, "a".to_owned()));
+    }
+    for n in graph.neighbors(i) {
+        let z = graph.atoms[n].atomic_number;
+        ret.push((5, format!("$(*~[#{z}])")));
+    }
+    ret
+}
+
+/// Suggest splits of the parameters in `handler` from their `samples`,
+/// returning the proposals and the parameters that could not be split
+fn suggest(
+    ff: &ForceField,
+    handler: &str,
+    graphs: &[&MoleculeGraph],
+    samples: HashMap<&str, Vec<Sample>>,
+) -> Result<(Vec<SplitProposal>, Vec<Skipped>), UnknownHandlerError> {
+    let parameters = ff.get_parameter_handler(handler)?;
+    let mut samples: Vec<_> = samples.into_iter().collect();
+    samples.sort_by_key(|(id, _)| *id);
+
+    let mut ret = Vec::new();
+    let mut skipped = Vec::new();
+    for (id, samples) in samples {
+        if samples.len() < 2 * MIN_GROUP_SIZE {
+            continue;
+        }
+        let values: Vec<f64> = samples.iter().map(|s| s.value).collect();
+        let (midpoint, explained) = two_means(&values);
+        if explained < MULTIMODAL_THRESHOLD {
+            continue;
+        }
+        let Some(parameter) = parameters.get_parameter_by_id(id) else {
+            let reason = format!("not found in {handler}");
+            skipped.push(Skipped::new(id, reason));
+            continue;
+        };
+        let parent = parameter.smirks();
+        let typ = parameter.typ();
+        let query = match Smarts::parse(parent) {
+            Ok(q) => q,
+            Err(e) => {
+                skipped.push(Skipped::new(id, e));
+                continue;
+            }
+        };
+
+        // collect the features of each atom in every ordering of the sample
+        // tuples matched by the parent
+        let mut features = BTreeSet::new();
+        let mut seen_graphs = HashSet::new();
+        for sample in &samples {
+            if !seen_graphs.insert(sample.graph) {
+                continue;
+            }
+            let graph = graphs[sample.graph];
+            let ring_bonds = graph.ring_bonds();
+            let tuples: HashSet<_> = samples
+                .iter()
+                .filter(|s| s.graph == sample.graph)
+                .map(|s| &s.atoms)
+                .collect();
+            for m in query.find_matches(graph) {
+                if !tuples.contains(&valence_key(typ, m.clone())) {
+                    continue;
+                }
+                for (p, &i) in m.iter().enumerate() {
+                    for (rank, feature) in atom_features(graph, &ring_bonds, i)
+                    {
+                        features.insert((rank, p + 1, feature));
+                    }
+                }
+            }
+        }
+
+        let clusters: Vec<bool> =
+            values.iter().map(|&v| v > midpoint).collect();
+        let total = sum_of_squares(&values);
+        let overall = mean(values.iter().copied());
+        let mut seen_splits = HashSet::new();
+        for (_, atom, feature) in features {
+            let Some(child) = add_feature(parent, atom, &feature) else {
+                continue;
+            };
+            let Ok(child_query) = Smarts::parse(&child) else {
+                continue;
+            };
+            let mut matched: HashMap<usize, HashSet<Vec<usize>>> =
+                HashMap::new();
+            let split: Vec<bool> = samples
+                .iter()
+                .map(|s| {
+                    matched
+                        .entry(s.graph)
+                        .or_insert_with(|| {
+                            child_query
+                                .find_matches(graphs[s.graph])
+                                .into_iter()
+                                .map(|m| valence_key(typ, m))
+                                .collect()
+                        })
+                        .contains(&s.atoms)
+                })
+                .collect();
+            let child_count = split.iter().filter(|&&b| b).count();
+            let parent_count = split.len() - child_count;
+            if child_count < MIN_GROUP_SIZE || parent_count < MIN_GROUP_SIZE {
+                continue;
+            }
+            if !seen_splits.insert(split.clone()) {
+                continue;
+            }
+
+            let group = |side: bool| {
+                values
+                    .iter()
+                    .zip(&split)
+                    .filter(move |(_, &b)| b == side)
+                    .map(|(&v, _)| v)
+            };
+            let child_mean = mean(group(true));
+            let parent_mean = mean(group(false));
+            let between = child_count as f64 * (child_mean - overall).powi(2)
+                + parent_count as f64 * (parent_mean - overall).powi(2);
+            let same = split.iter().zip(&clusters).filter(|(a, b)| a == b);
+            let same = same.count() as f64 / split.len() as f64;
+
+            ret.push(SplitProposal {
+                handler: handler.to_owned(),
+                id: id.to_owned(),
+                parent: parent.to_owned(),
+                child,
+                atom,
+                feature,
+                child_count,
+                child_mean,
+                parent_count,
+                parent_mean,
+                variance_explained: between / total,
+                agreement: same.max(1.0 - same),
+            });
+        }
+    }
+    Ok((ret, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    use super::*;
+
+    /// a linear alkane with carbon-carbon bond lengths `lengths`, with the
+    /// carbons at indices `0..=lengths.len()` followed by the hydrogens
+    fn alkane(lengths: &[f64]) -> MoleculeGraph {
+        let n = lengths.len() + 1;
+        let mut atoms = vec![6; n];
+        let mut bonds: Vec<_> = (1..n).map(|i| (i - 1, i, 1)).collect();
+        for c in 0..n {
+            let h = if c == 0 || c == n - 1 { 3 } else { 2 };
+            for _ in 0..h {
+                bonds.push((c, atoms.len(), 1));
+                atoms.push(1);
+            }
+        }
+        let mut graph = graph(&atoms, &bonds);
+        let mut conformer = vec![0.0; 3 * graph.n_atoms()];
+        let mut x = 0.0;
+        for (i, r) in lengths.iter().enumerate() {
+            x += r;
+            conformer[3 * (i + 1)] = x;
+        }
+        graph.conformers = vec![conformer];
+        graph
+    }
+
+    fn labels(n_bonds: usize) -> MoleculeLabels {
+        MoleculeLabels::from([(
+            "Bonds".to_owned(),
+            (1..=n_bonds)
+                .map(|i| (vec![i - 1, i], "b1".to_owned()))
+                .collect(),
+        )])
+    }
+
+    #[test]
+    fn feature() {
+        let smirks = "[#6X4:1]-[#6X4,#7X3:2]";
+        assert_eq!(
+            add_feature(smirks, 2, "R").unwrap(),
+            "[#6X4:1]-[#6X4,#7X3;R:2]"
+        );
+        assert_eq!(add_feature(smirks, 3, "R"), None);
+    }
+
+    #[test]
+    fn clusters() {
+        let (mid, explained) = two_means(&[1.0, 1.1, 2.0, 2.1]);
+        assert!((mid - 1.55).abs() < 1e-10);
+        assert!(explained > 0.99);
+        assert_eq!(two_means(&[1.0, 1.0]).1, 0.0);
+    }
+
+    #[test]
+    fn splits() {
+        let ff = ForceField::load("testfiles/sage-2.1.0rc.offxml").unwrap();
+        let graphs = vec![
+            ("1".to_owned(), alkane(&[1.50])),
+            ("2".to_owned(), alkane(&[1.51])),
+            ("3".to_owned(), alkane(&[1.60, 1.61])),
+        ];
+        let labels = [labels(1), labels(1), labels(2)];
+        let (got, skipped) =
+            graph_geometry_splits(&ff, &graphs, &labels).unwrap();
+        assert!(skipped.is_empty());
+        let best = &got[0];
+        assert_eq!(best.id, "b1");
+        assert_eq!(best.feature, "H2");
+        assert_eq!(best.child, "[#6X4;H2:1]-[#6X4:2]");
+        assert_eq!((best.child_count, best.parent_count), (2, 2));
+        assert!((best.child_mean - 1.605).abs() < 1e-10);
+        assert!((best.parent_mean - 1.505).abs() < 1e-10);
+        assert!(best.variance_explained > 0.99);
+        assert_eq!(best.agreement, 1.0);
+
+        assert!(graph_geometry_splits(&ff, &graphs, &labels[1..]).is_err());
+    }
+}
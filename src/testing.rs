@@ -0,0 +1,207 @@
+//! Molecules shared by the tests of several modules
+
+use crate::{
+    charges::bcc::BccCollection,
+    interchange::{AngleTerm, BondTerm, Interchange, TorsionTerm},
+    topology::{
+        molecule::{Atom, Bond, MoleculeGraph},
+        stereo::StereoAssignment,
+    },
+};
+
+/// build a neutral molecule from atomic numbers and (i, j, order) bonds,
+/// marking bonds of order 0 as aromatic with Kekulé order `1`
+pub(crate) fn graph(
+    atoms: &[u8],
+    bonds: &[(usize, usize, u8)],
+) -> MoleculeGraph {
+    let mut aromatic = vec![false; atoms.len()];
+    for &(i, j, order) in bonds {
+        if order == 0 {
+            aromatic[i] = true;
+            aromatic[j] = true;
+        }
+    }
+    MoleculeGraph::new(
+        atoms
+            .iter()
+            .zip(aromatic)
+            .map(|(&atomic_number, is_aromatic)| Atom {
+                atomic_number,
+                formal_charge: 0,
+                is_aromatic,
+            })
+            .collect(),
+        bonds
+            .iter()
+            .map(|&(atom1, atom2, order)| Bond {
+                atom1,
+                atom2,
+                bond_order: order.max(1),
+                is_aromatic: order == 0,
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn ethanol() -> MoleculeGraph {
+    graph(
+        &[6, 6, 8, 1, 1, 1, 1, 1, 1],
+        &[
+            (0, 1, 1),
+            (1, 2, 1),
+            (0, 3, 1),
+            (0, 4, 1),
+            (0, 5, 1),
+            (1, 6, 1),
+            (1, 7, 1),
+            (2, 8, 1),
+        ],
+    )
+}
+
+/// reverse the atom order of `g`
+pub(crate) fn reversed(g: &MoleculeGraph) -> MoleculeGraph {
+    let n = g.n_atoms();
+    let atoms = g.atoms.iter().rev().cloned().collect();
+    let bonds = g
+        .bonds
+        .iter()
+        .rev()
+        .map(|b| Bond {
+            atom1: n - 1 - b.atom1,
+            atom2: n - 1 - b.atom2,
+            ..b.clone()
+        })
+        .collect();
+    let mut ret = MoleculeGraph::new(atoms, bonds);
+    ret.conformers = g
+        .conformers
+        .iter()
+        .map(|c| c.chunks(3).rev().flatten().copied().collect())
+        .collect();
+    ret.stereo = g.stereo.as_ref().map(|s| StereoAssignment {
+        atoms: s.atoms.iter().rev().copied().collect(),
+        bonds: s.bonds.iter().rev().copied().collect(),
+    });
+    ret
+}
+
+/// L-alanine, N-CA(-CB)-C(=O)-OH, with the CA hydrogen last
+pub(crate) fn alanine() -> MoleculeGraph {
+    let mut g = graph(
+        &[7, 6, 6, 6, 8, 8, 1, 1, 1, 1, 1, 1, 1],
+        &[
+            (0, 1, 1),
+            (1, 2, 1),
+            (1, 3, 1),
+            (3, 4, 2),
+            (3, 5, 1),
+            (0, 6, 1),
+            (0, 7, 1),
+            (2, 8, 1),
+            (2, 9, 1),
+            (2, 10, 1),
+            (5, 11, 1),
+            (1, 12, 1),
+        ],
+    );
+    // looking down from +z with the hydrogen pointing away, the
+    // priorities N > C > CB run anticlockwise, so CA is S
+    let angle = |deg: f64| {
+        let r = deg.to_radians();
+        [r.cos(), r.sin(), 0.33]
+    };
+    let mut conf = vec![[0.0; 3]; 13];
+    conf[0] = angle(90.0);
+    conf[3] = angle(210.0);
+    conf[2] = angle(330.0);
+    conf[12] = [0.0, 0.0, -1.0];
+    g.conformers = vec![conf.concat()];
+    g
+}
+
+/// water with a single conformer, in angstroms
+pub(crate) fn water() -> MoleculeGraph {
+    let mut ret = graph(&[1, 8, 1], &[(0, 1, 1), (1, 2, 1)]);
+    ret.conformers =
+        vec![vec![0.76, 0.59, 0.0, 0.0, 0.0, 0.0, -0.76, 0.59, 0.0]];
+    ret
+}
+
+/// a made-up BCC collection with corrections for bonds to hydrogen
+pub(crate) fn bcc_collection() -> BccCollection {
+    serde_json::from_str(
+        r#"{
+            "parameters": [
+                {"smirks": "[#1:1]-[*:2]", "value": 0.5},
+                {"smirks": "[#8:1]-[#1:2]", "value": 0.1,
+                 "provenance": {"code": "1231"}}
+            ],
+            "aromaticity_model": "AM1BCC"
+        }"#,
+    )
+    .unwrap()
+}
+
+/// a butane carbon skeleton with 1.5 Å bonds, 110° angles, and `torsions`
+/// around its C-C-C-C dihedral
+pub(crate) fn butane(torsions: Vec<TorsionTerm>) -> Interchange {
+    let bond = |atoms| BondTerm {
+        atoms,
+        k: 500.0,
+        length: 1.5,
+        id: "b1".to_owned(),
+    };
+    let angle = |atoms| AngleTerm {
+        atoms,
+        k: 100.0,
+        angle: 110f64.to_radians(),
+        id: "a1".to_owned(),
+    };
+    Interchange {
+        n_atoms: 4,
+        bonds: vec![bond([0, 1]), bond([1, 2]), bond([2, 3])],
+        angles: vec![angle([0, 1, 2]), angle([1, 2, 3])],
+        torsions,
+        ..Default::default()
+    }
+}
+
+/// term `term` of the butane torsion `t1`, with phase 0
+pub(crate) fn butane_torsion(
+    periodicity: f64,
+    k: f64,
+    term: usize,
+) -> TorsionTerm {
+    TorsionTerm {
+        atoms: [0, 1, 2, 3],
+        periodicity,
+        phase: 0.0,
+        k,
+        idivf: 1.0,
+        id: "t1".to_owned(),
+        term,
+    }
+}
+
+/// butane positions with a C-C-C-C dihedral of `phi` degrees, ideal angles,
+/// and bonds of length `r`
+pub(crate) fn butane_positions(phi: f64, r: f64) -> Vec<f64> {
+    let phi = phi.to_radians();
+    let (c, s) = (70f64.to_radians().cos(), 70f64.to_radians().sin());
+    vec![
+        -r * c,
+        r * s,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+        r,
+        0.0,
+        0.0,
+        r + r * c,
+        r * s * phi.cos(),
+        r * s * phi.sin(),
+    ]
+}
@@ -1,6 +1,22 @@
+use std::{collections::HashMap, error::Error, fmt::Display};
+
 use ligand::molecule::Molecule;
 
+use crate::utils::BOHR_TO_ANGSTROM;
+
+use self::{molecule::MoleculeGraph, smarts::Smarts};
+
+pub mod aromaticity;
+pub mod isomorphism;
 pub mod molecule;
+pub mod molfile;
+pub mod pdb;
+pub mod polymer;
+pub mod resonance;
+pub mod rings;
+pub mod smarts;
+pub mod smiles;
+pub mod stereo;
 
 #[derive(Clone, Default)]
 pub struct ChemicalEnvironment {
@@ -9,19 +25,238 @@ pub struct ChemicalEnvironment {
 
 #[derive(Clone)]
 pub(crate) struct ChemicalEnvironmentMatch {
+    /// the index of the matched molecule in [Topology::molecules]
     #[allow(unused)]
-    pub(crate) reference_molecule: Molecule,
+    pub(crate) reference_molecule: usize,
     pub(crate) topology_atom_indices: Vec<usize>,
 }
 
-#[derive(Clone)]
+#[derive(Debug, PartialEq)]
+pub enum TopologyError {
+    /// per-atom data of the wrong length was supplied
+    WrongLength {
+        what: &'static str,
+        expected: usize,
+        found: usize,
+    },
+
+    /// there is no distinct molecule with this index
+    NoSuchMolecule(usize),
+}
+
+impl Error for TopologyError {}
+
+impl Display for TopologyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TopologyError::WrongLength {
+                what,
+                expected,
+                found,
+            } => write!(f, "expected {expected} {what}, found {found}"),
+            TopologyError::NoSuchMolecule(m) => {
+                write!(f, "topology has no molecule {m}")
+            }
+        }
+    }
+}
+
+/// The unit of lengths passed to and returned from a [Topology]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LengthUnit {
+    Angstrom,
+    Nanometer,
+    Bohr,
+}
+
+impl LengthUnit {
+    /// The number of angstroms in one of this unit
+    pub fn to_angstrom(self) -> f64 {
+        match self {
+            LengthUnit::Angstrom => 1.0,
+            LengthUnit::Nanometer => 10.0,
+            LengthUnit::Bohr => BOHR_TO_ANGSTROM,
+        }
+    }
+}
+
+/// Where an atom sits in the residue and chain hierarchy of a biomolecular
+/// system
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AtomHierarchy {
+    /// the atom name, like `CA`
+    pub name: String,
+    pub residue_name: String,
+    pub residue_number: i32,
+    pub chain_id: String,
+}
+
+/// A system of molecules. Each distinct molecule is stored once in
+/// [Topology::molecules], and the topology is made up of copies of them, so
+/// that a box of thousands of waters holds a single water graph. Topology
+/// atoms are numbered through the copies in order.
+#[derive(Clone, Debug, Default)]
 pub struct Topology {
-    pub molecules: Vec<Molecule>,
+    /// the distinct molecules, which also carry their partial charges in
+    /// [MoleculeGraph::partial_charges]
+    pub molecules: Vec<MoleculeGraph>,
+
+    /// the index into `molecules` of each copy
+    copies: Vec<usize>,
+
+    /// the index of the first topology atom of each copy
+    starts: Vec<usize>,
+
+    n_atoms: usize,
+
+    /// positions of every topology atom in angstroms
+    positions: Option<Vec<[f64; 3]>>,
+
+    /// periodic box vectors in angstroms
+    box_vectors: Option<[[f64; 3]; 3]>,
+
+    hierarchy: Option<Vec<AtomHierarchy>>,
 }
 
 impl Topology {
+    /// Build a topology with one copy of each of `molecules`, treating every
+    /// entry as a distinct molecule
     pub fn from_molecules(molecules: Vec<Molecule>) -> Self {
-        Self { molecules }
+        let mut ret = Self::default();
+        for molecule in &molecules {
+            ret.add_molecule(MoleculeGraph::from(molecule));
+        }
+        ret
+    }
+
+    /// Add `graph` as a new distinct molecule with a single copy at the end
+    /// of the topology, returning its index in [Topology::molecules]. Any
+    /// positions and hierarchy information are discarded, since they no
+    /// longer cover every atom.
+    pub fn add_molecule(&mut self, graph: MoleculeGraph) -> usize {
+        self.molecules.push(graph);
+        let m = self.molecules.len() - 1;
+        self.add_copies(m, 1).unwrap();
+        m
+    }
+
+    /// Append `n` more copies of distinct molecule `molecule`. As for
+    /// [Topology::add_molecule], positions and hierarchy are discarded.
+    pub fn add_copies(
+        &mut self,
+        molecule: usize,
+        n: usize,
+    ) -> Result<(), TopologyError> {
+        let size = self
+            .molecules
+            .get(molecule)
+            .ok_or(TopologyError::NoSuchMolecule(molecule))?
+            .n_atoms();
+        for _ in 0..n {
+            self.copies.push(molecule);
+            self.starts.push(self.n_atoms);
+            self.n_atoms += size;
+        }
+        self.positions = None;
+        self.hierarchy = None;
+        Ok(())
+    }
+
+    pub fn n_atoms(&self) -> usize {
+        self.n_atoms
+    }
+
+    /// The number of molecule copies in the topology
+    pub fn n_copies(&self) -> usize {
+        self.copies.len()
+    }
+
+    /// The index into [Topology::molecules] of each copy, in topology order
+    pub fn copies(&self) -> &[usize] {
+        &self.copies
+    }
+
+    /// The topology index of atom `atom` of copy `copy`
+    pub fn topology_atom(&self, copy: usize, atom: usize) -> usize {
+        self.starts[copy] + atom
+    }
+
+    /// The copy containing topology atom `atom` and the atom's index within
+    /// that copy's molecule, or None if `atom` is out of range
+    pub fn molecule_atom(&self, atom: usize) -> Option<(usize, usize)> {
+        if atom >= self.n_atoms {
+            return None;
+        }
+        let copy = self.starts.partition_point(|&s| s <= atom) - 1;
+        Some((copy, atom - self.starts[copy]))
+    }
+
+    fn check_len(
+        &self,
+        what: &'static str,
+        found: usize,
+    ) -> Result<(), TopologyError> {
+        if found == self.n_atoms {
+            Ok(())
+        } else {
+            Err(TopologyError::WrongLength {
+                what,
+                expected: self.n_atoms,
+                found,
+            })
+        }
+    }
+
+    /// Set the position of every topology atom, given in `unit`
+    pub fn set_positions(
+        &mut self,
+        positions: Vec<[f64; 3]>,
+        unit: LengthUnit,
+    ) -> Result<(), TopologyError> {
+        self.check_len("positions", positions.len())?;
+        let f = unit.to_angstrom();
+        self.positions =
+            Some(positions.into_iter().map(|p| p.map(|x| x * f)).collect());
+        Ok(())
+    }
+
+    /// The position of every topology atom in `unit`, if they have been set
+    pub fn positions(&self, unit: LengthUnit) -> Option<Vec<[f64; 3]>> {
+        let f = unit.to_angstrom();
+        self.positions
+            .as_ref()
+            .map(|ps| ps.iter().map(|p| p.map(|x| x / f)).collect())
+    }
+
+    /// Set the periodic box vectors, given in `unit`, or make the topology
+    /// non-periodic with None
+    pub fn set_box_vectors(
+        &mut self,
+        box_vectors: Option<[[f64; 3]; 3]>,
+        unit: LengthUnit,
+    ) {
+        let f = unit.to_angstrom();
+        self.box_vectors = box_vectors.map(|b| b.map(|v| v.map(|x| x * f)));
+    }
+
+    /// The periodic box vectors in `unit`, if the topology is periodic
+    pub fn box_vectors(&self, unit: LengthUnit) -> Option<[[f64; 3]; 3]> {
+        let f = unit.to_angstrom();
+        self.box_vectors.map(|b| b.map(|v| v.map(|x| x / f)))
+    }
+
+    /// Set the residue and chain information of every topology atom
+    pub fn set_hierarchy(
+        &mut self,
+        hierarchy: Vec<AtomHierarchy>,
+    ) -> Result<(), TopologyError> {
+        self.check_len("hierarchy entries", hierarchy.len())?;
+        self.hierarchy = Some(hierarchy);
+        Ok(())
+    }
+
+    pub fn hierarchy(&self) -> Option<&[AtomHierarchy]> {
+        self.hierarchy.as_deref()
     }
 
     #[cfg(feature = "openmm")]
@@ -32,10 +267,97 @@ impl Topology {
         todo!();
     }
 
+    /// Return the matches of `smirks` in each molecule copy in the topology,
+    /// with atom indices offset to index into the whole topology. Each
+    /// distinct molecule is only searched once. Panics if `smirks` cannot be
+    /// parsed.
     pub(crate) fn chemical_environment_matches(
         &self,
-        _smirks: &str,
+        smirks: &str,
     ) -> Vec<ChemicalEnvironmentMatch> {
-        todo!()
+        let smarts = match Smarts::parse(smirks) {
+            Ok(s) => s,
+            Err(e) => panic!("{e}"),
+        };
+        let mut cache = HashMap::new();
+        let mut ret = Vec::new();
+        for (&m, &offset) in self.copies.iter().zip(&self.starts) {
+            let hits = cache
+                .entry(m)
+                .or_insert_with(|| smarts.find_matches(&self.molecules[m]));
+            for hit in hits.iter() {
+                ret.push(ChemicalEnvironmentMatch {
+                    reference_molecule: m,
+                    topology_atom_indices: hit
+                        .iter()
+                        .map(|i| i + offset)
+                        .collect(),
+                });
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::water;
+
+    use super::*;
+
+    #[test]
+    fn copies() {
+        let mut top = Topology::default();
+        let w = top.add_molecule(water());
+        top.add_copies(w, 4).unwrap();
+        assert_eq!(top.molecules.len(), 1);
+        assert_eq!(top.n_copies(), 5);
+        assert_eq!(top.n_atoms(), 15);
+        assert_eq!(top.topology_atom(3, 1), 10);
+        assert_eq!(top.molecule_atom(10), Some((3, 1)));
+        assert_eq!(top.molecule_atom(14), Some((4, 2)));
+        assert_eq!(top.molecule_atom(15), None);
+        assert_eq!(top.add_copies(1, 1), Err(TopologyError::NoSuchMolecule(1)));
+
+        let matches = top.chemical_environment_matches("[#8:1]-[#1:2]");
+        assert_eq!(matches.len(), 10);
+        assert_eq!(matches[9].topology_atom_indices, [13, 14]);
+    }
+
+    #[test]
+    fn positions() {
+        let mut top = Topology::default();
+        top.add_molecule(water());
+        assert!(top.positions(LengthUnit::Angstrom).is_none());
+        assert!(top
+            .set_positions(vec![[0.0; 3]; 2], LengthUnit::Nanometer)
+            .is_err());
+        top.set_positions(vec![[0.1, 0.0, 0.0]; 3], LengthUnit::Nanometer)
+            .unwrap();
+        assert_eq!(
+            top.positions(LengthUnit::Angstrom).unwrap()[0],
+            [1.0, 0.0, 0.0]
+        );
+
+        let cube = [[2.0, 0.0, 0.0], [0.0, 2.0, 0.0], [0.0, 0.0, 2.0]];
+        top.set_box_vectors(Some(cube), LengthUnit::Nanometer);
+        assert_eq!(top.box_vectors(LengthUnit::Angstrom).unwrap()[1][1], 20.0);
+
+        let hierarchy = vec![
+            AtomHierarchy {
+                name: "H1".to_owned(),
+                residue_name: "HOH".to_owned(),
+                residue_number: 1,
+                chain_id: "A".to_owned(),
+            };
+            3
+        ];
+        top.set_hierarchy(hierarchy).unwrap();
+        assert_eq!(top.hierarchy().unwrap()[2].residue_name, "HOH");
+
+        // adding atoms invalidates the per-atom data
+        top.add_copies(0, 1).unwrap();
+        assert!(top.positions(LengthUnit::Angstrom).is_none());
+        assert!(top.hierarchy().is_none());
     }
 }
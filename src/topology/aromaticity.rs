@@ -0,0 +1,533 @@
+//! Aromaticity perception. The SMIRKS `a` and `:` primitives match the
+//! aromatic flags on atoms and bonds, so these have to be set by the model
+//! that a force field was fit with, named by its `aromaticity_model`
+//! attribute.
+//!
+//! The MDL and Hückel models look at the relevant rings of the molecule and
+//! at the envelopes of pairs of fused relevant rings, like the 10-membered
+//! perimeter of naphthalene, and call a cycle aromatic if every atom in it
+//! contributes π electrons and the total is 4n + 2. The AM1-BCC model only
+//! looks at six-membered rings. All of them need Kekulé bond orders, as
+//! provided by [ligand::molecule::Molecule]. Aromatic bonds read without
+//! them, like MDL bond type 4, are assigned Kekulé bond orders by
+//! [MoleculeGraph::kekulize] first.
+//!
+//! The flags are set on a [MoleculeGraph] rather than on the
+//! [ligand::molecule::Molecule] itself, which has no setters for them. Every
+//! labeling and typing path in the crate works on the graph, so
+//! [crate::smirnoff::ForceField::label_molecules] perceives aromaticity on the
+//! graphs of its topology, and
+//! [crate::topology::molecule::MoleculeExt::perceive_aromaticity] returns the
+//! perceived graph of a [ligand::molecule::Molecule].
+
+use std::{error::Error, fmt::Display, str::FromStr};
+
+use super::{molecule::MoleculeGraph, rings::Cycle};
+
+#[derive(Debug, PartialEq)]
+pub struct AromaticityError {
+    pub message: String,
+}
+
+impl Error for AromaticityError {}
+
+impl Display for AromaticityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "aromaticity: {}", self.message)
+    }
+}
+
+/// A model deciding which rings are aromatic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AromaticityModel {
+    /// The MDL model, `OEAroModel_MDL`, used by the OpenFF force fields. Only
+    /// carbon and nitrogen atoms with a double bond within the ring system
+    /// contribute, so five-membered heterocycles like pyrrole and furan are
+    /// not aromatic.
+    Mdl,
+
+    /// A Hückel model in the style of RDKit, named `Huckel`, where the lone
+    /// pairs of nitrogen, oxygen, and sulfur atoms without double bonds also
+    /// contribute two electrons, and carbonyl-like exocyclic double bonds
+    /// contribute none. Pyrrole, furan, thiophene, and 2-pyridone are
+    /// aromatic in this model.
+    Huckel,
+
+    /// The model of the AM1-BCC bond charge corrections, named `AM1BCC`,
+    /// where only six-membered rings with alternating double bonds, or fused
+    /// to such rings, are aromatic. Five-membered heterocycles are not.
+    Am1Bcc,
+}
+
+impl AromaticityModel {
+    /// The name of the model in a force field's `aromaticity_model`
+    pub fn name(&self) -> &'static str {
+        match self {
+            AromaticityModel::Mdl => "OEAroModel_MDL",
+            AromaticityModel::Huckel => "Huckel",
+            AromaticityModel::Am1Bcc => "AM1BCC",
+        }
+    }
+}
+
+impl FromStr for AromaticityModel {
+    type Err = AromaticityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "OEAroModel_MDL" => Ok(Self::Mdl),
+            "Huckel" => Ok(Self::Huckel),
+            "AM1BCC" => Ok(Self::Am1Bcc),
+            _ => Err(AromaticityError {
+                message: format!("unsupported aromaticity model {s}"),
+            }),
+        }
+    }
+}
+
+impl MoleculeGraph {
+    /// The π electrons that `atom` contributes to a cycle with atoms
+    /// `in_cycle` under `model`, or None if it prevents the cycle from being
+    /// aromatic
+    fn pi_electrons(
+        &self,
+        atom: usize,
+        in_cycle: &[bool],
+        model: AromaticityModel,
+    ) -> Option<usize> {
+        let a = &self.atoms[atom];
+        let mut doubles = self
+            .adjacent(atom)
+            .iter()
+            .filter(|&&(_, b)| self.bonds[b].bond_order > 1);
+        let double = doubles.next();
+        if doubles.next().is_some() {
+            return None;
+        }
+        if let Some(&(n, b)) = double {
+            if self.bonds[b].bond_order != 2 {
+                return None;
+            }
+            if in_cycle[n] {
+                return matches!(a.atomic_number, 6 | 7 | 8 | 15 | 16)
+                    .then_some(1)
+                    .filter(|_| {
+                        model != AromaticityModel::Mdl
+                            || matches!(a.atomic_number, 6 | 7)
+                    });
+            }
+            // an exocyclic double bond to an electronegative atom
+            let polar = matches!(self.atoms[n].atomic_number, 7 | 8 | 16);
+            return (model == AromaticityModel::Huckel
+                && a.atomic_number == 6
+                && polar)
+                .then_some(0);
+        }
+        if model == AromaticityModel::Mdl {
+            return None;
+        }
+        match (a.atomic_number, a.formal_charge, self.degree(atom)) {
+            (6, -1, _) => Some(2),
+            (6, 1, _) => Some(0),
+            (5, 0, 3) => Some(0),
+            (7, 0, 2 | 3) => Some(2),
+            (8 | 16 | 34, 0, 2) => Some(2),
+            _ => None,
+        }
+    }
+
+    /// The number of bonds `atom` is short of its lowest standard valence
+    /// that is at least its current valence, counting aromatic single bonds
+    /// as single bonds
+    fn valence_deficit(&self, atom: usize) -> usize {
+        let a = &self.atoms[atom];
+        let used: usize = self
+            .adjacent(atom)
+            .iter()
+            .map(|&(_, b)| usize::from(self.bonds[b].bond_order))
+            .sum();
+        let q = isize::from(a.formal_charge);
+        let valences: &[isize] = match a.atomic_number {
+            5 => &[3 - q],
+            6 => &[4 - q.abs()],
+            7 | 15 => &[3 + q, 5 + q],
+            8 | 16 | 34 => &[2 + q, 4 + q, 6 + q],
+            _ => &[],
+        };
+        valences
+            .iter()
+            .filter_map(|&v| usize::try_from(v).ok())
+            .find(|&v| v >= used)
+            .map_or(0, |v| v - used)
+    }
+
+    /// Assign Kekulé bond orders to aromatic systems stored with only single
+    /// bonds, so that each of their atoms with only single bonds that is
+    /// short of its valence gets exactly one double bond. Systems with an
+    /// aromatic double bond are taken to be kekulized already. Hydrogens must
+    /// be explicit. Returns an error if there is no such assignment.
+    pub fn kekulize(&mut self) -> Result<(), AromaticityError> {
+        // label the systems of atoms connected by aromatic bonds, and find
+        // those with a double bond
+        let mut system = vec![usize::MAX; self.n_atoms()];
+        let mut kekulized = Vec::new();
+        for start in 0..self.n_atoms() {
+            if system[start] != usize::MAX || !self.atoms[start].is_aromatic {
+                continue;
+            }
+            let s = kekulized.len();
+            kekulized.push(false);
+            system[start] = s;
+            let mut stack = vec![start];
+            while let Some(i) = stack.pop() {
+                for &(j, b) in self.adjacent(i) {
+                    let bond = &self.bonds[b];
+                    if !bond.is_aromatic {
+                        continue;
+                    }
+                    kekulized[s] |= bond.bond_order > 1;
+                    if system[j] == usize::MAX {
+                        system[j] = s;
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+        // atoms with a double bond already, like the carbonyl carbon of an
+        // aromatic 2-pyridone, need no other
+        let mut need: Vec<bool> = (0..self.n_atoms())
+            .map(|i| {
+                self.atoms[i].is_aromatic
+                    && !kekulized[system[i]]
+                    && self
+                        .adjacent(i)
+                        .iter()
+                        .all(|&(_, b)| self.bonds[b].bond_order == 1)
+                    && self.valence_deficit(i) > 0
+            })
+            .collect();
+        let candidates: Vec<bool> = self
+            .bonds
+            .iter()
+            .map(|b| {
+                b.is_aromatic
+                    && b.bond_order == 1
+                    && need[b.atom1]
+                    && need[b.atom2]
+            })
+            .collect();
+        let mut doubles = Vec::new();
+        if !self.match_doubles(&mut need, &candidates, &mut doubles) {
+            return Err(AromaticityError {
+                message: "aromatic bonds without a Kekulé structure".into(),
+            });
+        }
+        for b in doubles {
+            self.bonds[b].bond_order = 2;
+        }
+        Ok(())
+    }
+
+    /// Pair up the atoms in `need` along `candidates` bonds, pushing the
+    /// chosen bonds onto `doubles`. Backtracks from the atom with the fewest
+    /// options, so chains of forced choices cost nothing.
+    fn match_doubles(
+        &self,
+        need: &mut [bool],
+        candidates: &[bool],
+        doubles: &mut Vec<usize>,
+    ) -> bool {
+        let options = |i: usize, need: &[bool]| -> Vec<(usize, usize)> {
+            self.adjacent(i)
+                .iter()
+                .copied()
+                .filter(|&(j, b)| candidates[b] && need[j])
+                .collect()
+        };
+        let Some(i) = (0..need.len())
+            .filter(|&i| need[i])
+            .min_by_key(|&i| options(i, need).len())
+        else {
+            return true;
+        };
+        for (j, b) in options(i, need) {
+            need[i] = false;
+            need[j] = false;
+            doubles.push(b);
+            if self.match_doubles(need, candidates, doubles) {
+                return true;
+            }
+            doubles.pop();
+            need[i] = true;
+            need[j] = true;
+        }
+        false
+    }
+
+    /// Set the aromatic flags on every atom and bond according to `model`,
+    /// clearing any existing flags. Aromatic bonds without Kekulé bond orders
+    /// are kekulized first, returning an error if that fails.
+    pub fn perceive_aromaticity(
+        &mut self,
+        model: AromaticityModel,
+    ) -> Result<(), AromaticityError> {
+        self.kekulize()?;
+        let (atoms, bonds) = match model {
+            AromaticityModel::Am1Bcc => self.am1bcc_aromaticity(),
+            _ => self.electron_count_aromaticity(model),
+        };
+
+        for (atom, aromatic) in self.atoms.iter_mut().zip(atoms) {
+            atom.is_aromatic = aromatic;
+        }
+        for (bond, aromatic) in self.bonds.iter_mut().zip(bonds) {
+            bond.is_aromatic = aromatic;
+        }
+        Ok(())
+    }
+
+    /// The aromatic atoms and bonds under the [AromaticityModel::Mdl] or
+    /// [AromaticityModel::Huckel] models
+    fn electron_count_aromaticity(
+        &self,
+        model: AromaticityModel,
+    ) -> (Vec<bool>, Vec<bool>) {
+        let (_, relevant) = self.ring_sets();
+        let mut cycles: Vec<Cycle> = relevant.clone();
+        for (i, a) in relevant.iter().enumerate() {
+            for b in &relevant[i + 1..] {
+                if let Some(envelope) = self.envelope(a, b) {
+                    cycles.push(envelope);
+                }
+            }
+        }
+
+        let n = self.n_atoms();
+        let mut atoms = vec![false; n];
+        let mut bonds = vec![false; self.bonds.len()];
+        for cycle in &cycles {
+            let mut in_cycle = vec![false; n];
+            cycle.atoms.iter().for_each(|&i| in_cycle[i] = true);
+            let electrons: Option<usize> = cycle
+                .atoms
+                .iter()
+                .map(|&i| self.pi_electrons(i, &in_cycle, model))
+                .sum();
+            if electrons.is_some_and(|e| e % 4 == 2) {
+                cycle.atoms.iter().for_each(|&i| atoms[i] = true);
+                (0..self.bonds.len())
+                    .filter(|&b| cycle.has_bond(b))
+                    .for_each(|b| bonds[b] = true);
+            }
+        }
+        // bonds inside a fused aromatic system, like the central bond of
+        // naphthalene when only its perimeter is aromatic
+        for cycle in &relevant {
+            if cycle.atoms.iter().all(|&i| atoms[i]) {
+                (0..self.bonds.len())
+                    .filter(|&b| cycle.has_bond(b))
+                    .for_each(|b| bonds[b] = true);
+            }
+        }
+        (atoms, bonds)
+    }
+
+    /// The aromatic atoms and bonds under [AromaticityModel::Am1Bcc]. A
+    /// six-membered ring is aromatic if each of its atoms has a double bond
+    /// or an aromatic bond within the ring, which is repeated until no more
+    /// rings change so that rings fused to aromatic rings are found too.
+    fn am1bcc_aromaticity(&self) -> (Vec<bool>, Vec<bool>) {
+        let (_, relevant) = self.ring_sets();
+        let rings: Vec<(&Cycle, Vec<usize>)> = relevant
+            .iter()
+            .filter(|c| c.atoms.len() == 6)
+            .map(|c| {
+                (
+                    c,
+                    (0..self.bonds.len()).filter(|&b| c.has_bond(b)).collect(),
+                )
+            })
+            .collect();
+        let mut atoms = vec![false; self.n_atoms()];
+        let mut bonds = vec![false; self.bonds.len()];
+        let mut aromatic = vec![false; rings.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (r, (cycle, ring_bonds)) in rings.iter().enumerate() {
+                if aromatic[r] {
+                    continue;
+                }
+                let covered = cycle.atoms.iter().all(|&i| {
+                    ring_bonds.iter().any(|&b| {
+                        let bond = &self.bonds[b];
+                        (bond.atom1 == i || bond.atom2 == i)
+                            && (bond.bond_order == 2 || bonds[b])
+                    })
+                });
+                if covered {
+                    aromatic[r] = true;
+                    changed = true;
+                    cycle.atoms.iter().for_each(|&i| atoms[i] = true);
+                    ring_bonds.iter().for_each(|&b| bonds[b] = true);
+                }
+            }
+        }
+        (atoms, bonds)
+    }
+
+    /// The cycle around two fused rings `a` and `b`, if their shared bonds
+    /// leave a single cycle
+    fn envelope(&self, a: &Cycle, b: &Cycle) -> Option<Cycle> {
+        if a.bonds.iter().zip(&b.bonds).all(|(x, y)| x & y == 0) {
+            return None;
+        }
+        let bits: Vec<u64> =
+            a.bonds.iter().zip(&b.bonds).map(|(x, y)| x ^ y).collect();
+        let envelope = Cycle {
+            atoms: Vec::new(),
+            bonds: bits,
+        };
+        let n_bonds = (0..self.bonds.len())
+            .filter(|&b| envelope.has_bond(b))
+            .count();
+        // walk around the envelope from one of its atoms, failing if this
+        // branches or leaves bonds unvisited
+        let first = self.bonds.iter().enumerate().find_map(|(i, bond)| {
+            envelope.has_bond(i).then_some((bond.atom1, i))
+        })?;
+        let mut atoms = vec![first.0];
+        let (mut atom, mut via) = first;
+        loop {
+            let mut next = self
+                .adjacent(atom)
+                .iter()
+                .filter(|&&(_, b)| b != via && envelope.has_bond(b));
+            let &(j, b) = next.next()?;
+            if next.next().is_some() {
+                return None;
+            }
+            if j == first.0 {
+                break;
+            }
+            atoms.push(j);
+            (atom, via) = (j, b);
+        }
+        (atoms.len() == n_bonds).then_some(Cycle { atoms, ..envelope })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    use super::*;
+
+    /// a ring of `atoms` with alternating bond orders starting from `orders`,
+    /// followed by `extra` bonds
+    fn ring(
+        atoms: &[u8],
+        orders: &[u8],
+        extra: &[(usize, usize, u8)],
+    ) -> MoleculeGraph {
+        let n = orders.len();
+        let mut bonds: Vec<_> =
+            (0..n).map(|i| (i, (i + 1) % n, orders[i])).collect();
+        bonds.extend(extra);
+        graph(atoms, &bonds)
+    }
+
+    #[test]
+    fn models() {
+        use AromaticityModel::*;
+        let benzene = ring(&[6; 6], &[2, 1, 2, 1, 2, 1], &[]);
+        let pyrrole = ring(&[7, 6, 6, 6, 6], &[1, 2, 1, 2, 1], &[]);
+        let furan = ring(&[8, 6, 6, 6, 6], &[1, 2, 1, 2, 1], &[]);
+        let pyridone =
+            ring(&[7, 6, 6, 6, 6, 6, 8], &[1, 1, 2, 1, 2, 1], &[(1, 6, 2)]);
+        let cyclohexene = ring(&[6; 6], &[2, 1, 1, 1, 1, 1], &[]);
+        let cyclobutadiene = ring(&[6; 4], &[2, 1, 2, 1], &[]);
+        for (mut g, mdl, huckel, am1bcc) in [
+            (benzene, true, true, true),
+            (pyrrole, false, true, false),
+            (furan, false, true, false),
+            (pyridone, false, true, false),
+            (cyclohexene, false, false, false),
+            (cyclobutadiene, false, false, false),
+        ] {
+            g.perceive_aromaticity(Mdl).unwrap();
+            assert_eq!(g.atoms[2].is_aromatic, mdl);
+            assert_eq!(g.bonds[2].is_aromatic, mdl);
+            g.perceive_aromaticity(Huckel).unwrap();
+            assert_eq!(g.atoms[2].is_aromatic, huckel);
+            g.perceive_aromaticity(Am1Bcc).unwrap();
+            assert_eq!(g.atoms[2].is_aromatic, am1bcc);
+        }
+    }
+
+    #[test]
+    fn naphthalene() {
+        // the Kekulé structure with a single central bond, where neither
+        // ring has three double bonds of its own
+        let mut bonds: Vec<_> = (0..10)
+            .map(|i| (i, (i + 1) % 10, if i % 2 == 0 { 2 } else { 1 }))
+            .collect();
+        bonds.push((0, 5, 1));
+        for model in [AromaticityModel::Mdl, AromaticityModel::Am1Bcc] {
+            let mut g = graph(&[6; 10], &bonds);
+            g.perceive_aromaticity(model).unwrap();
+            assert!(g.atoms.iter().all(|a| a.is_aromatic), "{model:?}");
+            assert!(g.bonds.iter().all(|b| b.is_aromatic), "{model:?}");
+        }
+
+        // an odd number of aromatic atoms has no Kekulé structure
+        let mut flagged = graph(&[6; 6], &[(0, 1, 0), (1, 2, 0)]);
+        assert!(flagged.perceive_aromaticity(AromaticityModel::Mdl).is_err());
+    }
+
+    #[test]
+    fn kekulize() {
+        use AromaticityModel::*;
+        // aromatic single bonds as read from an MDL bond type 4, with
+        // explicit hydrogens on every ring atom but the pyridine nitrogen
+        let hydrogens = |ring: &[u8], skip: &[usize]| {
+            let n = ring.len();
+            let mut atoms = ring.to_vec();
+            let mut bonds: Vec<_> =
+                (0..n).map(|i| (i, (i + 1) % n, 0)).collect();
+            for i in (0..n).filter(|i| !skip.contains(i)) {
+                bonds.push((i, atoms.len(), 1));
+                atoms.push(1);
+            }
+            graph(&atoms, &bonds)
+        };
+        let pyridine = hydrogens(&[7, 6, 6, 6, 6, 6], &[0]);
+        let pyrrole = hydrogens(&[7, 6, 6, 6, 6], &[]);
+        for (mut g, mdl, huckel) in
+            [(pyridine, true, true), (pyrrole, false, true)]
+        {
+            g.perceive_aromaticity(Mdl).unwrap();
+            assert_eq!(g.atoms[2].is_aromatic, mdl);
+            g.perceive_aromaticity(Huckel).unwrap();
+            assert_eq!(g.atoms[2].is_aromatic, huckel);
+            // every carbon has exactly one double bond
+            for i in 1..5 {
+                let doubles = g
+                    .adjacent(i)
+                    .iter()
+                    .filter(|&&(_, b)| g.bonds[b].bond_order == 2)
+                    .count();
+                assert_eq!(doubles, 1, "{i}");
+            }
+        }
+    }
+
+    #[test]
+    fn names() {
+        use AromaticityModel::*;
+        for model in [Mdl, Huckel, Am1Bcc] {
+            assert_eq!(model.name().parse(), Ok(model));
+        }
+        assert!("OEAroModel_OpenEye".parse::<AromaticityModel>().is_err());
+    }
+}
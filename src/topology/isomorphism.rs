@@ -0,0 +1,381 @@
+//! Graph isomorphism and atom mapping between molecules.
+//!
+//! Candidate atoms are first narrowed by refining atom classes jointly over
+//! both graphs, so that an atom can only map to atoms with the same refined
+//! class, and the mapping is then extended one atom at a time in
+//! breadth-first order in the style of VF2, backtracking when a bond or the
+//! configuration of a stereo element is not preserved.
+
+use std::collections::VecDeque;
+
+use super::molecule::{distinct, rank_by, MoleculeGraph};
+
+/// Which properties must match for two molecules to be isomorphic. Elements
+/// and connectivity always have to match.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsomorphismOptions {
+    pub aromatic_matching: bool,
+    pub formal_charge_matching: bool,
+    pub bond_order_matching: bool,
+
+    /// require tetrahedral centres and double bonds to have the same
+    /// configuration, as given by [MoleculeGraph::known_stereo]. This has no
+    /// effect unless the stereochemistry of both molecules is known, and
+    /// double bonds are only compared when bond orders are.
+    pub stereochemistry_matching: bool,
+}
+
+impl IsomorphismOptions {
+    /// Match on elements and connectivity alone
+    pub const CONNECTIVITY: Self = Self {
+        aromatic_matching: false,
+        formal_charge_matching: false,
+        bond_order_matching: false,
+        stereochemistry_matching: false,
+    };
+}
+
+impl Default for IsomorphismOptions {
+    fn default() -> Self {
+        Self {
+            aromatic_matching: true,
+            formal_charge_matching: true,
+            bond_order_matching: true,
+            stereochemistry_matching: true,
+        }
+    }
+}
+
+impl MoleculeGraph {
+    fn atom_key(
+        &self,
+        i: usize,
+        options: &IsomorphismOptions,
+    ) -> (u8, usize, i8, bool) {
+        let atom = &self.atoms[i];
+        (
+            atom.atomic_number,
+            self.degree(i),
+            if options.formal_charge_matching {
+                atom.formal_charge
+            } else {
+                0
+            },
+            options.aromatic_matching && atom.is_aromatic,
+        )
+    }
+
+    fn bond_key(&self, b: usize, options: &IsomorphismOptions) -> (u8, bool) {
+        let bond = &self.bonds[b];
+        (
+            if options.bond_order_matching {
+                bond.bond_order
+            } else {
+                0
+            },
+            options.aromatic_matching && bond.is_aromatic,
+        )
+    }
+
+    /// Refine atom classes over `self` followed by `other`, so that atoms in
+    /// different classes can never be mapped onto each other
+    fn joint_classes(
+        &self,
+        other: &Self,
+        options: &IsomorphismOptions,
+    ) -> Vec<usize> {
+        let atoms: Vec<_> = (0..self.n_atoms())
+            .map(|i| (self, 0, i))
+            .chain((0..other.n_atoms()).map(|i| (other, self.n_atoms(), i)))
+            .collect();
+        let keys: Vec<_> = atoms
+            .iter()
+            .map(|&(g, _, i)| g.atom_key(i, options))
+            .collect();
+        let mut ranks = rank_by(&keys);
+        loop {
+            let keys: Vec<_> = atoms
+                .iter()
+                .map(|&(g, offset, i)| {
+                    let mut neighbors: Vec<_> = g
+                        .adjacent(i)
+                        .iter()
+                        .map(|&(n, b)| {
+                            (ranks[offset + n], g.bond_key(b, options))
+                        })
+                        .collect();
+                    neighbors.sort();
+                    (ranks[offset + i], neighbors)
+                })
+                .collect();
+            let refined = rank_by(&keys);
+            let done = distinct(&refined) == distinct(&ranks);
+            ranks = refined;
+            if done {
+                return ranks;
+            }
+        }
+    }
+
+    /// Return whether `self` and `other` are the same molecule under
+    /// `options`
+    pub fn is_isomorphic_with(
+        &self,
+        other: &Self,
+        options: &IsomorphismOptions,
+    ) -> bool {
+        self.atom_map_with(other, options).is_some()
+    }
+
+    /// Return a map from each atom of `self` to the corresponding atom of
+    /// `other`, matching everything in the default [IsomorphismOptions], or
+    /// None if the molecules differ
+    pub fn atom_map_to(&self, other: &Self) -> Option<Vec<usize>> {
+        self.atom_map_with(other, &IsomorphismOptions::default())
+    }
+
+    /// Return a map from each atom of `self` to the corresponding atom of
+    /// `other` under `options`, or None if the molecules are not isomorphic
+    pub fn atom_map_with(
+        &self,
+        other: &Self,
+        options: &IsomorphismOptions,
+    ) -> Option<Vec<usize>> {
+        let n = self.n_atoms();
+        if n != other.n_atoms() || self.bonds.len() != other.bonds.len() {
+            return None;
+        }
+        if n == 0 {
+            return Some(Vec::new());
+        }
+        let classes = self.joint_classes(other, options);
+        let (mine, theirs) = classes.split_at(n);
+        let mut sorted = [mine.to_vec(), theirs.to_vec()];
+        sorted.iter_mut().for_each(|c| c.sort());
+        if sorted[0] != sorted[1] {
+            return None;
+        }
+        let mut class_size = vec![0; distinct(&classes)];
+        mine.iter().for_each(|&c| class_size[c] += 1);
+
+        // visit atoms in breadth-first order, starting each component from
+        // its atom with the fewest candidates, and record a mapped neighbor
+        // of each later atom to draw candidates from
+        let mut order = Vec::with_capacity(n);
+        let mut seen = vec![false; n];
+        while let Some(start) = (0..n)
+            .filter(|&i| !seen[i])
+            .min_by_key(|&i| class_size[mine[i]])
+        {
+            seen[start] = true;
+            let mut queue = VecDeque::from([(start, None)]);
+            while let Some((i, parent)) = queue.pop_front() {
+                order.push((i, parent));
+                for j in self.neighbors(i) {
+                    if !seen[j] {
+                        seen[j] = true;
+                        queue.push_back((j, Some(i)));
+                    }
+                }
+            }
+        }
+
+        // check each stereocentre as it is mapped and each double bond once
+        // both of its atoms have been
+        let stereo = match (self.known_stereo(), other.known_stereo()) {
+            (Some(a), Some(b)) if options.stereochemistry_matching => {
+                Some((a, b))
+            }
+            _ => None,
+        };
+        let mut depth_of = vec![0; n];
+        for (d, &(i, _)) in order.iter().enumerate() {
+            depth_of[i] = d;
+        }
+        let mut triggers = vec![Vec::new(); n];
+        if options.bond_order_matching {
+            for (b, bond) in self.bonds.iter().enumerate() {
+                let last = depth_of[bond.atom1].max(depth_of[bond.atom2]);
+                triggers[last].push(b);
+            }
+        }
+
+        const UNMAPPED: usize = usize::MAX;
+        let mut map = vec![UNMAPPED; n];
+        let mut used = vec![false; n];
+        let candidates = |depth: usize, map: &[usize], used: &[bool]| {
+            let (atom, parent) = order[depth];
+            let pool: Vec<usize> = match parent {
+                Some(p) => other.neighbors(map[p]).collect(),
+                None => (0..n).collect(),
+            };
+            pool.into_iter()
+                .filter(|&c| {
+                    !used[c]
+                        && theirs[c] == mine[atom]
+                        && self.adjacent(atom).iter().all(|&(j, b)| {
+                            map[j] == UNMAPPED
+                                || other.bond_index(c, map[j]).is_some_and(
+                                    |ob| {
+                                        other.bond_key(ob, options)
+                                            == self.bond_key(b, options)
+                                    },
+                                )
+                        })
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // an explicit stack of (candidates, next candidate) for each depth
+        let mut frames = vec![(candidates(0, &map, &used), 0)];
+        while !frames.is_empty() {
+            let depth = frames.len() - 1;
+            let (cands, k) = &mut frames[depth];
+            let atom = order[depth].0;
+            if map[atom] != UNMAPPED {
+                used[map[atom]] = false;
+                map[atom] = UNMAPPED;
+            }
+            let Some(&c) = cands.get(*k) else {
+                frames.pop();
+                continue;
+            };
+            *k += 1;
+            map[atom] = c;
+            used[c] = true;
+            if let Some((a, b)) = &stereo {
+                let preserved = a.atoms[atom] == b.atoms[c]
+                    && triggers[depth].iter().all(|&i| {
+                        let bond = &self.bonds[i];
+                        other
+                            .bond_index(map[bond.atom1], map[bond.atom2])
+                            .is_some_and(|j| a.bonds[i] == b.bonds[j])
+                    });
+                if !preserved {
+                    continue;
+                }
+            }
+            if depth + 1 == n {
+                return Some(map);
+            }
+            frames.push((candidates(depth + 1, &map, &used), 0));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{alanine, ethanol, graph, reversed};
+
+    use crate::topology::molecule::{Bond, Stereochemistry};
+
+    use super::*;
+
+    /// Check that `map` takes every bond of `a` to a bond of `b`
+    fn check_map(a: &MoleculeGraph, b: &MoleculeGraph, map: &[usize]) {
+        for (i, atom) in a.atoms.iter().enumerate() {
+            assert_eq!(atom.atomic_number, b.atoms[map[i]].atomic_number);
+        }
+        for bond in &a.bonds {
+            assert!(b.bond_between(map[bond.atom1], map[bond.atom2]).is_some());
+        }
+    }
+
+    #[test]
+    fn ethanol_map() {
+        let a = ethanol();
+        let b = reversed(&a);
+        let map = a.atom_map_to(&b).unwrap();
+        check_map(&a, &b, &map);
+        assert_eq!(map[2], 6);
+
+        // dimethyl ether has the same atoms but different connectivity
+        let ether = graph(
+            &[6, 8, 6, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 1),
+                (0, 3, 1),
+                (0, 4, 1),
+                (0, 5, 1),
+                (2, 6, 1),
+                (2, 7, 1),
+                (2, 8, 1),
+            ],
+        );
+        assert!(a.atom_map_to(&ether).is_none());
+        assert!(
+            !a.is_isomorphic_with(&ether, &IsomorphismOptions::CONNECTIVITY)
+        );
+    }
+
+    #[test]
+    fn options() {
+        let a = alanine();
+        // move the carboxyl double bond to the other oxygen
+        let bonds = a
+            .bonds
+            .iter()
+            .map(|bond| match (bond.atom1, bond.atom2) {
+                (3, 4) | (3, 5) => Bond {
+                    bond_order: 3 - bond.bond_order,
+                    ..bond.clone()
+                },
+                _ => bond.clone(),
+            })
+            .collect();
+        let mut b = MoleculeGraph::new(a.atoms.clone(), bonds);
+        b.conformers = a.conformers.clone();
+        let b = reversed(&b);
+        assert!(!a.is_isomorphic_with(&b, &Default::default()));
+        let no_orders = IsomorphismOptions {
+            bond_order_matching: false,
+            ..Default::default()
+        };
+        assert!(a.is_isomorphic_with(&b, &no_orders));
+
+        let mut charged = a.clone();
+        charged.atoms[0].formal_charge = 1;
+        assert!(!a.is_isomorphic_with(&charged, &Default::default()));
+        let no_charges = IsomorphismOptions {
+            formal_charge_matching: false,
+            ..Default::default()
+        };
+        assert!(a.is_isomorphic_with(&charged, &no_charges));
+    }
+
+    #[test]
+    fn stereo() {
+        let a = alanine();
+        let mut mirror = reversed(&a);
+        mirror.conformers[0]
+            .iter_mut()
+            .step_by(3)
+            .for_each(|x| *x = -*x);
+        assert!(a.atom_map_to(&reversed(&a)).is_some());
+        assert!(a.atom_map_to(&mirror).is_none());
+        let no_stereo = IsomorphismOptions {
+            stereochemistry_matching: false,
+            ..Default::default()
+        };
+        let map = a.atom_map_with(&mirror, &no_stereo).unwrap();
+        check_map(&a, &mirror, &map);
+
+        // assigned stereochemistry without conformers, as from SMILES
+        let mut assigned = a.clone();
+        assigned.stereo = a.known_stereo();
+        assigned.conformers.clear();
+        let mut mirror = assigned.clone();
+        let centre = &mut mirror.stereo.as_mut().unwrap().atoms[1];
+        *centre = match centre {
+            Stereochemistry::R => Stereochemistry::S,
+            _ => Stereochemistry::R,
+        };
+        assert!(assigned.atom_map_to(&reversed(&a)).is_some());
+        assert!(reversed(&assigned).atom_map_to(&a).is_some());
+        assert!(assigned.atom_map_to(&mirror).is_none());
+        assert!(a.atom_map_to(&mirror).is_none());
+        assert!(assigned.atom_map_with(&mirror, &no_stereo).is_some());
+    }
+}
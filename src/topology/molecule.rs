@@ -1,10 +1,34 @@
-#[derive(Clone, Copy, PartialEq)]
+use std::{collections::VecDeque, error::Error};
+
+use ligand::molecule::Molecule;
+
+use crate::charges::{ChargeError, ChargeMethod};
+
+use super::{
+    aromaticity::{AromaticityError, AromaticityModel},
+    isomorphism::IsomorphismOptions,
+    smiles::{SmilesError, SmilesOptions},
+    stereo::StereoAssignment,
+};
+
+/// The CIP configuration of a tetrahedral centre, or None if the atom is not
+/// a stereocentre or its configuration is unknown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Stereochemistry {
     R,
     S,
     None,
 }
 
+/// The CIP configuration of a double bond, with None as for
+/// [Stereochemistry]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BondStereochemistry {
+    E,
+    Z,
+    None,
+}
+
 #[allow(unused)]
 enum Unit {
     Dalton,
@@ -15,3 +39,815 @@ struct Quantity {
     value: f64,
     unit: Unit,
 }
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Atom {
+    pub atomic_number: u8,
+    pub formal_charge: i8,
+    pub is_aromatic: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Bond {
+    pub atom1: usize,
+    pub atom2: usize,
+    pub bond_order: u8,
+    pub is_aromatic: bool,
+}
+
+impl Bond {
+    /// Return the atom on the other end of the bond from `atom`
+    pub fn other(&self, atom: usize) -> usize {
+        if self.atom1 == atom {
+            self.atom2
+        } else {
+            self.atom1
+        }
+    }
+}
+
+/// An owned copy of the chemical graph and conformers of a
+/// [ligand::molecule::Molecule], which is what the typing and analysis code in
+/// this crate actually operates on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoleculeGraph {
+    pub atoms: Vec<Atom>,
+    pub bonds: Vec<Bond>,
+
+    /// conformers as flattened `[x1, y1, z1, x2, ...]` coordinates in
+    /// angstroms
+    pub conformers: Vec<Vec<f64>>,
+
+    /// partial charges in units of the elementary charge, once assigned by
+    /// [MoleculeGraph::assign_partial_charges]
+    pub partial_charges: Option<Vec<f64>>,
+
+    /// the configuration of every stereo element when it is known apart from
+    /// the conformers, as set by [MoleculeGraph::from_mapped_smiles]. This
+    /// takes precedence over the conformers wherever stereochemistry is read.
+    pub stereo: Option<StereoAssignment>,
+
+    /// for each atom, a vector of (neighbor, bond index) pairs
+    adjacency: Vec<Vec<(usize, usize)>>,
+}
+
+impl MoleculeGraph {
+    pub fn new(atoms: Vec<Atom>, bonds: Vec<Bond>) -> Self {
+        let mut adjacency = vec![Vec::new(); atoms.len()];
+        for (b, bond) in bonds.iter().enumerate() {
+            adjacency[bond.atom1].push((bond.atom2, b));
+            adjacency[bond.atom2].push((bond.atom1, b));
+        }
+        Self {
+            atoms,
+            bonds,
+            conformers: Vec::new(),
+            partial_charges: None,
+            stereo: None,
+            adjacency,
+        }
+    }
+
+    pub fn n_atoms(&self) -> usize {
+        self.atoms.len()
+    }
+
+    /// Return an iterator over the neighbors of `atom`
+    pub fn neighbors(&self, atom: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adjacency[atom].iter().map(|&(n, _)| n)
+    }
+
+    /// Return the (neighbor, bond index) pairs for `atom`
+    pub fn adjacent(&self, atom: usize) -> &[(usize, usize)] {
+        &self.adjacency[atom]
+    }
+
+    /// Return the index of the bond between `i` and `j`, if there is one
+    pub fn bond_index(&self, i: usize, j: usize) -> Option<usize> {
+        self.adjacency[i]
+            .iter()
+            .find_map(|&(n, b)| (n == j).then_some(b))
+    }
+
+    pub fn bond_between(&self, i: usize, j: usize) -> Option<&Bond> {
+        self.bond_index(i, j).map(|b| &self.bonds[b])
+    }
+
+    /// The total number of explicit connections to `atom`
+    pub fn degree(&self, atom: usize) -> usize {
+        self.adjacency[atom].len()
+    }
+
+    /// The number of hydrogens attached to `atom`
+    pub fn n_hydrogens(&self, atom: usize) -> usize {
+        self.neighbors(atom)
+            .filter(|&n| self.atoms[n].atomic_number == 1)
+            .count()
+    }
+
+    /// The indices of the non-hydrogen atoms in the molecule
+    pub fn heavy_atoms(&self) -> Vec<usize> {
+        (0..self.n_atoms())
+            .filter(|&i| self.atoms[i].atomic_number != 1)
+            .collect()
+    }
+
+    pub fn n_heavy_atoms(&self) -> usize {
+        self.atoms.iter().filter(|a| a.atomic_number != 1).count()
+    }
+
+    /// Return the connected components of the graph, each as a sorted list
+    /// of atom indices, ordered by their first atom
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        let mut seen = vec![false; self.n_atoms()];
+        let mut ret = Vec::new();
+        for start in 0..self.n_atoms() {
+            if seen[start] {
+                continue;
+            }
+            seen[start] = true;
+            let mut component = vec![start];
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                for n in self.neighbors(i) {
+                    if !seen[n] {
+                        seen[n] = true;
+                        component.push(n);
+                        queue.push_back(n);
+                    }
+                }
+            }
+            component.sort();
+            ret.push(component);
+        }
+        ret
+    }
+
+    /// Return the shortest-path distance in bonds between every pair of atoms.
+    /// Atoms in different connected components are `usize::MAX` apart.
+    pub fn topological_distances(&self) -> Vec<Vec<usize>> {
+        (0..self.n_atoms())
+            .map(|i| self.distances_from(i))
+            .collect()
+    }
+
+    fn distances_from(&self, start: usize) -> Vec<usize> {
+        let mut dist = vec![usize::MAX; self.n_atoms()];
+        dist[start] = 0;
+        let mut queue = VecDeque::from([start]);
+        while let Some(i) = queue.pop_front() {
+            for n in self.neighbors(i) {
+                if dist[n] == usize::MAX {
+                    dist[n] = dist[i] + 1;
+                    queue.push_back(n);
+                }
+            }
+        }
+        dist
+    }
+
+    /// Return the size of the smallest ring containing bond `bond`, or `None`
+    /// if the bond is not in a ring
+    pub fn smallest_ring_with_bond(&self, bond: usize) -> Option<usize> {
+        let Bond { atom1, atom2, .. } = self.bonds[bond];
+        // breadth-first search from atom1 to atom2 without using `bond`
+        let mut dist = vec![usize::MAX; self.n_atoms()];
+        dist[atom1] = 0;
+        let mut queue = VecDeque::from([atom1]);
+        while let Some(i) = queue.pop_front() {
+            for &(n, b) in &self.adjacency[i] {
+                if b == bond || dist[n] != usize::MAX {
+                    continue;
+                }
+                dist[n] = dist[i] + 1;
+                if n == atom2 {
+                    return Some(dist[n] + 1);
+                }
+                queue.push_back(n);
+            }
+        }
+        None
+    }
+
+    /// Return whether each bond in the molecule is part of a ring
+    pub fn ring_bonds(&self) -> Vec<bool> {
+        (0..self.bonds.len())
+            .map(|b| self.smallest_ring_with_bond(b).is_some())
+            .collect()
+    }
+
+    /// Return the indices of the rotatable bonds in the molecule: single,
+    /// non-ring bonds between two atoms that each have at least one other
+    /// heavy-atom neighbor
+    pub fn rotatable_bonds(&self) -> Vec<usize> {
+        let ring_bonds = self.ring_bonds();
+        let heavy_degree = |i: usize| {
+            self.neighbors(i)
+                .filter(|&n| self.atoms[n].atomic_number != 1)
+                .count()
+        };
+        self.bonds
+            .iter()
+            .enumerate()
+            .filter(|&(b, bond)| {
+                bond.bond_order == 1
+                    && !bond.is_aromatic
+                    && !ring_bonds[b]
+                    && heavy_degree(bond.atom1) > 1
+                    && heavy_degree(bond.atom2) > 1
+            })
+            .map(|(b, _)| b)
+            .collect()
+    }
+
+    /// Return the symmetry class of each atom, refined from atomic invariants
+    /// and the classes of neighbors. Topologically equivalent atoms, like the
+    /// hydrogens of a methyl group, share a class.
+    pub fn symmetry_classes(&self) -> Vec<usize> {
+        self.stereo_classes(&[], &[])
+    }
+
+    /// Return the [MoleculeGraph::symmetry_classes] with atoms and bonds
+    /// further distinguished by `atom_stereo` and `bond_stereo`, labels like
+    /// CIP descriptors that do not depend on the atom order. Either may be
+    /// empty.
+    fn stereo_classes(
+        &self,
+        atom_stereo: &[u8],
+        bond_stereo: &[u8],
+    ) -> Vec<usize> {
+        let invariants: Vec<_> = (0..self.n_atoms())
+            .map(|i| {
+                let a = &self.atoms[i];
+                (
+                    a.atomic_number,
+                    a.formal_charge,
+                    a.is_aromatic,
+                    self.degree(i),
+                    self.n_hydrogens(i),
+                    atom_stereo.get(i).copied().unwrap_or(0),
+                )
+            })
+            .collect();
+        self.refine_ranks(rank_by(&invariants), bond_stereo)
+    }
+
+    /// refine `ranks` by neighbor ranks until the partition stops splitting
+    fn refine_ranks(
+        &self,
+        mut ranks: Vec<usize>,
+        bond_stereo: &[u8],
+    ) -> Vec<usize> {
+        loop {
+            let keys: Vec<_> = (0..self.n_atoms())
+                .map(|i| {
+                    let mut neighbors: Vec<_> = self.adjacency[i]
+                        .iter()
+                        .map(|&(n, b)| {
+                            let bond = &self.bonds[b];
+                            (
+                                ranks[n],
+                                bond.bond_order,
+                                bond.is_aromatic,
+                                bond_stereo.get(b).copied().unwrap_or(0),
+                            )
+                        })
+                        .collect();
+                    neighbors.sort();
+                    (ranks[i], neighbors)
+                })
+                .collect();
+            let refined = rank_by(&keys);
+            let done = distinct(&refined) == distinct(&ranks);
+            ranks = refined;
+            if done {
+                return ranks;
+            }
+        }
+    }
+
+    /// Return a canonical rank for each atom, so that two graphs differing
+    /// only in atom order give the same ranks to corresponding atoms, as
+    /// described in [MoleculeGraph::stereo_canonical_ranks]
+    pub fn canonical_ranks(&self) -> Vec<usize> {
+        self.stereo_canonical_ranks(&[], &[])
+    }
+
+    /// Return a canonical rank for each atom, distinguishing stereoisomers
+    /// by `atom_stereo` and `bond_stereo` as in
+    /// [MoleculeGraph::stereo_classes]. Ranks start from the refined classes,
+    /// and ties are broken by individualization and refinement: each atom of
+    /// the first tied class is ranked ahead of the others in turn, and the
+    /// ranking giving the smallest labelled graph is kept. Branches related
+    /// by a symmetry already found are skipped, and the search stops after
+    /// [MAX_LEAVES] rankings.
+    pub fn stereo_canonical_ranks(
+        &self,
+        atom_stereo: &[u8],
+        bond_stereo: &[u8],
+    ) -> Vec<usize> {
+        let mut search = Search {
+            graph: self,
+            atom_stereo,
+            bond_stereo,
+            best: None,
+            automorphisms: Vec::new(),
+            leaves: 0,
+        };
+        let ranks = self.stereo_classes(atom_stereo, bond_stereo);
+        search.individualize(ranks, &mut Vec::new());
+        search.best.map(|(_, ranks, _)| ranks).unwrap_or_default()
+    }
+
+    /// Return a string identifying the molecular graph independently of its
+    /// atom order, built from the atoms and bonds in canonical rank order
+    pub fn canonical_key(&self) -> String {
+        let ranks = self.canonical_ranks();
+        let mut order: Vec<_> = (0..self.n_atoms()).collect();
+        order.sort_by_key(|&i| ranks[i]);
+        let mut bonds: Vec<_> = self
+            .bonds
+            .iter()
+            .map(|b| {
+                let (i, j) = (ranks[b.atom1], ranks[b.atom2]);
+                (i.min(j), i.max(j), b.bond_order, b.is_aromatic)
+            })
+            .collect();
+        bonds.sort();
+
+        let mut ret = String::new();
+        for i in order {
+            let a = &self.atoms[i];
+            let aromatic = if a.is_aromatic { "a" } else { "" };
+            ret.push_str(&format!(
+                "[{}{aromatic}{:+}]",
+                a.atomic_number, a.formal_charge
+            ));
+        }
+        for (i, j, order, aromatic) in bonds {
+            let order = if aromatic {
+                "a".to_owned()
+            } else {
+                order.to_string()
+            };
+            ret.push_str(&format!("({i}-{j}:{order})"));
+        }
+        ret
+    }
+
+    /// Enumerate up to `limit` permutations of the heavy atoms that preserve
+    /// elements, formal charges, hydrogen counts, and heavy-atom bonds. Each
+    /// returned vector `p` maps `heavy_atoms()[n]` to `p[n]`, so the identity
+    /// mapping is always the first entry.
+    pub fn heavy_atom_automorphisms(&self, limit: usize) -> Vec<Vec<usize>> {
+        let heavy = self.heavy_atoms();
+        let invariant = |i: usize| {
+            (
+                self.atoms[i].atomic_number,
+                self.atoms[i].formal_charge,
+                self.degree(i),
+                self.n_hydrogens(i),
+            )
+        };
+
+        // visit atoms in breadth-first order so that each atom after the first
+        // in its component is constrained by an already mapped neighbor
+        let mut order = Vec::with_capacity(heavy.len());
+        let mut seen = vec![false; self.n_atoms()];
+        for &start in &heavy {
+            if seen[start] {
+                continue;
+            }
+            seen[start] = true;
+            let mut queue = VecDeque::from([start]);
+            while let Some(i) = queue.pop_front() {
+                order.push(i);
+                for n in self.neighbors(i) {
+                    if !seen[n] && self.atoms[n].atomic_number != 1 {
+                        seen[n] = true;
+                        queue.push_back(n);
+                    }
+                }
+            }
+        }
+
+        struct State<'a> {
+            graph: &'a MoleculeGraph,
+            order: &'a [usize],
+            map: Vec<Option<usize>>,
+            used: Vec<bool>,
+            results: Vec<Vec<usize>>,
+            limit: usize,
+        }
+
+        fn search<F>(state: &mut State, depth: usize, invariant: &F)
+        where
+            F: Fn(usize) -> (u8, i8, usize, usize),
+        {
+            if state.results.len() >= state.limit {
+                return;
+            }
+            let g = state.graph;
+            if depth == state.order.len() {
+                let map = &state.map;
+                state.results.push(
+                    g.heavy_atoms().iter().map(|&i| map[i].unwrap()).collect(),
+                );
+                return;
+            }
+            let atom = state.order[depth];
+            for candidate in g.heavy_atoms() {
+                if state.used[candidate]
+                    || invariant(atom) != invariant(candidate)
+                {
+                    continue;
+                }
+                // every mapped neighbor must be bonded to the candidate with
+                // the same bond order, and vice versa
+                let consistent = (0..g.n_atoms()).all(|other| {
+                    let Some(image) = state.map[other] else {
+                        return true;
+                    };
+                    let a = g.bond_between(atom, other).map(|b| b.bond_order);
+                    let b =
+                        g.bond_between(candidate, image).map(|b| b.bond_order);
+                    a == b
+                });
+                if !consistent {
+                    continue;
+                }
+                state.map[atom] = Some(candidate);
+                state.used[candidate] = true;
+                search(state, depth + 1, invariant);
+                state.map[atom] = None;
+                state.used[candidate] = false;
+            }
+        }
+
+        let mut state = State {
+            graph: self,
+            order: &order,
+            map: vec![None; self.n_atoms()],
+            used: vec![false; self.n_atoms()],
+            results: Vec::new(),
+            limit,
+        };
+        search(&mut state, 0, &invariant);
+
+        let mut ret = vec![heavy.clone()];
+        ret.extend(state.results.into_iter().filter(|p| *p != heavy));
+        ret.truncate(limit.max(1));
+        ret
+    }
+
+    /// Return a mapping from the atoms of `self` to the atoms of `other` that
+    /// preserves elements and connectivity, ignoring bond orders, charges,
+    /// and aromaticity, or None if the graphs are not isomorphic in this sense
+    pub fn element_isomorphism(&self, other: &Self) -> Option<Vec<usize>> {
+        self.atom_map_with(other, &IsomorphismOptions::CONNECTIVITY)
+    }
+}
+
+/// replace each key with its position among the sorted distinct keys
+pub(crate) fn rank_by<K: Ord>(keys: &[K]) -> Vec<usize> {
+    let mut sorted: Vec<_> = keys.iter().collect();
+    sorted.sort();
+    sorted.dedup();
+    keys.iter()
+        .map(|k| sorted.binary_search(&k).unwrap())
+        .collect()
+}
+
+/// The most complete rankings compared by
+/// [MoleculeGraph::stereo_canonical_ranks]
+const MAX_LEAVES: usize = 10_000;
+
+/// The atoms and bonds of a graph in rank order with their labels, compared
+/// to choose between rankings
+type Certificate = (Vec<(u8, i8, bool, u8)>, Vec<(usize, usize, u8, bool, u8)>);
+
+/// The state of the search for canonical ranks
+struct Search<'a> {
+    graph: &'a MoleculeGraph,
+    atom_stereo: &'a [u8],
+    bond_stereo: &'a [u8],
+
+    /// the smallest certificate so far, its ranks, and the atoms
+    /// individualized to reach it
+    best: Option<(Certificate, Vec<usize>, Vec<usize>)>,
+
+    /// atom permutations found to preserve the labelled graph
+    automorphisms: Vec<Vec<usize>>,
+
+    /// the number of complete rankings reached
+    leaves: usize,
+}
+
+impl Search<'_> {
+    fn certificate(&self, ranks: &[usize]) -> Certificate {
+        let g = self.graph;
+        let label =
+            |labels: &[u8], i: usize| labels.get(i).copied().unwrap_or(0);
+        let mut atoms = vec![(0, 0, false, 0); g.n_atoms()];
+        for (i, a) in g.atoms.iter().enumerate() {
+            atoms[ranks[i]] = (
+                a.atomic_number,
+                a.formal_charge,
+                a.is_aromatic,
+                label(self.atom_stereo, i),
+            );
+        }
+        let mut bonds: Vec<_> = g
+            .bonds
+            .iter()
+            .enumerate()
+            .map(|(b, bond)| {
+                let (i, j) = (ranks[bond.atom1], ranks[bond.atom2]);
+                (
+                    i.min(j),
+                    i.max(j),
+                    bond.bond_order,
+                    bond.is_aromatic,
+                    label(self.bond_stereo, b),
+                )
+            })
+            .collect();
+        bonds.sort();
+        (atoms, bonds)
+    }
+
+    /// Return a representative atom of the orbit of each atom under the
+    /// automorphisms found that fix the atoms in `path`
+    fn orbits(&self, path: &[usize]) -> Vec<usize> {
+        let mut ret: Vec<usize> = (0..self.graph.n_atoms()).collect();
+        fn find(ret: &mut [usize], mut i: usize) -> usize {
+            while ret[i] != i {
+                ret[i] = ret[ret[i]];
+                i = ret[i];
+            }
+            i
+        }
+        for g in &self.automorphisms {
+            if path.iter().any(|&p| g[p] != p) {
+                continue;
+            }
+            for (i, &j) in g.iter().enumerate() {
+                let (i, j) = (find(&mut ret, i), find(&mut ret, j));
+                ret[i.max(j)] = i.min(j);
+            }
+        }
+        (0..ret.len()).map(|i| find(&mut ret, i)).collect()
+    }
+
+    /// Search the rankings refined from `ranks`, which individualized the
+    /// atoms in `path`. Returns the depth to go back up to when a ranking
+    /// shows the current branch to be symmetric to one already searched.
+    fn individualize(
+        &mut self,
+        ranks: Vec<usize>,
+        path: &mut Vec<usize>,
+    ) -> Option<usize> {
+        let n = ranks.len();
+        if distinct(&ranks) == n {
+            self.leaves += 1;
+            let certificate = self.certificate(&ranks);
+            match &self.best {
+                Some((best, best_ranks, best_path)) if *best == certificate => {
+                    // map each atom to the atom of the same rank in the best
+                    let mut at_rank = vec![0; n];
+                    for (i, &r) in best_ranks.iter().enumerate() {
+                        at_rank[r] = i;
+                    }
+                    self.automorphisms
+                        .push(ranks.iter().map(|&r| at_rank[r]).collect());
+                    // the rest of the branch below the common ancestor maps
+                    // onto the searched branch holding the best ranking
+                    return Some(
+                        path.iter()
+                            .zip(best_path)
+                            .take_while(|(a, b)| a == b)
+                            .count(),
+                    );
+                }
+                Some((best, ..)) if *best < certificate => {}
+                _ => self.best = Some((certificate, ranks, path.clone())),
+            }
+            return None;
+        }
+        let mut counts = vec![0; n];
+        ranks.iter().for_each(|&r| counts[r] += 1);
+        let tied = counts.iter().position(|&c| c > 1).unwrap();
+        let cell: Vec<usize> = (0..n).filter(|&i| ranks[i] == tied).collect();
+        let mut explored: Vec<usize> = Vec::new();
+        for chosen in cell {
+            if self.leaves >= MAX_LEAVES {
+                return None;
+            }
+            let orbits = self.orbits(path);
+            if explored.iter().any(|&e| orbits[e] == orbits[chosen]) {
+                continue;
+            }
+            explored.push(chosen);
+            let keys: Vec<_> = (0..n)
+                .map(|i| (ranks[i], ranks[i] == tied && i != chosen))
+                .collect();
+            let refined =
+                self.graph.refine_ranks(rank_by(&keys), self.bond_stereo);
+            path.push(chosen);
+            let jump = self.individualize(refined, path);
+            path.pop();
+            match jump {
+                Some(depth) if depth < path.len() => return jump,
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// the number of distinct ranks in `ranks`
+pub(crate) fn distinct(ranks: &[usize]) -> usize {
+    ranks.iter().max().map_or(0, |r| r + 1)
+}
+
+/// Convert a [Molecule] to its graph and conformers. A [Molecule] does not
+/// expose its stereochemistry, so [MoleculeGraph::stereo] is left unset; build
+/// the graph with [MoleculeGraph::from_mapped_smiles] to keep the
+/// stereochemistry of a CMILES without a conformer.
+impl From<&ligand::molecule::Molecule> for MoleculeGraph {
+    fn from(molecule: &ligand::molecule::Molecule) -> Self {
+        let atoms = molecule
+            .atoms()
+            .into_iter()
+            .map(|atom| Atom {
+                atomic_number: atom.atomic_number() as u8,
+                formal_charge: atom.formal_charge() as i8,
+                is_aromatic: atom.is_aromatic(),
+            })
+            .collect();
+        let bonds = molecule
+            .bonds()
+            .into_iter()
+            .map(|bond| Bond {
+                atom1: bond.atom1_index(),
+                atom2: bond.atom2_index(),
+                bond_order: bond.bond_order() as u8,
+                is_aromatic: bond.is_aromatic(),
+            })
+            .collect();
+        let mut ret = Self::new(atoms, bonds);
+        ret.conformers = molecule
+            .conformers()
+            .into_iter()
+            .map(|c| c.to_vec())
+            .collect();
+        ret
+    }
+}
+
+impl MoleculeGraph {
+    /// Build the [Molecule] with the same atom order, bond orders, formal
+    /// charges, and conformers. [Molecule]s can only be built from SMILES, so
+    /// this goes through a mapped, explicit hydrogen SMILES, whose
+    /// stereochemistry is taken from [MoleculeGraph::stereo] if it is set and
+    /// otherwise from the first conformer.
+    pub fn to_molecule(&self) -> Result<Molecule, Box<dyn Error>> {
+        let smiles = self.to_smiles(&SmilesOptions {
+            canonical: false,
+            ..SmilesOptions::CMILES
+        })?;
+        let mut ret = Molecule::from_mapped_smiles(&smiles)
+            .map_err(|e| format!("{smiles}: {e:?}"))?;
+        for conformer in &self.conformers {
+            ret.add_conformer(conformer.clone());
+        }
+        Ok(ret)
+    }
+}
+
+/// Methods on [Molecule] that work on its [MoleculeGraph], for molecules that
+/// have not been converted already. A [Molecule] does not expose the
+/// stereochemistry of the SMILES it was built from, so these methods only see
+/// stereochemistry through its conformers. For a molecule without conformers,
+/// build the graph with [MoleculeGraph::from_mapped_smiles] and use its
+/// methods instead, which read [MoleculeGraph::stereo].
+///
+/// A [Molecule] cannot be modified in place either, so the methods that set
+/// aromatic flags or partial charges return the converted graph instead, and
+/// the rest of the crate should be given that graph rather than the
+/// [Molecule].
+pub trait MoleculeExt {
+    /// Write the SMILES of the molecule with `options`, as described in
+    /// [MoleculeGraph::to_smiles]
+    fn to_smiles(&self, options: &SmilesOptions)
+        -> Result<String, SmilesError>;
+
+    /// Return whether the molecule is the same as `other` under `options`, as
+    /// described in [MoleculeGraph::is_isomorphic_with]
+    fn is_isomorphic_with(
+        &self,
+        other: &Molecule,
+        options: &IsomorphismOptions,
+    ) -> bool;
+
+    /// Return the mapping from the atoms of the molecule to the atoms of
+    /// `other`, as described in [MoleculeGraph::atom_map_to]
+    fn atom_map_to(&self, other: &Molecule) -> Option<Vec<usize>>;
+
+    /// Assign the CIP configuration of every stereo element from the
+    /// geometry of conformer `conformer`, as described in
+    /// [MoleculeGraph::stereo_from_conformer]
+    fn stereo_from_conformer(&self, conformer: usize) -> StereoAssignment;
+
+    /// Return the stereocentres and stereogenic double bonds left undefined
+    /// in `assignment`
+    fn undefined_stereo(
+        &self,
+        assignment: &StereoAssignment,
+    ) -> (Vec<usize>, Vec<usize>);
+
+    /// Enumerate up to `limit` stereoisomers filling in the undefined stereo
+    /// elements of `assignment`, as described in
+    /// [MoleculeGraph::enumerate_stereoisomers]
+    fn enumerate_stereoisomers(
+        &self,
+        assignment: &StereoAssignment,
+        limit: usize,
+    ) -> Vec<StereoAssignment>;
+
+    /// Return the [MoleculeGraph] of the molecule with its aromatic flags set
+    /// by `model`. A [Molecule] cannot have its flags changed in place, so
+    /// the result is the converted graph.
+    fn perceive_aromaticity(
+        &self,
+        model: AromaticityModel,
+    ) -> Result<MoleculeGraph, AromaticityError>;
+
+    /// Return the [MoleculeGraph] of the molecule with partial charges
+    /// computed by `method` stored in [MoleculeGraph::partial_charges]. A
+    /// [Molecule] has no field for partial charges, so the result is the
+    /// converted graph.
+    fn assign_partial_charges(
+        &self,
+        method: ChargeMethod,
+    ) -> Result<MoleculeGraph, ChargeError>;
+}
+
+impl MoleculeExt for Molecule {
+    fn to_smiles(
+        &self,
+        options: &SmilesOptions,
+    ) -> Result<String, SmilesError> {
+        MoleculeGraph::from(self).to_smiles(options)
+    }
+
+    fn is_isomorphic_with(
+        &self,
+        other: &Molecule,
+        options: &IsomorphismOptions,
+    ) -> bool {
+        MoleculeGraph::from(self)
+            .is_isomorphic_with(&MoleculeGraph::from(other), options)
+    }
+
+    fn atom_map_to(&self, other: &Molecule) -> Option<Vec<usize>> {
+        MoleculeGraph::from(self).atom_map_to(&MoleculeGraph::from(other))
+    }
+
+    fn stereo_from_conformer(&self, conformer: usize) -> StereoAssignment {
+        MoleculeGraph::from(self).stereo_from_conformer(conformer)
+    }
+
+    fn undefined_stereo(
+        &self,
+        assignment: &StereoAssignment,
+    ) -> (Vec<usize>, Vec<usize>) {
+        MoleculeGraph::from(self).undefined_stereo(assignment)
+    }
+
+    fn enumerate_stereoisomers(
+        &self,
+        assignment: &StereoAssignment,
+        limit: usize,
+    ) -> Vec<StereoAssignment> {
+        MoleculeGraph::from(self).enumerate_stereoisomers(assignment, limit)
+    }
+
+    fn perceive_aromaticity(
+        &self,
+        model: AromaticityModel,
+    ) -> Result<MoleculeGraph, AromaticityError> {
+        let mut ret = MoleculeGraph::from(self);
+        ret.perceive_aromaticity(model)?;
+        Ok(ret)
+    }
+
+    fn assign_partial_charges(
+        &self,
+        method: ChargeMethod,
+    ) -> Result<MoleculeGraph, ChargeError> {
+        let mut ret = MoleculeGraph::from(self);
+        ret.assign_partial_charges(method)?;
+        Ok(ret)
+    }
+}
@@ -0,0 +1,1028 @@
+//! Reading and writing molecules in MDL SD files, in both the V2000 and V3000
+//! formats, and in Tripos MOL2 files. Consecutive records with the same name
+//! and chemical graph are read as conformers of a single molecule.
+//!
+//! Molecules are read into [MoleculeGraph]s, which can be converted to
+//! [ligand::molecule::Molecule]s with [FileMolecule::to_molecule].
+//! Aromatic bonds in SD files (MDL bond type 4) are kekulized on reading, so
+//! they keep their aromatic flags but get Kekulé bond orders, which requires
+//! explicit hydrogens. MOL2 `ar` bonds, which Tripos also uses for
+//! carboxylates, are read as aromatic single bonds. Bonds are always written
+//! with their stored bond orders.
+
+use std::{
+    error::Error,
+    fmt::{Display, Write},
+    fs::read_to_string,
+    path::Path,
+};
+
+use crate::utils::elements::{atomic_number, symbol};
+
+use super::{
+    molecule::{Atom, Bond, MoleculeGraph, Stereochemistry},
+    stereo::StereoAssignment,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct MolFileError {
+    /// the 1-based line where the error occurred
+    pub line: usize,
+    pub message: String,
+}
+
+impl Error for MolFileError {}
+
+impl Display for MolFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn err(line: usize, message: impl Into<String>) -> MolFileError {
+    MolFileError {
+        line,
+        message: message.into(),
+    }
+}
+
+/// A molecule with all of its conformers and the file metadata that does not
+/// fit in a [MoleculeGraph]
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileMolecule {
+    pub name: String,
+    pub graph: MoleculeGraph,
+
+    /// the MDL parity of each atom: 0 for none, 1 odd, 2 even, 3 either
+    pub atom_parity: Vec<u8>,
+
+    /// the MDL V2000 stereo flag of each bond. For single bonds, 1 is a wedge,
+    /// 6 a hash, and 4 either; for double bonds 3 is either cis or trans
+    pub bond_stereo: Vec<u8>,
+
+    /// the SD tags of each conformer as (name, value) pairs
+    pub properties: Vec<Vec<(String, String)>>,
+}
+
+impl FileMolecule {
+    /// Wrap `graph` with no stereo flags or properties
+    pub fn new(name: impl Into<String>, graph: MoleculeGraph) -> Self {
+        Self {
+            name: name.into(),
+            atom_parity: vec![0; graph.n_atoms()],
+            bond_stereo: vec![0; graph.bonds.len()],
+            properties: vec![Vec::new(); graph.conformers.len()],
+            graph,
+        }
+    }
+
+    /// Build the [ligand::molecule::Molecule] with all of the conformers and
+    /// the stereochemistry from [FileMolecule::stereo], as described in
+    /// [MoleculeGraph::to_molecule]
+    pub fn to_molecule(
+        &self,
+    ) -> Result<ligand::molecule::Molecule, Box<dyn Error>> {
+        let mut graph = self.graph.clone();
+        graph.stereo = Some(self.stereo());
+        graph.to_molecule()
+    }
+
+    /// Assign the configuration of every stereo element, taking stereocentres
+    /// from their MDL parities where those are set, as in
+    /// [MoleculeGraph::stereo_from_parities], and everything else from the
+    /// first conformer. A flat (2D) conformer is first lifted out of the plane
+    /// along its wedged and hashed bonds, so drawn stereocentres come from
+    /// their wedges; centres that are still flat are left undefined
+    pub fn stereo(&self) -> StereoAssignment {
+        let mut ret = match self.graph.conformers.first() {
+            None => self.graph.stereo_from_parities(&[]),
+            Some(conf) if is_flat(conf) => {
+                let mut graph = self.graph.clone();
+                graph.conformers = vec![self.lift_wedges(conf)];
+                graph.stereo_from_conformer(0)
+            }
+            Some(_) => self.graph.stereo_from_conformer(0),
+        };
+        let parities = self.graph.stereo_from_parities(&self.atom_parity);
+        for (got, parity) in ret.atoms.iter_mut().zip(parities.atoms) {
+            if parity != Stereochemistry::None {
+                *got = parity;
+            }
+        }
+        ret
+    }
+
+    /// Return a copy of `conformer` with the wide end of each wedge moved one
+    /// unit towards the viewer and of each hash one unit away
+    fn lift_wedges(&self, conformer: &[f64]) -> Vec<f64> {
+        let mut ret = conformer.to_vec();
+        for (bond, flag) in self.graph.bonds.iter().zip(&self.bond_stereo) {
+            match flag {
+                1 => ret[3 * bond.atom2 + 2] += 1.0,
+                6 => ret[3 * bond.atom2 + 2] -= 1.0,
+                _ => {}
+            }
+        }
+        ret
+    }
+
+    /// Append the conformers and properties of `other` if it is the same
+    /// molecule, returning whether it was merged
+    fn merge(&mut self, other: &mut FileMolecule) -> bool {
+        if self.name != other.name
+            || self.graph.atoms != other.graph.atoms
+            || self.graph.bonds != other.graph.bonds
+        {
+            return false;
+        }
+        self.graph.conformers.append(&mut other.graph.conformers);
+        self.properties.append(&mut other.properties);
+        true
+    }
+}
+
+/// Merge consecutive records of the same molecule
+fn group(records: Vec<FileMolecule>) -> Vec<FileMolecule> {
+    let mut ret: Vec<FileMolecule> = Vec::new();
+    for mut record in records {
+        if let Some(last) = ret.last_mut() {
+            if last.merge(&mut record) {
+                continue;
+            }
+        }
+        ret.push(record);
+    }
+    ret
+}
+
+/// Convert an MDL V2000 atom block charge code to a formal charge
+fn charge_from_code(code: i8) -> i8 {
+    match code {
+        1..=3 | 5..=7 => 4 - code,
+        _ => 0,
+    }
+}
+
+/// Read every molecule in the SD file at `path`
+pub fn read_sdf(
+    path: impl AsRef<Path>,
+) -> Result<Vec<FileMolecule>, Box<dyn Error>> {
+    Ok(parse_sdf(&read_to_string(path)?)?)
+}
+
+/// Parse the contents of an SD file
+pub fn parse_sdf(contents: &str) -> Result<Vec<FileMolecule>, MolFileError> {
+    let lines: Vec<&str> = contents.lines().collect();
+    let mut records = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        if lines[start..].iter().all(|l| l.trim().is_empty()) {
+            break;
+        }
+        let end = lines[start..]
+            .iter()
+            .position(|l| l.starts_with("$$$$"))
+            .map_or(lines.len(), |p| start + p);
+        records.push(parse_record(&lines[start..end], start)?);
+        start = end + 1;
+    }
+    Ok(group(records))
+}
+
+/// Parse one record spanning `lines`, which start at 0-based line `offset`
+fn parse_record(
+    lines: &[&str],
+    offset: usize,
+) -> Result<FileMolecule, MolFileError> {
+    let counts = lines
+        .get(3)
+        .ok_or_else(|| err(offset + 1, "truncated header"))?;
+    let (mut ret, end) = if counts.contains("V3000") {
+        parse_v3000(lines, offset)?
+    } else {
+        parse_v2000(lines, offset)?
+    };
+    ret.name = lines[0].trim().to_owned();
+    ret.graph
+        .kekulize()
+        .map_err(|e| err(offset + 1, e.message))?;
+
+    // the data items after M  END
+    let mut properties = Vec::new();
+    let mut i = end;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        let Some(rest) = line.strip_prefix('>') else {
+            continue;
+        };
+        let (Some(a), Some(b)) = (rest.find('<'), rest.rfind('>')) else {
+            return Err(err(offset + i, "invalid data header"));
+        };
+        let name = rest[a + 1..b].to_owned();
+        let mut value = Vec::new();
+        while i < lines.len() && !lines[i].trim().is_empty() {
+            value.push(lines[i]);
+            i += 1;
+        }
+        properties.push((name, value.join("\n")));
+    }
+    ret.properties = vec![properties];
+    Ok(ret)
+}
+
+/// Parse the V2000 connection table, returning the molecule and the index of
+/// the line after M  END
+fn parse_v2000(
+    lines: &[&str],
+    offset: usize,
+) -> Result<(FileMolecule, usize), MolFileError> {
+    let field = |l: usize, start: usize, len: usize| -> &str {
+        let line = lines.get(l).copied().unwrap_or("");
+        line.get(start..(start + len).min(line.len()))
+            .unwrap_or("")
+            .trim()
+    };
+    let int =
+        |l: usize, start: usize, len: usize| -> Result<i64, MolFileError> {
+            let s = field(l, start, len);
+            if s.is_empty() {
+                return Ok(0);
+            }
+            s.parse().map_err(|_| {
+                err(offset + l + 1, format!("invalid integer {s}"))
+            })
+        };
+    let n_atoms = int(3, 0, 3)? as usize;
+    let n_bonds = int(3, 3, 3)? as usize;
+    if lines.len() < 4 + n_atoms + n_bonds {
+        return Err(err(offset + lines.len(), "truncated connection table"));
+    }
+
+    let mut atoms = Vec::with_capacity(n_atoms);
+    let mut conformer = Vec::with_capacity(3 * n_atoms);
+    let mut parity = Vec::with_capacity(n_atoms);
+    for l in 4..4 + n_atoms {
+        for c in 0..3 {
+            let s = field(l, 10 * c, 10);
+            conformer.push(s.parse().map_err(|_| {
+                err(offset + l + 1, format!("invalid coordinate {s}"))
+            })?);
+        }
+        let sym = field(l, 31, 3);
+        let atomic_number = atomic_number(sym).ok_or_else(|| {
+            err(offset + l + 1, format!("unknown element {sym}"))
+        })?;
+        atoms.push(Atom {
+            atomic_number,
+            formal_charge: charge_from_code(int(l, 36, 3)? as i8),
+            is_aromatic: false,
+        });
+        parity.push(int(l, 39, 3)? as u8);
+    }
+
+    let mut bonds = Vec::with_capacity(n_bonds);
+    let mut stereo = Vec::with_capacity(n_bonds);
+    for l in 4 + n_atoms..4 + n_atoms + n_bonds {
+        let atom = |start| -> Result<usize, MolFileError> {
+            match int(l, start, 3)? as usize {
+                i @ 1.. if i <= n_atoms => Ok(i - 1),
+                i => Err(err(offset + l + 1, format!("invalid atom {i}"))),
+            }
+        };
+        let typ = int(l, 6, 3)? as u8;
+        bonds.push(Bond {
+            atom1: atom(0)?,
+            atom2: atom(3)?,
+            bond_order: if typ == 4 { 1 } else { typ },
+            is_aromatic: typ == 4,
+        });
+        stereo.push(int(l, 9, 3)? as u8);
+    }
+
+    // property lines, where any M  CHG replaces the atom block charges
+    let mut l = 4 + n_atoms + n_bonds;
+    let mut reset = false;
+    while l < lines.len() && !lines[l].starts_with("M  END") {
+        if lines[l].starts_with("M  CHG") {
+            if !reset {
+                atoms.iter_mut().for_each(|a| a.formal_charge = 0);
+                reset = true;
+            }
+            let n = int(l, 6, 3)? as usize;
+            for k in 0..n {
+                let i = int(l, 10 + 8 * k, 3)? as usize;
+                let charge = int(l, 14 + 8 * k, 3)? as i8;
+                let atom = i
+                    .checked_sub(1)
+                    .and_then(|i| atoms.get_mut(i))
+                    .ok_or_else(|| {
+                        err(offset + l + 1, "invalid M  CHG atom")
+                    })?;
+                atom.formal_charge = charge;
+            }
+        }
+        l += 1;
+    }
+    for bond in &bonds {
+        if bond.is_aromatic {
+            atoms[bond.atom1].is_aromatic = true;
+            atoms[bond.atom2].is_aromatic = true;
+        }
+    }
+
+    let mut graph = MoleculeGraph::new(atoms, bonds);
+    graph.conformers = vec![conformer];
+    let mut ret = FileMolecule::new("", graph);
+    ret.atom_parity = parity;
+    ret.bond_stereo = stereo;
+    Ok((ret, l + 1))
+}
+
+/// Return whether every atom of `conformer` has the same z coordinate, as in
+/// a 2D drawing
+fn is_flat(conformer: &[f64]) -> bool {
+    let mut z = conformer.iter().skip(2).step_by(3);
+    let Some(first) = z.next() else {
+        return true;
+    };
+    z.all(|z| (z - first).abs() < 1e-4)
+}
+
+/// Parse the V3000 connection table, returning the molecule and the index of
+/// the line after M  END
+fn parse_v3000(
+    lines: &[&str],
+    offset: usize,
+) -> Result<(FileMolecule, usize), MolFileError> {
+    // join continuation lines ending in `-`, keeping the original line number
+    let mut joined: Vec<(usize, String)> = Vec::new();
+    let mut end = lines.len();
+    let mut pending: Option<(usize, String)> = None;
+    for (l, line) in lines.iter().enumerate().skip(4) {
+        if line.starts_with("M  END") {
+            end = l + 1;
+            break;
+        }
+        let Some(body) = line.strip_prefix("M  V30 ") else {
+            continue;
+        };
+        let (l, mut text) = match pending.take() {
+            Some((l, prev)) => (l, prev + body),
+            None => (l, body.to_owned()),
+        };
+        if text.ends_with('-') {
+            text.pop();
+            pending = Some((l, text));
+        } else {
+            joined.push((l, text));
+        }
+    }
+
+    let mut atoms = Vec::new();
+    let mut conformer = Vec::new();
+    let mut parity = Vec::new();
+    let mut bonds = Vec::new();
+    let mut stereo = Vec::new();
+    let mut block = "";
+    for (l, text) in &joined {
+        let line = offset + l + 1;
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        match fields.as_slice() {
+            ["BEGIN", b, ..] => block = b,
+            ["END", ..] => block = "",
+            _ if block == "ATOM" => {
+                let &[_, sym, x, y, z, _, ref rest @ ..] = fields.as_slice()
+                else {
+                    return Err(err(line, "truncated atom line"));
+                };
+                let atomic_number = atomic_number(sym).ok_or_else(|| {
+                    err(line, format!("unknown element {sym}"))
+                })?;
+                for s in [x, y, z] {
+                    conformer.push(s.parse().map_err(|_| {
+                        err(line, format!("invalid coordinate {s}"))
+                    })?);
+                }
+                let keyword = |key: &str| -> Result<i64, MolFileError> {
+                    rest.iter().find_map(|f| f.strip_prefix(key)).map_or(
+                        Ok(0),
+                        |v| {
+                            v.parse()
+                                .map_err(|_| err(line, format!("bad {key}")))
+                        },
+                    )
+                };
+                atoms.push(Atom {
+                    atomic_number,
+                    formal_charge: keyword("CHG=")? as i8,
+                    is_aromatic: false,
+                });
+                parity.push(keyword("CFG=")? as u8);
+            }
+            _ if block == "BOND" => {
+                let &[_, typ, a, b, ref rest @ ..] = fields.as_slice() else {
+                    return Err(err(line, "truncated bond line"));
+                };
+                let index = |s: &str| match s.parse::<usize>() {
+                    Ok(i @ 1..) if i <= atoms.len() => Ok(i - 1),
+                    _ => Err(err(line, format!("invalid atom {s}"))),
+                };
+                let typ: u8 = typ.parse().map_err(|_| {
+                    err(line, format!("invalid bond type {typ}"))
+                })?;
+                let cfg = rest
+                    .iter()
+                    .find_map(|f| f.strip_prefix("CFG="))
+                    .unwrap_or("0");
+                // V3000 configurations in V2000 terms
+                stereo.push(match (cfg, typ) {
+                    ("1", _) => 1,
+                    ("3", _) => 6,
+                    ("2", 2) => 3,
+                    ("2", _) => 4,
+                    _ => 0,
+                });
+                bonds.push(Bond {
+                    atom1: index(a)?,
+                    atom2: index(b)?,
+                    bond_order: if typ == 4 { 1 } else { typ },
+                    is_aromatic: typ == 4,
+                });
+            }
+            _ => {}
+        }
+    }
+    for bond in &bonds {
+        if bond.is_aromatic {
+            atoms[bond.atom1].is_aromatic = true;
+            atoms[bond.atom2].is_aromatic = true;
+        }
+    }
+
+    let mut graph = MoleculeGraph::new(atoms, bonds);
+    graph.conformers = vec![conformer];
+    let mut ret = FileMolecule::new("", graph);
+    ret.atom_parity = parity;
+    ret.bond_stereo = stereo;
+    Ok((ret, end))
+}
+
+/// Write `molecules` as an SD file with one record per conformer. Molecules
+/// with more than 999 atoms or bonds are written in the V3000 format.
+pub fn write_sdf(molecules: &[FileMolecule]) -> String {
+    let mut ret = String::new();
+    for molecule in molecules {
+        let graph = &molecule.graph;
+        for (c, conformer) in graph.conformers.iter().enumerate() {
+            writeln!(ret, "{}\n  openff-toolkit\n", molecule.name).unwrap();
+            if graph.n_atoms() > 999 || graph.bonds.len() > 999 {
+                write_v3000(&mut ret, molecule, conformer);
+            } else {
+                write_v2000(&mut ret, molecule, conformer);
+            }
+            ret.push_str("M  END\n");
+            for (name, value) in
+                molecule.properties.get(c).into_iter().flatten()
+            {
+                writeln!(ret, "> <{name}>\n{value}\n").unwrap();
+            }
+            ret.push_str("$$$$\n");
+        }
+    }
+    ret
+}
+
+fn write_v2000(out: &mut String, molecule: &FileMolecule, conformer: &[f64]) {
+    let graph = &molecule.graph;
+    writeln!(
+        out,
+        "{:>3}{:>3}  0  0  0  0  0  0  0  0999 V2000",
+        graph.n_atoms(),
+        graph.bonds.len()
+    )
+    .unwrap();
+    for (i, atom) in graph.atoms.iter().enumerate() {
+        let [x, y, z] = [0, 1, 2].map(|c| conformer[3 * i + c]);
+        let parity = molecule.atom_parity.get(i).copied().unwrap_or(0);
+        writeln!(
+            out,
+            "{x:>10.4}{y:>10.4}{z:>10.4} {:<3} 0  0{parity:>3}  0  0  0  0  0  \
+             0  0  0  0",
+            symbol(atom.atomic_number).unwrap_or("*"),
+        )
+        .unwrap();
+    }
+    for (b, bond) in graph.bonds.iter().enumerate() {
+        writeln!(
+            out,
+            "{:>3}{:>3}{:>3}{:>3}",
+            bond.atom1 + 1,
+            bond.atom2 + 1,
+            bond.bond_order,
+            molecule.bond_stereo.get(b).copied().unwrap_or(0)
+        )
+        .unwrap();
+    }
+    let charged: Vec<_> = graph
+        .atoms
+        .iter()
+        .enumerate()
+        .filter(|(_, a)| a.formal_charge != 0)
+        .collect();
+    for chunk in charged.chunks(8) {
+        write!(out, "M  CHG{:>3}", chunk.len()).unwrap();
+        for (i, atom) in chunk {
+            write!(out, " {:>3} {:>3}", i + 1, atom.formal_charge).unwrap();
+        }
+        out.push('\n');
+    }
+}
+
+fn write_v3000(out: &mut String, molecule: &FileMolecule, conformer: &[f64]) {
+    let graph = &molecule.graph;
+    out.push_str("  0  0  0     0  0            999 V3000\n");
+    out.push_str("M  V30 BEGIN CTAB\n");
+    writeln!(
+        out,
+        "M  V30 COUNTS {} {} 0 0 0",
+        graph.n_atoms(),
+        graph.bonds.len()
+    )
+    .unwrap();
+    out.push_str("M  V30 BEGIN ATOM\n");
+    for (i, atom) in graph.atoms.iter().enumerate() {
+        let [x, y, z] = [0, 1, 2].map(|c| conformer[3 * i + c]);
+        write!(
+            out,
+            "M  V30 {} {} {x:.4} {y:.4} {z:.4} 0",
+            i + 1,
+            symbol(atom.atomic_number).unwrap_or("*"),
+        )
+        .unwrap();
+        if atom.formal_charge != 0 {
+            write!(out, " CHG={}", atom.formal_charge).unwrap();
+        }
+        match molecule.atom_parity.get(i) {
+            Some(&p) if p != 0 => writeln!(out, " CFG={p}").unwrap(),
+            _ => out.push('\n'),
+        }
+    }
+    out.push_str("M  V30 END ATOM\nM  V30 BEGIN BOND\n");
+    for (b, bond) in graph.bonds.iter().enumerate() {
+        write!(
+            out,
+            "M  V30 {} {} {} {}",
+            b + 1,
+            bond.bond_order,
+            bond.atom1 + 1,
+            bond.atom2 + 1
+        )
+        .unwrap();
+        let cfg = match molecule.bond_stereo.get(b) {
+            Some(1) => 1,
+            Some(6) => 3,
+            Some(3 | 4) => 2,
+            _ => 0,
+        };
+        if cfg != 0 {
+            write!(out, " CFG={cfg}").unwrap();
+        }
+        out.push('\n');
+    }
+    out.push_str("M  V30 END BOND\nM  V30 END CTAB\n");
+}
+
+/// Read every molecule in the MOL2 file at `path`
+pub fn read_mol2(
+    path: impl AsRef<Path>,
+) -> Result<Vec<FileMolecule>, Box<dyn Error>> {
+    Ok(parse_mol2(&read_to_string(path)?)?)
+}
+
+/// Parse the contents of a MOL2 file. Partial charges are read unless the
+/// charge type is `NO_CHARGES`. MOL2 files do not record formal charges, so
+/// every atom is read as neutral.
+pub fn parse_mol2(contents: &str) -> Result<Vec<FileMolecule>, MolFileError> {
+    let mut records = Vec::new();
+    let mut section = "";
+    let mut header = Vec::new();
+    let mut atoms = Vec::new();
+    let mut conformer = Vec::new();
+    let mut charges = Vec::new();
+    let mut bonds = Vec::new();
+
+    let mut finish = |header: &mut Vec<String>,
+                      atoms: &mut Vec<Atom>,
+                      conformer: &mut Vec<f64>,
+                      charges: &mut Vec<f64>,
+                      bonds: &mut Vec<Bond>| {
+        if header.is_empty() {
+            return;
+        }
+        for bond in bonds.iter() {
+            if bond.is_aromatic {
+                atoms[bond.atom1].is_aromatic = true;
+                atoms[bond.atom2].is_aromatic = true;
+            }
+        }
+        let mut graph =
+            MoleculeGraph::new(std::mem::take(atoms), std::mem::take(bonds));
+        graph.conformers = vec![std::mem::take(conformer)];
+        let charges = std::mem::take(charges);
+        if header.get(3).map(|s| s.trim()) != Some("NO_CHARGES") {
+            graph.partial_charges = Some(charges);
+        }
+        records.push(FileMolecule::new(header[0].trim(), graph));
+        header.clear();
+    };
+
+    for (l, line) in contents.lines().enumerate() {
+        let line_no = l + 1;
+        if let Some(name) = line.strip_prefix("@<TRIPOS>") {
+            if name == "MOLECULE" {
+                finish(
+                    &mut header,
+                    &mut atoms,
+                    &mut conformer,
+                    &mut charges,
+                    &mut bonds,
+                );
+            }
+            section = match name.trim() {
+                "MOLECULE" => "MOLECULE",
+                "ATOM" => "ATOM",
+                "BOND" => "BOND",
+                _ => "",
+            };
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match section {
+            "MOLECULE" => header.push(line.to_owned()),
+            "ATOM" if !fields.is_empty() => {
+                let &[_, _, x, y, z, typ, ref rest @ ..] = fields.as_slice()
+                else {
+                    return Err(err(line_no, "truncated atom line"));
+                };
+                let element = typ.split('.').next().unwrap();
+                let atomic_number =
+                    atomic_number(element).ok_or_else(|| {
+                        err(line_no, format!("unknown atom type {typ}"))
+                    })?;
+                for s in [x, y, z] {
+                    conformer.push(s.parse().map_err(|_| {
+                        err(line_no, format!("invalid coordinate {s}"))
+                    })?);
+                }
+                let charge = match rest.get(2) {
+                    Some(s) => s.parse().map_err(|_| {
+                        err(line_no, format!("invalid charge {s}"))
+                    })?,
+                    None => 0.0,
+                };
+                charges.push(charge);
+                atoms.push(Atom {
+                    atomic_number,
+                    formal_charge: 0,
+                    is_aromatic: false,
+                });
+            }
+            "BOND" if !fields.is_empty() => {
+                let &[_, a, b, typ, ..] = fields.as_slice() else {
+                    return Err(err(line_no, "truncated bond line"));
+                };
+                let index = |s: &str| match s.parse::<usize>() {
+                    Ok(i @ 1..) if i <= atoms.len() => Ok(i - 1),
+                    _ => Err(err(line_no, format!("invalid atom {s}"))),
+                };
+                let (bond_order, is_aromatic) = match typ {
+                    "ar" => (1, true),
+                    "am" => (1, false),
+                    t => (
+                        t.parse().map_err(|_| {
+                            err(line_no, format!("invalid bond type {t}"))
+                        })?,
+                        false,
+                    ),
+                };
+                bonds.push(Bond {
+                    atom1: index(a)?,
+                    atom2: index(b)?,
+                    bond_order,
+                    is_aromatic,
+                });
+            }
+            _ => {}
+        }
+    }
+    finish(
+        &mut header,
+        &mut atoms,
+        &mut conformer,
+        &mut charges,
+        &mut bonds,
+    );
+    Ok(group(records))
+}
+
+/// Return the SYBYL atom type of `atom` in `graph`
+fn sybyl_type(graph: &MoleculeGraph, atom: usize) -> String {
+    let a = &graph.atoms[atom];
+    let element = symbol(a.atomic_number).unwrap_or("Du");
+    let max_order = graph
+        .adjacent(atom)
+        .iter()
+        .map(|&(_, b)| graph.bonds[b].bond_order)
+        .max()
+        .unwrap_or(0);
+    let suffix = match a.atomic_number {
+        _ if a.is_aromatic && matches!(a.atomic_number, 6 | 7) => ".ar",
+        6 | 7 => match max_order {
+            3 => ".1",
+            2 => ".2",
+            _ if a.atomic_number == 7 && graph.degree(atom) == 4 => ".4",
+            _ => ".3",
+        },
+        8 if max_order == 2 => ".2",
+        8 | 15 | 16 => ".3",
+        _ => "",
+    };
+    format!("{element}{suffix}")
+}
+
+/// Write `molecules` as a MOL2 file with one record per conformer, including
+/// partial charges when they have been assigned
+pub fn write_mol2(molecules: &[FileMolecule]) -> String {
+    let mut ret = String::new();
+    for molecule in molecules {
+        let graph = &molecule.graph;
+        let charge_type = match graph.partial_charges {
+            Some(_) => "USER_CHARGES",
+            None => "NO_CHARGES",
+        };
+        for conformer in &graph.conformers {
+            writeln!(
+                ret,
+                "@<TRIPOS>MOLECULE\n{}\n{} {} 1 0 0\nSMALL\n{charge_type}\n",
+                molecule.name,
+                graph.n_atoms(),
+                graph.bonds.len()
+            )
+            .unwrap();
+            ret.push_str("@<TRIPOS>ATOM\n");
+            for (i, atom) in graph.atoms.iter().enumerate() {
+                let [x, y, z] = [0, 1, 2].map(|c| conformer[3 * i + c]);
+                let charge =
+                    graph.partial_charges.as_ref().map_or(0.0, |q| q[i]);
+                writeln!(
+                    ret,
+                    "{:>7} {:<8}{x:>10.4}{y:>10.4}{z:>10.4} {:<6}{:>5} \
+                     {:<8}{charge:>10.4}",
+                    i + 1,
+                    format!(
+                        "{}{}",
+                        symbol(atom.atomic_number).unwrap_or("Du"),
+                        i + 1
+                    ),
+                    sybyl_type(graph, i),
+                    1,
+                    "UNL1",
+                )
+                .unwrap();
+            }
+            ret.push_str("@<TRIPOS>BOND\n");
+            for (b, bond) in graph.bonds.iter().enumerate() {
+                let typ = if bond.is_aromatic
+                    && graph.atoms[bond.atom1].is_aromatic
+                    && graph.atoms[bond.atom2].is_aromatic
+                {
+                    "ar".to_owned()
+                } else {
+                    bond.bond_order.to_string()
+                };
+                writeln!(
+                    ret,
+                    "{:>6}{:>6}{:>6} {typ}",
+                    b + 1,
+                    bond.atom1 + 1,
+                    bond.atom2 + 1
+                )
+                .unwrap();
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::topology::smiles::chiral_volume;
+
+    use super::*;
+
+    /// acetate with two conformers differing in the methyl position
+    const SDF: &str = "\
+acetate
+  RDKit          3D
+
+  4  3  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    1.5000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000    1.0000    0.0000 O   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000   -1.0000    0.0000 O   0  5  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  2  3  2  0
+  2  4  1  1
+M  END
+> <energy>
+-1.5
+
+$$$$
+acetate
+  RDKit          3D
+
+  4  3  0  0  0  0  0  0  0  0999 V2000
+    0.1000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    1.5000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000    1.0000    0.0000 O   0  0  0  0  0  0  0  0  0  0  0  0
+    2.1000   -1.0000    0.0000 O   0  0  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  2  3  2  0
+  2  4  1  1
+M  CHG  1   4  -1
+M  END
+> <energy>
+-1.25
+
+$$$$
+";
+
+    #[test]
+    fn sdf() {
+        let got = parse_sdf(SDF).unwrap();
+        assert_eq!(got.len(), 1);
+        let m = &got[0];
+        assert_eq!(m.name, "acetate");
+        assert_eq!(m.graph.conformers.len(), 2);
+        assert_eq!(m.graph.conformers[1][0], 0.1);
+        assert_eq!(m.graph.atoms[3].formal_charge, -1);
+        assert_eq!(m.graph.bonds[1].bond_order, 2);
+        assert_eq!(m.bond_stereo, [0, 0, 1]);
+        assert_eq!(
+            m.properties[1],
+            [("energy".to_owned(), "-1.25".to_owned())]
+        );
+
+        // both formats round trip
+        assert_eq!(parse_sdf(&write_sdf(&got)).unwrap(), got);
+        let mut out = String::new();
+        write_v3000(&mut out, m, &m.graph.conformers[0]);
+        let v3000 = format!("acetate\n\n\n{out}M  END\n");
+        let mut back = parse_sdf(&v3000).unwrap().remove(0);
+        back.properties = vec![Vec::new()];
+        let mut want = m.clone();
+        want.graph.conformers.truncate(1);
+        want.properties = vec![Vec::new()];
+        assert_eq!(back, want);
+
+        let bad = SDF.replace("  2  4  1  1", "  2  9  1  1");
+        assert_eq!(parse_sdf(&bad).unwrap_err().line, 11);
+    }
+
+    #[test]
+    fn aromatic_bonds() {
+        // pyrrole with explicit hydrogens and MDL aromatic bonds
+        let mut sdf = String::from(
+            "pyrrole\n\n\n  9  9  0  0  0  0  0  0  0  0999 V2000\n",
+        );
+        for element in ["N", "C", "C", "C", "C", "H", "H", "H", "H"] {
+            sdf.push_str(&format!(
+                "    0.0000    0.0000    0.0000 {element:<3} 0  0\n"
+            ));
+        }
+        for (i, j, typ) in [
+            (1, 2, 4),
+            (2, 3, 4),
+            (3, 4, 4),
+            (4, 5, 4),
+            (5, 1, 4),
+            (1, 6, 1),
+            (2, 7, 1),
+            (3, 8, 1),
+            (4, 9, 1),
+        ] {
+            sdf.push_str(&format!("{i:>3}{j:>3}{typ:>3}  0\n"));
+        }
+        sdf.push_str("M  END\n$$$$\n");
+        let got = parse_sdf(&sdf).unwrap().remove(0);
+        let orders: Vec<_> =
+            got.graph.bonds.iter().map(|b| b.bond_order).collect();
+        assert_eq!(orders, [1, 2, 1, 2, 1, 1, 1, 1, 1]);
+        assert!(got.graph.bonds[0].is_aromatic);
+        assert!(got.graph.atoms[0].is_aromatic);
+
+        // without the hydrogen on nitrogen there is no Kekulé structure
+        let bad = sdf
+            .replace("  9  9", "  9  8")
+            .replace("  1  6  1  0\n", "");
+        let e = parse_sdf(&bad).unwrap_err();
+        assert!(e.message.contains("Kekulé"), "{e}");
+    }
+
+    #[test]
+    fn parities() {
+        // bromochlorofluoromethane, with its parity read from the geometry:
+        // odd if the others are clockwise with bromine pointing away
+        let sdf = "\
+CHFClBr
+
+
+  5  4  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    0.0000    0.0000    1.0900 H   0  0  0  0  0  0  0  0  0  0  0  0
+    1.0000    0.0000   -0.3600 F   0  0  0  0  0  0  0  0  0  0  0  0
+   -0.5000    0.8700   -0.3600 Cl  0  0  0  0  0  0  0  0  0  0  0  0
+   -0.5000   -0.8700   -0.3600 Br  0  0  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  1  3  1  0
+  1  4  1  0
+  1  5  1  0
+M  END
+$$$$
+";
+        let mut m = parse_sdf(sdf).unwrap().remove(0);
+        let conf = &m.graph.conformers[0];
+        let odd = chiral_volume(conf, [4, 1, 2, 3]) < 0.0;
+        let from_geometry = m.graph.stereo_from_conformer(0).atoms[0];
+        // Br, Cl, F run anticlockwise with H pointing away
+        assert_eq!(from_geometry, Stereochemistry::S);
+
+        m.atom_parity[0] = if odd { 1 } else { 2 };
+        let from_parity = m.graph.stereo_from_parities(&m.atom_parity);
+        assert_eq!(from_parity.atoms[0], from_geometry);
+
+        // the parity takes precedence over the geometry
+        m.atom_parity[0] = if odd { 2 } else { 1 };
+        assert_ne!(m.stereo().atoms[0], from_geometry);
+        m.atom_parity[0] = 0;
+        assert_eq!(m.stereo().atoms[0], from_geometry);
+        m.graph.conformers.clear();
+        assert_eq!(m.stereo().atoms[0], Stereochemistry::None);
+    }
+
+    #[test]
+    fn wedges() {
+        // the parities test molecule drawn flat, with hydrogen inside the
+        // triangle of the others
+        let sdf = "\
+CHFClBr
+
+
+  5  4  0  0  0  0  0  0  0  0999 V2000
+    0.0000    0.0000    0.0000 C   0  0  0  0  0  0  0  0  0  0  0  0
+    0.3000    0.4000    0.0000 H   0  0  0  0  0  0  0  0  0  0  0  0
+    1.0000    0.0000    0.0000 F   0  0  0  0  0  0  0  0  0  0  0  0
+   -0.5000    0.8700    0.0000 Cl  0  0  0  0  0  0  0  0  0  0  0  0
+   -0.5000   -0.8700    0.0000 Br  0  0  0  0  0  0  0  0  0  0  0  0
+  1  2  1  0
+  1  3  1  0
+  1  4  1  0
+  1  5  1  0
+M  END
+$$$$
+";
+        let mut m = parse_sdf(sdf).unwrap().remove(0);
+        assert_eq!(
+            m.graph.stereo_from_conformer(0).atoms[0],
+            Stereochemistry::None
+        );
+        assert_eq!(m.stereo().atoms[0], Stereochemistry::None);
+
+        // hydrogen towards the viewer, as in the 3D geometry
+        m.bond_stereo[0] = 1;
+        assert_eq!(m.stereo().atoms[0], Stereochemistry::S);
+        m.bond_stereo[0] = 6;
+        assert_eq!(m.stereo().atoms[0], Stereochemistry::R);
+        // the wedges only apply to flat conformers
+        m.graph.conformers[0][5] = 1.09;
+        assert_eq!(m.stereo().atoms[0], Stereochemistry::S);
+    }
+
+    #[test]
+    fn mol2() {
+        let mut m = parse_sdf(SDF).unwrap().remove(0);
+        m.graph.partial_charges = Some(vec![-0.2, 0.6, -0.7, -0.7]);
+        let text = write_mol2(&[m.clone()]);
+        assert!(text.contains("C.2"), "{text}");
+        assert!(text.contains("O.2"), "{text}");
+        let got = parse_mol2(&text).unwrap();
+        assert_eq!(got.len(), 1);
+        let got = &got[0];
+        assert_eq!(got.graph.conformers, m.graph.conformers);
+        assert_eq!(got.graph.partial_charges, m.graph.partial_charges);
+        assert_eq!(got.graph.bonds, m.graph.bonds);
+        // formal charges are not stored in MOL2
+        assert_eq!(got.graph.atoms[3].formal_charge, 0);
+    }
+}
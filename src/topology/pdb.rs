@@ -0,0 +1,470 @@
+//! Reading [Topology]s from PDB files. Only the first model is read, and bonds
+//! come from CONECT records, so every bond in the file must be listed there.
+
+use std::{
+    collections::HashMap, error::Error, fmt::Display, fs::read_to_string,
+    path::Path,
+};
+
+use ligand::molecule::Molecule;
+
+use crate::utils::elements::atomic_number;
+
+use super::{
+    molecule::{Atom, Bond, MoleculeGraph},
+    stereo::StereoAssignment,
+    AtomHierarchy, LengthUnit, Topology,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum PdbError {
+    /// a record could not be parsed
+    Parse { line: usize, message: String },
+
+    /// a connected component matched none of the templates. Each entry
+    /// describes one of its atoms
+    Unmatched { atoms: Vec<String> },
+
+    /// residues or atoms missing from the residue library, each described
+    /// by one entry
+    UnrecognizedResidues { residues: Vec<String> },
+}
+
+impl Error for PdbError {}
+
+impl Display for PdbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PdbError::Parse { line, message } => {
+                write!(f, "PDB line {line}: {message}")
+            }
+            PdbError::Unmatched { atoms } => write!(
+                f,
+                "no molecule template matches the component with atoms {}",
+                atoms.join(", ")
+            ),
+            PdbError::UnrecognizedResidues { residues } => {
+                write!(f, "unrecognized residues: {}", residues.join("; "))
+            }
+        }
+    }
+}
+
+/// An ATOM or HETATM record
+#[derive(Clone, Debug, PartialEq)]
+pub struct PdbAtom {
+    pub serial: usize,
+    pub hierarchy: AtomHierarchy,
+    pub atomic_number: u8,
+
+    /// the position in angstroms
+    pub position: [f64; 3],
+}
+
+impl Display for PdbAtom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let h = &self.hierarchy;
+        write!(
+            f,
+            "{} {} {}{} {}",
+            self.serial, h.name, h.residue_name, h.residue_number, h.chain_id
+        )
+    }
+}
+
+/// The contents of a PDB file relevant to building a [Topology]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PdbFile {
+    pub atoms: Vec<PdbAtom>,
+
+    /// pairs of indices into `atoms` from the CONECT records, each listed
+    /// once with the lower index first
+    pub bonds: Vec<(usize, usize)>,
+
+    /// the box vectors from the CRYST1 record, in angstroms
+    pub box_vectors: Option<[[f64; 3]; 3]>,
+}
+
+/// Return the trimmed contents of the 1-based, inclusive `columns` of `line`,
+/// which may be short
+fn columns(line: &str, start: usize, end: usize) -> &str {
+    let end = end.min(line.len());
+    line.get(start - 1..end).unwrap_or("").trim()
+}
+
+/// Guess the element of an ATOM or HETATM record without one in columns 77-78
+/// from its atom name. Element symbols are right-justified in columns 13-14,
+/// so a name starting in column 13 like `CL1` or `FE` begins with a
+/// two-letter element, unless it is a four-character name like `HG21`, which
+/// has to start there anyway. Otherwise the element is the first letter.
+fn element_from_name(line: &str) -> String {
+    let name = columns(line, 13, 16);
+    let two = columns(line, 13, 14);
+    if name.len() < 4
+        && two.len() == 2
+        && two.chars().all(|c| c.is_ascii_alphabetic())
+        && atomic_number(two).is_some()
+    {
+        return two.to_owned();
+    }
+    name.trim_start_matches(|c: char| c.is_ascii_digit())
+        .chars()
+        .take_while(char::is_ascii_alphabetic)
+        .take(1)
+        .collect()
+}
+
+/// Convert unit cell lengths in angstroms and angles in degrees to box
+/// vectors, with the first vector along x and the second in the xy plane
+pub fn box_vectors(lengths: [f64; 3], angles: [f64; 3]) -> [[f64; 3]; 3] {
+    let [a, b, c] = lengths;
+    let [alpha, beta, gamma] = angles.map(f64::to_radians);
+    // snap the cosines of right angles to zero
+    let cos = |x: f64| {
+        let c = x.cos();
+        if c.abs() < 1e-12 {
+            0.0
+        } else {
+            c
+        }
+    };
+    let cx = c * cos(beta);
+    let cy = c * (cos(alpha) - cos(beta) * cos(gamma)) / gamma.sin();
+    [
+        [a, 0.0, 0.0],
+        [b * cos(gamma), b * gamma.sin(), 0.0],
+        [cx, cy, (c * c - cx * cx - cy * cy).sqrt()],
+    ]
+}
+
+impl PdbFile {
+    pub fn parse(contents: &str) -> Result<Self, PdbError> {
+        let mut ret = Self::default();
+        let mut conect = Vec::new();
+        for (l, line) in contents.lines().enumerate() {
+            let err = |message: String| PdbError::Parse {
+                line: l + 1,
+                message,
+            };
+            let record = columns(line, 1, 6);
+            match record {
+                "ATOM" | "HETATM" => {
+                    let coord = |start| {
+                        let s = columns(line, start, start + 7);
+                        s.parse::<f64>()
+                            .map_err(|_| err(format!("invalid coordinate {s}")))
+                    };
+                    let serial = columns(line, 7, 11);
+                    let serial = serial
+                        .parse()
+                        .map_err(|_| err(format!("invalid serial {serial}")))?;
+                    let resnum = columns(line, 23, 26);
+                    let residue_number = resnum.parse().map_err(|_| {
+                        err(format!("invalid residue number {resnum}"))
+                    })?;
+                    let name = columns(line, 13, 16);
+                    let element = match columns(line, 77, 78) {
+                        "" => element_from_name(line),
+                        e => e.to_owned(),
+                    };
+                    let atomic_number =
+                        atomic_number(&element).ok_or_else(|| {
+                            err(format!("unknown element {element}"))
+                        })?;
+                    ret.atoms.push(PdbAtom {
+                        serial,
+                        hierarchy: AtomHierarchy {
+                            name: name.to_owned(),
+                            residue_name: columns(line, 18, 20).to_owned(),
+                            residue_number,
+                            chain_id: columns(line, 22, 22).to_owned(),
+                        },
+                        atomic_number,
+                        position: [coord(31)?, coord(39)?, coord(47)?],
+                    });
+                }
+                "CONECT" => {
+                    let serials = [7, 12, 17, 22, 27]
+                        .into_iter()
+                        .map(|start| columns(line, start, start + 4))
+                        .filter(|s| !s.is_empty())
+                        .map(|s| {
+                            s.parse::<usize>().map_err(|_| {
+                                err(format!("invalid CONECT serial {s}"))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if let Some((&first, rest)) = serials.split_first() {
+                        for &other in rest {
+                            conect.push((l + 1, first, other));
+                        }
+                    }
+                }
+                "CRYST1" => {
+                    let field = |start, end| {
+                        let s = columns(line, start, end);
+                        s.parse::<f64>()
+                            .map_err(|_| err(format!("invalid CRYST1 {s}")))
+                    };
+                    ret.box_vectors = Some(box_vectors(
+                        [field(7, 15)?, field(16, 24)?, field(25, 33)?],
+                        [field(34, 40)?, field(41, 47)?, field(48, 54)?],
+                    ));
+                }
+                "ENDMDL" => break,
+                _ => {}
+            }
+        }
+
+        let index: HashMap<usize, usize> = ret
+            .atoms
+            .iter()
+            .enumerate()
+            .map(|(i, a)| (a.serial, i))
+            .collect();
+        for (line, a, b) in conect {
+            let lookup = |s| {
+                index.get(&s).copied().ok_or_else(|| PdbError::Parse {
+                    line,
+                    message: format!("CONECT to unknown atom {s}"),
+                })
+            };
+            let (i, j) = (lookup(a)?, lookup(b)?);
+            ret.bonds.push((i.min(j), i.max(j)));
+        }
+        ret.bonds.sort();
+        ret.bonds.dedup();
+        Ok(ret)
+    }
+
+    /// Return the connected components of the bond graph, each as a sorted
+    /// list of atom indices, ordered by their first atom
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        let all: Vec<usize> = (0..self.atoms.len()).collect();
+        self.component_graph(&all).components()
+    }
+
+    /// Build the element-only graph of `component`, with single bonds
+    fn component_graph(&self, component: &[usize]) -> MoleculeGraph {
+        let local: HashMap<usize, usize> =
+            component.iter().enumerate().map(|(l, &i)| (i, l)).collect();
+        let atoms = component
+            .iter()
+            .map(|&i| Atom {
+                atomic_number: self.atoms[i].atomic_number,
+                formal_charge: 0,
+                is_aromatic: false,
+            })
+            .collect();
+        let bonds = self
+            .bonds
+            .iter()
+            .filter_map(|(i, j)| {
+                Some(Bond {
+                    atom1: *local.get(i)?,
+                    atom2: *local.get(j)?,
+                    bond_order: 1,
+                    is_aromatic: false,
+                })
+            })
+            .collect();
+        MoleculeGraph::new(atoms, bonds)
+    }
+}
+
+/// Return a copy of `template` without conformers whose atom `i` is atom
+/// `map[i]` of `template`
+fn permuted(template: &MoleculeGraph, map: &[usize]) -> MoleculeGraph {
+    let mut inverse = vec![0; map.len()];
+    for (i, &t) in map.iter().enumerate() {
+        inverse[t] = i;
+    }
+    let atoms = map.iter().map(|&t| template.atoms[t].clone()).collect();
+    let bonds = template
+        .bonds
+        .iter()
+        .map(|b| Bond {
+            atom1: inverse[b.atom1],
+            atom2: inverse[b.atom2],
+            ..b.clone()
+        })
+        .collect();
+    let mut ret = MoleculeGraph::new(atoms, bonds);
+    ret.partial_charges = template
+        .partial_charges
+        .as_ref()
+        .map(|q| map.iter().map(|&t| q[t]).collect());
+    ret.stereo = template.stereo.as_ref().map(|s| StereoAssignment {
+        atoms: map.iter().map(|&t| s.atoms[t]).collect(),
+        bonds: s.bonds.clone(),
+    });
+    ret
+}
+
+impl Topology {
+    /// Read a topology from the PDB file at `path`. Each connected component
+    /// of the file is matched to one of `unique_molecules` by elements and
+    /// connectivity, so bond orders and formal charges come from the matching
+    /// template while positions, box vectors, and residue information come
+    /// from the file. See [Topology::from_pdb_file].
+    pub fn from_pdb(
+        path: impl AsRef<Path>,
+        unique_molecules: &[Molecule],
+    ) -> Result<Self, Box<dyn Error>> {
+        let pdb = PdbFile::parse(&read_to_string(path)?)?;
+        let templates: Vec<_> =
+            unique_molecules.iter().map(MoleculeGraph::from).collect();
+        Ok(Self::from_pdb_file(&pdb, &templates)?)
+    }
+
+    /// Build a topology from `pdb` by matching each of its connected
+    /// components to one of `templates`. Atoms keep their order in the file,
+    /// with the matching template permuted to that order, so components that
+    /// list the atoms of the same template in different orders are added as
+    /// different entries of [Topology::molecules]. Only templates matching
+    /// some component are added.
+    pub fn from_pdb_file(
+        pdb: &PdbFile,
+        templates: &[MoleculeGraph],
+    ) -> Result<Self, PdbError> {
+        let mut ret = Self::default();
+        // the distinct molecule for each template and atom mapping
+        let mut added = HashMap::new();
+        // the PDB atom at each topology atom
+        let mut order = Vec::with_capacity(pdb.atoms.len());
+        for component in pdb.components() {
+            let graph = pdb.component_graph(&component);
+            let Some((t, map)) =
+                templates.iter().enumerate().find_map(|(t, tmpl)| {
+                    Some((t, graph.element_isomorphism(tmpl)?))
+                })
+            else {
+                return Err(PdbError::Unmatched {
+                    atoms: component
+                        .iter()
+                        .map(|&i| pdb.atoms[i].to_string())
+                        .collect(),
+                });
+            };
+            match added.get(&(t, map.clone())) {
+                Some(&m) => ret.add_copies(m, 1).unwrap(),
+                None => {
+                    let m = ret.add_molecule(permuted(&templates[t], &map));
+                    added.insert((t, map), m);
+                }
+            }
+            order.extend(component);
+        }
+
+        let positions = order.iter().map(|&i| pdb.atoms[i].position).collect();
+        ret.set_positions(positions, LengthUnit::Angstrom).unwrap();
+        let hierarchy = order
+            .iter()
+            .map(|&i| pdb.atoms[i].hierarchy.clone())
+            .collect();
+        ret.set_hierarchy(hierarchy).unwrap();
+        ret.set_box_vectors(pdb.box_vectors, LengthUnit::Angstrom);
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::water;
+
+    use super::*;
+
+    const PDB: &str = "\
+CRYST1   20.000   20.000   30.000  90.00  90.00  90.00 P 1           1
+HETATM    1  O   HOH A   1       0.000   0.000   0.000  1.00  0.00           O
+HETATM    2  H1  HOH A   1       0.760   0.590   0.000  1.00  0.00           H
+HETATM    3  H2  HOH A   1      -0.760   0.590   0.000  1.00  0.00           H
+HETATM    4  H1  HOH A   2       5.760   0.590   0.000  1.00  0.00
+HETATM    5  O   HOH A   2       5.000   0.000   0.000  1.00  0.00
+HETATM    6  H2  HOH A   2       4.240   0.590   0.000  1.00  0.00
+CONECT    1    2    3
+CONECT    4    5
+CONECT    5    6
+END
+";
+
+    #[test]
+    fn parse() {
+        let pdb = PdbFile::parse(PDB).unwrap();
+        assert_eq!(pdb.atoms.len(), 6);
+        assert_eq!(pdb.atoms[3].atomic_number, 1);
+        assert_eq!(pdb.atoms[4].hierarchy.residue_number, 2);
+        assert_eq!(pdb.bonds, [(0, 1), (0, 2), (3, 4), (4, 5)]);
+        assert_eq!(pdb.components(), [vec![0, 1, 2], vec![3, 4, 5]]);
+        assert_eq!(pdb.box_vectors.unwrap()[2], [0.0, 0.0, 30.0]);
+
+        // two-letter elements, from columns 77-78 or from the atom name
+        let ions = "\
+HETATM    1 CL    CL A   1       0.000   0.000   0.000  1.00  0.00          CL
+HETATM    2 CL1  LIG A   2       1.000   0.000   0.000  1.00  0.00
+HETATM    3  CA  ALA A   3       2.000   0.000   0.000  1.00  0.00
+HETATM    4 HG21 THR A   4       3.000   0.000   0.000  1.00  0.00
+";
+        let ions = PdbFile::parse(ions).unwrap();
+        let elements: Vec<_> =
+            ions.atoms.iter().map(|a| a.atomic_number).collect();
+        assert_eq!(elements, [17, 17, 6, 1]);
+
+        let v = box_vectors([10.0, 10.0, 10.0], [90.0, 90.0, 60.0]);
+        assert!((v[1][0] - 5.0).abs() < 1e-12);
+        assert!((v[1][1] - 75f64.sqrt()).abs() < 1e-12);
+
+        let bad = PDB.replace("CONECT    5    6", "CONECT    5    9");
+        assert!(matches!(
+            PdbFile::parse(&bad),
+            Err(PdbError::Parse { line: 10, .. })
+        ));
+    }
+
+    #[test]
+    fn from_template() {
+        let pdb = PdbFile::parse(PDB).unwrap();
+        let top = Topology::from_pdb_file(&pdb, &[water()]).unwrap();
+        assert_eq!(top.n_copies(), 2);
+
+        // the waters keep their atom order in the file, so the template,
+        // ordered H, O, H, is permuted for the first
+        assert_eq!(top.molecules.len(), 2);
+        let elements = |m: &MoleculeGraph| -> Vec<u8> {
+            m.atoms.iter().map(|a| a.atomic_number).collect()
+        };
+        assert_eq!(elements(&top.molecules[0]), [8, 1, 1]);
+        assert_eq!(elements(&top.molecules[1]), [1, 8, 1]);
+        assert_eq!(top.molecules[0].bonds.len(), 2);
+        assert!(top.molecules[0]
+            .bonds
+            .iter()
+            .all(|b| b.atom1 == 0 || b.atom2 == 0));
+        let positions = top.positions(LengthUnit::Angstrom).unwrap();
+        assert_eq!(positions[4], [5.0, 0.0, 0.0]);
+        let hierarchy = top.hierarchy().unwrap();
+        assert_eq!(hierarchy[0].name, "O");
+        assert_eq!(hierarchy[4].name, "O");
+        assert_eq!(top.box_vectors(LengthUnit::Nanometer).unwrap()[0][0], 2.0);
+
+        // a third water in the order of the second is another copy
+        let mut three = pdb.clone();
+        for i in 3..6 {
+            let mut atom = three.atoms[i].clone();
+            atom.position[0] += 5.0;
+            three.atoms.push(atom);
+        }
+        three.bonds.extend([(6, 7), (7, 8)]);
+        let top = Topology::from_pdb_file(&three, &[water()]).unwrap();
+        assert_eq!(top.molecules.len(), 2);
+        assert_eq!(top.n_copies(), 3);
+
+        let mut sulfide = water();
+        sulfide.atoms[1].atomic_number = 16;
+        let err = Topology::from_pdb_file(&pdb, &[sulfide]).unwrap_err();
+        let PdbError::Unmatched { atoms } = err else {
+            panic!("{err:?}");
+        };
+        assert_eq!(atoms[0], "1 O HOH1 A");
+    }
+}
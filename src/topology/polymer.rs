@@ -0,0 +1,486 @@
+//! Reading biopolymer [Topology]s from PDB files without molecule templates.
+//! Bonds between heavy atoms come from the bundled residue library in
+//! `residues.txt`, which covers the standard amino acids, ACE and NME caps,
+//! nucleic acids, and water. Hydrogens, which must all be present, are bonded
+//! to the nearest heavy atom in their residue, and peptide, phosphodiester,
+//! and disulfide bonds between residues are found by distance. The
+//! protonation state of each residue follows from its hydrogens: double bonds
+//! are moved away from protonated atoms where the residue allows it, and
+//! formal charges are assigned from the resulting valences.
+
+use std::{collections::HashMap, error::Error, fs::read_to_string, path::Path};
+
+use super::{
+    molecule::{Atom, Bond, MoleculeGraph},
+    pdb::{PdbError, PdbFile},
+    LengthUnit, Topology,
+};
+
+/// The bundled residue library
+const RESIDUES: &str = include_str!("residues.txt");
+
+/// The longest bond from a hydrogen to its heavy atom, in angstroms
+const MAX_HYDROGEN_DISTANCE: f64 = 1.5;
+
+/// The longest peptide or phosphodiester bond between residues, in angstroms
+const MAX_LINK_DISTANCE: f64 = 2.0;
+
+/// The longest disulfide bond, in angstroms
+const MAX_DISULFIDE_DISTANCE: f64 = 2.5;
+
+/// Residue names for alternative protonation states and naming conventions,
+/// and the library residue they map to
+const ALIASES: [(&str, &str); 22] = [
+    ("HID", "HIS"),
+    ("HIE", "HIS"),
+    ("HIP", "HIS"),
+    ("HSD", "HIS"),
+    ("HSE", "HIS"),
+    ("HSP", "HIS"),
+    ("CYX", "CYS"),
+    ("CYM", "CYS"),
+    ("ASH", "ASP"),
+    ("GLH", "GLU"),
+    ("LYN", "LYS"),
+    ("NMA", "NME"),
+    ("WAT", "HOH"),
+    ("SOL", "HOH"),
+    ("TIP", "HOH"),
+    ("RA", "A"),
+    ("RC", "C"),
+    ("RG", "G"),
+    ("RU", "U"),
+    ("ADE", "A"),
+    ("CYT", "C"),
+    ("GUA", "G"),
+];
+
+/// Monatomic ions by residue name, and their formal charges
+const IONS: [(&str, i8); 8] = [
+    ("LI", 1),
+    ("NA", 1),
+    ("K", 1),
+    ("MG", 2),
+    ("CA", 2),
+    ("ZN", 2),
+    ("CL", -1),
+    ("BR", -1),
+];
+
+/// The heavy-atom bonds of a library residue as pairs of atom names and bond
+/// orders
+type Template = Vec<(&'static str, &'static str, u8)>;
+
+fn library() -> HashMap<&'static str, Template> {
+    let mut ret = HashMap::new();
+    for line in RESIDUES.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, bonds) = line.split_once(':').unwrap();
+        let template = bonds
+            .split_whitespace()
+            .map(|bond| {
+                if let Some((a, b)) = bond.split_once('=') {
+                    (a, b, 2)
+                } else if let Some((a, b)) = bond.split_once('-') {
+                    (a, b, 1)
+                } else {
+                    // a lone atom
+                    (bond, bond, 0)
+                }
+            })
+            .collect();
+        ret.insert(name, template);
+    }
+    ret
+}
+
+/// Return the library name of residue `name`, resolving [ALIASES] and the
+/// terminal suffixes of Amber nucleic acid names like `DA5`
+fn library_name<'a>(
+    name: &'a str,
+    library: &HashMap<&str, Template>,
+) -> &'a str {
+    if let Some((_, to)) = ALIASES.iter().find(|(from, _)| *from == name) {
+        return to;
+    }
+    if !library.contains_key(name) {
+        if let Some(stem) = name.strip_suffix(['5', '3']) {
+            if library.contains_key(stem) {
+                return stem;
+            }
+        }
+    }
+    name
+}
+
+/// The formal charge of an atom with `atomic_number` whose bond orders sum
+/// to `valence`
+fn valence_charge(atomic_number: u8, valence: u8) -> i8 {
+    let valence = valence as i8;
+    match atomic_number {
+        7 => valence - 3,
+        8 => valence - 2,
+        16 if valence < 2 => valence - 2,
+        _ => 0,
+    }
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2))
+        .sqrt()
+}
+
+/// Build the chemical graph of the whole file, with formal charges, or
+/// return a description of each problem residue
+fn polymer_graph(pdb: &PdbFile) -> Result<MoleculeGraph, Vec<String>> {
+    let library = library();
+    let atoms = &pdb.atoms;
+
+    // consecutive atoms sharing a chain, residue number, and name
+    let mut residues: Vec<Vec<usize>> = Vec::new();
+    for i in 0..atoms.len() {
+        let same = |j: usize| {
+            let (a, b) = (&atoms[i].hierarchy, &atoms[j].hierarchy);
+            a.chain_id == b.chain_id
+                && a.residue_number == b.residue_number
+                && a.residue_name == b.residue_name
+        };
+        match residues.last_mut() {
+            Some(r) if same(r[0]) => r.push(i),
+            _ => residues.push(vec![i]),
+        }
+    }
+
+    let mut problems = Vec::new();
+    let mut charges = vec![None; atoms.len()];
+    let mut bonds: Vec<(usize, usize, u8)> = Vec::new();
+    let mut residue_of = vec![0; atoms.len()];
+    for (r, residue) in residues.iter().enumerate() {
+        let h = &atoms[residue[0]].hierarchy;
+        let label =
+            format!("{}{} {}", h.residue_name, h.residue_number, h.chain_id);
+        for &i in residue {
+            residue_of[i] = r;
+        }
+
+        if let (&[i], Some((_, charge))) = (
+            residue.as_slice(),
+            IONS.iter().find(|(name, _)| *name == h.residue_name),
+        ) {
+            charges[i] = Some(*charge);
+            continue;
+        }
+        let Some(template) =
+            library.get(library_name(&h.residue_name, &library))
+        else {
+            problems.push(format!("{label}: unrecognized residue"));
+            continue;
+        };
+
+        let (hydrogens, heavy): (Vec<usize>, Vec<usize>) =
+            residue.iter().partition(|&&i| atoms[i].atomic_number == 1);
+        let names: HashMap<&str, usize> = heavy
+            .iter()
+            .map(|&i| (atoms[i].hierarchy.name.as_str(), i))
+            .collect();
+        for &i in &heavy {
+            let name = atoms[i].hierarchy.name.as_str();
+            if !template.iter().any(|&(a, b, _)| a == name || b == name) {
+                problems.push(format!("{label}: unrecognized atom {name}"));
+            }
+        }
+        for &(a, b, order) in template {
+            if let (Some(&i), Some(&j), true) =
+                (names.get(a), names.get(b), order > 0)
+            {
+                bonds.push((i, j, order));
+            }
+        }
+        for i in hydrogens {
+            let nearest = heavy
+                .iter()
+                .map(|&j| (distance(atoms[i].position, atoms[j].position), j))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            match nearest {
+                Some((d, j)) if d <= MAX_HYDROGEN_DISTANCE => {
+                    bonds.push((j, i, 1))
+                }
+                _ => problems.push(format!(
+                    "{label}: no heavy atom for hydrogen {}",
+                    atoms[i].hierarchy.name
+                )),
+            }
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    // bonds between residues
+    let named = |name: &str| -> Vec<usize> {
+        (0..atoms.len())
+            .filter(|&i| atoms[i].hierarchy.name == name)
+            .collect()
+    };
+    for (a, b, max) in [
+        ("C", "N", MAX_LINK_DISTANCE),
+        ("O3'", "P", MAX_LINK_DISTANCE),
+        ("SG", "SG", MAX_DISULFIDE_DISTANCE),
+    ] {
+        let (from, to) = (named(a), named(b));
+        for &i in &from {
+            for &j in &to {
+                // disulfides are found once from each end
+                if residue_of[i] == residue_of[j] || (a == b && i > j) {
+                    continue;
+                }
+                if distance(atoms[i].position, atoms[j].position) <= max {
+                    bonds.push((i, j, 1));
+                }
+            }
+        }
+    }
+
+    let mut graph = MoleculeGraph::new(
+        atoms
+            .iter()
+            .map(|a| Atom {
+                atomic_number: a.atomic_number,
+                formal_charge: 0,
+                is_aromatic: false,
+            })
+            .collect(),
+        bonds
+            .into_iter()
+            .map(|(atom1, atom2, bond_order)| Bond {
+                atom1,
+                atom2,
+                bond_order,
+                is_aromatic: false,
+            })
+            .collect(),
+    );
+
+    // a double bond to a protonated nitrogen or oxygen moves to an
+    // unprotonated atom of the same element on the other end, as in the
+    // neutral forms of histidine, aspartate, and glutamate
+    for b in 0..graph.bonds.len() {
+        let bond = &graph.bonds[b];
+        if bond.bond_order != 2 {
+            continue;
+        }
+        for (x, y) in [(bond.atom1, bond.atom2), (bond.atom2, bond.atom1)] {
+            let element = graph.atoms[y].atomic_number;
+            if !matches!(element, 7 | 8) || graph.n_hydrogens(y) == 0 {
+                continue;
+            }
+            let sibling = graph.adjacent(x).iter().find(|&&(z, c)| {
+                z != y
+                    && graph.atoms[z].atomic_number == element
+                    && graph.n_hydrogens(z) == 0
+                    && graph.bonds[c].bond_order == 1
+            });
+            if let Some(&(_, c)) = sibling {
+                graph.bonds[b].bond_order = 1;
+                graph.bonds[c].bond_order = 2;
+                break;
+            }
+        }
+    }
+
+    for (i, charge) in charges.into_iter().enumerate() {
+        let valence = graph
+            .adjacent(i)
+            .iter()
+            .map(|&(_, b)| graph.bonds[b].bond_order)
+            .sum();
+        graph.atoms[i].formal_charge = charge.unwrap_or_else(|| {
+            valence_charge(graph.atoms[i].atomic_number, valence)
+        });
+    }
+    Ok(graph)
+}
+
+impl Topology {
+    /// Read a biopolymer topology from the PDB file at `path`. See
+    /// [Topology::from_polymer_pdb_file].
+    pub fn from_polymer_pdb(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let pdb = PdbFile::parse(&read_to_string(path)?)?;
+        Ok(Self::from_polymer_pdb_file(&pdb)?)
+    }
+
+    /// Build a topology from `pdb` using the bundled residue library. Each
+    /// connected component becomes a copy, and identical components, such as
+    /// waters, share a single entry in [Topology::molecules]. Residues or
+    /// atoms missing from the library are reported together in a
+    /// [PdbError::UnrecognizedResidues].
+    pub fn from_polymer_pdb_file(pdb: &PdbFile) -> Result<Self, PdbError> {
+        let graph = polymer_graph(pdb)
+            .map_err(|residues| PdbError::UnrecognizedResidues { residues })?;
+
+        let mut ret = Self::default();
+        let mut seen: HashMap<(Vec<Atom>, Vec<Bond>), usize> = HashMap::new();
+        let mut order = Vec::with_capacity(graph.n_atoms());
+        for component in graph.components() {
+            let local: HashMap<usize, usize> =
+                component.iter().enumerate().map(|(l, &i)| (i, l)).collect();
+            let atoms: Vec<Atom> =
+                component.iter().map(|&i| graph.atoms[i].clone()).collect();
+            let bonds: Vec<Bond> = graph
+                .bonds
+                .iter()
+                .filter(|b| local.contains_key(&b.atom1))
+                .map(|b| Bond {
+                    atom1: local[&b.atom1],
+                    atom2: local[&b.atom2],
+                    ..b.clone()
+                })
+                .collect();
+            match seen.get(&(atoms.clone(), bonds.clone())) {
+                Some(&m) => ret.add_copies(m, 1).unwrap(),
+                None => {
+                    let m = ret.add_molecule(MoleculeGraph::new(
+                        atoms.clone(),
+                        bonds.clone(),
+                    ));
+                    seen.insert((atoms, bonds), m);
+                }
+            }
+            order.extend(component);
+        }
+
+        let positions = order.iter().map(|&i| pdb.atoms[i].position).collect();
+        ret.set_positions(positions, LengthUnit::Angstrom).unwrap();
+        let hierarchy = order
+            .iter()
+            .map(|&i| pdb.atoms[i].hierarchy.clone())
+            .collect();
+        ret.set_hierarchy(hierarchy).unwrap();
+        ret.set_box_vectors(pdb.box_vectors, LengthUnit::Angstrom);
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a zwitterionic Gly-Gly dipeptide, two waters, and a sodium ion
+    fn pdb() -> String {
+        let mut ret = String::new();
+        for (serial, (name, residue, number, [x, y, z])) in [
+            ("N", "GLY", 1, [0.0, 0.0, 0.0]),
+            ("H1", "GLY", 1, [-0.5, 0.8, 0.0]),
+            ("H2", "GLY", 1, [-0.5, -0.8, 0.0]),
+            ("H3", "GLY", 1, [-0.3, 0.0, 0.9]),
+            ("CA", "GLY", 1, [1.5, 0.0, 0.0]),
+            ("HA2", "GLY", 1, [1.5, 1.0, 0.0]),
+            ("HA3", "GLY", 1, [1.5, -1.0, 0.0]),
+            ("C", "GLY", 1, [3.0, 0.0, 0.0]),
+            ("O", "GLY", 1, [3.0, 1.2, 0.0]),
+            ("N", "GLY", 2, [4.3, 0.0, 0.0]),
+            ("H", "GLY", 2, [4.3, -1.0, 0.0]),
+            ("CA", "GLY", 2, [5.8, 0.0, 0.0]),
+            ("HA2", "GLY", 2, [5.8, 1.0, 0.0]),
+            ("HA3", "GLY", 2, [5.8, -1.0, 0.0]),
+            ("C", "GLY", 2, [7.3, 0.0, 0.0]),
+            ("O", "GLY", 2, [7.3, 1.2, 0.0]),
+            ("OXT", "GLY", 2, [8.3, -0.5, 0.0]),
+            ("O", "HOH", 3, [0.0, 10.0, 0.0]),
+            ("H1", "HOH", 3, [0.76, 10.59, 0.0]),
+            ("H2", "HOH", 3, [-0.76, 10.59, 0.0]),
+            ("O", "WAT", 4, [0.0, 20.0, 0.0]),
+            ("H1", "WAT", 4, [0.76, 20.59, 0.0]),
+            ("H2", "WAT", 4, [-0.76, 20.59, 0.0]),
+            ("NA", "NA", 5, [10.0, 10.0, 10.0]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let element = if residue == "NA" { "NA" } else { &name[..1] };
+            ret.push_str(&format!(
+                "ATOM  {:>5} {name:<4} {residue:>3} A{number:>4}    \
+                 {x:>8.3}{y:>8.3}{z:>8.3}  1.00  0.00          {element:>2}\n",
+                serial + 1
+            ));
+        }
+        ret
+    }
+
+    #[test]
+    fn dipeptide() {
+        let pdb = PdbFile::parse(&pdb()).unwrap();
+        let top = Topology::from_polymer_pdb_file(&pdb).unwrap();
+        // the peptide, one water shared by both copies, and the ion
+        assert_eq!(top.molecules.len(), 3);
+        assert_eq!(top.copies(), [0, 1, 1, 2]);
+
+        let peptide = &top.molecules[0];
+        assert_eq!(peptide.n_atoms(), 17);
+        assert_eq!(peptide.bonds.len(), 16);
+        let charges: Vec<_> =
+            peptide.atoms.iter().map(|a| a.formal_charge).collect();
+        assert_eq!(charges[0], 1);
+        assert_eq!(charges[16], -1);
+        assert_eq!(charges.iter().map(|&c| c as i32).sum::<i32>(), 0);
+        // the peptide bond
+        assert_eq!(peptide.bond_between(7, 9).unwrap().bond_order, 1);
+        assert_eq!(peptide.bond_between(7, 8).unwrap().bond_order, 2);
+        assert_eq!(top.molecules[2].atoms[0].formal_charge, 1);
+        assert_eq!(top.hierarchy().unwrap()[20].residue_name, "WAT");
+    }
+
+    #[test]
+    fn protonation() {
+        // protonating the terminal carboxylate on O moves the double bond to
+        // OXT
+        let text: String = pdb()
+            .lines()
+            .flat_map(|line| {
+                let mut ret = vec![line.to_owned()];
+                if line.contains("OXT") {
+                    ret.push(format!(
+                        "ATOM  {:>5} {:<4} GLY A{:>4}    {:>8.3}{:>8.3}{:>8.3}\
+                         {:>24}",
+                        99, "HO", 2, 7.8, 2.0, 0.0, "H"
+                    ));
+                }
+                ret
+            })
+            .map(|line| line + "\n")
+            .collect();
+        let pdb = PdbFile::parse(&text).unwrap();
+        let graph = polymer_graph(&pdb).unwrap();
+        assert_eq!(graph.bond_between(14, 15).unwrap().bond_order, 1);
+        assert_eq!(graph.bond_between(14, 16).unwrap().bond_order, 2);
+        assert!(graph
+            .atoms
+            .iter()
+            .all(|a| a.atomic_number != 8 || a.formal_charge == 0));
+    }
+
+    #[test]
+    fn unrecognized() {
+        let text = pdb()
+            .replace("HOH A   3", "LIG A   3")
+            .replace("OXT  GLY", "OXX  GLY");
+        let pdb = PdbFile::parse(&text).unwrap();
+        let Err(PdbError::UnrecognizedResidues { residues }) =
+            Topology::from_polymer_pdb_file(&pdb)
+        else {
+            panic!("expected an error");
+        };
+        assert_eq!(
+            residues,
+            [
+                "GLY2 A: unrecognized atom OXX",
+                "LIG3 A: unrecognized residue"
+            ]
+        );
+    }
+}
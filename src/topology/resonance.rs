@@ -0,0 +1,230 @@
+//! Resonance structures related by moving a formal charge along a conjugated
+//! path, following the enumeration used by openff-recharge and OpenFF NAGL. A
+//! donor with a lone pair, such as `[O-]` or a neutral amine nitrogen, passes
+//! its charge to an acceptor with a double bond, such as a carbonyl oxygen or
+//! an iminium nitrogen, along a path of alternating single and double bonds,
+//! and the order of every bond on the path is flipped. Only nitrogen, oxygen
+//! and sulfur atoms act as donors and acceptors.
+//!
+//! The structures of lowest energy are those with the fewest formal charges,
+//! so a carboxylate has two and an amide only the neutral one.
+
+use std::collections::HashSet;
+
+use super::molecule::MoleculeGraph;
+
+/// The formal charges and bond orders of one resonance structure
+type Form = (Vec<i8>, Vec<u8>);
+
+impl MoleculeGraph {
+    /// Return the formal charge of each atom averaged over the resonance
+    /// structures of lowest energy. The bond orders must be Kekulé, as set by
+    /// [MoleculeGraph::kekulize].
+    pub fn average_formal_charges(&self) -> Vec<f64> {
+        let start: Form = (
+            self.atoms.iter().map(|a| a.formal_charge).collect(),
+            self.bonds.iter().map(|b| b.bond_order).collect(),
+        );
+        let mut ret: Vec<f64> = start.0.iter().map(|&q| f64::from(q)).collect();
+        // charges only move within a conjugated system, so each is
+        // enumerated on its own to avoid multiplying their structures
+        for system in self.conjugated_systems(&start) {
+            let forms = self.resonance_forms(&start, &system);
+            let energy =
+                |f: &Form| system.iter().map(|&i| f.0[i].abs()).sum::<i8>();
+            let lowest = forms.iter().map(energy).min().unwrap_or_default();
+            let lowest: Vec<_> =
+                forms.iter().filter(|f| energy(f) == lowest).collect();
+            for &i in &system {
+                let sum: f64 = lowest.iter().map(|f| f64::from(f.0[i])).sum();
+                ret[i] = sum / lowest.len() as f64;
+            }
+        }
+        ret
+    }
+
+    /// The sum of the orders of the bonds to `atom` in `form`, and whether
+    /// any of them is a multiple bond
+    fn valence_in(&self, form: &Form, atom: usize) -> (u8, bool) {
+        let orders = self.adjacent(atom).iter().map(|&(_, b)| form.1[b]);
+        (orders.clone().sum(), orders.into_iter().any(|o| o > 1))
+    }
+
+    fn is_donor(&self, form: &Form, atom: usize) -> bool {
+        let (valence, multiple) = self.valence_in(form, atom);
+        match (self.atoms[atom].atomic_number, form.0[atom]) {
+            (8 | 16, -1) => valence == 1,
+            (7, -1) => valence == 2,
+            (7, 0) => valence == 3 && !multiple,
+            _ => false,
+        }
+    }
+
+    fn is_acceptor(&self, form: &Form, atom: usize) -> bool {
+        let (valence, multiple) = self.valence_in(form, atom);
+        multiple
+            && match (self.atoms[atom].atomic_number, form.0[atom]) {
+                (8 | 16, 0) => valence == 2,
+                (7, 0) => valence == 3,
+                (7, 1) => valence == 4,
+                _ => false,
+            }
+    }
+
+    /// Return the atoms of each conjugated system containing a donor in
+    /// `form`: the connected sets of donors, acceptors, and atoms with a
+    /// multiple bond. Transfers keep every atom of a system in the system.
+    fn conjugated_systems(&self, form: &Form) -> Vec<Vec<usize>> {
+        let member: Vec<bool> = (0..self.n_atoms())
+            .map(|i| {
+                self.is_donor(form, i)
+                    || self.is_acceptor(form, i)
+                    || self.valence_in(form, i).1
+            })
+            .collect();
+        let mut seen = vec![false; self.n_atoms()];
+        let mut ret = Vec::new();
+        for start in 0..self.n_atoms() {
+            if seen[start] || !member[start] {
+                continue;
+            }
+            seen[start] = true;
+            let mut system = vec![start];
+            let mut stack = vec![start];
+            while let Some(i) = stack.pop() {
+                for j in self.neighbors(i) {
+                    if member[j] && !seen[j] {
+                        seen[j] = true;
+                        system.push(j);
+                        stack.push(j);
+                    }
+                }
+            }
+            if system.iter().any(|&i| self.is_donor(form, i)) {
+                system.sort();
+                ret.push(system);
+            }
+        }
+        ret
+    }
+
+    /// Return every resonance structure reachable from `start` by transfers
+    /// between the atoms of `system`, including `start` itself
+    fn resonance_forms(&self, start: &Form, system: &[usize]) -> Vec<Form> {
+        let mut seen = HashSet::from([start.clone()]);
+        let mut ret = vec![start.clone()];
+        let mut queue = vec![start.clone()];
+        while let Some(form) = queue.pop() {
+            for &donor in system {
+                if !self.is_donor(&form, donor) {
+                    continue;
+                }
+                let mut on_path = vec![false; self.n_atoms()];
+                on_path[donor] = true;
+                let mut paths = Vec::new();
+                self.transfer_paths(
+                    &form,
+                    donor,
+                    &mut on_path,
+                    &mut Vec::new(),
+                    &mut paths,
+                );
+                for (path, acceptor) in paths {
+                    let mut next = form.clone();
+                    next.0[donor] += 1;
+                    next.0[acceptor] -= 1;
+                    for b in path {
+                        next.1[b] = 3 - next.1[b];
+                    }
+                    if seen.insert(next.clone()) {
+                        ret.push(next.clone());
+                        queue.push(next);
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Extend the alternating single and double bond path in `path`, which
+    /// ends at `atom`, pushing each path ending in a double bond to an
+    /// acceptor onto `out` along with the acceptor
+    fn transfer_paths(
+        &self,
+        form: &Form,
+        atom: usize,
+        on_path: &mut [bool],
+        path: &mut Vec<usize>,
+        out: &mut Vec<(Vec<usize>, usize)>,
+    ) {
+        let order = if path.len().is_multiple_of(2) { 1 } else { 2 };
+        for &(j, b) in self.adjacent(atom) {
+            if on_path[j] || form.1[b] != order {
+                continue;
+            }
+            on_path[j] = true;
+            path.push(b);
+            if order == 2 && self.is_acceptor(form, j) {
+                out.push((path.clone(), j));
+            }
+            self.transfer_paths(form, j, on_path, path, out);
+            path.pop();
+            on_path[j] = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    #[test]
+    fn average_formal_charges() {
+        // acetate: the charge is shared by the oxygens
+        let mut acetate =
+            graph(&[6, 6, 8, 8], &[(0, 1, 1), (1, 2, 2), (1, 3, 1)]);
+        acetate.atoms[3].formal_charge = -1;
+        assert_eq!(acetate.average_formal_charges(), [0.0, 0.0, -0.5, -0.5]);
+
+        // guanidinium: the charge is shared by the nitrogens
+        let mut guanidinium = graph(
+            &[6, 7, 7, 7, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 2),
+                (0, 2, 1),
+                (0, 3, 1),
+                (1, 4, 1),
+                (1, 5, 1),
+                (2, 6, 1),
+                (2, 7, 1),
+                (3, 8, 1),
+                (3, 9, 1),
+            ],
+        );
+        guanidinium.atoms[1].formal_charge = 1;
+        let got = guanidinium.average_formal_charges();
+        for i in 1..4 {
+            assert!((got[i] - 1.0 / 3.0).abs() < 1e-12, "{got:?}");
+        }
+        assert_eq!(got[0], 0.0);
+
+        // formamide: the zwitterion has more formal charges
+        let formamide = graph(
+            &[6, 8, 7, 1, 1, 1],
+            &[(0, 1, 2), (0, 2, 1), (0, 3, 1), (2, 4, 1), (2, 5, 1)],
+        );
+        assert_eq!(formamide.average_formal_charges(), [0.0; 6]);
+
+        // oxalate: each carboxylate shares its own charge
+        let mut oxalate = graph(
+            &[6, 6, 8, 8, 8, 8],
+            &[(0, 1, 1), (0, 2, 2), (0, 3, 1), (1, 4, 2), (1, 5, 1)],
+        );
+        oxalate.atoms[3].formal_charge = -1;
+        oxalate.atoms[5].formal_charge = -1;
+        assert_eq!(
+            oxalate.average_formal_charges(),
+            [0.0, 0.0, -0.5, -0.5, -0.5, -0.5]
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Ring perception. Candidate cycles are built from shortest paths in the
+//! style of Horton, and the smallest set of smallest rings (SSSR) and the
+//! relevant rings, the union of all minimum cycle bases, are then chosen by
+//! Gaussian elimination on the bond sets of the candidates. Only bonds that
+//! lie in some ring are searched, so large acyclic stretches like protein
+//! backbones cost little.
+
+use std::collections::VecDeque;
+
+use super::molecule::MoleculeGraph;
+
+/// A ring as its atoms in order around the ring and its bonds as a bit set
+#[derive(Clone)]
+pub(crate) struct Cycle {
+    pub(crate) atoms: Vec<usize>,
+    pub(crate) bonds: Vec<u64>,
+}
+
+impl Cycle {
+    pub(crate) fn has_bond(&self, bond: usize) -> bool {
+        self.bonds[bond / 64] & (1 << (bond % 64)) != 0
+    }
+}
+
+/// A basis of bond sets over GF(2), keyed by the lowest bit of each row
+#[derive(Default)]
+struct Basis {
+    rows: Vec<(usize, Vec<u64>)>,
+}
+
+impl Basis {
+    /// Reduce `bits` by the basis, returning the remainder if it is
+    /// independent of the rows
+    fn reduce(&self, mut bits: Vec<u64>) -> Option<Vec<u64>> {
+        while let Some(pivot) = lowest_bit(&bits) {
+            match self.rows.iter().find(|(p, _)| *p == pivot) {
+                Some((_, row)) => {
+                    bits.iter_mut().zip(row).for_each(|(b, r)| *b ^= r)
+                }
+                None => return Some(bits),
+            }
+        }
+        None
+    }
+
+    fn insert(&mut self, bits: Vec<u64>) {
+        self.rows.push((lowest_bit(&bits).unwrap(), bits));
+    }
+}
+
+fn lowest_bit(bits: &[u64]) -> Option<usize> {
+    bits.iter()
+        .enumerate()
+        .find(|(_, &w)| w != 0)
+        .map(|(i, w)| 64 * i + w.trailing_zeros() as usize)
+}
+
+impl MoleculeGraph {
+    /// Return whether each bond lies in a ring, found as the bonds that are
+    /// not bridges
+    pub fn cyclic_bonds(&self) -> Vec<bool> {
+        let n = self.n_atoms();
+        let mut ret = vec![true; self.bonds.len()];
+        let mut order = vec![usize::MAX; n];
+        let mut low = vec![0; n];
+        let mut count = 0;
+        for root in 0..n {
+            if order[root] != usize::MAX {
+                continue;
+            }
+            order[root] = count;
+            low[root] = count;
+            count += 1;
+            // (atom, the bond used to reach it, next neighbor)
+            let mut stack = vec![(root, usize::MAX, 0)];
+            while let Some(&mut (a, via, ref mut k)) = stack.last_mut() {
+                if let Some(&(j, b)) = self.adjacent(a).get(*k) {
+                    *k += 1;
+                    if b == via {
+                        continue;
+                    }
+                    if order[j] == usize::MAX {
+                        order[j] = count;
+                        low[j] = count;
+                        count += 1;
+                        stack.push((j, b, 0));
+                    } else {
+                        low[a] = low[a].min(order[j]);
+                    }
+                    continue;
+                }
+                stack.pop();
+                if let Some(&(parent, _, _)) = stack.last() {
+                    low[parent] = low[parent].min(low[a]);
+                    if low[a] > order[parent] {
+                        ret[via] = false;
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Return the Horton candidate cycles through the ring bonds, sorted by
+    /// size
+    fn candidate_cycles(&self) -> Vec<Cycle> {
+        let n = self.n_atoms();
+        let cyclic = self.cyclic_bonds();
+        let words = self.bonds.len().div_ceil(64);
+        let mut ret: Vec<Cycle> = Vec::new();
+        let in_ring: Vec<bool> = (0..n)
+            .map(|i| self.adjacent(i).iter().any(|&(_, b)| cyclic[b]))
+            .collect();
+        for v in (0..n).filter(|&v| in_ring[v]) {
+            // shortest path tree from v over ring bonds
+            let mut parent: Vec<Option<(usize, usize)>> = vec![None; n];
+            let mut seen = vec![false; n];
+            seen[v] = true;
+            let mut queue = VecDeque::from([v]);
+            while let Some(i) = queue.pop_front() {
+                for &(j, b) in self.adjacent(i) {
+                    if cyclic[b] && !seen[j] {
+                        seen[j] = true;
+                        parent[j] = Some((i, b));
+                        queue.push_back(j);
+                    }
+                }
+            }
+            // the path from v to `x` as (atoms from x back to v, bonds)
+            let path = |mut x: usize| {
+                let mut atoms = vec![x];
+                let mut bonds = Vec::new();
+                while let Some((p, b)) = parent[x] {
+                    atoms.push(p);
+                    bonds.push(b);
+                    x = p;
+                }
+                (atoms, bonds)
+            };
+            for (b, bond) in self.bonds.iter().enumerate() {
+                let (x, y) = (bond.atom1, bond.atom2);
+                if !cyclic[b]
+                    || !seen[x]
+                    || !seen[y]
+                    || parent[x].is_some_and(|(_, pb)| pb == b)
+                    || parent[y].is_some_and(|(_, pb)| pb == b)
+                {
+                    continue;
+                }
+                let (px, bx) = path(x);
+                let (py, by) = path(y);
+                // the paths may only meet at v
+                if px.iter().filter(|a| py.contains(a)).count() != 1 {
+                    continue;
+                }
+                let mut bits = vec![0u64; words];
+                for &e in bx.iter().chain(&by).chain([&b]) {
+                    bits[e / 64] |= 1 << (e % 64);
+                }
+                if ret.iter().any(|c| c.bonds == bits) {
+                    continue;
+                }
+                // out from v to x, then back from y
+                let mut atoms: Vec<_> = px.into_iter().rev().collect();
+                atoms.extend(&py[..py.len() - 1]);
+                ret.push(Cycle { atoms, bonds: bits });
+            }
+        }
+        ret.sort_by(|a, b| {
+            (a.atoms.len(), &a.bonds).cmp(&(b.atoms.len(), &b.bonds))
+        });
+        ret
+    }
+
+    /// The smallest set of smallest rings and the relevant rings
+    pub(crate) fn ring_sets(&self) -> (Vec<Cycle>, Vec<Cycle>) {
+        let mut sssr = Vec::new();
+        let mut relevant = Vec::new();
+        let mut basis = Basis::default();
+        let candidates = self.candidate_cycles();
+        for group in candidates.chunk_by(|a, b| a.atoms.len() == b.atoms.len())
+        {
+            // relevant cycles are independent of all strictly smaller ones
+            let independent: Vec<_> = group
+                .iter()
+                .filter(|c| basis.reduce(c.bonds.clone()).is_some())
+                .cloned()
+                .collect();
+            for cycle in &independent {
+                if let Some(bits) = basis.reduce(cycle.bonds.clone()) {
+                    basis.insert(bits);
+                    sssr.push(cycle.clone());
+                }
+            }
+            relevant.extend(independent);
+        }
+        (sssr, relevant)
+    }
+
+    /// Return the smallest set of smallest rings, each as its atoms in order
+    /// around the ring. When there is a choice between rings of the same
+    /// size, as in cubane, one is picked arbitrarily but deterministically.
+    pub fn sssr(&self) -> Vec<Vec<usize>> {
+        self.ring_sets().0.into_iter().map(|c| c.atoms).collect()
+    }
+
+    /// Return the relevant rings, the rings in any minimum cycle basis, with
+    /// atoms in order around each ring
+    pub fn relevant_rings(&self) -> Vec<Vec<usize>> {
+        self.ring_sets().1.into_iter().map(|c| c.atoms).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::graph;
+
+    #[test]
+    fn naphthalene() {
+        // the carbons of naphthalene with a methyl on C1, without hydrogens
+        let mut bonds: Vec<_> = (0..9).map(|i| (i, i + 1, 1)).collect();
+        bonds.extend([(9, 0, 1), (4, 9, 1), (0, 10, 1)]);
+        let g = graph(&[6; 11], &bonds);
+        let cyclic = g.cyclic_bonds();
+        assert_eq!(cyclic.iter().filter(|&&c| c).count(), 11);
+        assert!(!cyclic[11]);
+
+        let mut sssr = g.sssr();
+        assert_eq!(sssr.len(), 2);
+        for ring in &mut sssr {
+            assert_eq!(ring.len(), 6);
+            // consecutive atoms are bonded
+            for i in 0..6 {
+                assert!(g.bond_between(ring[i], ring[(i + 1) % 6]).is_some());
+            }
+            ring.sort();
+        }
+        sssr.sort();
+        assert_eq!(sssr, [vec![0, 1, 2, 3, 4, 9], vec![4, 5, 6, 7, 8, 9]]);
+        assert_eq!(g.relevant_rings().len(), 2);
+    }
+
+    #[test]
+    fn cubane() {
+        let mut bonds: Vec<_> = (0..4)
+            .flat_map(|i| {
+                [
+                    (i, (i + 1) % 4, 1),
+                    (i + 4, (i + 1) % 4 + 4, 1),
+                    (i, i + 4, 1),
+                ]
+            })
+            .collect();
+        bonds.sort();
+        let g = graph(&[6; 8], &bonds);
+        // five faces make a basis, but all six are relevant
+        assert_eq!(g.sssr().len(), 5);
+        assert_eq!(g.relevant_rings().len(), 6);
+    }
+}
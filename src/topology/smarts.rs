@@ -0,0 +1,803 @@
+//! Parsing and substructure matching for the subset of SMARTS used by SMIRKS
+//! patterns in SMIRNOFF force fields. This covers atomic numbers and element
+//! symbols, aromaticity, hydrogen counts, connectivity, ring membership and
+//! ring sizes, formal charges, recursive SMARTS, atom maps, and the logical
+//! operators on both atoms and bonds. Chirality and bond stereochemistry are
+//! accepted but ignored when matching.
+
+use std::{collections::HashSet, error::Error, fmt::Display};
+
+use crate::utils::elements;
+
+use super::molecule::MoleculeGraph;
+
+#[derive(Debug)]
+pub struct SmartsError {
+    pub pattern: String,
+    pub message: String,
+}
+
+impl Error for SmartsError {}
+
+impl Display for SmartsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to parse `{}`: {}", self.pattern, self.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr<T> {
+    Primitive(T),
+    Not(Box<Expr<T>>),
+    And(Box<Expr<T>>, Box<Expr<T>>),
+    Or(Box<Expr<T>>, Box<Expr<T>>),
+}
+
+impl<T> Expr<T> {
+    fn eval(&self, f: &impl Fn(&T) -> bool) -> bool {
+        match self {
+            Expr::Primitive(p) => f(p),
+            Expr::Not(e) => !e.eval(f),
+            Expr::And(a, b) => a.eval(f) && b.eval(f),
+            Expr::Or(a, b) => a.eval(f) || b.eval(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum AtomPrimitive {
+    Any,
+    AtomicNumber(u8),
+    /// an element written as a symbol, which also constrains aromaticity
+    Element(u8, bool),
+    Aromatic,
+    Aliphatic,
+    TotalH(usize),
+    Connectivity(usize),
+    Degree(usize),
+    RingConnectivity(usize),
+    /// `R` with no count: in any ring. `R0` is represented as `Not(InRing)`
+    InRing,
+    /// the number of rings the atom is in, approximated by its number of
+    /// ring bonds minus one
+    RingCount(usize),
+    RingSize(usize),
+    Charge(i8),
+    Recursive(Box<Smarts>),
+    /// chirality and other primitives that are accepted but not checked
+    Ignored,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum BondPrimitive {
+    Single,
+    Double,
+    Triple,
+    Aromatic,
+    Any,
+    Ring,
+    /// the implicit bond between adjacent atoms: single or aromatic
+    Default,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct QueryAtom {
+    expr: Expr<AtomPrimitive>,
+    map: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct QueryBond {
+    atom1: usize,
+    atom2: usize,
+    expr: Expr<BondPrimitive>,
+}
+
+/// A parsed SMARTS or SMIRKS pattern
+#[derive(Clone, Debug, PartialEq)]
+pub struct Smarts {
+    atoms: Vec<QueryAtom>,
+    bonds: Vec<QueryBond>,
+}
+
+/// Per-atom and per-bond properties of a [MoleculeGraph] that are needed for
+/// matching but expensive enough that they should only be computed once
+struct Context<'a> {
+    graph: &'a MoleculeGraph,
+    ring_bonds: Vec<bool>,
+    /// for each atom, the sizes of the smallest rings through each of its ring
+    /// bonds
+    ring_sizes: Vec<Vec<usize>>,
+}
+
+impl<'a> Context<'a> {
+    fn new(graph: &'a MoleculeGraph) -> Self {
+        let smallest: Vec<_> = (0..graph.bonds.len())
+            .map(|b| graph.smallest_ring_with_bond(b))
+            .collect();
+        let ring_bonds = smallest.iter().map(Option::is_some).collect();
+        let mut ring_sizes = vec![Vec::new(); graph.n_atoms()];
+        for (bond, size) in graph.bonds.iter().zip(&smallest) {
+            if let Some(size) = size {
+                ring_sizes[bond.atom1].push(*size);
+                ring_sizes[bond.atom2].push(*size);
+            }
+        }
+        Self {
+            graph,
+            ring_bonds,
+            ring_sizes,
+        }
+    }
+
+    fn ring_connectivity(&self, atom: usize) -> usize {
+        self.graph
+            .adjacent(atom)
+            .iter()
+            .filter(|(_, b)| self.ring_bonds[*b])
+            .count()
+    }
+
+    fn atom_matches(&self, prim: &AtomPrimitive, atom: usize) -> bool {
+        let a = &self.graph.atoms[atom];
+        match prim {
+            AtomPrimitive::Any | AtomPrimitive::Ignored => true,
+            AtomPrimitive::AtomicNumber(n) => a.atomic_number == *n,
+            AtomPrimitive::Element(n, aromatic) => {
+                a.atomic_number == *n && a.is_aromatic == *aromatic
+            }
+            AtomPrimitive::Aromatic => a.is_aromatic,
+            AtomPrimitive::Aliphatic => !a.is_aromatic,
+            AtomPrimitive::TotalH(n) => self.graph.n_hydrogens(atom) == *n,
+            AtomPrimitive::Connectivity(n) | AtomPrimitive::Degree(n) => {
+                self.graph.degree(atom) == *n
+            }
+            AtomPrimitive::RingConnectivity(n) => {
+                self.ring_connectivity(atom) == *n
+            }
+            AtomPrimitive::InRing => !self.ring_sizes[atom].is_empty(),
+            AtomPrimitive::RingCount(n) => {
+                self.ring_connectivity(atom).saturating_sub(1) == *n
+            }
+            AtomPrimitive::RingSize(n) => self.ring_sizes[atom].contains(n),
+            AtomPrimitive::Charge(c) => a.formal_charge == *c,
+            AtomPrimitive::Recursive(smarts) => smarts.matches_from(self, atom),
+        }
+    }
+
+    fn bond_matches(&self, prim: &BondPrimitive, bond: usize) -> bool {
+        let b = &self.graph.bonds[bond];
+        match prim {
+            BondPrimitive::Single => b.bond_order == 1 && !b.is_aromatic,
+            BondPrimitive::Double => b.bond_order == 2 && !b.is_aromatic,
+            BondPrimitive::Triple => b.bond_order == 3 && !b.is_aromatic,
+            BondPrimitive::Aromatic => b.is_aromatic,
+            BondPrimitive::Any => true,
+            BondPrimitive::Ring => self.ring_bonds[bond],
+            BondPrimitive::Default => b.is_aromatic || b.bond_order == 1,
+        }
+    }
+}
+
+struct Parser<'a> {
+    pattern: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(pattern: &'a str) -> Self {
+        Self {
+            pattern,
+            chars: pattern.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> SmartsError {
+        SmartsError {
+            pattern: self.pattern.to_owned(),
+            message: format!("{} at position {}", message.into(), self.pos),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn number(&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return None;
+        }
+        String::from_iter(&self.chars[start..self.pos]).parse().ok()
+    }
+
+    fn parse(mut self) -> Result<Smarts, SmartsError> {
+        let mut atoms: Vec<QueryAtom> = Vec::new();
+        let mut bonds = Vec::new();
+        // the atom that the next atom will be bonded to
+        let mut prev: Option<usize> = None;
+        let mut branches = Vec::new();
+        let mut pending_bond: Option<Expr<BondPrimitive>> = None;
+        let mut ring_closures: Vec<Option<(usize, Option<Expr<_>>)>> =
+            vec![None; 100];
+
+        while let Some(c) = self.peek() {
+            match c {
+                '(' => {
+                    self.pos += 1;
+                    branches
+                        .push(prev.ok_or(self.error("branch before atom"))?);
+                }
+                ')' => {
+                    self.pos += 1;
+                    prev = Some(
+                        branches.pop().ok_or(self.error("unmatched `)`"))?,
+                    );
+                }
+                '.' => return Err(self.error("disconnected patterns")),
+                '-' | '=' | '#' | ':' | '~' | '@' | '!' | '/' | '\\' | '$' => {
+                    // `$` here would be a quadruple bond, which will fail to
+                    // parse as a bond primitive below
+                    pending_bond = Some(self.bond_expr()?);
+                }
+                '0'..='9' | '%' => {
+                    let ring = if c == '%' {
+                        self.pos += 1;
+                        let start = self.pos;
+                        self.pos += 2;
+                        String::from_iter(
+                            self.chars
+                                .get(start..self.pos)
+                                .ok_or(self.error("bad ring closure"))?,
+                        )
+                        .parse()
+                        .map_err(|_| self.error("bad ring closure"))?
+                    } else {
+                        self.pos += 1;
+                        c.to_digit(10).unwrap() as usize
+                    };
+                    let atom =
+                        prev.ok_or(self.error("ring bond before atom"))?;
+                    match ring_closures[ring].take() {
+                        Some((other, expr)) => {
+                            let expr = pending_bond.take().or(expr).unwrap_or(
+                                Expr::Primitive(BondPrimitive::Default),
+                            );
+                            bonds.push(QueryBond {
+                                atom1: other,
+                                atom2: atom,
+                                expr,
+                            });
+                        }
+                        None => {
+                            ring_closures[ring] =
+                                Some((atom, pending_bond.take()));
+                        }
+                    }
+                }
+                _ => {
+                    let atom = self.atom()?;
+                    let idx = atoms.len();
+                    atoms.push(atom);
+                    if let Some(p) = prev {
+                        bonds.push(QueryBond {
+                            atom1: p,
+                            atom2: idx,
+                            expr: pending_bond.take().unwrap_or(
+                                Expr::Primitive(BondPrimitive::Default),
+                            ),
+                        });
+                    } else if pending_bond.is_some() {
+                        return Err(self.error("bond before first atom"));
+                    }
+                    prev = Some(idx);
+                }
+            }
+        }
+
+        if !branches.is_empty() {
+            return Err(self.error("unclosed branch"));
+        }
+        if ring_closures.iter().any(Option::is_some) {
+            return Err(self.error("unclosed ring"));
+        }
+        if atoms.is_empty() {
+            return Err(self.error("empty pattern"));
+        }
+        Ok(Smarts { atoms, bonds })
+    }
+
+    fn atom(&mut self) -> Result<QueryAtom, SmartsError> {
+        if self.peek() == Some('[') {
+            self.pos += 1;
+            let mut map = None;
+            let expr = self.atom_expr(&mut map)?;
+            if self.next() != Some(']') {
+                return Err(self.error("expected `]`"));
+            }
+            return Ok(QueryAtom { expr, map });
+        }
+
+        // organic subset outside of brackets
+        let c = self.next().ok_or(self.error("expected atom"))?;
+        let prim = match c {
+            '*' => AtomPrimitive::Any,
+            'a' => AtomPrimitive::Aromatic,
+            'A' => AtomPrimitive::Aliphatic,
+            'C' if self.peek() == Some('l') => {
+                self.pos += 1;
+                AtomPrimitive::Element(17, false)
+            }
+            'B' if self.peek() == Some('r') => {
+                self.pos += 1;
+                AtomPrimitive::Element(35, false)
+            }
+            'B' | 'C' | 'N' | 'O' | 'P' | 'S' | 'F' | 'I' => {
+                let n = elements::atomic_number(&c.to_string()).unwrap();
+                AtomPrimitive::Element(n, false)
+            }
+            'b' | 'c' | 'n' | 'o' | 'p' | 's' => {
+                let n = elements::atomic_number(&c.to_string()).unwrap();
+                AtomPrimitive::Element(n, true)
+            }
+            _ => {
+                self.pos -= 1;
+                return Err(self.error(format!("unexpected `{c}`")));
+            }
+        };
+        Ok(QueryAtom {
+            expr: Expr::Primitive(prim),
+            map: None,
+        })
+    }
+
+    /// low-precedence conjunction: `;`
+    fn atom_expr(
+        &mut self,
+        map: &mut Option<usize>,
+    ) -> Result<Expr<AtomPrimitive>, SmartsError> {
+        let mut lhs = self.atom_or(map)?;
+        while self.peek() == Some(';') {
+            self.pos += 1;
+            let rhs = self.atom_or(map)?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn atom_or(
+        &mut self,
+        map: &mut Option<usize>,
+    ) -> Result<Expr<AtomPrimitive>, SmartsError> {
+        let mut lhs = self.atom_and(map)?;
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            let rhs = self.atom_and(map)?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    /// high-precedence conjunction: `&` or implicit
+    fn atom_and(
+        &mut self,
+        map: &mut Option<usize>,
+    ) -> Result<Expr<AtomPrimitive>, SmartsError> {
+        let mut lhs = self.atom_not(map)?;
+        loop {
+            match self.peek() {
+                Some('&') => self.pos += 1,
+                Some(';' | ',' | ']') | None => break,
+                Some(':') => {
+                    self.pos += 1;
+                    *map = Some(
+                        self.number()
+                            .ok_or(self.error("expected map index"))?,
+                    );
+                    continue;
+                }
+                _ => {}
+            }
+            let rhs = self.atom_not(map)?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn atom_not(
+        &mut self,
+        map: &mut Option<usize>,
+    ) -> Result<Expr<AtomPrimitive>, SmartsError> {
+        if self.peek() == Some('!') {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.atom_not(map)?)));
+        }
+        if self.peek() == Some(':') {
+            // a map index on its own, as in `[:1]`
+            self.pos += 1;
+            *map = Some(self.number().ok_or(self.error("expected map index"))?);
+            return Ok(Expr::Primitive(AtomPrimitive::Any));
+        }
+        self.atom_primitive()
+    }
+
+    fn atom_primitive(&mut self) -> Result<Expr<AtomPrimitive>, SmartsError> {
+        let start = self.pos;
+        let c = self.next().ok_or(self.error("expected atom primitive"))?;
+        use AtomPrimitive as P;
+        let prim = match c {
+            '*' => P::Any,
+            '#' => P::AtomicNumber(
+                self.number().ok_or(self.error("expected atomic number"))?
+                    as u8,
+            ),
+            'a' if self.peek() != Some('s') => P::Aromatic,
+            'A' if !self.peek().is_some_and(|c| c.is_ascii_lowercase()) => {
+                P::Aliphatic
+            }
+            'H' if start > 0 && self.chars[start - 1] == '[' => {
+                // a leading H is the element, unless it's followed by a count
+                // and something else, as in `[H1+]`. Treat it as an element
+                // since SMIRKS patterns always spell hydrogen `#1` otherwise
+                P::AtomicNumber(1)
+            }
+            'H' => P::TotalH(self.number().unwrap_or(1)),
+            'X' => P::Connectivity(self.number().unwrap_or(1)),
+            'D' => P::Degree(self.number().unwrap_or(1)),
+            'x' => match self.number() {
+                Some(n) => P::RingConnectivity(n),
+                None => P::InRing,
+            },
+            'R' => match self.number() {
+                Some(0) => {
+                    return Ok(Expr::Not(Box::new(Expr::Primitive(P::InRing))))
+                }
+                Some(n) => P::RingCount(n),
+                None => P::InRing,
+            },
+            'r' => match self.number() {
+                Some(n) => P::RingSize(n),
+                None => P::InRing,
+            },
+            '+' | '-' => {
+                let sign = if c == '+' { 1 } else { -1 };
+                let mut magnitude = 1;
+                match self.number() {
+                    Some(n) => magnitude = n as i8,
+                    None => {
+                        while self.peek() == Some(c) {
+                            self.pos += 1;
+                            magnitude += 1;
+                        }
+                    }
+                }
+                P::Charge(sign * magnitude)
+            }
+            '$' => {
+                if self.next() != Some('(') {
+                    return Err(self.error("expected `(` after `$`"));
+                }
+                let open = self.pos;
+                let mut depth = 1;
+                while depth > 0 {
+                    match self.next() {
+                        Some('(') => depth += 1,
+                        Some(')') => depth -= 1,
+                        Some(_) => {}
+                        None => return Err(self.error("unclosed `$(`")),
+                    }
+                }
+                let inner = String::from_iter(&self.chars[open..self.pos - 1]);
+                P::Recursive(Box::new(Smarts::parse(&inner)?))
+            }
+            '@' => {
+                while self.peek() == Some('@') {
+                    self.pos += 1;
+                }
+                P::Ignored
+            }
+            c if c.is_ascii_alphabetic() => {
+                let aromatic = c.is_ascii_lowercase();
+                // try a two-letter symbol first
+                let two = self
+                    .peek()
+                    .filter(|n| n.is_ascii_lowercase())
+                    .map(|n| format!("{}{n}", c.to_ascii_uppercase()))
+                    .and_then(|s| elements::atomic_number(&s));
+                match two {
+                    Some(n) if !aromatic || matches!(n, 33 | 34) => {
+                        self.pos += 1;
+                        P::Element(n, aromatic)
+                    }
+                    _ => {
+                        let n = elements::atomic_number(&c.to_string())
+                            .ok_or(self.error(format!("unknown `{c}`")))?;
+                        P::Element(n, aromatic)
+                    }
+                }
+            }
+            _ => {
+                self.pos = start;
+                return Err(self.error(format!("unexpected `{c}`")));
+            }
+        };
+        Ok(Expr::Primitive(prim))
+    }
+
+    fn bond_expr(&mut self) -> Result<Expr<BondPrimitive>, SmartsError> {
+        let mut lhs = self.bond_or()?;
+        while self.peek() == Some(';') {
+            self.pos += 1;
+            let rhs = self.bond_or()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn bond_or(&mut self) -> Result<Expr<BondPrimitive>, SmartsError> {
+        let mut lhs = self.bond_and()?;
+        while self.peek() == Some(',') {
+            self.pos += 1;
+            let rhs = self.bond_and()?;
+            lhs = Expr::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn bond_and(&mut self) -> Result<Expr<BondPrimitive>, SmartsError> {
+        let mut lhs = self.bond_not()?;
+        loop {
+            match self.peek() {
+                Some('&') => self.pos += 1,
+                Some('-' | '=' | '#' | ':' | '~' | '@' | '!' | '/' | '\\') => {}
+                _ => break,
+            }
+            let rhs = self.bond_not()?;
+            lhs = Expr::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn bond_not(&mut self) -> Result<Expr<BondPrimitive>, SmartsError> {
+        if self.peek() == Some('!') {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.bond_not()?)));
+        }
+        let prim = match self.next() {
+            Some('-' | '/' | '\\') => BondPrimitive::Single,
+            Some('=') => BondPrimitive::Double,
+            Some('#') => BondPrimitive::Triple,
+            Some(':') => BondPrimitive::Aromatic,
+            Some('~') => BondPrimitive::Any,
+            Some('@') => BondPrimitive::Ring,
+            c => {
+                self.pos -= 1;
+                return Err(self.error(format!("unexpected bond `{c:?}`")));
+            }
+        };
+        Ok(Expr::Primitive(prim))
+    }
+}
+
+impl Smarts {
+    pub fn parse(pattern: &str) -> Result<Self, SmartsError> {
+        Parser::new(pattern).parse()
+    }
+
+    /// Return the map indices of the tagged atoms in the pattern in order
+    pub fn map_indices(&self) -> Vec<usize> {
+        let mut ret: Vec<_> = self.atoms.iter().filter_map(|a| a.map).collect();
+        ret.sort();
+        ret
+    }
+
+    /// Return every distinct match of the pattern in `graph`. Each match
+    /// contains the indices of the atoms matched by the tagged atoms in the
+    /// pattern in the order of their map indices, or every matched atom in
+    /// pattern order if no atoms are tagged. Matches that differ only in the
+    /// untagged atoms are reported once.
+    pub fn find_matches(&self, graph: &MoleculeGraph) -> Vec<Vec<usize>> {
+        let ctx = Context::new(graph);
+        let mut tagged: Vec<_> = self
+            .atoms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, a)| a.map.map(|m| (m, i)))
+            .collect();
+        tagged.sort();
+        let tagged: Vec<usize> = if tagged.is_empty() {
+            (0..self.atoms.len()).collect()
+        } else {
+            tagged.into_iter().map(|(_, i)| i).collect()
+        };
+
+        let mut seen = HashSet::new();
+        let mut ret = Vec::new();
+        self.search(&ctx, &mut vec![None; self.atoms.len()], 0, &mut |m| {
+            let hit: Vec<usize> =
+                tagged.iter().map(|&i| m[i].unwrap()).collect();
+            if seen.insert(hit.clone()) {
+                ret.push(hit);
+            }
+            true
+        });
+        ret
+    }
+
+    /// Report whether `graph` contains the pattern at all
+    pub fn is_match(&self, graph: &MoleculeGraph) -> bool {
+        let ctx = Context::new(graph);
+        let mut found = false;
+        self.search(&ctx, &mut vec![None; self.atoms.len()], 0, &mut |_| {
+            found = true;
+            false
+        });
+        found
+    }
+
+    /// Report whether the pattern matches with its first atom on `root`. This
+    /// is how recursive SMARTS are evaluated
+    fn matches_from(&self, ctx: &Context, root: usize) -> bool {
+        let mut map = vec![None; self.atoms.len()];
+        if !self.atom_ok(ctx, &map, 0, root) {
+            return false;
+        }
+        map[0] = Some(root);
+        let mut found = false;
+        self.search(ctx, &mut map, 1, &mut |_| {
+            found = true;
+            false
+        });
+        found
+    }
+
+    /// Check whether query atom `q` can be mapped to graph atom `atom`, given
+    /// the partial mapping in `map`
+    fn atom_ok(
+        &self,
+        ctx: &Context,
+        map: &[Option<usize>],
+        q: usize,
+        atom: usize,
+    ) -> bool {
+        if map.contains(&Some(atom)) {
+            return false;
+        }
+        if !self.atoms[q]
+            .expr
+            .eval(&|p: &AtomPrimitive| ctx.atom_matches(p, atom))
+        {
+            return false;
+        }
+        // every bond to an already mapped atom must be present and match
+        self.bonds.iter().all(|bond| {
+            let other = if bond.atom1 == q {
+                bond.atom2
+            } else if bond.atom2 == q {
+                bond.atom1
+            } else {
+                return true;
+            };
+            let Some(image) = map[other] else {
+                return true;
+            };
+            match ctx.graph.bond_index(atom, image) {
+                Some(b) => {
+                    bond.expr.eval(&|p: &BondPrimitive| ctx.bond_matches(p, b))
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Depth-first search over assignments of query atoms in pattern order.
+    /// `found` is called for each complete match and should return whether to
+    /// keep searching.
+    fn search(
+        &self,
+        ctx: &Context,
+        map: &mut [Option<usize>],
+        q: usize,
+        found: &mut dyn FnMut(&[Option<usize>]) -> bool,
+    ) -> bool {
+        if q == self.atoms.len() {
+            return found(map);
+        }
+        // atoms after the first are always bonded to an earlier atom by the
+        // parser, so only that atom's neighbors need to be considered
+        let parent = self
+            .bonds
+            .iter()
+            .find(|b| b.atom2 == q && b.atom1 < q)
+            .map(|b| b.atom1);
+        let candidates: Vec<usize> = match parent.and_then(|p| map[p]) {
+            Some(image) => ctx.graph.neighbors(image).collect(),
+            None => (0..ctx.graph.n_atoms()).collect(),
+        };
+        for atom in candidates {
+            if !self.atom_ok(ctx, map, q, atom) {
+                continue;
+            }
+            map[q] = Some(atom);
+            let keep_going = self.search(ctx, map, q + 1, found);
+            map[q] = None;
+            if !keep_going {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{ethanol, graph};
+
+    use super::*;
+
+    #[test]
+    fn parse_sage() {
+        let ff =
+            std::fs::read_to_string("testfiles/sage-2.1.0rc.offxml").unwrap();
+        for line in ff.lines() {
+            let Some(start) = line.find("smirks=\"") else {
+                continue;
+            };
+            let rest = &line[start + 8..];
+            let smirks = &rest[..rest.find('"').unwrap()];
+            if let Err(e) = Smarts::parse(smirks) {
+                panic!("{e}");
+            }
+        }
+    }
+
+    #[test]
+    fn bonds_and_angles() {
+        let mol = ethanol();
+        let got = Smarts::parse("[#6X4:1]-[#8X2H1:2]")
+            .unwrap()
+            .find_matches(&mol);
+        assert_eq!(got, vec![vec![1, 2]]);
+
+        let got = Smarts::parse("[#1:1]-[#6X4:2]-[#1:3]")
+            .unwrap()
+            .find_matches(&mol);
+        // three HCH angles on the methyl and one on the methylene, each found
+        // in both directions
+        assert_eq!(got.len(), 8);
+    }
+
+    #[test]
+    fn recursive_and_logic() {
+        let mol = ethanol();
+        let smarts =
+            Smarts::parse("[#1:1]-[#6X4;$([#6]-[#8]),$([#6]-[#7]):2]").unwrap();
+        let mut got = smarts.find_matches(&mol);
+        got.sort();
+        assert_eq!(got, vec![vec![6, 1], vec![7, 1]]);
+
+        assert!(Smarts::parse("[!#1:1]~[#8]").unwrap().is_match(&mol));
+        assert!(!Smarts::parse("[#6X3]").unwrap().is_match(&mol));
+    }
+
+    #[test]
+    fn rings() {
+        // cyclopropane carbons
+        let mol = graph(&[6, 6, 6], &[(0, 1, 1), (1, 2, 1), (2, 0, 1)]);
+        assert!(Smarts::parse("[#6r3:1]@[#6:2]").unwrap().is_match(&mol));
+        assert!(Smarts::parse("C1CC1").unwrap().is_match(&mol));
+        assert!(!Smarts::parse("[#6r4]").unwrap().is_match(&mol));
+        assert!(!Smarts::parse("[#6:1]!@[#6:2]").unwrap().is_match(&mol));
+    }
+}
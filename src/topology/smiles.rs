@@ -0,0 +1,970 @@
+//! Writing SMILES strings, including the canonical, isomeric, explicit
+//! hydrogen, mapped SMILES (CMILES) that QCArchive uses to identify
+//! molecules.
+//!
+//! Isomeric SMILES take their tetrahedral and double-bond stereochemistry
+//! from [MoleculeGraph::stereo] if it is set, and otherwise from the first
+//! conformer.
+//! Stereocentres are atoms whose four neighbors are in distinct
+//! [MoleculeGraph::symmetry_classes], which misses centres that are only
+//! stereogenic because of other centres, such as in 1,4-disubstituted
+//! cyclohexanes. Canonical, isomeric SMILES also rank the atoms by their CIP
+//! labels, so that the atom order chosen for one
+//! stereoisomer does not depend on the input order.
+
+use std::{error::Error, fmt::Display};
+
+use crate::utils::elements::symbol;
+
+use super::{
+    molecule::{BondStereochemistry, MoleculeGraph, Stereochemistry},
+    stereo::{is_even, StereoAssignment},
+};
+
+#[derive(Debug, PartialEq)]
+pub struct SmilesError {
+    pub message: String,
+}
+
+impl Error for SmilesError {}
+
+impl Display for SmilesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "smiles: {}", self.message)
+    }
+}
+
+/// Where isomeric SMILES take their stereochemistry from
+#[derive(Clone, Copy)]
+enum StereoSource<'a> {
+    Assigned(&'a StereoAssignment),
+    Conformer(&'a [f64]),
+}
+
+/// Options controlling the SMILES produced by [MoleculeGraph::to_smiles]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SmilesOptions {
+    /// include tetrahedral and double bond stereochemistry, from
+    /// [MoleculeGraph::stereo] or the first conformer
+    pub isomeric: bool,
+
+    /// write every hydrogen as its own atom instead of folding ordinary
+    /// hydrogens into their neighbors
+    pub explicit_hydrogens: bool,
+
+    /// label each atom with its index plus one as the atom map number
+    pub mapped: bool,
+
+    /// traverse the atoms in canonical order, so that the same molecule
+    /// gives the same SMILES whatever its atom order, apart from any map
+    /// numbers. Otherwise atoms are visited in index order.
+    pub canonical: bool,
+}
+
+impl SmilesOptions {
+    /// The options for `canonical_isomeric_explicit_hydrogen_mapped_smiles`
+    pub const CMILES: Self = Self {
+        isomeric: true,
+        explicit_hydrogens: true,
+        mapped: true,
+        canonical: true,
+    };
+}
+
+impl Default for SmilesOptions {
+    /// Canonical, isomeric SMILES with implicit hydrogens and no atom maps
+    fn default() -> Self {
+        Self {
+            isomeric: true,
+            explicit_hydrogens: false,
+            mapped: false,
+            canonical: true,
+        }
+    }
+}
+
+/// The standard valences of the organic subset elements that may be written
+/// without brackets
+fn default_valences(atomic_number: u8) -> &'static [usize] {
+    match atomic_number {
+        5 => &[3],
+        6 => &[4],
+        7 | 15 => &[3, 5],
+        8 => &[2],
+        16 => &[2, 4, 6],
+        9 | 17 | 35 | 53 => &[1],
+        _ => &[],
+    }
+}
+
+/// The depth-first spanning tree that a SMILES string is written from
+struct Tree {
+    /// the position of each atom in the traversal, or `usize::MAX` for atoms
+    /// that are not written
+    visit: Vec<usize>,
+
+    /// the atoms at which each connected component starts
+    roots: Vec<usize>,
+
+    /// the (parent, bond) of each atom
+    parent: Vec<Option<(usize, usize)>>,
+
+    /// the (child, bond) branches of each atom, in order
+    children: Vec<Vec<(usize, usize)>>,
+
+    /// the (partner, bond) ring closures of each atom in the order they are
+    /// written, closing rings before opening new ones
+    rings: Vec<Vec<(usize, usize)>>,
+}
+
+impl MoleculeGraph {
+    /// Return whether `atom` is an ordinary hydrogen that can be left
+    /// implicit on its only neighbor
+    fn is_implicit_hydrogen(&self, atom: usize) -> bool {
+        self.atoms[atom].atomic_number == 1
+            && self.atoms[atom].formal_charge == 0
+            && self.degree(atom) == 1
+            && self
+                .neighbors(atom)
+                .all(|n| self.atoms[n].atomic_number != 1)
+    }
+
+    fn smiles_tree(&self, written: &[bool], rank: &[usize]) -> Tree {
+        let n = self.n_atoms();
+        let mut tree = Tree {
+            visit: vec![usize::MAX; n],
+            roots: Vec::new(),
+            parent: vec![None; n],
+            children: vec![Vec::new(); n],
+            rings: vec![Vec::new(); n],
+        };
+        let neighbors: Vec<Vec<(usize, usize)>> = (0..n)
+            .map(|i| {
+                let mut ns: Vec<_> = self
+                    .adjacent(i)
+                    .iter()
+                    .copied()
+                    .filter(|&(j, _)| written[j])
+                    .collect();
+                ns.sort_by_key(|&(j, _)| rank[j]);
+                ns
+            })
+            .collect();
+        let mut starts: Vec<_> = (0..n).filter(|&i| written[i]).collect();
+        starts.sort_by_key(|&i| rank[i]);
+
+        let mut closed = vec![false; self.bonds.len()];
+        let mut openings = vec![Vec::new(); n];
+        let mut count = 0;
+        for start in starts {
+            if tree.visit[start] != usize::MAX {
+                continue;
+            }
+            tree.roots.push(start);
+            tree.visit[start] = count;
+            count += 1;
+            // iterative so that long polymers do not overflow the stack
+            let mut stack = vec![(start, 0)];
+            while let Some((a, k)) = stack.last_mut() {
+                let a = *a;
+                let Some(&(j, b)) = neighbors[a].get(*k) else {
+                    stack.pop();
+                    continue;
+                };
+                *k += 1;
+                if tree.parent[a].is_some_and(|(_, pb)| pb == b) || closed[b] {
+                    continue;
+                }
+                if tree.visit[j] == usize::MAX {
+                    tree.visit[j] = count;
+                    count += 1;
+                    tree.parent[j] = Some((a, b));
+                    tree.children[a].push((j, b));
+                    stack.push((j, 0));
+                } else {
+                    // a back edge to an ancestor, which opens the ring
+                    closed[b] = true;
+                    tree.rings[a].push((j, b));
+                    openings[j].push((a, b));
+                }
+            }
+        }
+        for (rings, opening) in tree.rings.iter_mut().zip(openings) {
+            rings.extend(opening);
+        }
+        tree
+    }
+
+    /// Return the atoms that are tetrahedral stereocentres
+    pub(crate) fn tetrahedral_centres(&self, classes: &[usize]) -> Vec<bool> {
+        (0..self.n_atoms())
+            .map(|i| {
+                let mut neighbor_classes: Vec<_> =
+                    self.neighbors(i).map(|n| classes[n]).collect();
+                neighbor_classes.sort();
+                neighbor_classes.dedup();
+                !self.atoms[i].is_aromatic
+                    && neighbor_classes.len() == 4
+                    && self.degree(i) == 4
+                    && self
+                        .adjacent(i)
+                        .iter()
+                        .all(|&(_, b)| self.bonds[b].bond_order == 1)
+            })
+            .collect()
+    }
+
+    /// Return whether the substituents on each end of bond `bond` make it a
+    /// stereogenic double bond
+    pub(crate) fn is_stereo_double_bond(
+        &self,
+        bond: usize,
+        classes: &[usize],
+    ) -> bool {
+        let b = &self.bonds[bond];
+        if b.bond_order != 2
+            || b.is_aromatic
+            || self.smallest_ring_with_bond(bond).is_some_and(|r| r < 8)
+        {
+            return false;
+        }
+        [(b.atom1, b.atom2), (b.atom2, b.atom1)]
+            .iter()
+            .all(|&(a, other)| {
+                let subs: Vec<_> = self
+                    .neighbors(a)
+                    .filter(|&n| n != other)
+                    .map(|n| classes[n])
+                    .collect();
+                match subs.as_slice() {
+                    [_] => true,
+                    [x, y] => x != y,
+                    _ => false,
+                }
+            })
+    }
+
+    /// Assign `/` and `\` directions to the single bonds around stereogenic
+    /// double bonds, as (reference atom, whether the other atom is up from
+    /// the reference) for each bond
+    fn bond_directions(
+        &self,
+        source: StereoSource,
+        classes: &[usize],
+        written: &[bool],
+        tree: &Tree,
+    ) -> Vec<Option<(usize, bool)>> {
+        let up = |dir: Option<(usize, bool)>, atom: usize| {
+            dir.map(|(r, u)| if r == atom { u } else { !u })
+        };
+        let mut ret: Vec<Option<(usize, bool)>> = vec![None; self.bonds.len()];
+
+        let mut double: Vec<_> = (0..self.bonds.len())
+            .filter(|&b| {
+                let bond = &self.bonds[b];
+                written[bond.atom1]
+                    && written[bond.atom2]
+                    && self.is_stereo_double_bond(b, classes)
+            })
+            .collect();
+        double.sort_by_key(|&b| {
+            let bond = &self.bonds[b];
+            tree.visit[bond.atom1].min(tree.visit[bond.atom2])
+        });
+        for d in double {
+            let (a, b) = (self.bonds[d].atom1, self.bonds[d].atom2);
+            // the written substituents joined by plain single bonds
+            let subs = |a: usize, other: usize| {
+                let mut subs: Vec<_> = self
+                    .adjacent(a)
+                    .iter()
+                    .copied()
+                    .filter(|&(n, nb)| {
+                        n != other
+                            && written[n]
+                            && self.bonds[nb].bond_order == 1
+                            && !self.bonds[nb].is_aromatic
+                    })
+                    .collect();
+                subs.sort_by_key(|&(n, _)| tree.visit[n]);
+                subs
+            };
+            let (subs_a, subs_b) = (subs(a, b), subs(b, a));
+            let pick = |subs: &[(usize, usize)]| {
+                subs.iter()
+                    .find(|&&(_, nb)| ret[nb].is_some())
+                    .or(subs.first())
+                    .copied()
+            };
+            let (Some((x, bx)), Some((y, by))) = (pick(&subs_a), pick(&subs_b))
+            else {
+                continue;
+            };
+
+            let Some(cis) = self.cis(source, d, [x, a, b, y]) else {
+                continue;
+            };
+
+            match (up(ret[bx], a), up(ret[by], b)) {
+                (None, None) => {
+                    // start with `/` on the first written bond
+                    let first =
+                        if tree.visit[x] < tree.visit[a] { x } else { a };
+                    ret[bx] = Some((first, true));
+                    let ua = up(ret[bx], a).unwrap();
+                    ret[by] = Some((b, ua == cis));
+                }
+                (Some(ua), None) => ret[by] = Some((b, ua == cis)),
+                (None, Some(ub)) => ret[bx] = Some((a, ub == cis)),
+                (Some(_), Some(_)) => {}
+            }
+            // any second substituent lies on the other side
+            for (subs, end, first, bond) in
+                [(&subs_a, a, x, bx), (&subs_b, b, y, by)]
+            {
+                let u = up(ret[bond], end).unwrap();
+                for &(n, nb) in subs {
+                    if n != first && ret[nb].is_none() {
+                        ret[nb] = Some((end, !u));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    /// Return where isomeric SMILES take their stereochemistry from, if
+    /// anywhere
+    fn stereo_source(&self) -> Option<StereoSource<'_>> {
+        match (&self.stereo, self.conformers.first()) {
+            (Some(stereo), _) => Some(StereoSource::Assigned(stereo)),
+            (None, Some(conformer)) => Some(StereoSource::Conformer(conformer)),
+            (None, None) => None,
+        }
+    }
+
+    /// Return whether the neighbors `order` of stereocentre `centre` run
+    /// anticlockwise looking from the first, or None if its configuration is
+    /// unknown
+    fn anticlockwise(
+        &self,
+        source: StereoSource,
+        centre: usize,
+        order: [usize; 4],
+    ) -> Option<bool> {
+        match source {
+            StereoSource::Conformer(conf) => handedness(conf, order),
+            StereoSource::Assigned(stereo) => {
+                let r = match stereo.atoms[centre] {
+                    Stereochemistry::None => return None,
+                    Stereochemistry::R => true,
+                    Stereochemistry::S => false,
+                };
+                let ranked = self.cip_ranked(centre, order.to_vec())?;
+                // `@` in the CIP order is R, as in
+                // [MoleculeGraph::stereo_from_mapped_smiles]
+                Some(r == is_even(&order, ranked))
+            }
+        }
+    }
+
+    /// Return whether `x` and `y` are on the same side of the double bond
+    /// `bond` for the atoms `[x, a, b, y]`, or None if its configuration is
+    /// unknown
+    fn cis(
+        &self,
+        source: StereoSource,
+        bond: usize,
+        atoms: [usize; 4],
+    ) -> Option<bool> {
+        match source {
+            StereoSource::Conformer(conf) => Some(is_cis(conf, atoms)),
+            StereoSource::Assigned(stereo) => {
+                let z = match stereo.bonds[bond] {
+                    BondStereochemistry::None => return None,
+                    BondStereochemistry::Z => true,
+                    BondStereochemistry::E => false,
+                };
+                let [top_x, a, _, top_y] = self.top_substituents(bond)?;
+                let [x, _, _, y] = if atoms[1] == a {
+                    atoms
+                } else {
+                    [atoms[3], atoms[2], atoms[1], atoms[0]]
+                };
+                Some(z == ((x == top_x) == (y == top_y)))
+            }
+        }
+    }
+
+    /// Return the canonical ranks of the atoms, distinguishing stereoisomers
+    /// by their CIP labels if they are known
+    fn isomeric_ranks(&self) -> Vec<usize> {
+        let Some(stereo) = self.known_stereo() else {
+            return self.canonical_ranks();
+        };
+        let atoms: Vec<u8> = stereo
+            .atoms
+            .iter()
+            .map(|s| match s {
+                Stereochemistry::None => 0,
+                Stereochemistry::R => 1,
+                Stereochemistry::S => 2,
+            })
+            .collect();
+        let bonds: Vec<u8> = stereo
+            .bonds
+            .iter()
+            .map(|s| match s {
+                BondStereochemistry::None => 0,
+                BondStereochemistry::E => 1,
+                BondStereochemistry::Z => 2,
+            })
+            .collect();
+        self.stereo_canonical_ranks(&atoms, &bonds)
+    }
+
+    /// Write the molecule as a SMILES string according to `options`. Returns
+    /// an error if more than the 99 available ring closure numbers would be
+    /// open at once.
+    pub fn to_smiles(
+        &self,
+        options: &SmilesOptions,
+    ) -> Result<String, SmilesError> {
+        let n = self.n_atoms();
+        let written: Vec<bool> = (0..n)
+            .map(|i| {
+                options.explicit_hydrogens || !self.is_implicit_hydrogen(i)
+            })
+            .collect();
+        let rank = if options.canonical && options.isomeric {
+            self.isomeric_ranks()
+        } else if options.canonical {
+            self.canonical_ranks()
+        } else {
+            (0..n).collect()
+        };
+        let tree = self.smiles_tree(&written, &rank);
+        let hidden: Vec<Vec<usize>> = (0..n)
+            .map(|i| self.neighbors(i).filter(|&j| !written[j]).collect())
+            .collect();
+
+        let source = self.stereo_source().filter(|_| options.isomeric);
+        let classes = match source {
+            Some(_) => self.symmetry_classes(),
+            None => Vec::new(),
+        };
+        let (centres, directions) = match source {
+            Some(source) => (
+                self.tetrahedral_centres(&classes),
+                self.bond_directions(source, &classes, &written, &tree),
+            ),
+            None => (vec![false; n], vec![None; self.bonds.len()]),
+        };
+
+        let bond_symbol = |b: usize, from: usize| -> &'static str {
+            if let Some((r, u)) = directions[b] {
+                let up = if r == from { u } else { !u };
+                return if up { "/" } else { "\\" };
+            }
+            let bond = &self.bonds[b];
+            let aromatic_ends = self.atoms[bond.atom1].is_aromatic
+                && self.atoms[bond.atom2].is_aromatic;
+            match bond.bond_order {
+                _ if bond.is_aromatic && aromatic_ends => "",
+                1 if aromatic_ends => "-",
+                2 => "=",
+                3 => "#",
+                4 => "$",
+                _ => "",
+            }
+        };
+
+        let atom_text = |i: usize| -> String {
+            let atom = &self.atoms[i];
+            let element = symbol(atom.atomic_number).unwrap_or("*");
+            let aromatic = atom.is_aromatic
+                && matches!(
+                    atom.atomic_number,
+                    5 | 6 | 7 | 8 | 15 | 16 | 33 | 34
+                );
+            let element = if aromatic {
+                element.to_lowercase()
+            } else {
+                element.to_owned()
+            };
+
+            let chirality = match source {
+                Some(source) if centres[i] => {
+                    // the neighbors in the order they appear in the string
+                    let mut order: Vec<usize> = Vec::new();
+                    order.extend(tree.parent[i].map(|(p, _)| p));
+                    order.extend(&hidden[i]);
+                    order.extend(tree.rings[i].iter().map(|&(j, _)| j));
+                    order.extend(tree.children[i].iter().map(|&(j, _)| j));
+                    let order = [order[0], order[1], order[2], order[3]];
+                    match self.anticlockwise(source, i, order) {
+                        Some(true) => "@",
+                        Some(false) => "@@",
+                        None => "",
+                    }
+                }
+                _ => "",
+            };
+
+            let n_hidden = hidden[i].len();
+            // readers count aromatic bonds as single bonds plus one extra
+            // valence for the whole ring system
+            let bonds: Vec<_> = tree.parent[i]
+                .iter()
+                .chain(&tree.children[i])
+                .chain(&tree.rings[i])
+                .map(|&(_, b)| &self.bonds[b])
+                .collect();
+            let in_ring = bonds.iter().any(|b| b.is_aromatic);
+            let valence: usize = bonds
+                .iter()
+                .map(|b| {
+                    if b.is_aromatic {
+                        1
+                    } else {
+                        b.bond_order as usize
+                    }
+                })
+                .sum::<usize>()
+                + in_ring as usize;
+            let implicit = default_valences(atom.atomic_number)
+                .iter()
+                .find(|&&v| v >= valence)
+                .map_or(0, |v| v - valence);
+            let bare = atom.formal_charge == 0
+                && !options.mapped
+                && chirality.is_empty()
+                && !default_valences(atom.atomic_number).is_empty()
+                && implicit == n_hidden
+                && (!aromatic || atom.atomic_number == 6 || n_hidden == 0);
+            if bare {
+                return element;
+            }
+
+            let mut ret = format!("[{element}{chirality}");
+            match n_hidden {
+                0 => {}
+                1 => ret.push('H'),
+                h => ret.push_str(&format!("H{h}")),
+            }
+            match atom.formal_charge {
+                0 => {}
+                1 => ret.push('+'),
+                -1 => ret.push('-'),
+                q => ret.push_str(&format!("{q:+}")),
+            }
+            if options.mapped {
+                ret.push_str(&format!(":{}", i + 1));
+            }
+            ret.push(']');
+            ret
+        };
+
+        enum Task {
+            Atom(usize),
+            Open,
+            Close,
+        }
+        let mut ret = String::new();
+        let mut digits: Vec<Option<usize>> = vec![None; self.bonds.len()];
+        let mut in_use = [false; 100];
+        for (c, &root) in tree.roots.iter().enumerate() {
+            if c > 0 {
+                ret.push('.');
+            }
+            let mut stack = vec![Task::Atom(root)];
+            while let Some(task) = stack.pop() {
+                let i = match task {
+                    Task::Open => {
+                        ret.push('(');
+                        continue;
+                    }
+                    Task::Close => {
+                        ret.push(')');
+                        continue;
+                    }
+                    Task::Atom(i) => i,
+                };
+                if let Some((p, b)) = tree.parent[i] {
+                    ret.push_str(bond_symbol(b, p));
+                }
+                ret.push_str(&atom_text(i));
+
+                let mut released = Vec::new();
+                for &(_, b) in &tree.rings[i] {
+                    let d = match digits[b] {
+                        Some(d) => {
+                            released.push(d);
+                            d
+                        }
+                        None => {
+                            let d = (1..100).find(|&d| !in_use[d]).ok_or_else(
+                                || SmilesError {
+                                    message: "more than 99 ring closures \
+                                              open at once"
+                                        .to_owned(),
+                                },
+                            )?;
+                            in_use[d] = true;
+                            digits[b] = Some(d);
+                            ret.push_str(bond_symbol(b, i));
+                            d
+                        }
+                    };
+                    if d < 10 {
+                        ret.push_str(&d.to_string());
+                    } else {
+                        ret.push_str(&format!("%{d}"));
+                    }
+                }
+                for d in released {
+                    in_use[d] = false;
+                }
+
+                // the last child continues the chain, the rest are branches
+                if let Some((&(last, _), rest)) = tree.children[i].split_last()
+                {
+                    stack.push(Task::Atom(last));
+                    for &(child, _) in rest.iter().rev() {
+                        stack.push(Task::Close);
+                        stack.push(Task::Atom(child));
+                        stack.push(Task::Open);
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// The signed volume spanned by atoms `atoms` in `conformer`, which is
+/// negative when the last three run anticlockwise looking from the first
+pub(crate) fn chiral_volume(conformer: &[f64], atoms: [usize; 4]) -> f64 {
+    let [p0, p1, p2, p3] = atoms.map(|i| position(conformer, i));
+    dot(sub(p1, p0), cross(sub(p2, p0), sub(p3, p0)))
+}
+
+/// The smallest [chiral_volume], relative to the product of the lengths of
+/// the three edges spanning it, for which [handedness] reads a configuration.
+/// The neighbors of a stereocentre in a 2D drawing are flatter than this.
+const MIN_CHIRAL_VOLUME: f64 = 1e-2;
+
+/// Return whether the last three of `atoms` run anticlockwise looking from
+/// the first in `conformer`, or None if the four atoms are too close to
+/// coplanar to tell
+pub(crate) fn handedness(conformer: &[f64], atoms: [usize; 4]) -> Option<bool> {
+    let [p0, p1, p2, p3] = atoms.map(|i| position(conformer, i));
+    let edges = [p1, p2, p3].map(|p| dot(sub(p, p0), sub(p, p0)).sqrt());
+    let volume = chiral_volume(conformer, atoms);
+    (volume.abs() >= MIN_CHIRAL_VOLUME * edges.iter().product::<f64>())
+        .then_some(volume < 0.0)
+}
+
+/// Return whether `x` and `y` lie on the same side of the `a`-`b` axis for
+/// atoms `[x, a, b, y]` in `conformer`
+pub(crate) fn is_cis(conformer: &[f64], atoms: [usize; 4]) -> bool {
+    let [x, a, b, y] = atoms.map(|i| position(conformer, i));
+    let axis = sub(b, a);
+    let project = |v: [f64; 3]| {
+        let f = dot(v, axis) / dot(axis, axis);
+        sub(v, axis.map(|x| x * f))
+    };
+    dot(project(sub(x, a)), project(sub(y, b))) > 0.0
+}
+
+fn position(conformer: &[f64], atom: usize) -> [f64; 3] {
+    [0, 1, 2].map(|c| conformer[3 * atom + c])
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{alanine, ethanol, graph, reversed};
+
+    use super::*;
+
+    #[test]
+    fn ethanol_smiles() {
+        let g = ethanol();
+        let plain = SmilesOptions {
+            canonical: false,
+            ..Default::default()
+        };
+        assert_eq!(g.to_smiles(&plain).unwrap(), "CCO");
+        let mapped = SmilesOptions {
+            canonical: false,
+            ..SmilesOptions::CMILES
+        };
+        assert_eq!(
+            g.to_smiles(&mapped).unwrap(),
+            "[C:1]([C:2]([O:3][H:9])([H:7])[H:8])([H:4])([H:5])[H:6]"
+        );
+
+        let canonical = g.to_smiles(&SmilesOptions::default()).unwrap();
+        assert_eq!(
+            canonical,
+            reversed(&g).to_smiles(&Default::default()).unwrap()
+        );
+        let explicit = SmilesOptions {
+            explicit_hydrogens: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            g.to_smiles(&explicit).unwrap(),
+            reversed(&g).to_smiles(&explicit).unwrap()
+        );
+    }
+
+    #[test]
+    fn rings_and_charges() {
+        // benzene and acetate, as two components of one graph
+        let mut atoms = vec![6; 6];
+        atoms.extend([1; 6]);
+        atoms.extend([6, 6, 8, 8, 1, 1, 1]);
+        let mut bonds: Vec<_> = (0..6).map(|i| (i, (i + 1) % 6, 0)).collect();
+        bonds.extend((0..6).map(|i| (i, i + 6, 1)));
+        bonds.extend([
+            (12, 13, 1),
+            (13, 14, 2),
+            (13, 15, 1),
+            (12, 16, 1),
+            (12, 17, 1),
+            (12, 18, 1),
+        ]);
+        let mut g = graph(&atoms, &bonds);
+        g.atoms[15].formal_charge = -1;
+        let plain = SmilesOptions {
+            canonical: false,
+            ..Default::default()
+        };
+        assert_eq!(g.to_smiles(&plain).unwrap(), "c1ccccc1.CC(=O)[O-]");
+        assert_eq!(
+            g.to_smiles(&Default::default()).unwrap(),
+            reversed(&g).to_smiles(&Default::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn chirality() {
+        let g = alanine();
+        let plain = SmilesOptions {
+            canonical: false,
+            ..Default::default()
+        };
+        assert_eq!(g.to_smiles(&plain).unwrap(), "N[C@@H](C)C(=O)O");
+        let flat = SmilesOptions {
+            isomeric: false,
+            ..plain
+        };
+        assert_eq!(g.to_smiles(&flat).unwrap(), "NC(C)C(=O)O");
+
+        // mirroring the conformer inverts the centre
+        let mut mirror = g.clone();
+        mirror.conformers[0]
+            .iter_mut()
+            .step_by(3)
+            .for_each(|x| *x = -*x);
+        assert_eq!(mirror.to_smiles(&plain).unwrap(), "N[C@H](C)C(=O)O");
+
+        // the canonical string describes the same centre in any atom order
+        let r = reversed(&g);
+        assert_eq!(
+            g.to_smiles(&Default::default()).unwrap(),
+            r.to_smiles(&Default::default()).unwrap()
+        );
+        assert_ne!(
+            g.to_smiles(&Default::default()).unwrap(),
+            mirror.to_smiles(&Default::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn double_bonds() {
+        // 2-butene, C0-C1=C2-C3, with the hydrogens on C1 and C2 last
+        let mut g = graph(
+            &[6, 6, 6, 6, 1, 1, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 2),
+                (2, 3, 1),
+                (0, 4, 1),
+                (0, 5, 1),
+                (0, 6, 1),
+                (3, 7, 1),
+                (3, 8, 1),
+                (3, 9, 1),
+                (1, 10, 1),
+                (2, 11, 1),
+            ],
+        );
+        let mut trans = vec![[0.0; 3]; 12];
+        trans[0] = [-1.0, 1.0, 0.0];
+        trans[1] = [-0.5, 0.0, 0.0];
+        trans[2] = [0.5, 0.0, 0.0];
+        trans[3] = [1.0, -1.0, 0.0];
+        trans[10] = [-1.0, -1.0, 0.0];
+        trans[11] = [1.0, 1.0, 0.0];
+        let mut cis = trans.clone();
+        cis[3] = [1.0, 1.0, 0.0];
+        cis[11] = [1.0, -1.0, 0.0];
+
+        let plain = SmilesOptions {
+            canonical: false,
+            ..Default::default()
+        };
+        g.conformers = vec![trans.concat()];
+        assert_eq!(g.to_smiles(&plain).unwrap(), "C/C=C/C");
+        g.conformers = vec![cis.concat()];
+        assert_eq!(g.to_smiles(&plain).unwrap(), "C/C=C\\C");
+        let explicit = SmilesOptions {
+            explicit_hydrogens: true,
+            ..plain
+        };
+        assert_eq!(
+            g.to_smiles(&explicit).unwrap(),
+            "C(/C(=C(\\C([H])([H])[H])/[H])/[H])([H])([H])[H]"
+        );
+    }
+
+    #[test]
+    fn individualization() {
+        // the Frucht graph is cubic but has no symmetry, so refinement alone
+        // leaves every atom tied
+        let lcf = [-5, -2, -4, 2, 5, -2, 2, 5, -2, -5, 4, 2];
+        let mut bonds: Vec<_> = (0..12).map(|i| (i, (i + 1) % 12, 1)).collect();
+        for (i, d) in lcf.into_iter().enumerate() {
+            let j = (i as isize + d).rem_euclid(12) as usize;
+            if i < j {
+                bonds.push((i, j, 1));
+            }
+        }
+        let frucht = graph(&[6; 12], &bonds);
+        let moved: Vec<_> = bonds
+            .iter()
+            .map(|&(i, j, o)| ((5 * i + 3) % 12, j, o))
+            .collect();
+        let moved: Vec<_> = moved
+            .iter()
+            .map(|&(i, j, o)| (i, (5 * j + 3) % 12, o))
+            .collect();
+        let want = frucht.to_smiles(&Default::default()).unwrap();
+        for g in [reversed(&frucht), graph(&[6; 12], &moved)] {
+            assert_eq!(g.to_smiles(&Default::default()).unwrap(), want);
+        }
+
+        // symmetry prunes the search over many identical rings
+        let mut bonds = Vec::new();
+        for r in 0..12 {
+            bonds.extend((0..6).map(|i| (6 * r + i, 6 * r + (i + 1) % 6, 0)));
+        }
+        bonds.extend((0..72).map(|i| (i, 72 + i, 1)));
+        let mut atoms = vec![6; 72];
+        atoms.extend([1; 72]);
+        let rings = graph(&atoms, &bonds);
+        assert_eq!(
+            rings.to_smiles(&Default::default()).unwrap(),
+            ["c1ccccc1"; 12].join(".")
+        );
+    }
+
+    #[test]
+    fn assigned_stereo() {
+        // the CMILES of a molecule without a conformer regenerates itself
+        // from the stereochemistry read back from it
+        let g = alanine();
+        let cmiles = g.to_smiles(&SmilesOptions::CMILES).unwrap();
+        assert!(cmiles.contains('@'), "{cmiles}");
+        let mut flat = g.clone();
+        flat.conformers.clear();
+        assert!(!flat
+            .to_smiles(&SmilesOptions::CMILES)
+            .unwrap()
+            .contains('@'));
+        flat.stereo = Some(flat.stereo_from_mapped_smiles(&cmiles).unwrap());
+        assert_eq!(flat.to_smiles(&SmilesOptions::CMILES).unwrap(), cmiles);
+        let want = g.to_smiles(&Default::default()).unwrap();
+        assert_eq!(
+            reversed(&flat).to_smiles(&Default::default()).unwrap(),
+            want
+        );
+
+        // and likewise for double bonds
+        let mut butene = graph(
+            &[6, 6, 6, 6, 1, 1, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 2),
+                (2, 3, 1),
+                (0, 4, 1),
+                (0, 5, 1),
+                (0, 6, 1),
+                (3, 7, 1),
+                (3, 8, 1),
+                (3, 9, 1),
+                (1, 10, 1),
+                (2, 11, 1),
+            ],
+        );
+        for z in [BondStereochemistry::E, BondStereochemistry::Z] {
+            let mut stereo = StereoAssignment {
+                atoms: vec![Stereochemistry::None; 12],
+                bonds: vec![BondStereochemistry::None; 11],
+            };
+            stereo.bonds[1] = z;
+            butene.stereo = Some(stereo.clone());
+            let cmiles = butene.to_smiles(&SmilesOptions::CMILES).unwrap();
+            assert_eq!(
+                butene.stereo_from_mapped_smiles(&cmiles).unwrap(),
+                stereo
+            );
+            let want = match z {
+                BondStereochemistry::E => "C/C=C/C",
+                _ => "C/C=C\\C",
+            };
+            let plain = SmilesOptions {
+                canonical: false,
+                ..Default::default()
+            };
+            assert_eq!(butene.to_smiles(&plain).unwrap(), want);
+        }
+    }
+
+    #[test]
+    fn ring_closures() {
+        // a hub bonded to every atom of a chain of `n` opens a ring closure to
+        // each of them but the first
+        let hub = |n: usize| {
+            let mut bonds: Vec<_> = (1..=n).map(|i| (0, i, 1)).collect();
+            bonds.extend((1..n).map(|i| (i, i + 1, 1)));
+            graph(&vec![6; n + 1], &bonds)
+        };
+        let plain = SmilesOptions {
+            canonical: false,
+            ..Default::default()
+        };
+        let smiles = hub(100).to_smiles(&plain).unwrap();
+        assert!(smiles.contains("%99"), "{smiles}");
+        let err = hub(101).to_smiles(&plain).unwrap_err();
+        assert!(err.message.contains("99 ring closures"), "{err}");
+    }
+}
@@ -0,0 +1,957 @@
+//! CIP stereochemistry perception. Neighbors are ranked by the hierarchical
+//! digraph rules for atomic number (CIP rule 1a), with duplicate atoms for
+//! multiple bonds and ring closures, and the R/S and E/Z labels are then read
+//! either from conformer coordinates or from the chirality and bond direction
+//! marks of a mapped SMILES string. Later CIP rules, like those for
+//! isotopes and for stereo-dependent priorities, are not applied, so centres
+//! whose neighbors only differ by those rules are left unlabelled.
+//!
+//! Stereocentres and stereogenic double bonds are found as in the
+//! [super::smiles] writer, from the symmetry classes of the neighbors.
+
+use std::{
+    cmp::Ordering, cmp::Reverse, collections::HashSet, error::Error,
+    fmt::Display, iter::repeat,
+};
+
+use ligand::molecule::Molecule;
+
+use crate::utils::elements::atomic_number;
+
+use super::{
+    molecule::{BondStereochemistry, MoleculeGraph, Stereochemistry},
+    smiles::{handedness, is_cis},
+};
+
+#[derive(Debug)]
+pub struct StereoError {
+    pub message: String,
+}
+
+impl Error for StereoError {}
+
+impl Display for StereoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stereo: {}", self.message)
+    }
+}
+
+fn err(message: impl Into<String>) -> StereoError {
+    StereoError {
+        message: message.into(),
+    }
+}
+
+/// The configuration of every atom and bond in a molecule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StereoAssignment {
+    pub atoms: Vec<Stereochemistry>,
+    pub bonds: Vec<BondStereochemistry>,
+}
+
+/// A node in the hierarchical digraph explored to rank CIP priorities
+#[derive(Clone)]
+struct Node {
+    atom: usize,
+    atomic_number: u8,
+
+    /// the atoms from the root to this node, or empty for duplicate atoms,
+    /// which have no substituents of their own
+    path: Vec<usize>,
+}
+
+/// The most nodes explored in one comparison before calling the branches
+/// equal
+const MAX_NODES: usize = 10_000;
+
+/// The most symmetries of a molecule considered in recognizing equivalent
+/// stereoisomers
+const MAX_SYMMETRIES: usize = 1000;
+
+impl MoleculeGraph {
+    /// Build the molecule described by the mapped SMILES `smiles` with its
+    /// [MoleculeGraph::stereo] read from the SMILES, since the stereochemistry
+    /// is lost in converting a [Molecule] to a [MoleculeGraph]
+    pub fn from_mapped_smiles(smiles: &str) -> Result<Self, Box<dyn Error>> {
+        let molecule = Molecule::from_mapped_smiles(smiles)
+            .map_err(|e| err(format!("{e:?}")))?;
+        let mut ret = Self::from(&molecule);
+        ret.stereo = Some(ret.stereo_from_mapped_smiles(smiles)?);
+        Ok(ret)
+    }
+
+    /// Return the configuration of every stereo element from
+    /// [MoleculeGraph::stereo] if it is set and otherwise from the first
+    /// conformer, or None if there is neither
+    pub fn known_stereo(&self) -> Option<StereoAssignment> {
+        match &self.stereo {
+            Some(stereo) => Some(stereo.clone()),
+            None if !self.conformers.is_empty() => {
+                Some(self.stereo_from_conformer(0))
+            }
+            None => None,
+        }
+    }
+
+    fn cip_children(&self, node: &Node) -> Vec<Node> {
+        let Some(&parent) = node.path.iter().rev().nth(1) else {
+            return Vec::new();
+        };
+        let duplicate = |atom: usize| Node {
+            atom,
+            atomic_number: self.atoms[atom].atomic_number,
+            path: Vec::new(),
+        };
+        let mut ret = Vec::new();
+        for &(n, b) in self.adjacent(node.atom) {
+            let extra = self.bonds[b].bond_order.max(1) as usize - 1;
+            if n != parent {
+                if node.path.contains(&n) {
+                    ret.push(duplicate(n));
+                } else {
+                    let mut path = node.path.clone();
+                    path.push(n);
+                    ret.push(Node {
+                        path,
+                        ..duplicate(n)
+                    });
+                }
+            }
+            ret.extend((0..extra).map(|_| duplicate(n)));
+        }
+        ret.sort_by_key(|n| (Reverse(n.atomic_number), n.path.is_empty()));
+        ret
+    }
+
+    /// Compare the CIP priorities of the branches starting at `a` and `b`,
+    /// both neighbors of `root`
+    fn cip_compare(&self, root: usize, a: usize, b: usize) -> Ordering {
+        let node = |atom: usize| Node {
+            atom,
+            atomic_number: self.atoms[atom].atomic_number,
+            path: vec![root, atom],
+        };
+        let mut budget = MAX_NODES;
+        self.compare_nodes(&node(a), &node(b), &mut budget)
+    }
+
+    /// Compare the branches of the hierarchical digraph below `a` and `b` by
+    /// CIP rule 1a: first by their atomic numbers, and then sphere by sphere
+    /// by the sets of atomic numbers of the substituents of each node, taking
+    /// the nodes of each sphere in order of their own priority. Duplicate
+    /// atoms have only phantom substituents of atomic number 0. The branches
+    /// are called equal once `budget` nodes have been explored.
+    fn compare_nodes(
+        &self,
+        a: &Node,
+        b: &Node,
+        budget: &mut usize,
+    ) -> Ordering {
+        let ord = a.atomic_number.cmp(&b.atomic_number);
+        if ord.is_ne() {
+            return ord;
+        }
+        let mut fa = vec![a.clone()];
+        let mut fb = vec![b.clone()];
+        while !fa.is_empty() {
+            let Some(left) = budget.checked_sub(fa.len() + fb.len()) else {
+                return Ordering::Equal;
+            };
+            *budget = left;
+            let ca: Vec<_> =
+                fa.iter().map(|n| self.ranked_children(n, budget)).collect();
+            let cb: Vec<_> =
+                fb.iter().map(|n| self.ranked_children(n, budget)).collect();
+            for (x, y) in ca.iter().zip(&cb) {
+                let len = x.len().max(y.len());
+                let set = |children: &[Node]| {
+                    children
+                        .iter()
+                        .map(|n| n.atomic_number)
+                        .chain(repeat(0))
+                        .take(len)
+                        .collect::<Vec<_>>()
+                };
+                let ord = set(x).cmp(&set(y));
+                if ord.is_ne() {
+                    return ord;
+                }
+            }
+            // equal sets have the same number of real atoms, so the next
+            // spheres line up
+            fa = ca.concat();
+            fb = cb.concat();
+        }
+        Ordering::Equal
+    }
+
+    /// Return the substituents of `node` in decreasing CIP priority
+    fn ranked_children(&self, node: &Node, budget: &mut usize) -> Vec<Node> {
+        let mut ret = self.cip_children(node);
+        ret.sort_by(|x, y| self.compare_nodes(y, x, budget));
+        ret
+    }
+
+    /// Return `branches`, neighbors of `root`, in decreasing CIP priority, or
+    /// None if any two have the same priority
+    pub fn cip_ranked(
+        &self,
+        root: usize,
+        mut branches: Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        branches.sort_by(|&a, &b| self.cip_compare(root, b, a));
+        branches
+            .windows(2)
+            .all(|w| self.cip_compare(root, w[0], w[1]).is_ne())
+            .then_some(branches)
+    }
+
+    /// The indices of the tetrahedral stereocentres
+    pub fn stereocentres(&self) -> Vec<usize> {
+        let classes = self.symmetry_classes();
+        self.tetrahedral_centres(&classes)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, c)| c.then_some(i))
+            .collect()
+    }
+
+    /// The indices of the stereogenic double bonds
+    pub fn stereo_bonds(&self) -> Vec<usize> {
+        let classes = self.symmetry_classes();
+        (0..self.bonds.len())
+            .filter(|&b| self.is_stereo_double_bond(b, &classes))
+            .collect()
+    }
+
+    /// The highest priority substituent of each end of bond `bond`, as
+    /// `[x, a, b, y]` for the bond `a=b`
+    pub(crate) fn top_substituents(&self, bond: usize) -> Option<[usize; 4]> {
+        let (a, b) = (self.bonds[bond].atom1, self.bonds[bond].atom2);
+        let top = |a: usize, other: usize| {
+            let subs = self.neighbors(a).filter(|&n| n != other).collect();
+            self.cip_ranked(a, subs).map(|r| r[0])
+        };
+        Some([top(a, b)?, a, b, top(b, a)?])
+    }
+
+    /// Assign the configuration of every stereocentre and stereogenic double
+    /// bond from the geometry of conformer `conformer`. Centres whose
+    /// neighbors are nearly coplanar, as in a 2D drawing, are left undefined.
+    pub fn stereo_from_conformer(&self, conformer: usize) -> StereoAssignment {
+        let conf = &self.conformers[conformer];
+        let mut ret = StereoAssignment {
+            atoms: vec![Stereochemistry::None; self.n_atoms()],
+            bonds: vec![BondStereochemistry::None; self.bonds.len()],
+        };
+        for c in self.stereocentres() {
+            let Some(r) = self.cip_ranked(c, self.neighbors(c).collect())
+            else {
+                continue;
+            };
+            // looking from the lowest priority neighbor, anticlockwise is
+            // clockwise with it pointing away
+            ret.atoms[c] = match handedness(conf, [r[3], r[0], r[1], r[2]]) {
+                Some(true) => Stereochemistry::R,
+                Some(false) => Stereochemistry::S,
+                None => Stereochemistry::None,
+            };
+        }
+        for b in self.stereo_bonds() {
+            if let Some(atoms) = self.top_substituents(b) {
+                ret.bonds[b] = if is_cis(conf, atoms) {
+                    BondStereochemistry::Z
+                } else {
+                    BondStereochemistry::E
+                };
+            }
+        }
+        ret
+    }
+
+    /// Assign the configuration of every stereocentre and stereogenic double
+    /// bond from the `@`/`@@` and `/`/`\` marks in `smiles`, a SMILES string
+    /// for this molecule where every atom carries its index plus one as the
+    /// map number. Stereo elements without marks are left undefined.
+    pub fn stereo_from_mapped_smiles(
+        &self,
+        smiles: &str,
+    ) -> Result<StereoAssignment, StereoError> {
+        let parsed = parse_smiles(smiles)?;
+        let index: Vec<usize> = parsed
+            .atoms
+            .iter()
+            .map(|a| match a.map {
+                Some(m @ 1..) if m <= self.n_atoms() => Ok(m - 1),
+                _ => Err(err("every atom must have a valid map number")),
+            })
+            .collect::<Result<_, _>>()?;
+        for (p, atom) in parsed.atoms.iter().enumerate() {
+            if self.atoms[index[p]].atomic_number != atom.atomic_number {
+                return Err(err(format!(
+                    "atom {} has the wrong element",
+                    p + 1
+                )));
+            }
+        }
+
+        let mut ret = StereoAssignment {
+            atoms: vec![Stereochemistry::None; self.n_atoms()],
+            bonds: vec![BondStereochemistry::None; self.bonds.len()],
+        };
+        for (p, atom) in parsed.atoms.iter().enumerate() {
+            let (c, Some(clockwise)) = (index[p], atom.chirality) else {
+                continue;
+            };
+            // the written neighbor order, filling in any implicit hydrogen
+            let listed: Vec<_> = atom
+                .neighbors
+                .iter()
+                .filter_map(|n| n.map(|n| index[n]))
+                .collect();
+            let order: Vec<usize> = atom
+                .neighbors
+                .iter()
+                .map(|n| match n {
+                    Some(n) => Some(index[*n]),
+                    None => self.neighbors(c).find(|i| !listed.contains(i)),
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| err("implicit hydrogen not in molecule"))?;
+            if order.len() != 4 || self.degree(c) != 4 {
+                continue;
+            }
+            let Some(r) = self.cip_ranked(c, order.clone()) else {
+                continue;
+            };
+            // `@` (anticlockwise) in the CIP order is R, as in
+            // [MoleculeGraph::stereo_from_conformer]
+            let anticlockwise = !clockwise;
+            ret.atoms[c] = if anticlockwise == is_even(&order, r) {
+                Stereochemistry::R
+            } else {
+                Stereochemistry::S
+            };
+        }
+
+        // whether atom `to` is written above atom `from`
+        let up = |from: usize, to: usize| {
+            parsed.directions.iter().find_map(|&(f, t, up)| {
+                let (f, t) = (index[f], index[t]);
+                if (f, t) == (from, to) {
+                    Some(up)
+                } else if (t, f) == (from, to) {
+                    Some(!up)
+                } else {
+                    None
+                }
+            })
+        };
+        for b in self.stereo_bonds() {
+            let Some([top_x, a, c, top_y]) = self.top_substituents(b) else {
+                continue;
+            };
+            let marked = |a: usize, other: usize| {
+                self.neighbors(a)
+                    .filter(|&n| n != other)
+                    .find_map(|n| up(a, n).map(|u| (n, u)))
+            };
+            let (Some((x, ux)), Some((y, uy))) = (marked(a, c), marked(c, a))
+            else {
+                continue;
+            };
+            // the unmarked substituents lie on the other side
+            let cis = (ux == uy) == ((x == top_x) == (y == top_y));
+            ret.bonds[b] = if cis {
+                BondStereochemistry::Z
+            } else {
+                BondStereochemistry::E
+            };
+        }
+        Ok(ret)
+    }
+
+    /// Assign the configuration of every stereocentre from its MDL parity in
+    /// `parities`, as read from an SD file. Looking with the bond to the
+    /// highest numbered neighbor pointing away, the other neighbors are
+    /// numbered clockwise for parity 1 (odd) and anticlockwise for parity 2
+    /// (even). Centres with other parities and double bonds, which have no
+    /// MDL parity, are left undefined.
+    pub fn stereo_from_parities(&self, parities: &[u8]) -> StereoAssignment {
+        let mut ret = StereoAssignment {
+            atoms: vec![Stereochemistry::None; self.n_atoms()],
+            bonds: vec![BondStereochemistry::None; self.bonds.len()],
+        };
+        for c in self.stereocentres() {
+            let clockwise = match parities.get(c) {
+                Some(1) => true,
+                Some(2) => false,
+                _ => continue,
+            };
+            let mut order: Vec<usize> = self.neighbors(c).collect();
+            if order.len() != 4 {
+                continue;
+            }
+            // [highest, lowest, second lowest, third lowest]
+            order.sort();
+            order.rotate_right(1);
+            let Some(r) = self.cip_ranked(c, order.clone()) else {
+                continue;
+            };
+            // clockwise from the CIP lowest priority pointing away is R
+            ret.atoms[c] = if clockwise == is_even(&order, r) {
+                Stereochemistry::R
+            } else {
+                Stereochemistry::S
+            };
+        }
+        ret
+    }
+
+    /// Return the stereocentres and stereogenic double bonds that are left
+    /// undefined in `assignment`
+    pub fn undefined_stereo(
+        &self,
+        assignment: &StereoAssignment,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let atoms = self
+            .stereocentres()
+            .into_iter()
+            .filter(|&i| assignment.atoms[i] == Stereochemistry::None)
+            .collect();
+        let bonds = self
+            .stereo_bonds()
+            .into_iter()
+            .filter(|&b| assignment.bonds[b] == BondStereochemistry::None)
+            .collect();
+        (atoms, bonds)
+    }
+
+    /// Enumerate up to `limit` distinct stereoisomers filling in every
+    /// undefined stereo element of `assignment` in turn. Assignments related
+    /// by a symmetry of the molecule, like the two labellings of a meso
+    /// compound, describe the same stereoisomer, so only the first of them
+    /// is kept.
+    pub fn enumerate_stereoisomers(
+        &self,
+        assignment: &StereoAssignment,
+        limit: usize,
+    ) -> Vec<StereoAssignment> {
+        let (atoms, bonds) = self.undefined_stereo(assignment);
+        let symmetries = self.stereo_symmetries();
+        let mut seen = HashSet::new();
+        let mut ret = Vec::new();
+        // count through the choices in binary, so the first element changes
+        // slowest
+        let mut choice = vec![false; atoms.len() + bonds.len()];
+        while ret.len() < limit {
+            let mut s = assignment.clone();
+            for (&a, &c) in atoms.iter().zip(&choice) {
+                s.atoms[a] = if c {
+                    Stereochemistry::S
+                } else {
+                    Stereochemistry::R
+                };
+            }
+            for (&b, &c) in bonds.iter().zip(&choice[atoms.len()..]) {
+                s.bonds[b] = if c {
+                    BondStereochemistry::Z
+                } else {
+                    BondStereochemistry::E
+                };
+            }
+            if seen.insert(self.canonical_stereo(&s, &symmetries)) {
+                ret.push(s);
+            }
+            let Some(i) = choice.iter().rposition(|&c| !c) else {
+                break;
+            };
+            choice[i] = true;
+            choice[i + 1..].iter_mut().for_each(|c| *c = false);
+        }
+        ret
+    }
+
+    /// Return the (atom, bond) permutations of the molecule that preserve
+    /// elements, formal charges, hydrogen counts, and bond orders, up to
+    /// [MAX_SYMMETRIES] of them
+    fn stereo_symmetries(&self) -> Vec<(Vec<usize>, Vec<usize>)> {
+        let heavy = self.heavy_atoms();
+        let mut ret = Vec::new();
+        for p in self.heavy_atom_automorphisms(MAX_SYMMETRIES) {
+            let mut atoms: Vec<usize> = (0..self.n_atoms()).collect();
+            let hydrogens = |i: usize| {
+                self.neighbors(i)
+                    .filter(|&h| self.atoms[h].atomic_number == 1)
+            };
+            for (&i, &j) in heavy.iter().zip(&p) {
+                atoms[i] = j;
+                // no stereo element depends on which hydrogen is which
+                for (h, k) in hydrogens(i).zip(hydrogens(j)) {
+                    atoms[h] = k;
+                }
+            }
+            let bonds: Option<Vec<usize>> = self
+                .bonds
+                .iter()
+                .map(|bond| {
+                    let image =
+                        self.bond_index(atoms[bond.atom1], atoms[bond.atom2])?;
+                    (self.bonds[image].bond_order == bond.bond_order)
+                        .then_some(image)
+                })
+                .collect();
+            if let Some(bonds) = bonds {
+                ret.push((atoms, bonds));
+            }
+        }
+        ret
+    }
+
+    /// Return the smallest of the labels of `assignment` carried over by each
+    /// of `symmetries`, which is the same for assignments describing the same
+    /// stereoisomer
+    fn canonical_stereo(
+        &self,
+        assignment: &StereoAssignment,
+        symmetries: &[(Vec<usize>, Vec<usize>)],
+    ) -> (Vec<u8>, Vec<u8>) {
+        symmetries
+            .iter()
+            .map(|(atoms, bonds)| {
+                let mut a = vec![0; atoms.len()];
+                for (i, &j) in atoms.iter().enumerate() {
+                    a[j] = assignment.atoms[i] as u8;
+                }
+                let mut b = vec![0; bonds.len()];
+                for (i, &j) in bonds.iter().enumerate() {
+                    b[j] = assignment.bonds[i] as u8;
+                }
+                (a, b)
+            })
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+/// Return whether the permutation taking the neighbor `order` of a
+/// stereocentre to the CIP order `[lowest, highest, second, third]` is even,
+/// given the neighbors `ranked` in decreasing priority
+pub(crate) fn is_even(order: &[usize], ranked: Vec<usize>) -> bool {
+    let pos = [ranked[3], ranked[0], ranked[1], ranked[2]]
+        .map(|a| order.iter().position(|&o| o == a).unwrap());
+    let inversions = (0..4)
+        .flat_map(|i| (i + 1..4).map(move |j| (i, j)))
+        .filter(|&(i, j)| pos[i] > pos[j])
+        .count();
+    inversions % 2 == 0
+}
+
+struct SmilesAtom {
+    atomic_number: u8,
+    map: Option<usize>,
+
+    /// Some(true) for `@@` and Some(false) for `@`
+    chirality: Option<bool>,
+
+    /// neighbors in written order, with None for an implicit hydrogen
+    neighbors: Vec<Option<usize>>,
+}
+
+struct ParsedSmiles {
+    atoms: Vec<SmilesAtom>,
+
+    /// (from, to, whether `to` is above `from`) for each `/` or `\` bond
+    directions: Vec<(usize, usize, bool)>,
+}
+
+/// Parse the atoms, neighbor order, and stereo marks of a SMILES string
+fn parse_smiles(smiles: &str) -> Result<ParsedSmiles, StereoError> {
+    let chars: Vec<char> = smiles.chars().collect();
+    let mut atoms: Vec<SmilesAtom> = Vec::new();
+    let mut directions = Vec::new();
+    let mut prev: Option<usize> = None;
+    let mut branches = Vec::new();
+    let mut direction: Option<bool> = None;
+    // the opening atom, its neighbor slot, and its direction for each ring
+    let mut rings: Vec<Option<(usize, usize, Option<bool>)>> = vec![None; 100];
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        let element = match c {
+            '(' => {
+                branches.push(prev);
+                continue;
+            }
+            ')' => {
+                prev = branches.pop().ok_or_else(|| err("unmatched )"))?;
+                continue;
+            }
+            '.' => {
+                prev = None;
+                continue;
+            }
+            '/' | '\\' => {
+                direction = Some(c == '/');
+                continue;
+            }
+            '-' | '=' | '#' | '$' | ':' => continue,
+            '0'..='9' | '%' => {
+                let digit = if c == '%' {
+                    let s: String =
+                        chars.get(i..i + 2).unwrap_or(&[]).iter().collect();
+                    i += 2;
+                    s.parse::<usize>()
+                        .map_err(|_| err(format!("bad ring number %{s}")))?
+                } else {
+                    c.to_digit(10).unwrap() as usize
+                };
+                let atom =
+                    prev.ok_or_else(|| err("ring bond without an atom"))?;
+                match rings[digit].take() {
+                    Some((open, slot, open_dir)) => {
+                        atoms[open].neighbors[slot] = Some(atom);
+                        atoms[atom].neighbors.push(Some(open));
+                        if let Some(up) = open_dir {
+                            directions.push((open, atom, up));
+                        } else if let Some(up) = direction {
+                            directions.push((atom, open, up));
+                        }
+                    }
+                    None => {
+                        // reserve the neighbor slot for the closing atom
+                        atoms[atom].neighbors.push(None);
+                        let slot = atoms[atom].neighbors.len() - 1;
+                        rings[digit] = Some((atom, slot, direction));
+                    }
+                }
+                direction = None;
+                continue;
+            }
+            '[' => {
+                let end = chars[i..]
+                    .iter()
+                    .position(|&c| c == ']')
+                    .ok_or_else(|| err("unclosed ["))?;
+                let text: String = chars[i..i + end].iter().collect();
+                i += end + 1;
+                Some(text)
+            }
+            _ => None,
+        };
+
+        let (atomic_number, chirality, hydrogens, map) = match element {
+            Some(text) => parse_bracket(&text)?,
+            None => {
+                // the organic subset, including two-letter halogens
+                let two: String =
+                    chars[i - 1..(i + 1).min(chars.len())].iter().collect();
+                let symbol = if two == "Cl" || two == "Br" {
+                    i += 1;
+                    two
+                } else {
+                    c.to_string()
+                };
+                let z = element_number(&symbol)
+                    .ok_or_else(|| err(format!("unknown atom {symbol}")))?;
+                (z, None, 0, None)
+            }
+        };
+
+        let index = atoms.len();
+        let mut neighbors = Vec::new();
+        if let Some(p) = prev {
+            neighbors.push(Some(p));
+            atoms[p].neighbors.push(Some(index));
+            if let Some(up) = direction.take() {
+                directions.push((p, index, up));
+            }
+        }
+        neighbors.extend((0..hydrogens).map(|_| None));
+        atoms.push(SmilesAtom {
+            atomic_number,
+            map,
+            chirality,
+            neighbors,
+        });
+        prev = Some(index);
+    }
+    if rings.iter().any(Option::is_some) {
+        return Err(err("unclosed ring"));
+    }
+    Ok(ParsedSmiles { atoms, directions })
+}
+
+/// Look up an element symbol, which is lowercase for aromatic atoms
+fn element_number(symbol: &str) -> Option<u8> {
+    let mut chars = symbol.chars();
+    let first = chars.next()?.to_ascii_uppercase();
+    atomic_number(&format!("{first}{}", chars.as_str()))
+}
+
+/// Parse the text of a bracket atom into its atomic number, chirality,
+/// hydrogen count, and map number
+fn parse_bracket(
+    text: &str,
+) -> Result<(u8, Option<bool>, usize, Option<usize>), StereoError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+    while i < chars.len() && chars[i].is_ascii_digit() {
+        i += 1; // isotope
+    }
+    // prefer two-letter symbols like Cl and se
+    let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+    let one: String = chars[i..(i + 1).min(chars.len())].iter().collect();
+    let two_letter = chars.get(i + 1).is_some_and(|c| c.is_ascii_lowercase());
+    let (z, len) = match element_number(&two) {
+        Some(z) if two_letter => (z, 2),
+        _ => (
+            element_number(&one)
+                .ok_or_else(|| err(format!("unknown atom [{text}]")))?,
+            1,
+        ),
+    };
+    i += len;
+
+    let mut chirality = None;
+    if chars.get(i) == Some(&'@') {
+        i += 1;
+        chirality = Some(chars.get(i) == Some(&'@'));
+        if chirality == Some(true) {
+            i += 1;
+        }
+    }
+    let mut hydrogens = 0;
+    if chars.get(i) == Some(&'H') {
+        i += 1;
+        hydrogens = 1;
+        if let Some(d) = chars.get(i).and_then(|c| c.to_digit(10)) {
+            hydrogens = d as usize;
+        }
+    }
+    let map = text
+        .rsplit_once(':')
+        .map(|(_, m)| {
+            m.parse::<usize>()
+                .map_err(|_| err(format!("bad map number in [{text}]")))
+        })
+        .transpose()?;
+    Ok((z, chirality, hydrogens, map))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        testing::{alanine, graph},
+        topology::smiles::SmilesOptions,
+    };
+
+    use super::*;
+
+    fn mirror(g: &MoleculeGraph) -> MoleculeGraph {
+        let mut ret = g.clone();
+        ret.conformers[0]
+            .iter_mut()
+            .step_by(3)
+            .for_each(|x| *x = -*x);
+        ret
+    }
+
+    #[test]
+    fn alanine_centre() {
+        let g = alanine();
+        assert_eq!(g.stereocentres(), [1]);
+        // N > COOH > CH3 > H
+        assert_eq!(
+            g.cip_ranked(1, g.neighbors(1).collect()),
+            Some(vec![0, 3, 2, 12])
+        );
+        assert_eq!(g.stereo_from_conformer(0).atoms[1], Stereochemistry::S);
+        let m = mirror(&g);
+        assert_eq!(m.stereo_from_conformer(0).atoms[1], Stereochemistry::R);
+
+        // the same labels from the chirality marks of a mapped SMILES
+        for (g, want) in [(g, Stereochemistry::S), (m, Stereochemistry::R)] {
+            for canonical in [true, false] {
+                let smiles = g
+                    .to_smiles(&SmilesOptions {
+                        canonical,
+                        ..SmilesOptions::CMILES
+                    })
+                    .unwrap();
+                let got = g.stereo_from_mapped_smiles(&smiles).unwrap();
+                assert_eq!(got.atoms[1], want, "{smiles}");
+                assert!(g.undefined_stereo(&got).0.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn flat_conformer() {
+        // flattening the conformer onto the xy plane loses the centre
+        let mut g = alanine();
+        g.conformers[0]
+            .iter_mut()
+            .skip(2)
+            .step_by(3)
+            .for_each(|z| *z = 0.0);
+        let got = g.stereo_from_conformer(0);
+        assert_eq!(got.atoms[1], Stereochemistry::None);
+        assert_eq!(g.undefined_stereo(&got).0, [1]);
+        let smiles = g.to_smiles(&SmilesOptions::CMILES).unwrap();
+        assert!(!smiles.contains('@'), "{smiles}");
+    }
+
+    #[test]
+    fn hierarchical_digraph() {
+        // C1 outranks C2 on C0: the {O} set below C1's second carbon beats
+        // the {N} sets below C2's, although C1's first carbon has nothing
+        let g = graph(
+            &[6, 6, 6, 6, 6, 6, 6, 8, 7, 7],
+            &[
+                (0, 1, 1),
+                (0, 2, 1),
+                (1, 3, 1),
+                (1, 4, 1),
+                (2, 5, 1),
+                (2, 6, 1),
+                (4, 7, 1),
+                (5, 8, 1),
+                (6, 9, 1),
+            ],
+        );
+        assert_eq!(g.cip_ranked(0, vec![2, 1]), Some(vec![1, 2]));
+
+        // vinyl C1=C2 outranks isopropyl C3(C4)C5, since the duplicate of C1
+        // on C2 beats the bare methyls
+        let g = graph(
+            &[6, 6, 6, 6, 6, 6],
+            &[(0, 1, 1), (1, 2, 2), (0, 3, 1), (3, 4, 1), (3, 5, 1)],
+        );
+        assert_eq!(g.cip_ranked(0, vec![3, 1]), Some(vec![1, 3]));
+    }
+
+    #[test]
+    fn ring_centre() {
+        // 3-methylcyclohexanone, where the path to the carbonyl outranks
+        // the other way around the ring
+        let mut bonds = vec![
+            (0, 1, 1),
+            (1, 2, 1),
+            (2, 3, 1),
+            (3, 4, 1),
+            (4, 5, 1),
+            (5, 0, 1),
+            (0, 6, 2),
+            (2, 7, 1),
+        ];
+        let mut atoms = vec![6, 6, 6, 6, 6, 6, 8, 6];
+        for (c, n) in [(1, 2), (2, 1), (3, 2), (4, 2), (5, 2), (7, 3)] {
+            for _ in 0..n {
+                bonds.push((c, atoms.len(), 1));
+                atoms.push(1);
+            }
+        }
+        let g = graph(&atoms, &bonds);
+        assert_eq!(g.stereocentres(), [2]);
+        assert_eq!(
+            g.cip_ranked(2, g.neighbors(2).collect()),
+            Some(vec![1, 3, 7, 10])
+        );
+    }
+
+    #[test]
+    fn meso() {
+        // tartaric acid has three stereoisomers, since (R,S) and (S,R) are
+        // both the meso form
+        let g = graph(
+            &[6, 6, 6, 6, 8, 8, 8, 8, 8, 8, 1, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 1),
+                (2, 3, 1),
+                (0, 4, 2),
+                (0, 5, 1),
+                (1, 6, 1),
+                (2, 7, 1),
+                (3, 8, 2),
+                (3, 9, 1),
+                (1, 10, 1),
+                (2, 11, 1),
+                (5, 12, 1),
+                (6, 13, 1),
+                (7, 14, 1),
+                (9, 15, 1),
+            ],
+        );
+        assert_eq!(g.stereocentres(), [1, 2]);
+        let undefined = g.stereo_from_parities(&[]);
+        let got: Vec<_> = g
+            .enumerate_stereoisomers(&undefined, 10)
+            .into_iter()
+            .map(|s| (s.atoms[1], s.atoms[2]))
+            .collect();
+        use Stereochemistry::{R, S};
+        assert_eq!(got, [(R, R), (R, S), (S, S)]);
+    }
+
+    #[test]
+    fn undefined() {
+        let g = alanine();
+        let smiles = g
+            .to_smiles(&SmilesOptions::CMILES)
+            .unwrap()
+            .replace('@', "");
+        let got = g.stereo_from_mapped_smiles(&smiles).unwrap();
+        assert_eq!(g.undefined_stereo(&got), (vec![1], vec![]));
+        let isomers = g.enumerate_stereoisomers(&got, 10);
+        assert_eq!(isomers.len(), 2);
+        assert_eq!(isomers[0].atoms[1], Stereochemistry::R);
+        assert_eq!(isomers[1].atoms[1], Stereochemistry::S);
+        assert_eq!(g.enumerate_stereoisomers(&got, 1).len(), 1);
+
+        let bad = smiles.replacen(":1]", ":2]", 1);
+        assert!(g.stereo_from_mapped_smiles(&bad).is_err());
+    }
+
+    #[test]
+    fn double_bonds() {
+        // 1-chloropropene, Cl0-C1=C2-C3, where Cl outranks H on C1
+        let mut g = graph(
+            &[17, 6, 6, 6, 1, 1, 1, 1, 1],
+            &[
+                (0, 1, 1),
+                (1, 2, 2),
+                (2, 3, 1),
+                (3, 4, 1),
+                (3, 5, 1),
+                (3, 6, 1),
+                (1, 7, 1),
+                (2, 8, 1),
+            ],
+        );
+        let mut trans = vec![[0.0; 3]; 9];
+        trans[0] = [-1.0, 1.0, 0.0];
+        trans[1] = [-0.5, 0.0, 0.0];
+        trans[2] = [0.5, 0.0, 0.0];
+        trans[3] = [1.0, -1.0, 0.0];
+        trans[7] = [-1.0, -1.0, 0.0];
+        trans[8] = [1.0, 1.0, 0.0];
+        let mut cis = trans.clone();
+        cis[3] = [1.0, 1.0, 0.0];
+        cis[8] = [1.0, -1.0, 0.0];
+
+        for (conf, want) in [
+            (trans, BondStereochemistry::E),
+            (cis, BondStereochemistry::Z),
+        ] {
+            g.conformers = vec![conf.concat()];
+            assert_eq!(g.stereo_from_conformer(0).bonds[1], want);
+            let smiles = g.to_smiles(&SmilesOptions::CMILES).unwrap();
+            let got = g.stereo_from_mapped_smiles(&smiles).unwrap();
+            assert_eq!(got.bonds[1], want, "{smiles}");
+        }
+    }
+}
@@ -0,0 +1,38 @@
+//! Helpers shared across the crate that don't belong to any particular
+//! toolkit object
+
+use serde::{Deserialize, Serialize};
+
+pub mod elements;
+pub mod geometry;
+pub mod minimize;
+pub mod npy;
+
+/// Conversion factor from hartrees, the energy unit used by QCArchive, to
+/// kcal/mol, the energy unit used by the force field
+pub const HARTREE_TO_KCAL: f64 = 627.509474;
+
+/// Conversion factor from bohr, the length unit used by QCArchive, to angstroms
+pub const BOHR_TO_ANGSTROM: f64 = 0.529177210903;
+
+/// Coulomb's constant e²/4πε₀ in kcal/mol Å per squared elementary charge,
+/// which is one hartree bohr
+pub const COULOMB_CONSTANT: f64 = HARTREE_TO_KCAL * BOHR_TO_ANGSTROM;
+
+/// An input left out of a result, such as a record without a conformer or a
+/// molecule that could not be parameterized
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Skipped {
+    /// the id of the record or parameter that was left out
+    pub id: String,
+    pub reason: String,
+}
+
+impl Skipped {
+    pub fn new(id: impl Into<String>, reason: impl ToString) -> Self {
+        Self {
+            id: id.into(),
+            reason: reason.to_string(),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Element symbols and masses indexed by atomic number
+
+/// Element symbols through radon. `SYMBOLS[0]` is a placeholder so that the
+/// array can be indexed directly by atomic number
+pub const SYMBOLS: [&str; 87] = [
+    "", "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al",
+    "Si", "P", "S", "Cl", "Ar", "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe",
+    "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr", "Rb", "Sr",
+    "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In", "Sn",
+    "Sb", "Te", "I", "Xe", "Cs", "Ba", "La", "Ce", "Pr", "Nd", "Pm", "Sm",
+    "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu", "Hf", "Ta", "W",
+    "Re", "Os", "Ir", "Pt", "Au", "Hg", "Tl", "Pb", "Bi", "Po", "At", "Rn",
+];
+
+/// Standard atomic masses in daltons for the first four rows of the periodic
+/// table plus iodine. Other elements return `None` from [mass]
+const MASSES: [(u8, f64); 37] = [
+    (1, 1.008),
+    (2, 4.0026),
+    (3, 6.94),
+    (4, 9.0122),
+    (5, 10.81),
+    (6, 12.011),
+    (7, 14.007),
+    (8, 15.999),
+    (9, 18.998),
+    (10, 20.180),
+    (11, 22.990),
+    (12, 24.305),
+    (13, 26.982),
+    (14, 28.085),
+    (15, 30.974),
+    (16, 32.06),
+    (17, 35.45),
+    (18, 39.948),
+    (19, 39.098),
+    (20, 40.078),
+    (21, 44.956),
+    (22, 47.867),
+    (23, 50.942),
+    (24, 51.996),
+    (25, 54.938),
+    (26, 55.845),
+    (27, 58.933),
+    (28, 58.693),
+    (29, 63.546),
+    (30, 65.38),
+    (31, 69.723),
+    (32, 72.630),
+    (33, 74.922),
+    (34, 78.971),
+    (35, 79.904),
+    (36, 83.798),
+    (53, 126.90),
+];
+
+/// Return the atomic number of the element with symbol `symbol`, ignoring case
+/// beyond the first letter. For example, both `Cl` and `CL` return `Some(17)`
+pub fn atomic_number(symbol: &str) -> Option<u8> {
+    SYMBOLS
+        .iter()
+        .skip(1)
+        .position(|s| s.eq_ignore_ascii_case(symbol))
+        .map(|i| i as u8 + 1)
+}
+
+/// Return the symbol for `atomic_number`, or `None` if it is out of range
+pub fn symbol(atomic_number: u8) -> Option<&'static str> {
+    match atomic_number {
+        0 => None,
+        n => SYMBOLS.get(n as usize).copied(),
+    }
+}
+
+/// Return the standard atomic mass of `atomic_number` in daltons
+pub fn mass(atomic_number: u8) -> Option<f64> {
+    MASSES
+        .iter()
+        .find(|(n, _)| *n == atomic_number)
+        .map(|(_, m)| *m)
+}
@@ -0,0 +1,216 @@
+//! Internal coordinates and their Cartesian derivatives. Positions are passed
+//! around as flat `[x1, y1, z1, x2, ...]` slices, matching the layout of
+//! QCArchive geometries and [crate::topology::molecule::MoleculeGraph]
+//! conformers.
+
+use nalgebra::Vector3;
+
+/// Return the position of atom `i` in the flat coordinate slice `coords`
+#[inline]
+pub fn position(coords: &[f64], i: usize) -> Vector3<f64> {
+    Vector3::new(coords[3 * i], coords[3 * i + 1], coords[3 * i + 2])
+}
+
+/// Add `v` to the three components of atom `i` in the flat slice `grad`
+#[inline]
+pub(crate) fn accumulate(grad: &mut [f64], i: usize, v: Vector3<f64>) {
+    grad[3 * i] += v.x;
+    grad[3 * i + 1] += v.y;
+    grad[3 * i + 2] += v.z;
+}
+
+/// The distance between atoms `i` and `j`
+pub fn distance(coords: &[f64], i: usize, j: usize) -> f64 {
+    (position(coords, i) - position(coords, j)).norm()
+}
+
+/// The angle in radians between atoms `i`, `j`, and `k`, where `j` is the
+/// central atom
+pub fn angle(coords: &[f64], i: usize, j: usize, k: usize) -> f64 {
+    let u = position(coords, i) - position(coords, j);
+    let v = position(coords, k) - position(coords, j);
+    (u.dot(&v) / (u.norm() * v.norm())).clamp(-1.0, 1.0).acos()
+}
+
+/// The IUPAC dihedral angle in radians, in the range (-π, π], between the
+/// planes formed by atoms `i`, `j`, `k` and `j`, `k`, `l`
+pub fn dihedral(coords: &[f64], i: usize, j: usize, k: usize, l: usize) -> f64 {
+    dihedral_gradient(coords, [i, j, k, l]).0
+}
+
+/// Return the distance between atoms `i` and `j` along with its gradient with
+/// respect to the positions of `i` and `j`
+pub fn distance_gradient(
+    coords: &[f64],
+    [i, j]: [usize; 2],
+) -> (f64, [Vector3<f64>; 2]) {
+    let d = position(coords, i) - position(coords, j);
+    let r = d.norm();
+    let u = d / r;
+    (r, [u, -u])
+}
+
+/// Return the angle `i`-`j`-`k` in radians along with its gradient with respect
+/// to the positions of the three atoms. The gradient is zero for linear angles,
+/// where it is undefined.
+pub fn angle_gradient(
+    coords: &[f64],
+    [i, j, k]: [usize; 3],
+) -> (f64, [Vector3<f64>; 3]) {
+    let u = position(coords, i) - position(coords, j);
+    let v = position(coords, k) - position(coords, j);
+    let (ru, rv) = (u.norm(), v.norm());
+    let (uh, vh) = (u / ru, v / rv);
+    let cos = uh.dot(&vh).clamp(-1.0, 1.0);
+    let theta = cos.acos();
+    let sin = theta.sin();
+    if sin.abs() < 1e-8 {
+        let z = Vector3::zeros();
+        return (theta, [z, z, z]);
+    }
+    let gi = (cos * uh - vh) / (ru * sin);
+    let gk = (cos * vh - uh) / (rv * sin);
+    (theta, [gi, -gi - gk, gk])
+}
+
+/// Return the dihedral `i`-`j`-`k`-`l` in radians along with its gradient with
+/// respect to the positions of the four atoms, following Blondel and Karplus,
+/// J. Comput. Chem. 17, 1132 (1996)
+pub fn dihedral_gradient(
+    coords: &[f64],
+    [i, j, k, l]: [usize; 4],
+) -> (f64, [Vector3<f64>; 4]) {
+    let f = position(coords, i) - position(coords, j);
+    let g = position(coords, j) - position(coords, k);
+    let h = position(coords, l) - position(coords, k);
+    let a = f.cross(&g);
+    let b = h.cross(&g);
+    let (a2, b2, gn) = (a.norm_squared(), b.norm_squared(), g.norm());
+    let phi = b.cross(&a).dot(&g).atan2(gn * a.dot(&b));
+    if a2 < 1e-12 || b2 < 1e-12 {
+        let z = Vector3::zeros();
+        return (phi, [z, z, z, z]);
+    }
+    let fg = f.dot(&g);
+    let hg = h.dot(&g);
+    let gi = -gn / a2 * a;
+    let gl = gn / b2 * b;
+    let gj = -gi + fg / (a2 * gn) * a - hg / (b2 * gn) * b;
+    let gk = -gl - fg / (a2 * gn) * a + hg / (b2 * gn) * b;
+    (phi, [gi, gj, gk, gl])
+}
+
+/// Translate `coords` in place so that their (unweighted) centroid lies at the
+/// origin
+fn center(coords: &mut [Vector3<f64>]) {
+    let n = coords.len() as f64;
+    let c = coords.iter().sum::<Vector3<f64>>() / n;
+    for x in coords {
+        *x -= c;
+    }
+}
+
+/// Return the root-mean-square deviation between the atoms `atoms` of `a` and
+/// `b` after optimally superimposing them with the Kabsch algorithm. If `map`
+/// is provided, atom `atoms[n]` in `a` is compared with atom `map[n]` in `b`,
+/// allowing symmetry-equivalent atoms to be swapped.
+pub fn kabsch_rmsd(
+    a: &[f64],
+    b: &[f64],
+    atoms: &[usize],
+    map: Option<&[usize]>,
+) -> f64 {
+    let mut p: Vec<_> = atoms.iter().map(|&i| position(a, i)).collect();
+    let mut q: Vec<_> = match map {
+        Some(map) => map.iter().map(|&i| position(b, i)).collect(),
+        None => atoms.iter().map(|&i| position(b, i)).collect(),
+    };
+    if p.is_empty() {
+        return 0.0;
+    }
+    center(&mut p);
+    center(&mut q);
+
+    let mut h = nalgebra::Matrix3::zeros();
+    for (x, y) in p.iter().zip(&q) {
+        h += x * y.transpose();
+    }
+    let svd = h.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    let d = (v_t.transpose() * u.transpose()).determinant().signum();
+    let s = nalgebra::Matrix3::from_diagonal(&Vector3::new(1.0, 1.0, d));
+    let r = v_t.transpose() * s * u.transpose();
+
+    let n = p.len() as f64;
+    let sum: f64 = p
+        .iter()
+        .zip(&q)
+        .map(|(x, y)| (r * x - y).norm_squared())
+        .sum();
+    (sum / n).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// check an analytic gradient against central finite differences
+    fn check_gradient<const N: usize>(
+        coords: &[f64],
+        atoms: [usize; N],
+        f: impl Fn(&[f64]) -> (f64, [Vector3<f64>; N]),
+    ) {
+        let (_, got) = f(coords);
+        let h = 1e-6;
+        for (n, &atom) in atoms.iter().enumerate() {
+            for c in 0..3 {
+                let mut fwd = coords.to_vec();
+                fwd[3 * atom + c] += h;
+                let mut bwd = coords.to_vec();
+                bwd[3 * atom + c] -= h;
+                let want = (f(&fwd).0 - f(&bwd).0) / (2.0 * h);
+                assert!(
+                    (got[n][c] - want).abs() < 1e-6,
+                    "atom {atom} component {c}: got {}, want {want}",
+                    got[n][c]
+                );
+            }
+        }
+    }
+
+    const COORDS: [f64; 12] =
+        [0.1, 1.2, 0.3, 0.0, 0.0, 0.0, 1.5, 0.1, -0.2, 1.9, 1.1, 0.8];
+
+    #[test]
+    fn gradients() {
+        check_gradient(&COORDS, [0, 1], |c| distance_gradient(c, [0, 1]));
+        check_gradient(&COORDS, [0, 1, 2], |c| angle_gradient(c, [0, 1, 2]));
+        check_gradient(&COORDS, [0, 1, 2, 3], |c| {
+            dihedral_gradient(c, [0, 1, 2, 3])
+        });
+    }
+
+    #[test]
+    fn iupac_dihedral() {
+        // cis is 0 and a perpendicular twist is ±90
+        let cis = [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0];
+        assert!(dihedral(&cis, 0, 1, 2, 3).abs() < 1e-12);
+        let twist =
+            [0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 1.0];
+        let got = dihedral(&twist, 0, 1, 2, 3).to_degrees();
+        assert!((got.abs() - 90.0).abs() < 1e-10);
+    }
+
+    #[test]
+    fn rmsd_of_rotated_copy() {
+        let (s, c) = (0.3_f64.sin(), 0.3_f64.cos());
+        let mut rotated = COORDS;
+        for i in 0..4 {
+            let (x, y) = (COORDS[3 * i], COORDS[3 * i + 1]);
+            rotated[3 * i] = c * x - s * y + 5.0;
+            rotated[3 * i + 1] = s * x + c * y - 2.0;
+        }
+        let got = kabsch_rmsd(&COORDS, &rotated, &[0, 1, 2, 3], None);
+        assert!(got < 1e-10, "{got}");
+    }
+}
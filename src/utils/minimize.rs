@@ -0,0 +1,165 @@
+//! Limited-memory BFGS minimization of smooth functions
+
+use std::collections::VecDeque;
+
+/// Settings for [lbfgs]
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// the number of correction pairs used to approximate the inverse Hessian
+    pub memory: usize,
+
+    /// the maximum number of iterations before giving up
+    pub max_iterations: usize,
+
+    /// convergence threshold on the largest gradient component
+    pub gradient_tolerance: f64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            memory: 10,
+            max_iterations: 5000,
+            gradient_tolerance: 1e-4,
+        }
+    }
+}
+
+/// The result of a minimization
+#[derive(Clone, Debug)]
+pub struct Minimum {
+    pub x: Vec<f64>,
+    pub value: f64,
+    pub iterations: usize,
+    pub converged: bool,
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn max_abs(a: &[f64]) -> f64 {
+    a.iter().fold(0.0, |m, x| x.abs().max(m))
+}
+
+/// Minimize `f`, which returns the function value and its gradient at a point,
+/// starting from `x0`. A backtracking line search enforcing the Armijo
+/// condition is used at each step.
+pub fn lbfgs<F>(mut f: F, x0: &[f64], options: Options) -> Minimum
+where
+    F: FnMut(&[f64]) -> (f64, Vec<f64>),
+{
+    let mut x = x0.to_vec();
+    let (mut value, mut grad) = f(&x);
+    let mut history: VecDeque<(Vec<f64>, Vec<f64>, f64)> = VecDeque::new();
+
+    for iteration in 0..options.max_iterations {
+        if max_abs(&grad) < options.gradient_tolerance {
+            return Minimum {
+                x,
+                value,
+                iterations: iteration,
+                converged: true,
+            };
+        }
+
+        // two-loop recursion for the search direction
+        let mut q = grad.clone();
+        let mut alphas = Vec::with_capacity(history.len());
+        for (s, y, rho) in history.iter().rev() {
+            let alpha = rho * dot(s, &q);
+            q.iter_mut().zip(y).for_each(|(q, y)| *q -= alpha * y);
+            alphas.push(alpha);
+        }
+        if let Some((s, y, _)) = history.back() {
+            let gamma = dot(s, y) / dot(y, y);
+            q.iter_mut().for_each(|q| *q *= gamma);
+        }
+        for ((s, y, rho), alpha) in history.iter().zip(alphas.iter().rev()) {
+            let beta = rho * dot(y, &q);
+            q.iter_mut()
+                .zip(s)
+                .for_each(|(q, s)| *q += (alpha - beta) * s);
+        }
+        let mut dir: Vec<f64> = q.into_iter().map(|q| -q).collect();
+
+        // fall back on steepest descent if the approximation is not
+        // positive definite
+        let mut slope = dot(&grad, &dir);
+        if slope >= 0.0 {
+            history.clear();
+            dir = grad.iter().map(|g| -g).collect();
+            slope = dot(&grad, &dir);
+        }
+
+        // without history, keep the first step from being enormous
+        let mut step = if history.is_empty() {
+            (1.0 / max_abs(&dir)).min(1.0)
+        } else {
+            1.0
+        };
+        let (new_x, new_value, new_grad) = loop {
+            let trial: Vec<f64> =
+                x.iter().zip(&dir).map(|(x, d)| x + step * d).collect();
+            let (v, g) = f(&trial);
+            if v <= value + 1e-4 * step * slope {
+                break (trial, v, g);
+            }
+            step *= 0.5;
+            if step < 1e-16 {
+                // no further progress is possible along any direction
+                return Minimum {
+                    x,
+                    value,
+                    iterations: iteration,
+                    converged: false,
+                };
+            }
+        };
+
+        let s: Vec<f64> = new_x.iter().zip(&x).map(|(a, b)| a - b).collect();
+        let y: Vec<f64> =
+            new_grad.iter().zip(&grad).map(|(a, b)| a - b).collect();
+        let sy = dot(&s, &y);
+        if sy > 1e-12 {
+            if history.len() == options.memory {
+                history.pop_front();
+            }
+            history.push_back((s, y, 1.0 / sy));
+        }
+
+        x = new_x;
+        value = new_value;
+        grad = new_grad;
+    }
+
+    let converged = max_abs(&grad) < options.gradient_tolerance;
+    Minimum {
+        x,
+        value,
+        iterations: options.max_iterations,
+        converged,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rosenbrock() {
+        let f = |x: &[f64]| {
+            let (a, b) = (x[0], x[1]);
+            let v = (1.0 - a).powi(2) + 100.0 * (b - a * a).powi(2);
+            let g = vec![
+                -2.0 * (1.0 - a) - 400.0 * a * (b - a * a),
+                200.0 * (b - a * a),
+            ];
+            (v, g)
+        };
+        let got = lbfgs(f, &[-1.2, 1.0], Options::default());
+        assert!(got.converged);
+        assert!((got.x[0] - 1.0).abs() < 1e-4);
+        assert!((got.x[1] - 1.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,238 @@
+//! Writing NumPy `.npy` arrays and uncompressed `.npz` archives, as read by
+//! `numpy.load`. Only little-endian `f8` and `i8` arrays in C order are
+//! supported.
+
+use std::{fs::write, io, path::Path};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Data {
+    F64(Vec<f64>),
+    I64(Vec<i64>),
+}
+
+/// An n-dimensional array stored in row-major order
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    shape: Vec<usize>,
+    data: Data,
+}
+
+impl NpyArray {
+    /// Construct an array of `f64` with `shape`. Panics if the number of
+    /// elements in `data` does not match `shape`.
+    pub fn f64(shape: &[usize], data: Vec<f64>) -> Self {
+        assert_eq!(shape.iter().product::<usize>(), data.len());
+        Self {
+            shape: shape.to_vec(),
+            data: Data::F64(data),
+        }
+    }
+
+    /// Construct an array of `i64` with `shape`. Panics if the number of
+    /// elements in `data` does not match `shape`.
+    pub fn i64(shape: &[usize], data: Vec<i64>) -> Self {
+        assert_eq!(shape.iter().product::<usize>(), data.len());
+        Self {
+            shape: shape.to_vec(),
+            data: Data::I64(data),
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Serialize the array in version 1.0 of the `.npy` format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let descr = match self.data {
+            Data::F64(_) => "<f8",
+            Data::I64(_) => "<i8",
+        };
+        let shape = match self.shape.as_slice() {
+            [n] => format!("({n},)"),
+            s => format!(
+                "({})",
+                s.iter()
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let mut header = format!(
+            "{{'descr': '{descr}', 'fortran_order': False, 'shape': {shape}, }}"
+        );
+        // the magic string, version, and header length take 10 bytes, and the
+        // header is padded with spaces and a newline so that the data starts
+        // on a multiple of 64 bytes
+        let total = (10 + header.len() + 1).div_ceil(64) * 64;
+        header.extend(std::iter::repeat_n(' ', total - 10 - header.len() - 1));
+        header.push('\n');
+
+        let mut ret = Vec::with_capacity(total + 8 * self.len());
+        ret.extend(b"\x93NUMPY\x01\x00");
+        ret.extend((header.len() as u16).to_le_bytes());
+        ret.extend(header.as_bytes());
+        match &self.data {
+            Data::F64(v) => v.iter().for_each(|x| ret.extend(x.to_le_bytes())),
+            Data::I64(v) => v.iter().for_each(|x| ret.extend(x.to_le_bytes())),
+        }
+        ret
+    }
+
+    fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// Write the array to a `.npy` file at `path`
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write(path, self.to_bytes())
+    }
+}
+
+/// The CRC-32 checksum used by the zip format
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Serialize the named `arrays` as an uncompressed zip archive, the format
+/// written by `numpy.savez`. Each array is stored as `{name}.npy`.
+pub fn npz_bytes(arrays: &[(&str, &NpyArray)]) -> io::Result<Vec<u8>> {
+    let too_large =
+        || io::Error::new(io::ErrorKind::InvalidInput, "npz archive too large");
+    // 1980-01-01 00:00, the earliest date the zip format can represent
+    const DATE: u16 = 0x21;
+    const VERSION: u16 = 20;
+
+    let mut ret = Vec::new();
+    let mut central = Vec::new();
+    for (name, array) in arrays {
+        let name = format!("{name}.npy");
+        let data = array.to_bytes();
+        let crc = crc32(&data);
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(ret.len()).map_err(|_| too_large())?;
+        let name_len = name.len() as u16;
+
+        // fields shared by the local and central headers: version needed,
+        // flags, method (stored), time, date, crc, sizes, and name length
+        let mut common = Vec::new();
+        common.extend(VERSION.to_le_bytes());
+        common.extend(0u16.to_le_bytes());
+        common.extend(0u16.to_le_bytes());
+        common.extend(0u16.to_le_bytes());
+        common.extend(DATE.to_le_bytes());
+        common.extend(crc.to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend(size.to_le_bytes());
+        common.extend(name_len.to_le_bytes());
+
+        ret.extend(0x04034b50u32.to_le_bytes());
+        ret.extend(&common);
+        ret.extend(0u16.to_le_bytes()); // extra field length
+        ret.extend(name.as_bytes());
+        ret.extend(data);
+
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(VERSION.to_le_bytes()); // version made by
+        central.extend(&common);
+        central.extend([0u8; 12]); // extra, comment, disk, and attributes
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+    }
+
+    let n = u16::try_from(arrays.len()).map_err(|_| too_large())?;
+    let central_offset = u32::try_from(ret.len()).map_err(|_| too_large())?;
+    let central_len = central.len() as u32;
+    ret.extend(central);
+    ret.extend(0x06054b50u32.to_le_bytes());
+    ret.extend([0u8; 4]); // disk numbers
+    ret.extend(n.to_le_bytes());
+    ret.extend(n.to_le_bytes());
+    ret.extend(central_len.to_le_bytes());
+    ret.extend(central_offset.to_le_bytes());
+    ret.extend(0u16.to_le_bytes()); // comment length
+    Ok(ret)
+}
+
+/// Write the named `arrays` to an `.npz` archive at `path`
+pub fn write_npz(
+    path: impl AsRef<Path>,
+    arrays: &[(&str, &NpyArray)],
+) -> io::Result<()> {
+    write(path, npz_bytes(arrays)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(b: &[u8], i: usize) -> usize {
+        u16::from_le_bytes([b[i], b[i + 1]]) as usize
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes(b[i..i + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn npy() {
+        let a = NpyArray::f64(&[2, 3], vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let got = a.to_bytes();
+        assert_eq!(&got[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16_at(&got, 8);
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&got[10..10 + header_len]).unwrap();
+        assert!(header.starts_with(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (2, 3), }"
+        ));
+        assert!(header.ends_with('\n'));
+        assert_eq!(got.len(), 10 + header_len + 48);
+        assert_eq!(&got[got.len() - 8..], 6.0f64.to_le_bytes());
+
+        let b = NpyArray::i64(&[3], vec![1, 2, 3]).to_bytes();
+        let header = std::str::from_utf8(&b[10..10 + u16_at(&b, 8)]).unwrap();
+        assert!(header.contains("'descr': '<i8'"));
+        assert!(header.contains("'shape': (3,)"));
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn npz() {
+        let a = NpyArray::i64(&[2], vec![7, 8]);
+        let b = NpyArray::f64(&[0], Vec::new());
+        let got = npz_bytes(&[("a", &a), ("b", &b)]).unwrap();
+
+        // walk the local file headers
+        let mut offset = 0;
+        for (name, array) in [("a.npy", &a), ("b.npy", &b)] {
+            assert_eq!(u32_at(&got, offset), 0x04034b50);
+            let size = u32_at(&got, offset + 18) as usize;
+            let name_len = u16_at(&got, offset + 26);
+            let start = offset + 30 + name_len;
+            assert_eq!(&got[offset + 30..start], name.as_bytes());
+            let data = &got[start..start + size];
+            assert_eq!(data, array.to_bytes());
+            assert_eq!(u32_at(&got, offset + 14), crc32(data));
+            offset = start + size;
+        }
+
+        // the end of central directory record points back to the directory
+        let end = got.len() - 22;
+        assert_eq!(u32_at(&got, end), 0x06054b50);
+        assert_eq!(u16_at(&got, end + 10), 2);
+        assert_eq!(u32_at(&got, end + 16) as usize, offset);
+        assert_eq!(u32_at(&got, offset), 0x02014b50);
+    }
+}